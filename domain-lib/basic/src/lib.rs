@@ -10,9 +10,24 @@ use alloc::sync::Arc;
 
 use corelib::domain_info::DomainInfo;
 pub use corelib::{
-    backtrace, blk_crash_trick, checkout_shared_data, create_domain, get_domain, impl_has_timer,
-    kernel, new_mutex, new_spinlock, register_domain, reload_domain, update_domain, write_console,
-    CoreFunction, LinuxError, LinuxResult, SafePtr,
+    audit, backtrace, bench_pinned_path, bind_domain_log, blk_crash_trick, block_domain_pause,
+    block_domain_resume, call_canceled, cancel_call, check_upgrade_compat, checkout_shared_data,
+    clone_domain_with_state, compact_shared_heap, create_domain, create_domain_id,
+    create_domain_with_id, create_domains, device_read_interruptible, domain_affinity, domain_call,
+    domain_call_counts, domain_call_timeout, domain_describe, domain_exists, domain_idle_ms,
+    domain_is_ready, domain_is_upgrading, domain_latency, domain_load_info, domain_local_alloc,
+    domain_local_get, domain_memory_map, domain_metrics_reset, domain_nice, domain_set_affinity,
+    domain_touch, domain_type, domain_unready_calls, domain_warmup, domain_yield,
+    export_domain_graph, force_srcu_barrier, frame_bits, frame_size, freeze_domain, get_domain,
+    get_domain_id, get_domain_tags, impl_has_timer, inject_latency, kernel, list_domains_filtered,
+    new_mutex, new_spinlock, read_domain_log, register_domain, register_domain_begin,
+    register_domain_chunk, register_domain_finish, reload_domain, rename_domain, reserve_domain_id,
+    restart_domain, restore_domain, set_cache_mode, set_domain_nice, set_domain_policy,
+    set_domain_rate_limit, set_domain_tag, set_log_prefix, set_panic_policy, set_queue_depth,
+    set_registry_reloadable, set_upgrade_freeze, set_upgrade_reserve, shared_data_owner,
+    shutdown_all, snapshot_domain, thaw_domain, trim_registry, trim_registry_all,
+    unregister_domain, update_domain, update_domain_with, upgrade_history, wait_domain_quiescent,
+    wait_domain_ready, write_console, CoreFunction, LinuxError, LinuxResult, SafePtr,
 };
 pub use domain_main::domain_main;
 use ksync::Mutex;
@@ -2,6 +2,8 @@ use alloc::{collections::BTreeMap, string::String, vec::Vec};
 use core::fmt::Display;
 
 use interface::DomainTypeRaw;
+use pconst::LinuxErrno;
+use rref::wire::{Decode, Decoder, Encode, Encoder};
 
 #[derive(Debug, Default)]
 pub struct DomainInfo {
@@ -38,12 +40,92 @@ impl Display for DomainInfo {
     }
 }
 
+impl DomainInfo {
+    /// The domains of type `ty` whose name starts with `name_prefix`, sorted by id, skipping
+    /// the first `offset` of them and keeping at most `limit`. `None` matches all the types.
+    pub fn list_page(
+        &self,
+        ty: Option<DomainTypeRaw>,
+        name_prefix: &str,
+        offset: usize,
+        limit: usize,
+    ) -> DomainPage {
+        let matches = self
+            .domain_list
+            .iter()
+            .filter(|(_, data)| ty.is_none_or(|ty| data.ty == ty))
+            .filter(|(_, data)| data.name.starts_with(name_prefix));
+        let total = matches.clone().count();
+        let (ids, domains) = matches
+            .skip(offset)
+            .take(limit)
+            .map(|(&id, data)| (id, data.clone()))
+            .unzip();
+        DomainPage {
+            ids,
+            domains,
+            total,
+        }
+    }
+
+    /// The id of the domain which name is `name`
+    pub fn domain_id(&self, name: &str) -> Option<u64> {
+        self.domain_list
+            .iter()
+            .find(|(_, data)| data.name == name)
+            .map(|(&id, _)| id)
+    }
+
+    /// Whether shared data can be moved to `domain_id`, which must be a registered domain
+    /// or the kernel `kernel_id`. The data moved to any other id is orphaned.
+    pub fn is_move_target(&self, domain_id: u64, kernel_id: u64) -> bool {
+        domain_id == kernel_id || self.domain_list.contains_key(&domain_id)
+    }
+}
+
+/// A page of the domains matching a filter, see [DomainInfo::list_page]
+#[derive(Debug, Clone)]
+pub struct DomainPage {
+    /// The ids of the domains of the page
+    pub ids: Vec<u64>,
+    /// The domains of the page, in the order of `ids`
+    pub domains: Vec<DomainDataInfo>,
+    /// The number of all the domains matching the filter
+    pub total: usize,
+}
+
+impl Encode for DomainPage {
+    fn encode_to(&self, encoder: &mut Encoder) {
+        encoder.put(&self.ids);
+        encoder.put(&self.domains);
+        encoder.put(&self.total);
+    }
+}
+
+impl Decode for DomainPage {
+    fn decode_from(decoder: &mut Decoder) -> Result<Self, LinuxErrno> {
+        let ids: Vec<u64> = decoder.get()?;
+        let domains: Vec<DomainDataInfo> = decoder.get()?;
+        if ids.len() != domains.len() {
+            return Err(LinuxErrno::EINVAL);
+        }
+        Ok(Self {
+            ids,
+            domains,
+            total: decoder.get()?,
+        })
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct DomainDataInfo {
     pub name: String,
     pub ty: DomainTypeRaw,
     pub panic_count: usize,
     pub file_info: DomainFileInfo,
+    /// The labels attached by the operator with `sys_set_domain_tag`, they are kept across
+    /// the upgrades
+    pub tags: BTreeMap<String, String>,
 }
 
 #[derive(Debug, Clone)]
@@ -57,3 +139,852 @@ impl DomainFileInfo {
         Self { name, size }
     }
 }
+
+impl Encode for DomainFileInfo {
+    fn encode_to(&self, encoder: &mut Encoder) {
+        encoder.put(&self.name);
+        encoder.put(&self.size);
+    }
+}
+
+impl Decode for DomainFileInfo {
+    fn decode_from(decoder: &mut Decoder) -> Result<Self, LinuxErrno> {
+        Ok(Self {
+            name: decoder.get()?,
+            size: decoder.get()?,
+        })
+    }
+}
+
+impl Encode for DomainDataInfo {
+    fn encode_to(&self, encoder: &mut Encoder) {
+        encoder.put(&self.name);
+        encoder.put(&(self.ty as u8));
+        encoder.put(&self.panic_count);
+        encoder.put(&self.file_info);
+        encoder.put(&self.tags.keys().cloned().collect::<Vec<_>>());
+        encoder.put(&self.tags.values().cloned().collect::<Vec<_>>());
+    }
+}
+
+impl Decode for DomainDataInfo {
+    fn decode_from(decoder: &mut Decoder) -> Result<Self, LinuxErrno> {
+        let name = decoder.get()?;
+        let ty = DomainTypeRaw::try_from(decoder.get::<u8>()?).map_err(|_| LinuxErrno::EINVAL)?;
+        Ok(Self {
+            name,
+            ty,
+            panic_count: decoder.get()?,
+            file_info: decoder.get()?,
+            tags: decode_tags(decoder)?,
+        })
+    }
+}
+
+fn decode_tags(decoder: &mut Decoder) -> Result<BTreeMap<String, String>, LinuxErrno> {
+    let keys: Vec<String> = decoder.get()?;
+    let values: Vec<String> = decoder.get()?;
+    if keys.len() != values.len() {
+        return Err(LinuxErrno::EINVAL);
+    }
+    Ok(keys.into_iter().zip(values).collect())
+}
+
+/// The ELF image a domain is running
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DomainLoadInfo {
+    /// The name the ELF is registered with
+    pub name: String,
+    /// The size of the ELF data
+    pub size: usize,
+    /// The virtual address the ELF is loaded at, 0 if it is not loaded
+    pub base: usize,
+    /// The virtual address of the entry point, 0 if it is not loaded
+    pub entry: usize,
+}
+
+impl Encode for DomainLoadInfo {
+    fn encode_to(&self, encoder: &mut Encoder) {
+        encoder.put(&self.name);
+        encoder.put(&self.size);
+        encoder.put(&self.base);
+        encoder.put(&self.entry);
+    }
+}
+
+impl Decode for DomainLoadInfo {
+    fn decode_from(decoder: &mut Decoder) -> Result<Self, LinuxErrno> {
+        Ok(Self {
+            name: decoder.get()?,
+            size: decoder.get()?,
+            base: decoder.get()?,
+            entry: decoder.get()?,
+        })
+    }
+}
+
+/// What the watchdog does when a call into a domain panics
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PanicAction {
+    /// Restart the domain from its ELF without migrating its state, until it has been
+    /// restarted `max_restarts` times, then disable it
+    Restart,
+    /// Fail all the later calls into the domain with `EIO`
+    Disable,
+    /// Only return the error of the call
+    Ignore,
+    /// Panic the kernel, so that a crash dump of the failing domain is taken
+    Abort,
+}
+
+impl PanicAction {
+    /// Whether the shared heap allocations the panicked call made and did not return are
+    /// freed
+    ///
+    /// The domain may have stored some of them in its own state, so they are only freed
+    /// when the domain never runs again.
+    pub fn reclaims_call_allocations(self) -> bool {
+        matches!(self, PanicAction::Restart | PanicAction::Disable)
+    }
+}
+
+/// How a domain responds to a panicking call, set by `sys_set_panic_policy`
+///
+/// A watchdog policy set by `sys_set_domain_policy` is more specific, so it is followed by
+/// `Unwind` and `Fence`, which only differ for the domains without one.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PanicPolicy {
+    /// Unwind the call and recover, only the call fails
+    Unwind,
+    /// Panic the whole kernel, for debugging with a crash dump
+    Abort,
+    /// Disable the domain and continue
+    #[default]
+    Fence,
+}
+
+impl PanicPolicy {
+    /// What the proxy does with a panicked call, `watchdog` is the action of the watchdog
+    /// policy of the domain, if it has one
+    pub fn action(self, watchdog: Option<PanicAction>) -> PanicAction {
+        match self {
+            PanicPolicy::Abort => PanicAction::Abort,
+            PanicPolicy::Unwind => watchdog.unwrap_or(PanicAction::Ignore),
+            PanicPolicy::Fence => watchdog.unwrap_or(PanicAction::Disable),
+        }
+    }
+}
+
+/// A hot upgrade of a domain
+#[derive(Debug, Clone)]
+pub struct UpgradeRecord {
+    /// When the upgrade happened, in nanoseconds of `CLOCK_MONOTONIC`
+    pub timestamp_ns: i64,
+    /// The ELF name of the old domain
+    pub from: String,
+    /// The ELF name of the new domain
+    pub to: String,
+    pub success: bool,
+    /// How many times the proxy polled for the in-flight readers to drain
+    pub drain_iterations: usize,
+}
+
+impl Encode for UpgradeRecord {
+    fn encode_to(&self, encoder: &mut Encoder) {
+        encoder.put(&self.timestamp_ns);
+        encoder.put(&self.from);
+        encoder.put(&self.to);
+        encoder.put(&self.success);
+        encoder.put(&self.drain_iterations);
+    }
+}
+
+impl Decode for UpgradeRecord {
+    fn decode_from(decoder: &mut Decoder) -> Result<Self, LinuxErrno> {
+        Ok(Self {
+            timestamp_ns: decoder.get()?,
+            from: decoder.get()?,
+            to: decoder.get()?,
+            success: decoder.get()?,
+            drain_iterations: decoder.get()?,
+        })
+    }
+}
+
+/// The live shared heap allocations counted by `checkout_shared_data`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SharedDataReport {
+    /// The number of the allocations
+    pub total: usize,
+    /// The number of the domains owning them
+    pub owners: usize,
+    /// The number of the allocations owned by a domain which is not live, they are leaked
+    /// until the domain id is reused
+    pub orphaned: usize,
+}
+
+impl SharedDataReport {
+    /// Make the report from the number of allocations owned by each domain, the
+    /// allocations of the domains which are not `live` are orphaned
+    pub fn from_owners(owners: &BTreeMap<u64, usize>, live: impl Fn(u64) -> bool) -> Self {
+        Self {
+            total: owners.values().sum(),
+            owners: owners.len(),
+            orphaned: owners
+                .iter()
+                .filter(|(&id, _)| !live(id))
+                .map(|(_, &count)| count)
+                .sum(),
+        }
+    }
+}
+
+/// A live shared heap allocation, see [SharedMemoryMap]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SharedAllocation {
+    pub addr: usize,
+    pub size: usize,
+    /// The `TypeId` of the value, formatted with `Debug`. The type names are only known
+    /// to the domain which made the allocation
+    pub type_id: String,
+    /// The id set by `RRef::set_trace_id`, 0 if the data is not traced
+    pub trace_id: u64,
+}
+
+impl Encode for SharedAllocation {
+    fn encode_to(&self, encoder: &mut Encoder) {
+        encoder.put(&self.addr);
+        encoder.put(&self.size);
+        encoder.put(&self.type_id);
+        encoder.put(&self.trace_id);
+    }
+}
+
+impl Decode for SharedAllocation {
+    fn decode_from(decoder: &mut Decoder) -> Result<Self, LinuxErrno> {
+        Ok(Self {
+            addr: decoder.get()?,
+            size: decoder.get()?,
+            type_id: decoder.get()?,
+            trace_id: decoder.get()?,
+        })
+    }
+}
+
+/// The live shared heap allocations owned by a domain, returned by `sys_domain_memory_map`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SharedMemoryMap {
+    /// The allocations with the lowest addresses, sorted by address
+    pub allocations: Vec<SharedAllocation>,
+    /// The number of all the allocations, it is larger than `allocations.len()` if the map
+    /// is truncated
+    pub total: usize,
+    /// The bytes of all the allocations
+    pub bytes: usize,
+}
+
+impl SharedMemoryMap {
+    /// Make the map from the allocations sorted by address, keeping at most `limit` of them
+    pub fn from_sorted(allocations: impl Iterator<Item = SharedAllocation>, limit: usize) -> Self {
+        let mut map = Self::default();
+        for allocation in allocations {
+            map.total += 1;
+            map.bytes += allocation.size;
+            if map.allocations.len() < limit {
+                map.allocations.push(allocation);
+            }
+        }
+        map
+    }
+}
+
+impl Encode for SharedMemoryMap {
+    fn encode_to(&self, encoder: &mut Encoder) {
+        encoder.put(&self.allocations);
+        encoder.put(&self.total);
+        encoder.put(&self.bytes);
+    }
+}
+
+impl Decode for SharedMemoryMap {
+    fn decode_from(decoder: &mut Decoder) -> Result<Self, LinuxErrno> {
+        Ok(Self {
+            allocations: decoder.get()?,
+            total: decoder.get()?,
+            bytes: decoder.get()?,
+        })
+    }
+}
+
+/// Which shared data of the old domain a hot upgrade moves to the new domain
+///
+/// The shared data is told apart by the class set by `RRef::set_resource_class`: a domain
+/// puts e.g. its caches in a class, and the upgrade frees them instead of moving them. The
+/// default moves all the shared data.
+///
+/// The data moved to the new domain must not refer to the freed data, e.g. a buffer which
+/// records a cache RRef, as the new domain inherits the reference and it dangles. A domain
+/// which puts data in a class must keep it out of everything it hands over.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReplaceOptions {
+    /// Free all the shared data, nothing is moved
+    pub free_all: bool,
+    /// Free the shared data in one of these resource classes
+    pub free_classes: Vec<u64>,
+}
+
+impl ReplaceOptions {
+    /// Free the shared data in the resource class `class` instead of moving it
+    pub fn free_class(mut self, class: u64) -> Self {
+        self.free_classes.push(class);
+        self
+    }
+
+    /// Whether the shared data in the resource class `class` is freed, the data without a
+    /// class is only freed by `free_all`
+    pub fn frees(&self, class: u64) -> bool {
+        self.free_all || (class != 0 && self.free_classes.contains(&class))
+    }
+}
+
+/// The upper bounds in nanoseconds of the buckets of a domain call latency histogram, the
+/// last bucket counts the calls slower than all of them
+pub const LATENCY_BUCKETS_NS: [u64; 7] =
+    [1_000, 4_000, 16_000, 64_000, 256_000, 1_000_000, 4_000_000];
+
+/// The number of the calls of a method, see `sys_domain_call_counts`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MethodCount {
+    pub method: String,
+    pub calls: u64,
+}
+
+impl Encode for MethodCount {
+    fn encode_to(&self, encoder: &mut Encoder) {
+        encoder.put(&self.method);
+        encoder.put(&self.calls);
+    }
+}
+
+impl Decode for MethodCount {
+    fn decode_from(decoder: &mut Decoder) -> Result<Self, LinuxErrno> {
+        Ok(Self {
+            method: decoder.get()?,
+            calls: decoder.get()?,
+        })
+    }
+}
+
+/// The fewest requests a block queue accepts, like `BLKDEV_MIN_RQ` of the kernel
+pub const BLKDEV_MIN_RQ: u32 = 4;
+
+/// Whether a block queue can accept `depth` requests, see `sys_set_queue_depth`
+///
+/// The depth must be within [BLKDEV_MIN_RQ] and the queue depth `max` of the tag set, and
+/// leave some tags beside the `reserved_tags` of the tag set.
+pub fn queue_depth_valid(depth: u32, reserved_tags: u32, max: u32) -> bool {
+    (BLKDEV_MIN_RQ..=max).contains(&depth) && depth > reserved_tags
+}
+
+/// An output of a domain captured by its log sink
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogEntry {
+    /// The sequence number, it increases by 1 for each output of the domain
+    pub seq: u64,
+    pub text: String,
+}
+
+/// The entries returned by `sys_read_domain_log`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LogTail {
+    /// The entries whose sequence number is not less than the one asked for
+    pub entries: Vec<LogEntry>,
+    /// The sequence number to ask for next time to get only the new entries
+    pub next_seq: u64,
+    /// How many entries after the one asked for were overwritten before being read
+    pub missed: u64,
+}
+
+/// A domain to create with `sys_create_domains`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ManifestEntry {
+    /// The name the ELF is registered with
+    pub file: String,
+    /// The identifier to register the new domain with
+    pub identifier: String,
+    pub ty: DomainTypeRaw,
+    /// The config of the domain in the [rref::wire] format, empty for the default config
+    pub args: Vec<u8>,
+    /// The identifiers of the entries which must be created before this one
+    pub deps: Vec<String>,
+    /// All the domains created from the manifest are removed if this one fails
+    pub required: bool,
+}
+
+impl Encode for ManifestEntry {
+    fn encode_to(&self, encoder: &mut Encoder) {
+        encoder.put(&self.file);
+        encoder.put(&self.identifier);
+        encoder.put(&(self.ty as u8));
+        encoder.put_bytes(&self.args);
+        encoder.put(&self.deps);
+        encoder.put(&self.required);
+    }
+}
+
+impl Decode for ManifestEntry {
+    fn decode_from(decoder: &mut Decoder) -> Result<Self, LinuxErrno> {
+        let file = decoder.get()?;
+        let identifier = decoder.get()?;
+        let ty = DomainTypeRaw::try_from(decoder.get::<u8>()?).map_err(|_| LinuxErrno::EINVAL)?;
+        Ok(Self {
+            file,
+            identifier,
+            ty,
+            args: decoder.get_bytes()?.to_vec(),
+            deps: decoder.get()?,
+            required: decoder.get()?,
+        })
+    }
+}
+
+/// The result of creating a [ManifestEntry]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CreateOutcome {
+    pub identifier: String,
+    /// The id of the new domain, meaningless if `errno` is not 0
+    pub domain_id: u64,
+    /// 0 if the domain is created, `ENOENT` if one of its dependencies failed and
+    /// `ECANCELED` if it is removed because a required entry failed
+    pub errno: i32,
+}
+
+impl Encode for CreateOutcome {
+    fn encode_to(&self, encoder: &mut Encoder) {
+        encoder.put(&self.identifier);
+        encoder.put(&self.domain_id);
+        encoder.put(&self.errno);
+    }
+}
+
+impl Decode for CreateOutcome {
+    fn decode_from(decoder: &mut Decoder) -> Result<Self, LinuxErrno> {
+        Ok(Self {
+            identifier: decoder.get()?,
+            domain_id: decoder.get()?,
+            errno: decoder.get()?,
+        })
+    }
+}
+
+/// The domains to create with `sys_create_domains`, encoded as `Vec<ManifestEntry>`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Manifest {
+    pub entries: Vec<ManifestEntry>,
+}
+
+impl Encode for Manifest {
+    fn encode_to(&self, encoder: &mut Encoder) {
+        encoder.put(&self.entries);
+    }
+}
+
+impl Decode for Manifest {
+    fn decode_from(decoder: &mut Decoder) -> Result<Self, LinuxErrno> {
+        Ok(Self {
+            entries: decoder.get()?,
+        })
+    }
+}
+
+impl Manifest {
+    /// The indexes of the entries in an order where each entry comes after its
+    /// dependencies, the entries without an order between them keep the manifest order
+    ///
+    /// Return `EINVAL` if an identifier is repeated, a dependency is not in the manifest
+    /// or the dependencies form a cycle.
+    pub fn creation_order(&self) -> Result<Vec<usize>, LinuxErrno> {
+        let mut index = BTreeMap::new();
+        for (i, entry) in self.entries.iter().enumerate() {
+            if index.insert(entry.identifier.as_str(), i).is_some() {
+                return Err(LinuxErrno::EINVAL);
+            }
+        }
+        let mut deps = Vec::with_capacity(self.entries.len());
+        for entry in self.entries.iter() {
+            let ids = entry
+                .deps
+                .iter()
+                .map(|dep| index.get(dep.as_str()).copied().ok_or(LinuxErrno::EINVAL))
+                .collect::<Result<Vec<usize>, _>>()?;
+            deps.push(ids);
+        }
+        let mut placed = alloc::vec![false; self.entries.len()];
+        let mut order = Vec::with_capacity(self.entries.len());
+        while order.len() < self.entries.len() {
+            let next = (0..self.entries.len())
+                .find(|&i| !placed[i] && deps[i].iter().all(|&d| placed[d]))
+                .ok_or(LinuxErrno::EINVAL)?;
+            placed[next] = true;
+            order.push(next);
+        }
+        Ok(order)
+    }
+
+    /// Create the domains in [Manifest::creation_order] with `create`, which returns the id
+    /// of the new domain, and return the outcomes in the manifest order
+    ///
+    /// An entry is not created if one of its dependencies failed. If a required entry
+    /// fails, the domains created so far are removed with `remove` in the reverse order
+    /// and the entries left are not created.
+    pub fn create_all(
+        &self,
+        mut create: impl FnMut(&ManifestEntry) -> Result<u64, LinuxErrno>,
+        mut remove: impl FnMut(&ManifestEntry),
+    ) -> Result<Vec<CreateOutcome>, LinuxErrno> {
+        let order = self.creation_order()?;
+        let mut results = alloc::vec![Err(LinuxErrno::ECANCELED); self.entries.len()];
+        let mut created = Vec::new();
+        for i in order {
+            let entry = &self.entries[i];
+            let deps_ok = entry.deps.iter().all(|dep| {
+                self.entries
+                    .iter()
+                    .zip(results.iter())
+                    .any(|(e, r)| e.identifier == *dep && r.is_ok())
+            });
+            let res = if deps_ok {
+                create(entry)
+            } else {
+                Err(LinuxErrno::ENOENT)
+            };
+            let failed = res.is_err();
+            results[i] = res;
+            if !failed {
+                created.push(i);
+            } else if entry.required {
+                for &j in created.iter().rev() {
+                    remove(&self.entries[j]);
+                    results[j] = Err(LinuxErrno::ECANCELED);
+                }
+                break;
+            }
+        }
+        Ok(self
+            .entries
+            .iter()
+            .zip(results)
+            .map(|(entry, res)| CreateOutcome {
+                identifier: entry.identifier.clone(),
+                domain_id: *res.as_ref().unwrap_or(&0),
+                errno: res.err().map_or(0, |e| e as i32),
+            })
+            .collect())
+    }
+}
+
+/// The smallest identifier buffer accepted by `create_domain`
+pub const IDENTIFIER_BUF_MIN: usize = 32;
+
+/// Check the identifier buffer passed to `create_domain`, return `EINVAL` if it is shorter
+/// than [IDENTIFIER_BUF_MIN]
+///
+/// A longer identifier still fails with `ENOSPC` when the tcb writes it back.
+pub fn check_identifier_buf(buf: &[u8]) -> Result<(), LinuxErrno> {
+    if buf.len() < IDENTIFIER_BUF_MIN {
+        return Err(LinuxErrno::EINVAL);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::string::ToString;
+
+    use super::*;
+
+    #[test]
+    fn test_shared_data_report() {
+        // domain 1 was upgraded to 3 and its data moved, domain 2 was unloaded but its
+        // data was moved to 4 which does not exist
+        let live = |id| id == 0 || id == 3;
+        let mut owners = BTreeMap::new();
+        owners.insert(0, 2);
+        owners.insert(3, 5);
+        owners.insert(4, 1);
+        let report = SharedDataReport::from_owners(&owners, live);
+        assert_eq!(
+            report,
+            SharedDataReport {
+                total: 8,
+                owners: 3,
+                orphaned: 1,
+            }
+        );
+        assert_eq!(
+            SharedDataReport::from_owners(&BTreeMap::new(), live),
+            SharedDataReport::default()
+        );
+    }
+
+    fn entry(identifier: &str, deps: &[&str], required: bool) -> ManifestEntry {
+        ManifestEntry {
+            file: "null".to_string(),
+            identifier: identifier.to_string(),
+            ty: DomainTypeRaw::EmptyDeviceDomain,
+            args: Vec::new(),
+            deps: deps.iter().map(|d| d.to_string()).collect(),
+            required,
+        }
+    }
+
+    #[test]
+    fn test_manifest() {
+        let manifest = Manifest {
+            entries: alloc::vec![
+                entry("client", &["server"], true),
+                entry("server", &[], true),
+                entry("other", &[], false),
+            ],
+        };
+        let decoded = Manifest::decode_from_slice(&manifest.encode_to_vec()).unwrap();
+        assert_eq!(decoded, manifest);
+        // the dependency is created first
+        assert_eq!(manifest.creation_order(), Ok(alloc::vec![1, 0, 2]));
+
+        let mut created = Vec::new();
+        let outcomes = manifest
+            .create_all(
+                |e| {
+                    created.push(e.identifier.clone());
+                    Ok(created.len() as u64)
+                },
+                |_| unreachable!(),
+            )
+            .unwrap();
+        assert_eq!(created, ["server", "client", "other"]);
+        assert_eq!(
+            outcomes
+                .iter()
+                .map(|o| (o.domain_id, o.errno))
+                .collect::<Vec<_>>(),
+            [(2, 0), (1, 0), (3, 0)]
+        );
+
+        // a failed required entry rolls back the domains created before it
+        let mut removed = Vec::new();
+        let outcomes = manifest
+            .create_all(
+                |e| match e.identifier.as_str() {
+                    "client" => Err(LinuxErrno::ENOMEM),
+                    _ => Ok(1),
+                },
+                |e| removed.push(e.identifier.clone()),
+            )
+            .unwrap();
+        assert_eq!(removed, ["server"]);
+        assert_eq!(outcomes[0].errno, LinuxErrno::ENOMEM as i32);
+        assert_eq!(outcomes[1].errno, LinuxErrno::ECANCELED as i32);
+        assert_eq!(outcomes[2].errno, LinuxErrno::ECANCELED as i32);
+
+        // the dependents of a failed optional entry are not created
+        let optional = Manifest {
+            entries: alloc::vec![entry("a", &[], false), entry("b", &["a"], false)],
+        };
+        let outcomes = optional
+            .create_all(|_| Err(LinuxErrno::EIO), |_| unreachable!())
+            .unwrap();
+        assert_eq!(outcomes[1].errno, LinuxErrno::ENOENT as i32);
+
+        let cycle = Manifest {
+            entries: alloc::vec![entry("a", &["b"], false), entry("b", &["a"], false)],
+        };
+        assert_eq!(cycle.creation_order(), Err(LinuxErrno::EINVAL));
+        let unknown = Manifest {
+            entries: alloc::vec![entry("a", &["b"], false)],
+        };
+        assert_eq!(unknown.creation_order(), Err(LinuxErrno::EINVAL));
+    }
+
+    #[test]
+    fn test_panic_action_reclaims() {
+        assert!(PanicAction::Restart.reclaims_call_allocations());
+        assert!(PanicAction::Disable.reclaims_call_allocations());
+        // the domain keeps running and may still use them
+        assert!(!PanicAction::Ignore.reclaims_call_allocations());
+        assert!(!PanicAction::Abort.reclaims_call_allocations());
+        assert!(!PanicPolicy::Unwind.action(None).reclaims_call_allocations());
+        assert!(PanicPolicy::Fence.action(None).reclaims_call_allocations());
+    }
+
+    #[test]
+    fn test_panic_policy() {
+        // fence is the default, the domain is disabled
+        assert_eq!(PanicPolicy::default(), PanicPolicy::Fence);
+        assert_eq!(PanicPolicy::Fence.action(None), PanicAction::Disable);
+        // unwind only fails the call
+        assert_eq!(PanicPolicy::Unwind.action(None), PanicAction::Ignore);
+        // both follow the watchdog policy of the domain
+        for policy in [PanicPolicy::Fence, PanicPolicy::Unwind] {
+            assert_eq!(
+                policy.action(Some(PanicAction::Restart)),
+                PanicAction::Restart
+            );
+            assert_eq!(
+                policy.action(Some(PanicAction::Ignore)),
+                PanicAction::Ignore
+            );
+        }
+        // abort always panics the kernel
+        assert_eq!(PanicPolicy::Abort.action(None), PanicAction::Abort);
+        assert_eq!(
+            PanicPolicy::Abort.action(Some(PanicAction::Restart)),
+            PanicAction::Abort
+        );
+    }
+
+    #[test]
+    fn test_queue_depth_valid() {
+        assert!(queue_depth_valid(64, 0, 128));
+        assert!(queue_depth_valid(128, 0, 128));
+        assert!(queue_depth_valid(BLKDEV_MIN_RQ, 0, 128));
+        assert!(!queue_depth_valid(BLKDEV_MIN_RQ - 1, 0, 128));
+        assert!(!queue_depth_valid(129, 0, 128));
+        // the reserved tags must leave some tags to the normal requests
+        assert!(queue_depth_valid(9, 8, 128));
+        assert!(!queue_depth_valid(8, 8, 128));
+        assert!(!queue_depth_valid(4, 16, 128));
+    }
+
+    #[test]
+    fn test_domain_page() {
+        let mut info = DomainInfo::new();
+        for (id, name, ty) in [
+            (1, "null_0", DomainTypeRaw::EmptyDeviceDomain),
+            (2, "logger", DomainTypeRaw::LogDomain),
+            (3, "null_1", DomainTypeRaw::EmptyDeviceDomain),
+            (4, "null_block", DomainTypeRaw::BlockDeviceDomain),
+            (5, "null_2", DomainTypeRaw::EmptyDeviceDomain),
+        ] {
+            let data = DomainDataInfo {
+                name: name.into(),
+                ty,
+                panic_count: 0,
+                file_info: DomainFileInfo::new("gnull".into(), 4096),
+                tags: BTreeMap::new(),
+            };
+            info.domain_list.insert(id, data);
+        }
+
+        let ty = Some(DomainTypeRaw::EmptyDeviceDomain);
+        let page = info.list_page(ty, "", 0, 2);
+        assert_eq!((page.ids.as_slice(), page.total), (&[1, 3][..], 3));
+        let page = info.list_page(ty, "", 2, 2);
+        assert_eq!((page.ids.as_slice(), page.total), (&[5][..], 3));
+        assert!(info.list_page(ty, "", 3, 2).ids.is_empty());
+
+        let page = info.list_page(None, "null_", 0, 10);
+        assert_eq!((page.ids.as_slice(), page.total), (&[1, 3, 4, 5][..], 4));
+        let page = DomainPage::decode_from_slice(&page.encode_to_vec()).unwrap();
+        assert_eq!(page.domains[2].name, "null_block");
+        assert_eq!(page.total, 4);
+    }
+
+    #[test]
+    fn test_domain_id() {
+        let mut info = DomainInfo::new();
+        let data = |name: &str| DomainDataInfo {
+            name: name.into(),
+            ty: DomainTypeRaw::EmptyDeviceDomain,
+            panic_count: 0,
+            file_info: DomainFileInfo::new("gnull".into(), 4096),
+            tags: BTreeMap::new(),
+        };
+        // a new domain is registered under the id `sys_create_domain_id` returns
+        info.domain_list.insert(3, data("null_0"));
+        info.domain_list.insert(8, data("null_1"));
+        assert_eq!(info.domain_id("null_1"), Some(8));
+        assert_eq!(info.domain_id("null_2"), None);
+
+        // an upgrade moves the record to the id of the new domain
+        let old = info.domain_list.remove(&8).unwrap();
+        info.domain_list.insert(9, old);
+        assert_eq!(info.domain_id("null_1"), Some(9));
+        assert_eq!(info.domain_id("null_0"), Some(3));
+    }
+
+    #[test]
+    fn test_move_target() {
+        let mut info = DomainInfo::new();
+        info.domain_list.insert(
+            3,
+            DomainDataInfo {
+                name: "null".into(),
+                ty: DomainTypeRaw::EmptyDeviceDomain,
+                panic_count: 0,
+                file_info: DomainFileInfo::new("gnull".into(), 4096),
+                tags: BTreeMap::new(),
+            },
+        );
+        assert!(info.is_move_target(3, 0));
+        assert!(info.is_move_target(0, 0));
+        // an id which was never allocated is flagged
+        assert!(!info.is_move_target(42, 0));
+        // so is the old id of an upgraded domain
+        let old = info.domain_list.remove(&3).unwrap();
+        info.domain_list.insert(4, old);
+        assert!(!info.is_move_target(3, 0));
+        assert!(info.is_move_target(4, 0));
+    }
+
+    #[test]
+    fn test_shared_memory_map() {
+        let allocations = (0..5).map(|i| SharedAllocation {
+            addr: 0x1000 * (i + 1),
+            size: 64 << i,
+            type_id: "TypeId(0x1)".into(),
+            trace_id: i as u64,
+        });
+        let map = SharedMemoryMap::from_sorted(allocations, 3);
+        assert_eq!((map.total, map.bytes), (5, 64 + 128 + 256 + 512 + 1024));
+        // the map is truncated, the lowest addresses are kept
+        let addrs = map.allocations.iter().map(|a| a.addr).collect::<Vec<_>>();
+        assert_eq!(addrs, [0x1000, 0x2000, 0x3000]);
+        let decoded = SharedMemoryMap::decode_from_slice(&map.encode_to_vec()).unwrap();
+        assert_eq!(decoded, map);
+    }
+
+    #[test]
+    fn test_replace_options() {
+        const BUFFER: u64 = 1;
+        const CACHE: u64 = 2;
+        let data = [
+            (0x1000, 0),
+            (0x2000, BUFFER),
+            (0x3000, CACHE),
+            (0x4000, CACHE),
+        ];
+        let split = |options: &ReplaceOptions| {
+            let (freed, moved): (Vec<_>, Vec<_>) =
+                data.iter().partition(|(_, class)| options.frees(*class));
+            let addrs = |v: Vec<&(usize, u64)>| v.iter().map(|(a, _)| *a).collect::<Vec<_>>();
+            (addrs(freed), addrs(moved))
+        };
+        // the default moves everything
+        let all = [0x1000, 0x2000, 0x3000, 0x4000];
+        assert_eq!(split(&ReplaceOptions::default()), (vec![], all.to_vec()));
+        // drop the caches, keep the buffers and the data without a class
+        let drop_caches = ReplaceOptions::default().free_class(CACHE);
+        assert_eq!(
+            split(&drop_caches),
+            (vec![0x3000, 0x4000], vec![0x1000, 0x2000])
+        );
+        // the data without a class is not freed by a class
+        assert!(!ReplaceOptions::default().free_class(0).frees(0));
+        let free_all = ReplaceOptions {
+            free_all: true,
+            ..Default::default()
+        };
+        assert_eq!(split(&free_all), (all.to_vec(), vec![]));
+    }
+}
@@ -0,0 +1,291 @@
+// SPDX-License-Identifier: GPL-2.0
+
+//! A write cache for the pages of a memory backed block device
+
+use alloc::boxed::Box;
+
+use crate::{
+    kernel::{
+        error::{linux_err, KernelResult as Result},
+        mm::pages::Pages,
+        radix_tree::RadixTree,
+    },
+    PAGE_SIZE,
+};
+
+/// The pages of a block device by page index
+pub trait PageStore {
+    type Page;
+
+    /// Allocate a zeroed page, it may sleep
+    fn alloc_page() -> Result<Box<Self::Page>>;
+    /// Copy the whole page `src` to `dst`
+    fn copy_page(dst: &mut Self::Page, src: &Self::Page) -> Result;
+    fn page(&self, idx: u64) -> Option<&Self::Page>;
+    fn page_mut(&mut self, idx: u64) -> Option<&mut Self::Page>;
+    /// Insert a page at `idx`, which must be free, `page` is dropped on failure
+    fn insert_page(&mut self, idx: u64, page: Box<Self::Page>) -> Result;
+    /// Remove all the pages and pass them to `f`, return the number of pages removed
+    fn drain_pages(&mut self, f: impl FnMut(u64, Box<Self::Page>)) -> usize;
+}
+
+impl PageStore for RadixTree<Box<Pages<0>>> {
+    type Page = Pages<0>;
+
+    fn alloc_page() -> Result<Box<Pages<0>>> {
+        Ok(Box::try_new(Pages::new()?)?)
+    }
+
+    fn copy_page(dst: &mut Pages<0>, src: &Pages<0>) -> Result {
+        let map = src.kmap_atomic();
+        // SAFETY: `map` maps the whole page `src`, which is not `dst`
+        unsafe { dst.write_atomic(map.get_ptr() as *const u8, 0, PAGE_SIZE as usize) }
+    }
+
+    fn page(&self, idx: u64) -> Option<&Pages<0>> {
+        self.get(idx)
+    }
+
+    fn page_mut(&mut self, idx: u64) -> Option<&mut Pages<0>> {
+        self.get_mut(idx)
+    }
+
+    fn insert_page(&mut self, idx: u64, page: Box<Pages<0>>) -> Result {
+        self.try_insert(idx, page)
+    }
+
+    fn drain_pages(&mut self, f: impl FnMut(u64, Box<Pages<0>>)) -> usize {
+        self.drain_with(f)
+    }
+}
+
+/// The pages a write may need, allocated before the lock of the [CachedStore] is taken
+///
+/// A write inserts at most two pages, the backing page and the cached page.
+pub struct SparePages<S: PageStore>([Option<Box<S::Page>>; 2]);
+
+impl<S: PageStore> SparePages<S> {
+    pub const fn new() -> Self {
+        Self([None, None])
+    }
+
+    /// Allocate the pages used by the last write again, it must not be called under a
+    /// spinlock
+    pub fn fill(&mut self) -> Result {
+        for page in self.0.iter_mut().filter(|page| page.is_none()) {
+            *page = Some(S::alloc_page()?);
+        }
+        Ok(())
+    }
+
+    fn take(&mut self) -> Result<Box<S::Page>> {
+        self.0
+            .iter_mut()
+            .find_map(|page| page.take())
+            .ok_or(linux_err::ENOMEM)
+    }
+}
+
+impl<S: PageStore> Default for SparePages<S> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A page store with a write-back cache in front of it
+///
+/// In write-back mode the writes go to the cache, which is copied to the store by
+/// [CachedStore::flush], so they are lost if the cache is lost before. A cached page
+/// always has a backing page in the store, so a flush only copies the pages and never
+/// allocates.
+pub struct CachedStore<S: PageStore> {
+    store: S,
+    cache: S,
+    write_back: bool,
+}
+
+impl<S: PageStore> CachedStore<S> {
+    /// Create a store in write-through mode
+    pub fn new(store: S, cache: S) -> Self {
+        Self {
+            store,
+            cache,
+            write_back: false,
+        }
+    }
+
+    pub fn write_back(&self) -> bool {
+        self.write_back
+    }
+
+    /// Switch to write-back mode or to write-through mode, the cache is flushed before
+    /// switching to write-through mode
+    pub fn set_write_back(&mut self, write_back: bool) -> Result {
+        if !write_back {
+            self.flush()?;
+        }
+        self.write_back = write_back;
+        Ok(())
+    }
+
+    /// Return the page the data at `idx` is written to, the missing pages are taken from
+    /// `spare`
+    ///
+    /// A new cached page starts with the stored data, as a write may cover a part of the
+    /// page only.
+    pub fn write_page(&mut self, idx: u64, spare: &mut SparePages<S>) -> Result<&mut S::Page> {
+        if self.store.page(idx).is_none() {
+            self.store.insert_page(idx, spare.take()?)?;
+        }
+        if !self.write_back {
+            return self.store.page_mut(idx).ok_or(linux_err::EINVAL);
+        }
+        if self.cache.page(idx).is_none() {
+            let mut page = spare.take()?;
+            let stored = self.store.page(idx).ok_or(linux_err::EINVAL)?;
+            S::copy_page(&mut page, stored)?;
+            self.cache.insert_page(idx, page)?;
+        }
+        self.cache.page_mut(idx).ok_or(linux_err::EINVAL)
+    }
+
+    /// Return the latest data at `idx`, the cached page if there is one
+    pub fn read_page(&self, idx: u64) -> Option<&S::Page> {
+        self.cache.page(idx).or_else(|| self.store.page(idx))
+    }
+
+    /// Copy the cached pages to the store, return the number of pages written back
+    pub fn flush(&mut self) -> Result<usize> {
+        let store = &mut self.store;
+        let mut res = Ok(());
+        let count = self.cache.drain_pages(|idx, page| {
+            // the backing page is inserted by `write_page` before the cached one
+            let copied = match store.page_mut(idx) {
+                Some(stored) => S::copy_page(stored, &page),
+                None => store.insert_page(idx, page),
+            };
+            if let Err(e) = copied {
+                res = Err(e);
+            }
+        });
+        res.map(|_| count)
+    }
+
+    /// Free all the pages, the cached ones are not written back, return the number of
+    /// pages freed
+    pub fn drain(&mut self) -> usize {
+        self.store.drain_pages(|_, _| {}) + self.cache.drain_pages(|_, _| {})
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::collections::BTreeMap;
+
+    use super::*;
+
+    type Page = [u8; 4];
+
+    impl PageStore for BTreeMap<u64, Box<Page>> {
+        type Page = Page;
+
+        fn alloc_page() -> Result<Box<Page>> {
+            Ok(Box::new([0; 4]))
+        }
+
+        fn copy_page(dst: &mut Page, src: &Page) -> Result {
+            *dst = *src;
+            Ok(())
+        }
+
+        fn page(&self, idx: u64) -> Option<&Page> {
+            self.get(&idx).map(|page| &**page)
+        }
+
+        fn page_mut(&mut self, idx: u64) -> Option<&mut Page> {
+            self.get_mut(&idx).map(|page| &mut **page)
+        }
+
+        fn insert_page(&mut self, idx: u64, page: Box<Page>) -> Result {
+            if self.contains_key(&idx) {
+                return Err(linux_err::EEXIST);
+            }
+            self.insert(idx, page);
+            Ok(())
+        }
+
+        fn drain_pages(&mut self, mut f: impl FnMut(u64, Box<Page>)) -> usize {
+            let pages = core::mem::take(self);
+            let count = pages.len();
+            pages.into_iter().for_each(|(idx, page)| f(idx, page));
+            count
+        }
+    }
+
+    type Store = CachedStore<BTreeMap<u64, Box<Page>>>;
+
+    fn write(store: &mut Store, idx: u64, offset: usize, byte: u8) {
+        let mut spare = SparePages::new();
+        spare.fill().unwrap();
+        store.write_page(idx, &mut spare).unwrap()[offset] = byte;
+    }
+
+    /// The data left in the backing store if the cache is lost, as by a crash
+    fn crash(store: Store) -> BTreeMap<u64, Box<Page>> {
+        store.store
+    }
+
+    #[test]
+    fn test_cached_store() {
+        // a write-through write is durable once it returns
+        let mut store = Store::new(BTreeMap::new(), BTreeMap::new());
+        write(&mut store, 1, 0, 7);
+        assert_eq!(store.read_page(1), Some(&[7, 0, 0, 0]));
+        assert_eq!(crash(store).page(1), Some(&[7, 0, 0, 0]));
+
+        // a write-back write is lost until it is flushed
+        let mut store = Store::new(BTreeMap::new(), BTreeMap::new());
+        write(&mut store, 1, 0, 7);
+        store.set_write_back(true).unwrap();
+        write(&mut store, 1, 1, 8);
+        write(&mut store, 2, 0, 9);
+        // the cached page starts with the stored data
+        assert_eq!(store.read_page(1), Some(&[7, 8, 0, 0]));
+        assert_eq!(store.read_page(2), Some(&[9, 0, 0, 0]));
+        let mut stored = crash(store);
+        assert_eq!(stored.page(1), Some(&[7, 0, 0, 0]));
+        assert_eq!(stored.page(2), Some(&[0; 4]));
+        assert_eq!(stored.drain_pages(|_, _| {}), 2);
+
+        let mut store = Store::new(BTreeMap::new(), BTreeMap::new());
+        store.set_write_back(true).unwrap();
+        write(&mut store, 2, 0, 9);
+        assert_eq!(store.flush().unwrap(), 1);
+        assert_eq!(store.flush().unwrap(), 0);
+        write(&mut store, 3, 0, 5);
+        // switching to write-through writes the cache back
+        store.set_write_back(false).unwrap();
+        assert!(!store.write_back());
+        let stored = crash(store);
+        assert_eq!(stored.page(2), Some(&[9, 0, 0, 0]));
+        assert_eq!(stored.page(3), Some(&[5, 0, 0, 0]));
+    }
+
+    #[test]
+    fn test_spare_pages() {
+        let mut store = Store::new(BTreeMap::new(), BTreeMap::new());
+        store.set_write_back(true).unwrap();
+        let mut spare = SparePages::new();
+        let e = store
+            .write_page(1, &mut spare)
+            .map(|_| ())
+            .map_err(|e| e.to_errno());
+        assert_eq!(e, Err(linux_err::ENOMEM.to_errno()));
+        spare.fill().unwrap();
+        store.write_page(1, &mut spare).unwrap();
+        // both pages are taken, the next write of the page needs none
+        assert!(spare.0.iter().all(Option::is_none));
+        store.write_page(1, &mut spare).unwrap();
+        assert_eq!(store.drain(), 2);
+    }
+}
@@ -3,6 +3,7 @@
 //! Types for working with the block layer
 
 pub mod bio;
+pub mod cache;
 pub mod mq;
 
-pub use crate::bindings::{req_op, req_op_REQ_OP_READ, req_op_REQ_OP_WRITE};
+pub use crate::bindings::{req_op, req_op_REQ_OP_FLUSH, req_op_REQ_OP_READ, req_op_REQ_OP_WRITE};
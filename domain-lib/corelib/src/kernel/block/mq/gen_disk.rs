@@ -15,6 +15,7 @@ use kbind::safe_ptr::SafePtr;
 
 use crate::{
     bindings,
+    domain_info::queue_depth_valid,
     kernel::{
         block::mq::{raw_writer::RawWriter, Operations, TagSet},
         error,
@@ -24,6 +25,41 @@ use crate::{
     },
 };
 
+/// The limits of the requests handled by a block device, see [GenDisk::set_queue_limits]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueueLimits {
+    /// The maximum number of sectors of a request
+    pub max_hw_sectors: u32,
+    /// The maximum number of segments of a request
+    pub max_segments: u16,
+    /// The maximum number of sectors of a discard request, the device supports discard if it
+    /// is not 0
+    ///
+    /// There is no `QUEUE_FLAG_DISCARD` since Linux 5.19, the block layer only checks this
+    /// limit.
+    pub max_discard_sectors: u32,
+}
+
+impl QueueLimits {
+    /// Read the limits of a request queue
+    pub fn from_raw(limits: &bindings::queue_limits) -> Self {
+        Self {
+            max_hw_sectors: limits.max_hw_sectors,
+            max_segments: limits.max_segments,
+            max_discard_sectors: limits.max_discard_sectors,
+        }
+    }
+
+    /// Check the limits like the block layer does: a request must hold at least a page and
+    /// a segment, smaller limits would be silently raised by the kernel
+    pub fn check(&self) -> Result {
+        if self.max_hw_sectors < crate::PAGE_SIZE >> 9 || self.max_segments == 0 {
+            return Err(error::linux_err::EINVAL);
+        }
+        Ok(())
+    }
+}
+
 /// A generic block device
 ///
 /// # Invariants
@@ -145,6 +181,52 @@ impl<T: Operations> GenDisk<T> {
         unsafe { crate::sys_blk_queue_physical_block_size((*self.gendisk).queue, size) };
     }
 
+    /// Set the limits of the requests handled by the device
+    ///
+    /// Return `EINVAL` if the limits are rejected by [QueueLimits::check].
+    pub fn set_queue_limits(&self, limits: &QueueLimits) -> Result {
+        limits.check()?;
+        // SAFETY: `gendisk` is valid by the type invariant.
+        let queue = unsafe { (*self.gendisk).queue };
+        unsafe {
+            crate::sys_blk_queue_max_hw_sectors(queue, limits.max_hw_sectors);
+            crate::sys_blk_queue_max_segments(queue, limits.max_segments);
+            crate::sys_blk_queue_max_discard_sectors(queue, limits.max_discard_sectors);
+        }
+        Ok(())
+    }
+
+    /// Set whether the device has a volatile write cache, the block layer only sends the
+    /// flush requests to a device with a write cache
+    pub fn set_queue_write_cache(&self, enabled: bool) {
+        unsafe { crate::sys_blk_queue_write_cache((*self.gendisk).queue, enabled, false) };
+    }
+
+    /// Get the limits of the requests handled by the device
+    pub fn queue_limits(&self) -> QueueLimits {
+        // SAFETY: `gendisk` is valid by the type invariant.
+        QueueLimits::from_raw(unsafe { &(*(*self.gendisk).queue).limits })
+    }
+
+    /// Set the number of requests the queue accepts, see [queue_depth_valid]
+    ///
+    /// The queue is frozen and quiesced while the depth is changed. Return `EBUSY` if the
+    /// queue has an I/O scheduler.
+    pub fn set_queue_depth(&self, depth: u32) -> Result {
+        // SAFETY: `gendisk` is valid by the type invariant, and its queue has a tag set.
+        let queue = unsafe { (*self.gendisk).queue };
+        let set = unsafe { &*(*queue).tag_set };
+        if !queue_depth_valid(depth, set.reserved_tags, set.queue_depth) {
+            return Err(error::linux_err::EINVAL);
+        }
+        error::to_result(crate::sys_blk_mq_update_nr_requests(queue, depth))
+    }
+
+    /// Get the number of requests the queue accepts
+    pub fn queue_depth(&self) -> u32 {
+        unsafe { (*(*self.gendisk).queue).nr_requests }
+    }
+
     /// Set the rotational media attribute for the device
     pub fn set_rotational(&self, rotational: bool) {
         if !rotational {
@@ -171,3 +253,44 @@ impl<T: Operations> Drop for GenDisk<T> {
         let _queue_data = unsafe { T::QueueData::from_foreign(queue_data) };
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_queue_limits() {
+        let limits = QueueLimits {
+            max_hw_sectors: 1024,
+            max_segments: 128,
+            max_discard_sectors: u32::MAX >> 9,
+        };
+        assert!(limits.check().is_ok());
+
+        // the limits are read back from the queue as they were set
+        // SAFETY: `queue_limits` is a plain C struct, all zeroes is a valid value.
+        let mut raw: bindings::queue_limits = unsafe { core::mem::zeroed() };
+        raw.max_hw_sectors = limits.max_hw_sectors;
+        raw.max_segments = limits.max_segments;
+        raw.max_discard_sectors = limits.max_discard_sectors;
+        assert_eq!(QueueLimits::from_raw(&raw), limits);
+
+        // a request must hold a page and a segment
+        let small = QueueLimits {
+            max_hw_sectors: (crate::PAGE_SIZE >> 9) - 1,
+            ..limits
+        };
+        assert!(small.check().is_err());
+        let no_segment = QueueLimits {
+            max_segments: 0,
+            ..limits
+        };
+        assert!(no_segment.check().is_err());
+        // discard is optional
+        let no_discard = QueueLimits {
+            max_discard_sectors: 0,
+            ..limits
+        };
+        assert!(no_discard.check().is_ok());
+    }
+}
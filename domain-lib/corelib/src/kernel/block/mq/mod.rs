@@ -11,7 +11,7 @@ mod request;
 mod tag_set;
 
 pub use converter::OperationsConverter;
-pub use gen_disk::GenDisk;
+pub use gen_disk::{GenDisk, QueueLimits};
 pub use operations::Operations;
 pub use request::Request;
 pub use tag_set::TagSet;
@@ -40,7 +40,9 @@ impl<T: Operations> TagSet<T> {
         // inner.ops = unsafe { OperationsVtable::<T>::build() };
         inner.nr_hw_queues = nr_hw_queues;
         inner.timeout = 0; // 0 means default which is 30 * HZ in C
-        inner.numa_node = bindings::NUMA_NO_NODE;
+
+        // allocate the tags near the CPUs the domain is pinned to
+        inner.numa_node = crate::sys_domain_numa_node(rref::domain_id());
         inner.queue_depth = num_tags;
         inner.cmd_size = core::mem::size_of::<T::RequestData>().try_into()?;
         inner.flags = bindings::BLK_MQ_F_SHOULD_MERGE;
@@ -10,7 +10,7 @@ use core::{marker::PhantomData, pin::Pin};
 use crate::{
     bindings,
     kernel::{
-        error::{to_result, KernelResult as Result},
+        error::{linux_err, to_result, KernelResult as Result},
         types::{ForeignOwnable, Opaque},
     },
 };
@@ -46,10 +46,29 @@ impl<V: ForeignOwnable> RadixTree<V> {
     }
 
     /// Try to insert a value into the tree
+    ///
+    /// Return `EEXIST` if `key` is already in the tree, the existing value is kept. Return
+    /// `EINVAL` if `value` can not be stored in the tree, see [check_payload]. `value` is
+    /// dropped on failure.
     pub fn try_insert(&mut self, key: Key, value: V) -> Result<()> {
         // SAFETY: `self.tree` points to a valid and initialized `struct radix_tree`
-        let ret = crate::sys_radix_tree_insert(self.tree.get(), key, value.into_foreign() as _);
-        to_result(ret)
+        if !crate::sys_radix_tree_lookup(self.tree.get(), key).is_null() {
+            return Err(linux_err::EEXIST);
+        }
+        let item = value.into_foreign();
+        let res = check_payload(item).and_then(|_| {
+            // SAFETY: `self.tree` points to a valid and initialized `struct radix_tree`
+            insert_result(crate::sys_radix_tree_insert(
+                self.tree.get(),
+                key,
+                item as _,
+            ))
+        });
+        if res.is_err() {
+            // SAFETY: `item` was created by `into_foreign()` above and is not in the tree
+            drop(unsafe { V::from_foreign(item) });
+        }
+        res
     }
 
     /// Search for `key` in the map. Returns a reference to the associated
@@ -90,16 +109,23 @@ impl<V: ForeignOwnable> RadixTree<V> {
         // because this function takes a `&mut self`.
         Some(unsafe { ForeignOwnable::from_foreign(item.as_ptr()) })
     }
-}
 
-impl<V: ForeignOwnable> Drop for RadixTree<V> {
-    fn drop(&mut self) {
+    /// Remove all entries from the tree and drop their values, leaving the
+    /// tree empty. Returns the number of entries removed.
+    pub fn drain(&mut self) -> usize {
+        self.drain_with(drop_entry)
+    }
+
+    /// Remove all entries from the tree in ascending key order and pass them
+    /// to `f`, leaving the tree empty. Returns the number of entries removed.
+    pub fn drain_with(&mut self, mut f: impl FnMut(Key, V)) -> usize {
         let mut iter = bindings::radix_tree_iter {
             index: 0,
             next_index: 0,
             tags: 0,
             node: core::ptr::null_mut(),
         };
+        let mut count = 0;
 
         // SAFETY: Iter is valid as we allocated it on the stack above
         let mut slot = crate::sys_radix_tree_iter_init(&mut iter, 0);
@@ -120,12 +146,66 @@ impl<V: ForeignOwnable> Drop for RadixTree<V> {
 
             // SAFETY: All items in the tree are created by a call to
             // `ForeignOwnable::into_foreign()`.
-            let _ = unsafe { V::from_foreign(item) };
+            f(iter.index, unsafe { V::from_foreign(item) });
+            count += 1;
 
             // SAFETY: `self.tree` is valid and iter is managed by
             // `radix_tree_next_chunk()` and `radix_tree_next_slot()`. Slot is
             // not null.
             slot = crate::sys_radix_tree_next_slot(slot, &mut iter, 0);
         }
+        count
+    }
+}
+
+fn drop_entry<V>(_key: Key, _value: V) {}
+
+/// The low bits of an entry the radix tree uses to mark its internal nodes
+const RADIX_TREE_ENTRY_MASK: usize = 3;
+const RADIX_TREE_INTERNAL_NODE: usize = 2;
+
+/// Check that `item` can be stored in the radix tree
+///
+/// A null entry is the same as no entry, and an entry looking like an internal node
+/// would be followed as one, so both are rejected with `EINVAL`.
+fn check_payload(item: *const core::ffi::c_void) -> Result<()> {
+    if item.is_null() || item as usize & RADIX_TREE_ENTRY_MASK == RADIX_TREE_INTERNAL_NODE {
+        return Err(linux_err::EINVAL);
+    }
+    Ok(())
+}
+
+/// Translate the return value of `radix_tree_insert`, e.g. `-EEXIST` for a duplicate key
+/// and `-ENOMEM` if a node could not be allocated
+fn insert_result(ret: core::ffi::c_int) -> Result<()> {
+    to_result(ret)
+}
+
+impl<V: ForeignOwnable> Drop for RadixTree<V> {
+    fn drop(&mut self) {
+        self.drain();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_payload() {
+        let errno = |item: usize| check_payload(item as _).map_err(|e| e.to_errno());
+        assert_eq!(errno(0), Err(linux_err::EINVAL.to_errno()));
+        assert_eq!(errno(0x1002), Err(linux_err::EINVAL.to_errno()));
+        assert_eq!(errno(0x1000), Ok(()));
+        // the dangling pointer of `()`
+        assert_eq!(errno(1), Ok(()));
+    }
+
+    #[test]
+    fn test_insert_result() {
+        let errno = |ret| insert_result(ret).map_err(|e| e.to_errno());
+        assert_eq!(errno(0), Ok(()));
+        assert_eq!(errno(-17), Err(linux_err::EEXIST.to_errno()));
+        assert_eq!(errno(-12), Err(linux_err::ENOMEM.to_errno()));
     }
 }
@@ -6,7 +6,14 @@
 
 use core::ops::DerefMut;
 
+#[cfg(test)]
+use self::tests::{raw_lock, raw_lock_irqsave, raw_unlock, raw_unlock_irqrestore};
 use crate::{bindings, kernel::mm::cache_padded::CachePadded};
+#[cfg(not(test))]
+use crate::{
+    sys_spin_lock as raw_lock, sys_spin_lock_irqsave as raw_lock_irqsave,
+    sys_spin_unlock as raw_unlock, sys_spin_unlock_irqrestore as raw_unlock_irqrestore,
+};
 
 /// Creates a [`SpinLock`] initialiser with the given name and a newly-created lock class.
 ///
@@ -77,3 +84,135 @@ unsafe impl super::IrqSaveBackend for SpinLockBackend {
         Some(crate::sys_spin_lock_irqsave((&mut *ptr).deref_mut()))
     }
 }
+
+/// Holds a raw kernel `spinlock_t` locked until it is dropped.
+///
+/// It is for the `spinlock_t` owned by a kernel object, e.g. the one in a request queue, which
+/// can't be wrapped in a [`SpinLock`].
+#[must_use = "the lock unlocks immediately when the guard is unused"]
+pub struct SpinLockGuard {
+    ptr: *mut bindings::spinlock_t,
+}
+
+impl SpinLockGuard {
+    /// Locks the spinlock pointed by `ptr`.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must point to an initialised `spinlock_t` which stays valid until the guard is
+    /// dropped.
+    pub unsafe fn new(ptr: *mut bindings::spinlock_t) -> Self {
+        raw_lock(ptr);
+        Self { ptr }
+    }
+}
+
+impl Drop for SpinLockGuard {
+    fn drop(&mut self) {
+        // SAFETY: The lock was acquired in `new`, whose safety requirements ensure that `ptr` is
+        // still valid.
+        raw_unlock(self.ptr)
+    }
+}
+
+/// Holds a raw kernel `spinlock_t` locked with the interrupts disabled until it is dropped.
+///
+/// The interrupt state saved when locking is restored when unlocking.
+#[must_use = "the lock unlocks immediately when the guard is unused"]
+pub struct IrqSpinLockGuard {
+    ptr: *mut bindings::spinlock_t,
+    flags: core::ffi::c_ulong,
+}
+
+impl IrqSpinLockGuard {
+    /// Disables the interrupts and locks the spinlock pointed by `ptr`.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must point to an initialised `spinlock_t` which stays valid until the guard is
+    /// dropped.
+    pub unsafe fn new(ptr: *mut bindings::spinlock_t) -> Self {
+        let flags = raw_lock_irqsave(ptr);
+        Self { ptr, flags }
+    }
+}
+
+impl Drop for IrqSpinLockGuard {
+    fn drop(&mut self) {
+        // SAFETY: The lock was acquired in `new`, whose safety requirements ensure that `ptr` is
+        // still valid, and `flags` is the interrupt state saved by it.
+        raw_unlock_irqrestore(self.ptr, self.flags)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::{
+        ffi::c_ulong,
+        ptr,
+        sync::atomic::{AtomicPtr, AtomicU64, Ordering},
+    };
+
+    use super::*;
+
+    /// The lock currently held, the tests lock at most one lock at a time
+    static HELD: AtomicPtr<bindings::spinlock_t> = AtomicPtr::new(ptr::null_mut());
+    /// The interrupt state restored by the last `raw_unlock_irqrestore`
+    static RESTORED: AtomicU64 = AtomicU64::new(0);
+    const SAVED_FLAGS: c_ulong = 0x200;
+
+    pub(super) fn raw_lock(ptr: *mut bindings::spinlock_t) {
+        assert!(HELD.swap(ptr, Ordering::SeqCst).is_null());
+    }
+
+    pub(super) fn raw_unlock(ptr: *mut bindings::spinlock_t) {
+        assert_eq!(HELD.swap(ptr::null_mut(), Ordering::SeqCst), ptr);
+    }
+
+    pub(super) fn raw_lock_irqsave(ptr: *mut bindings::spinlock_t) -> c_ulong {
+        raw_lock(ptr);
+        SAVED_FLAGS
+    }
+
+    pub(super) fn raw_unlock_irqrestore(ptr: *mut bindings::spinlock_t, flags: c_ulong) {
+        RESTORED.store(flags as u64, Ordering::SeqCst);
+        raw_unlock(ptr)
+    }
+
+    fn held() -> bool {
+        !HELD.load(Ordering::SeqCst).is_null()
+    }
+
+    fn locked_until_return(ptr: *mut bindings::spinlock_t, early: bool) -> bool {
+        let _guard = unsafe { SpinLockGuard::new(ptr) };
+        if early {
+            return held();
+        }
+        assert!(held());
+        false
+    }
+
+    // Both guards share `HELD`, so they are checked in one test to keep them serialized.
+    #[test]
+    fn test_guards_unlock_on_drop() {
+        let mut lock = core::mem::MaybeUninit::<bindings::spinlock_t>::uninit();
+        let ptr = lock.as_mut_ptr();
+        {
+            let _guard = unsafe { SpinLockGuard::new(ptr) };
+            assert!(held());
+        }
+        assert!(!held());
+
+        assert!(locked_until_return(ptr, true));
+        assert!(!held());
+        assert!(!locked_until_return(ptr, false));
+        assert!(!held());
+
+        {
+            let _guard = unsafe { IrqSpinLockGuard::new(ptr) };
+            assert!(held());
+        }
+        assert!(!held());
+        assert_eq!(RESTORED.load(Ordering::SeqCst), SAVED_FLAGS as u64);
+    }
+}
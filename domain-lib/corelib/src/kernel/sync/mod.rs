@@ -1,7 +1,10 @@
 use crate::{bindings, kernel::types::Opaque};
 mod lock;
 
-pub use lock::{mutex::Mutex, spinlock::SpinLock};
+pub use lock::{
+    mutex::Mutex,
+    spinlock::{IrqSpinLockGuard, SpinLock, SpinLockGuard},
+};
 
 /// Represents a lockdep class. It's a wrapper around C's `lock_class_key`.
 #[repr(transparent)]
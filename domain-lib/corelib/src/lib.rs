@@ -9,8 +9,10 @@ use core::any::Any;
 
 #[cfg(feature = "core_impl")]
 pub use core_impl::*;
-use interface::{DomainType, DomainTypeRaw};
+use domain_info::{LogTail, PanicAction, PanicPolicy, ReplaceOptions, SharedDataReport};
+use interface::{null_block::CacheMode, DomainType, DomainTypeRaw};
 pub use pconst::LinuxErrno;
+use rref::RRefVec;
 use spin::Once;
 
 pub mod bindings;
@@ -25,18 +27,187 @@ pub use kbind::safe_ptr::SafePtr;
 pub trait CoreFunction: Send + Sync {
     fn sys_alloc_pages(&self, domain_id: u64, n: usize) -> *mut u8;
     fn sys_free_pages(&self, domain_id: u64, p: *mut u8, n: usize);
-    fn sys_write_console(&self, s: &str);
+    /// The page size of `sys_alloc_pages` in bits, the pages are aligned to it
+    fn sys_frame_bits(&self) -> u32;
+    /// The page size of `sys_alloc_pages` in bytes, always `1 << sys_frame_bits()`
+    fn sys_frame_size(&self) -> usize;
+    /// Write the output of the domain `caller` to its log sink, or the console if it has no
+    /// sink
+    fn sys_write_console(&self, caller: u64, s: &str);
+    /// Print `[prefix]` instead of the default `[0][Domain:<id>]` tag in front of the output
+    /// of the domain, an empty `prefix` restores the default. The control characters are
+    /// removed and a long prefix is cut. Return `EPERM` if `caller` is another domain and
+    /// `EINVAL` if the domain is not live
+    fn sys_set_log_prefix(&self, caller: u64, domain_id: u64, prefix: &str) -> LinuxResult<()>;
+    /// Capture the output of the domain in a log sink of `capacity` bytes. Only the domain
+    /// itself can bind its sink, return `EPERM` if `caller` is another domain and `EINVAL`
+    /// if `capacity` is 0 or too large
+    fn sys_bind_domain_log(&self, caller: u64, domain_id: u64, capacity: usize) -> LinuxResult<()>;
+    /// Read the outputs captured by the log sink of the domain from the sequence number
+    /// `from_seq` without consuming them. A gap before the first entry is reported in
+    /// `missed`. Only the domain itself can read its sink, return `EPERM` if `caller` is
+    /// another domain
+    fn sys_read_domain_log(
+        &self,
+        caller: u64,
+        domain_id: u64,
+        from_seq: u64,
+    ) -> LinuxResult<LogTail>;
+    /// Allocate a zeroed scratch area of `size` bytes under `key` for the domain `caller`,
+    /// which persists across calls and is freed when the domain is freed
+    fn sys_domain_local_alloc(&self, caller: u64, key: u64, size: usize) -> LinuxResult<*mut u8>;
+    /// Get the scratch area and its size allocated under `key` by the domain `caller`
+    fn sys_domain_local_get(&self, caller: u64, key: u64) -> LinuxResult<(*mut u8, usize)>;
+    /// Pin the work of the domain to the CPUs in `cpumask`, bit `i` is CPU `i`. All the
+    /// CPUs must be online. Only the domain itself can set its affinity, return `EPERM` if
+    /// `caller` is another domain
+    fn sys_domain_set_affinity(&self, caller: u64, domain_id: u64, cpumask: u64)
+        -> LinuxResult<()>;
+    /// Get the cpumask set by `sys_domain_set_affinity`, it is kept across the upgrades and
+    /// the restarts of the domain
+    fn sys_domain_affinity(&self, domain_id: u64) -> Option<u64>;
+    /// Set the nice value the work of the domain runs at, e.g. its restart and its memory
+    /// pressure callback, it must be in `[-20, 19]`. It is kept across the upgrades and the
+    /// restarts of the domain. Return `EPERM` if `caller` is another domain
+    fn sys_set_domain_nice(&self, caller: u64, domain_id: u64, nice: i32) -> LinuxResult<()>;
+    /// Get the nice value set by `sys_set_domain_nice`
+    fn sys_domain_nice(&self, domain_id: u64) -> Option<i32>;
+    /// Limit the calls into the domain through its proxy to `calls_per_sec` on average and
+    /// `burst` at once, the calls over the limit return `EAGAIN`. A `calls_per_sec` of 0
+    /// removes the limit, the domains are not limited by default
+    ///
+    /// Only the submissions are limited: `queue_rq` and `invoke` of a block device, the
+    /// reads, the writes and `invoke` of an empty device. The limit is kept by the proxy,
+    /// so it survives the upgrades. Return `EINVAL` for a logger and `EPERM` if `caller` is
+    /// another domain
+    fn sys_set_domain_rate_limit(
+        &self,
+        caller: u64,
+        domain_id: u64,
+        calls_per_sec: u64,
+        burst: u64,
+    ) -> LinuxResult<()>;
+    /// Attach the label `key=value` to the domain, an empty `value` removes it. The labels
+    /// are kept across the upgrades and their total size is bounded. Return `EPERM` if
+    /// `caller` is another domain
+    fn sys_set_domain_tag(
+        &self,
+        caller: u64,
+        domain_id: u64,
+        key: &str,
+        value: &str,
+    ) -> LinuxResult<()>;
+    /// Get the labels of the domain, one `key=value` per line
+    fn sys_get_domain_tags(&self, domain_id: u64) -> LinuxResult<RRefVec<u8>>;
+    /// Get the numa node of the first CPU in the affinity of the domain, or `NUMA_NO_NODE`
+    fn sys_domain_numa_node(&self, domain_id: u64) -> core::ffi::c_int;
     fn sys_backtrace(&self, domain_id: u64);
     /// This func will be deleted
     fn blk_crash_trick(&self) -> bool;
-    fn sys_get_domain(&self, name: &str) -> Option<DomainType>;
+    /// Get the domain `name` for the domain `caller`, which is recorded as depending on it
+    fn sys_get_domain(&self, caller: u64, name: &str) -> Option<DomainType>;
+    /// Check whether the domain exists without getting it
+    fn sys_domain_exists(&self, name: &str) -> bool;
+    /// Get the id of the domain without getting it
+    fn sys_get_domain_id(&self, name: &str) -> Option<u64>;
+    /// Get the type of the domain, which is needed by `sys_update_domain`
+    fn sys_domain_type(&self, name: &str) -> Option<DomainTypeRaw>;
+    /// Create a domain from the ELF `domain_file_name` and write its identifier to
+    /// `identifier`, return `ENOSPC` if the identifier does not fit in it, see
+    /// [domain_info::check_identifier_buf]
     fn sys_create_domain(
         &self,
         domain_file_name: &str,
         identifier: &mut [u8],
     ) -> LinuxResult<DomainType>;
-    /// Register a new domain with the given name and type
+    /// Create a domain like `sys_create_domain`, but only return the id of the new domain
+    fn sys_create_domain_id(
+        &self,
+        domain_file_name: &str,
+        identifier: &mut [u8],
+    ) -> LinuxResult<u64>;
+    /// Allocate a domain id without creating a domain, the domain is created later by
+    /// `sys_create_domain_with_id`
+    fn sys_reserve_domain_id(&self) -> u64;
+    /// Create the domain `identifier` from the ELF `domain_file_name` with the id reserved
+    /// by `sys_reserve_domain_id` and the default config. Return `EEXIST` if the id or the
+    /// identifier is already used, and `EINVAL` if the id is not reserved or the domain is
+    /// a block device. The id is still reserved if the creation fails
+    fn sys_create_domain_with_id(
+        &self,
+        domain_id: u64,
+        domain_file_name: &str,
+        identifier: &str,
+    ) -> LinuxResult<()>;
+    /// Create all the domains of a `Manifest` encoded in the [rref::wire] format, each
+    /// after its dependencies, and return a `Vec<CreateOutcome>` in the manifest order.
+    /// Return `EINVAL` if the manifest is malformed, then no domain is created
+    fn sys_create_domains(&self, manifest: RRefVec<u8>) -> LinuxResult<RRefVec<u8>>;
+    /// Rename the domain `old_name` to `new_name`
+    fn sys_rename_domain(&self, old_name: &str, new_name: &str) -> LinuxResult<()>;
+    /// Register a new domain with the given name and type, the names registered with the
+    /// same data share one copy of it
     fn sys_register_domain(&self, ident: &str, ty: DomainTypeRaw, data: &[u8]) -> LinuxResult<()>;
+    /// Unregister the domain data, return the bytes released
+    fn sys_unregister_domain(&self, ident: &str) -> LinuxResult<usize>;
+    /// Start to register a new domain whose data will be sent chunk by chunk
+    fn sys_register_domain_begin(
+        &self,
+        ident: &str,
+        ty: DomainTypeRaw,
+        total_len: usize,
+    ) -> LinuxResult<()>;
+    /// Append a chunk of data to the domain which is being registered
+    fn sys_register_domain_chunk(&self, ident: &str, offset: usize, data: &[u8])
+        -> LinuxResult<()>;
+    /// Finish the registration of the domain which is sent chunk by chunk
+    fn sys_register_domain_finish(&self, ident: &str) -> LinuxResult<()>;
+    /// Mark whether the registered domain should be kept by `sys_trim_registry` for reloading
+    fn sys_set_registry_reloadable(&self, ident: &str, reloadable: bool) -> LinuxResult<()>;
+    /// Remove the registered domain which is not reloadable and not loaded, return the bytes
+    /// released
+    fn sys_trim_registry(&self, ident: &str) -> LinuxResult<usize>;
+    /// Remove all the registered domains which are not reloadable and not loaded, return the
+    /// bytes released
+    fn sys_trim_registry_all(&self) -> LinuxResult<usize>;
+    /// Block new calls into the domain until it is thawed, once the calls in flight drain.
+    /// Return `EBUSY` if it is already frozen and `ETIMEDOUT` if the calls do not drain in
+    /// time, the domain is then not frozen
+    fn sys_freeze_domain(&self, domain_name: &str) -> LinuxResult<()>;
+    /// Resume a domain frozen by `sys_freeze_domain`
+    fn sys_thaw_domain(&self, domain_name: &str) -> LinuxResult<()>;
+    /// Whether the calls into the domain go through the lock path because it is being
+    /// upgraded or frozen
+    fn sys_domain_is_upgrading(&self, domain_name: &str) -> LinuxResult<bool>;
+    /// Called by the domain `domain_id` at a safe point of a long call. Return `EAGAIN`
+    /// if the domain is being upgraded or frozen, the domain should then give up the call
+    /// and return, so the upgrade does not wait for it. The retried call takes the lock
+    /// path and runs on the new domain
+    fn sys_domain_yield(&self, domain_id: u64) -> LinuxResult<()>;
+    /// Whether the domain is initialized, the calls into a domain which is not ready fail
+    /// with `EAGAIN`
+    fn sys_domain_is_ready(&self, domain_name: &str) -> LinuxResult<bool>;
+    /// Get how many calls into the domain were refused with `EAGAIN` because it was not
+    /// ready, which usually means the domain is used before it is loaded. It is zeroed by
+    /// `sys_domain_metrics_reset`
+    fn sys_domain_unready_calls(&self, domain_name: &str) -> LinuxResult<u64>;
+    /// Wait until the domain is ready, or return `ETIMEDOUT` after `timeout_ms` milliseconds
+    fn sys_wait_domain_ready(&self, domain_name: &str, timeout_ms: u64) -> LinuxResult<()>;
+    /// Wait until the domain has no in-flight calls, or return `ETIMEDOUT` after
+    /// `timeout_ms` milliseconds. The new calls are not blocked
+    fn sys_wait_domain_quiescent(&self, domain_name: &str, timeout_ms: u64) -> LinuxResult<()>;
+    /// Touch all the pages the domain `domain_id` allocated by `sys_alloc_pages` and has
+    /// not freed, so its first call does not pay for the page faults. Return the number of
+    /// the pages touched, or `EINVAL` if the domain is not live
+    fn sys_domain_warmup(&self, domain_id: u64) -> LinuxResult<usize>;
+    /// Tear down all the domains at shutdown. `on_shutdown` of every domain is called first,
+    /// so they flush their buffers before the block layer is quiesced and their resources
+    /// are freed. Return the number of the domains torn down
+    fn sys_shutdown_all(&self) -> LinuxResult<usize>;
+    /// Wait until all the callbacks queued on the SRCU of every domain have run, so no
+    /// deferred free is still waiting for a grace period. It sleeps, so it must not be
+    /// called in the atomic context. Return the number of the domains waited for
+    fn sys_force_srcu_barrier(&self) -> LinuxResult<usize>;
     /// Replace the old domain with the new domain
     fn sys_update_domain(
         &self,
@@ -44,8 +215,174 @@ pub trait CoreFunction: Send + Sync {
         new_domain_name: &str,
         ty: DomainTypeRaw,
     ) -> LinuxResult<()>;
+    /// Replace the old domain with the new domain like `sys_update_domain`, the shared data
+    /// of the old domain selected by `options`, e.g. its caches, is freed instead of moved
+    fn sys_update_domain_with(
+        &self,
+        old_domain_name: &str,
+        new_domain_name: &str,
+        ty: DomainTypeRaw,
+        options: &ReplaceOptions,
+    ) -> LinuxResult<()>;
+    /// Check whether the registered domain `new_domain_name` of type `ty` satisfies the
+    /// domains depending on the domain `old_domain_name` without replacing anything, return
+    /// the requirements violated as text, one per line, or `compatible`
+    fn sys_check_upgrade_compat(
+        &self,
+        old_domain_name: &str,
+        new_domain_name: &str,
+        ty: DomainTypeRaw,
+    ) -> LinuxResult<RRefVec<u8>>;
     fn sys_reload_domain(&self, domain_name: &str) -> LinuxResult<()>;
-    fn checkout_shared_data(&self) -> LinuxResult<()>;
+    /// Read from the empty device domain `domain_id`, the read can be canceled by
+    /// `sys_cancel_call`. Return `EINTR` with the bytes read so far kept in `data` if it is
+    /// canceled
+    fn sys_device_read_interruptible(
+        &self,
+        domain_id: u64,
+        data: &mut RRefVec<u8>,
+    ) -> LinuxResult<usize>;
+    /// Cancel the interruptible calls in flight into the domain, return how many are canceled
+    fn sys_cancel_call(&self, domain_id: u64) -> LinuxResult<usize>;
+    /// Whether the interruptible call `call_id` has been canceled
+    fn sys_call_canceled(&self, call_id: u64) -> bool;
+    /// Restart the domain from its ELF without migrating its state
+    fn sys_restart_domain(&self, domain_name: &str) -> LinuxResult<()>;
+    /// Forbid or allow the upgrades of all domains, the update, reload and restart of a
+    /// domain return `EAGAIN` while the upgrades are frozen. The upgrades in progress are
+    /// not affected
+    fn sys_set_upgrade_freeze(&self, frozen: bool);
+    /// Set what the proxy does when a call into the domain panics
+    fn sys_set_domain_policy(
+        &self,
+        domain_name: &str,
+        max_restarts: usize,
+        action: PanicAction,
+    ) -> LinuxResult<()>;
+    /// Set how the domain responds to a panicking call: unwind and fail the call, abort
+    /// the kernel or disable the domain, which is the default. It is kept across the
+    /// restarts and upgrades of the domain. Return `EPERM` if `caller` is another domain and
+    /// `EINVAL` if the domain is not registered
+    fn sys_set_panic_policy(
+        &self,
+        caller: u64,
+        domain_id: u64,
+        policy: PanicPolicy,
+    ) -> LinuxResult<()>;
+    /// Set the cache mode of the block domain, it is kept across the hot upgrades
+    fn sys_set_cache_mode(&self, domain_name: &str, mode: CacheMode) -> LinuxResult<()>;
+    /// Set the number of requests the queue of the block domain accepts, see
+    /// [domain_info::queue_depth_valid]
+    fn sys_set_queue_depth(&self, domain_name: &str, depth: u32) -> LinuxResult<()>;
+    /// Quiesce the queue of the block domain, the block layer holds the new requests until
+    /// [CoreFunction::sys_block_domain_resume]
+    fn sys_block_domain_pause(&self, domain_name: &str) -> LinuxResult<()>;
+    /// Delay each call into the domain `domain_id` by `us` microseconds, 0 removes the delay
+    ///
+    /// Return `ENOSYS` if the kernel is built without the `fault_injection` feature, and
+    /// `EPERM` if `caller` is another domain.
+    fn sys_inject_latency(&self, caller: u64, domain_id: u64, us: u64) -> LinuxResult<()>;
+    /// Unquiesce the queue paused by [CoreFunction::sys_block_domain_pause], the requests
+    /// held by the block layer are dispatched
+    fn sys_block_domain_resume(&self, domain_name: &str) -> LinuxResult<()>;
+    /// Set the shared heap budget reserved before the domain is upgraded, the upgrade fails
+    /// with `ENOMEM` before the new domain is loaded if it can't be reserved
+    fn sys_set_upgrade_reserve(&self, domain_name: &str, bytes: usize) -> LinuxResult<()>;
+    /// Zero the latency histogram, the call counts and the unready call count of the domain
+    /// without blocking the calls in flight. The panic count and the watchdog restart
+    /// count, which drive the panic policy, are only zeroed if `reset_panics` is set. The
+    /// upgrade history is a log rather than a metric, it is kept
+    ///
+    /// The counters are zeroed one after the other, not as a snapshot: a call in flight may
+    /// be counted in some of them and not in the others, and an increment racing with the
+    /// reset may be lost
+    fn sys_domain_metrics_reset(&self, domain_name: &str, reset_panics: bool) -> LinuxResult<()>;
+    /// Get the latency histogram of the calls into the domain, encoded as the `Vec<u64>` of
+    /// the counts of the buckets bounded by `LATENCY_BUCKETS_NS` in the [rref::wire] format
+    fn sys_domain_latency(&self, domain_name: &str) -> LinuxResult<RRefVec<u8>>;
+    /// Get the number of the calls into the domain by method, encoded as
+    /// `Vec<MethodCount>` in the [rref::wire] format. The counts are kept by the proxy, so
+    /// they survive the upgrades
+    fn sys_domain_call_counts(&self, domain_name: &str) -> LinuxResult<RRefVec<u8>>;
+    /// Get the milliseconds since the last call into the domain, or since it was created if
+    /// it has not been called. The time is kept by the proxy, so an upgrade does not reset it
+    fn sys_domain_idle_ms(&self, domain_name: &str) -> LinuxResult<u64>;
+    /// Time `iterations` calls of `domain_id` through the upgradable and through the pinned
+    /// path of the proxy of the empty device `domain_name`, return the nanoseconds per call
+    /// of both. The difference is the saving of a domain listed in `PINNED_DOMAINS`
+    fn sys_bench_pinned_path(&self, domain_name: &str, iterations: u64) -> LinuxResult<(u64, u64)>;
+    /// Record an activity of the domain `domain_id` which does not go through its proxy, so
+    /// `sys_domain_idle_ms` does not report it as idle
+    fn sys_domain_touch(&self, domain_id: u64) -> LinuxResult<()>;
+    /// Call the operation `op` of the domain `domain_id` with the arguments in `in_buf`,
+    /// return `ENOSYS` if the domain does not implement it
+    fn sys_domain_call(
+        &self,
+        domain_id: u64,
+        op: u32,
+        in_buf: RRefVec<u8>,
+    ) -> LinuxResult<RRefVec<u8>>;
+    /// Call the operation `op` of the domain `domain_id` like `sys_domain_call`, through
+    /// `invoke_interruptible` with a call id which is canceled after `timeout_ms`
+    /// milliseconds. Return `ETIMEDOUT` if the domain stopped the call with `EINTR` once it
+    /// was canceled. The domain must poll `sys_call_canceled`, a domain which does not is
+    /// not interrupted
+    fn sys_domain_call_timeout(
+        &self,
+        domain_id: u64,
+        op: u32,
+        in_buf: RRefVec<u8>,
+        timeout_ms: u64,
+    ) -> LinuxResult<RRefVec<u8>>;
+    /// Export the state of the domain through its proxy without upgrading it, return
+    /// `ENOSYS` if the domain does not implement `export_state`
+    fn sys_snapshot_domain(&self, domain_name: &str) -> LinuxResult<RRefVec<u8>>;
+    /// Import a state returned by `sys_snapshot_domain` into the domain
+    fn sys_restore_domain(&self, domain_name: &str, state: &RRefVec<u8>) -> LinuxResult<()>;
+    /// Create the domain `new_name` from the ELF of the domain `src_name` with the default
+    /// config, and import the state exported by the source, so the copy starts where the
+    /// source is and then runs on its own. Return the id of the copy, `EBUSY` if the source
+    /// is being upgraded or is frozen, and `EEXIST` if `new_name` is taken
+    fn sys_clone_domain_with_state(&self, src_name: &str, new_name: &str) -> LinuxResult<u64>;
+    /// Get the ELF image the domain is running, encoded as `DomainLoadInfo` in the
+    /// [rref::wire] format
+    fn sys_domain_load_info(&self, domain_name: &str) -> LinuxResult<RRefVec<u8>>;
+    /// Describe the domain in a readable multi-line text, for debugging
+    fn sys_domain_describe(&self, domain_name: &str) -> LinuxResult<RRefVec<u8>>;
+    /// List a page of the domains of type `ty` whose name starts with `name_prefix`, sorted
+    /// by id, encoded as [domain_info::DomainPage] in the [rref::wire] format. The page skips
+    /// `offset` domains and has at most `limit`, `None` matches all the types. Return
+    /// `EINVAL` if `limit` is 0
+    fn sys_list_domains_filtered(
+        &self,
+        ty: Option<DomainTypeRaw>,
+        name_prefix: &str,
+        offset: usize,
+        limit: usize,
+    ) -> LinuxResult<RRefVec<u8>>;
+    /// Export all the domains and the dependencies recorded by `sys_get_domain` as a DOT
+    /// digraph
+    fn sys_export_domain_graph(&self) -> LinuxResult<RRefVec<u8>>;
+    /// Check the invariants of the isolation subsystem without changing anything, return
+    /// the violations found as text, one per line, or `no violations`
+    fn sys_audit(&self) -> LinuxResult<RRefVec<u8>>;
+    /// Get the recent upgrade records of the domain, encoded as `Vec<UpgradeRecord>` in the
+    /// [rref::wire] format
+    fn sys_upgrade_history(&self, domain_name: &str) -> LinuxResult<RRefVec<u8>>;
+    /// Count the live shared heap allocations by owner, see [SharedDataReport]
+    fn checkout_shared_data(&self) -> LinuxResult<SharedDataReport>;
+    /// Release the free blocks cached by the shared heap, return the bytes released
+    ///
+    /// Only the cache is drained: the live allocations are not moved, so the fragmentation
+    /// they cause stays.
+    fn sys_compact_shared_heap(&self) -> LinuxResult<usize>;
+    /// Get the id of the domain which owns the shared heap allocation containing `addr`
+    fn sys_shared_data_owner(&self, addr: usize) -> Option<u64>;
+    /// List the live shared heap allocations owned by the domain `domain_id`, encoded as
+    /// [domain_info::SharedMemoryMap] in the [rref::wire] format. Only the allocations with
+    /// the lowest addresses are listed if there are too many, the others are counted.
+    /// Return `EINVAL` if the domain is not live
+    fn sys_domain_memory_map(&self, domain_id: u64) -> LinuxResult<RRefVec<u8>>;
     fn domain_info(&self) -> LinuxResult<Arc<dyn Any + Send + Sync>>;
 
     // linux kernel func list
@@ -83,7 +420,26 @@ pub trait CoreFunction: Send + Sync {
     fn sys_blk_queue_physical_block_size(&self, arg1: *mut request_queue, arg2: core::ffi::c_uint);
     fn sys_blk_queue_flag_set(&self, flag: core::ffi::c_uint, q: *mut request_queue);
     fn sys_blk_queue_flag_clear(&self, flag: core::ffi::c_uint, q: *mut request_queue);
+    fn sys_blk_queue_max_hw_sectors(
+        &self,
+        q: *mut request_queue,
+        max_hw_sectors: core::ffi::c_uint,
+    );
+    fn sys_blk_queue_max_segments(&self, q: *mut request_queue, max_segments: core::ffi::c_ushort);
+    fn sys_blk_queue_max_discard_sectors(
+        &self,
+        q: *mut request_queue,
+        max_discard_sectors: core::ffi::c_uint,
+    );
+    fn sys_blk_queue_write_cache(&self, q: *mut request_queue, enabled: bool, fua: bool);
     fn sys_del_gendisk(&self, disk: *mut gendisk);
+    /// Set the number of requests of a queue without an I/O scheduler, the queue is frozen
+    /// and quiesced meanwhile
+    fn sys_blk_mq_update_nr_requests(
+        &self,
+        q: *mut request_queue,
+        nr: core::ffi::c_uint,
+    ) -> core::ffi::c_int;
     fn sys_blk_mq_rq_to_pdu(&self, rq: *mut request) -> *mut core::ffi::c_void;
     fn sys_blk_mq_start_request(&self, rq: *mut request);
     fn sys_blk_mq_end_request(&self, rq: *mut request, status: blk_status_t);
@@ -152,8 +508,10 @@ pub trait CoreFunction: Send + Sync {
     // time
     fn sys_hrtimer_init(&self, timer: *mut hrtimer, which_clock: clockid_t, mode: hrtimer_mode);
     fn sys_hrtimer_cancel(&self, timer: *mut hrtimer) -> core::ffi::c_int;
+    /// Start the timer on a CPU in the affinity of the domain `domain_id`
     fn sys_hrtimer_start_range_ns(
         &self,
+        domain_id: u64,
         timer: *mut hrtimer,
         tim: ktime_t,
         range_ns: u64_,
@@ -167,11 +525,19 @@ mod core_impl {
     use core::any::Any;
 
     use bindings::*;
-    use interface::{DomainType, DomainTypeRaw};
+    use interface::{null_block::CacheMode, DomainType, DomainTypeRaw};
     use kbind::blk_status_t;
+    use rref::RRefVec;
     use spin::Once;
 
-    use super::{bindings, LinuxError, LinuxResult, OnceGet};
+    use super::{
+        bindings,
+        domain_info::{
+            check_identifier_buf, LogTail, PanicAction, PanicPolicy, ReplaceOptions,
+            SharedDataReport,
+        },
+        LinuxResult, OnceGet,
+    };
     use crate::CoreFunction;
 
     static CORE_FUNC: Once<&'static dyn CoreFunction> = Once::new();
@@ -203,8 +569,93 @@ mod core_impl {
         CORE_FUNC.get_must().sys_free_pages(domain_id, p, n);
     }
 
+    pub fn frame_bits() -> u32 {
+        CORE_FUNC.get_must().sys_frame_bits()
+    }
+
+    pub fn frame_size() -> usize {
+        CORE_FUNC.get_must().sys_frame_size()
+    }
+
     pub fn write_console(s: &str) {
-        CORE_FUNC.get_must().sys_write_console(s);
+        CORE_FUNC.get_must().sys_write_console(rref::domain_id(), s);
+    }
+
+    pub fn set_log_prefix(domain_id: u64, prefix: &str) -> LinuxResult<()> {
+        CORE_FUNC
+            .get_must()
+            .sys_set_log_prefix(rref::domain_id(), domain_id, prefix)
+    }
+
+    pub fn bind_domain_log(domain_id: u64, capacity: usize) -> LinuxResult<()> {
+        CORE_FUNC
+            .get_must()
+            .sys_bind_domain_log(rref::domain_id(), domain_id, capacity)
+    }
+
+    pub fn read_domain_log(domain_id: u64, from_seq: u64) -> LinuxResult<LogTail> {
+        CORE_FUNC
+            .get_must()
+            .sys_read_domain_log(rref::domain_id(), domain_id, from_seq)
+    }
+
+    pub fn domain_local_alloc(key: u64, size: usize) -> LinuxResult<*mut u8> {
+        CORE_FUNC
+            .get_must()
+            .sys_domain_local_alloc(rref::domain_id(), key, size)
+    }
+
+    pub fn domain_local_get(key: u64) -> LinuxResult<(*mut u8, usize)> {
+        CORE_FUNC
+            .get_must()
+            .sys_domain_local_get(rref::domain_id(), key)
+    }
+
+    pub fn domain_set_affinity(domain_id: u64, cpumask: u64) -> LinuxResult<()> {
+        CORE_FUNC
+            .get_must()
+            .sys_domain_set_affinity(rref::domain_id(), domain_id, cpumask)
+    }
+
+    pub fn domain_affinity(domain_id: u64) -> Option<u64> {
+        CORE_FUNC.get_must().sys_domain_affinity(domain_id)
+    }
+
+    pub fn set_domain_nice(domain_id: u64, nice: i32) -> LinuxResult<()> {
+        CORE_FUNC
+            .get_must()
+            .sys_set_domain_nice(rref::domain_id(), domain_id, nice)
+    }
+
+    pub fn domain_nice(domain_id: u64) -> Option<i32> {
+        CORE_FUNC.get_must().sys_domain_nice(domain_id)
+    }
+
+    pub fn set_domain_rate_limit(
+        domain_id: u64,
+        calls_per_sec: u64,
+        burst: u64,
+    ) -> LinuxResult<()> {
+        CORE_FUNC.get_must().sys_set_domain_rate_limit(
+            rref::domain_id(),
+            domain_id,
+            calls_per_sec,
+            burst,
+        )
+    }
+
+    pub fn set_domain_tag(domain_id: u64, key: &str, value: &str) -> LinuxResult<()> {
+        CORE_FUNC
+            .get_must()
+            .sys_set_domain_tag(rref::domain_id(), domain_id, key, value)
+    }
+
+    pub fn get_domain_tags(domain_id: u64) -> LinuxResult<RRefVec<u8>> {
+        CORE_FUNC.get_must().sys_get_domain_tags(domain_id)
+    }
+
+    pub(crate) fn sys_domain_numa_node(domain_id: u64) -> core::ffi::c_int {
+        CORE_FUNC.get_must().sys_domain_numa_node(domain_id)
     }
 
     pub fn backtrace(domain_id: u64) {
@@ -217,25 +668,153 @@ mod core_impl {
     }
 
     pub fn get_domain(name: &str) -> Option<DomainType> {
-        CORE_FUNC.get_must().sys_get_domain(name)
+        CORE_FUNC.get_must().sys_get_domain(rref::domain_id(), name)
+    }
+
+    pub fn domain_exists(name: &str) -> bool {
+        CORE_FUNC.get_must().sys_domain_exists(name)
+    }
+
+    pub fn get_domain_id(name: &str) -> Option<u64> {
+        CORE_FUNC.get_must().sys_get_domain_id(name)
+    }
+
+    pub fn domain_type(name: &str) -> Option<DomainTypeRaw> {
+        CORE_FUNC.get_must().sys_domain_type(name)
     }
 
     pub fn create_domain(
         domain_file_name: &str,
         domain_identifier: &mut [u8],
     ) -> LinuxResult<DomainType> {
-        if domain_identifier.len() < 32 {
-            return Err(LinuxError::EINVAL);
-        }
+        check_identifier_buf(domain_identifier)?;
         CORE_FUNC
             .get_must()
             .sys_create_domain(domain_file_name, domain_identifier)
     }
 
+    pub fn reserve_domain_id() -> u64 {
+        CORE_FUNC.get_must().sys_reserve_domain_id()
+    }
+
+    pub fn create_domain_with_id(
+        domain_id: u64,
+        domain_file_name: &str,
+        identifier: &str,
+    ) -> LinuxResult<()> {
+        CORE_FUNC
+            .get_must()
+            .sys_create_domain_with_id(domain_id, domain_file_name, identifier)
+    }
+
+    pub fn create_domain_id(
+        domain_file_name: &str,
+        domain_identifier: &mut [u8],
+    ) -> LinuxResult<u64> {
+        check_identifier_buf(domain_identifier)?;
+        CORE_FUNC
+            .get_must()
+            .sys_create_domain_id(domain_file_name, domain_identifier)
+    }
+
+    pub fn create_domains(manifest: RRefVec<u8>) -> LinuxResult<RRefVec<u8>> {
+        CORE_FUNC.get_must().sys_create_domains(manifest)
+    }
+
+    pub fn rename_domain(old_name: &str, new_name: &str) -> LinuxResult<()> {
+        CORE_FUNC.get_must().sys_rename_domain(old_name, new_name)
+    }
+
     pub fn register_domain(ident: &str, ty: DomainTypeRaw, data: &[u8]) -> LinuxResult<()> {
         CORE_FUNC.get_must().sys_register_domain(ident, ty, data)
     }
 
+    pub fn unregister_domain(ident: &str) -> LinuxResult<usize> {
+        CORE_FUNC.get_must().sys_unregister_domain(ident)
+    }
+
+    pub fn register_domain_begin(
+        ident: &str,
+        ty: DomainTypeRaw,
+        total_len: usize,
+    ) -> LinuxResult<()> {
+        CORE_FUNC
+            .get_must()
+            .sys_register_domain_begin(ident, ty, total_len)
+    }
+
+    pub fn register_domain_chunk(ident: &str, offset: usize, data: &[u8]) -> LinuxResult<()> {
+        CORE_FUNC
+            .get_must()
+            .sys_register_domain_chunk(ident, offset, data)
+    }
+
+    pub fn register_domain_finish(ident: &str) -> LinuxResult<()> {
+        CORE_FUNC.get_must().sys_register_domain_finish(ident)
+    }
+
+    pub fn set_registry_reloadable(ident: &str, reloadable: bool) -> LinuxResult<()> {
+        CORE_FUNC
+            .get_must()
+            .sys_set_registry_reloadable(ident, reloadable)
+    }
+
+    pub fn trim_registry(ident: &str) -> LinuxResult<usize> {
+        CORE_FUNC.get_must().sys_trim_registry(ident)
+    }
+
+    pub fn trim_registry_all() -> LinuxResult<usize> {
+        CORE_FUNC.get_must().sys_trim_registry_all()
+    }
+
+    pub fn freeze_domain(domain_name: &str) -> LinuxResult<()> {
+        CORE_FUNC.get_must().sys_freeze_domain(domain_name)
+    }
+
+    pub fn thaw_domain(domain_name: &str) -> LinuxResult<()> {
+        CORE_FUNC.get_must().sys_thaw_domain(domain_name)
+    }
+
+    pub fn domain_is_upgrading(domain_name: &str) -> LinuxResult<bool> {
+        CORE_FUNC.get_must().sys_domain_is_upgrading(domain_name)
+    }
+
+    pub fn domain_yield() -> LinuxResult<()> {
+        CORE_FUNC.get_must().sys_domain_yield(rref::domain_id())
+    }
+
+    pub fn domain_is_ready(domain_name: &str) -> LinuxResult<bool> {
+        CORE_FUNC.get_must().sys_domain_is_ready(domain_name)
+    }
+
+    pub fn domain_unready_calls(domain_name: &str) -> LinuxResult<u64> {
+        CORE_FUNC.get_must().sys_domain_unready_calls(domain_name)
+    }
+
+    pub fn wait_domain_ready(domain_name: &str, timeout_ms: u64) -> LinuxResult<()> {
+        CORE_FUNC
+            .get_must()
+            .sys_wait_domain_ready(domain_name, timeout_ms)
+    }
+
+    pub fn wait_domain_quiescent(domain_name: &str, timeout_ms: u64) -> LinuxResult<()> {
+        CORE_FUNC
+            .get_must()
+            .sys_wait_domain_quiescent(domain_name, timeout_ms)
+    }
+
+    pub fn domain_warmup(domain_id: u64) -> LinuxResult<usize> {
+        CORE_FUNC.get_must().sys_domain_warmup(domain_id)
+    }
+
+    pub fn shutdown_all() -> LinuxResult<usize> {
+        CORE_FUNC.get_must().sys_shutdown_all()
+    }
+
+    pub fn force_srcu_barrier() -> LinuxResult<usize> {
+        CORE_FUNC.get_must().sys_force_srcu_barrier()
+    }
+
     pub fn update_domain(
         old_domain_name: &str,
         new_domain_name: &str,
@@ -246,13 +825,201 @@ mod core_impl {
             .sys_update_domain(old_domain_name, new_domain_name, ty)
     }
 
+    pub fn update_domain_with(
+        old_domain_name: &str,
+        new_domain_name: &str,
+        ty: DomainTypeRaw,
+        options: &ReplaceOptions,
+    ) -> LinuxResult<()> {
+        CORE_FUNC
+            .get_must()
+            .sys_update_domain_with(old_domain_name, new_domain_name, ty, options)
+    }
+
     pub fn reload_domain(domain_name: &str) -> LinuxResult<()> {
         CORE_FUNC.get_must().sys_reload_domain(domain_name)
     }
-    pub fn checkout_shared_data() -> LinuxResult<()> {
+
+    pub fn device_read_interruptible(domain_id: u64, data: &mut RRefVec<u8>) -> LinuxResult<usize> {
+        CORE_FUNC
+            .get_must()
+            .sys_device_read_interruptible(domain_id, data)
+    }
+
+    pub fn cancel_call(domain_id: u64) -> LinuxResult<usize> {
+        CORE_FUNC.get_must().sys_cancel_call(domain_id)
+    }
+
+    pub fn call_canceled(call_id: u64) -> bool {
+        CORE_FUNC.get_must().sys_call_canceled(call_id)
+    }
+
+    pub fn restart_domain(domain_name: &str) -> LinuxResult<()> {
+        CORE_FUNC.get_must().sys_restart_domain(domain_name)
+    }
+
+    pub fn set_upgrade_freeze(frozen: bool) {
+        CORE_FUNC.get_must().sys_set_upgrade_freeze(frozen)
+    }
+
+    pub fn set_domain_policy(
+        domain_name: &str,
+        max_restarts: usize,
+        action: PanicAction,
+    ) -> LinuxResult<()> {
+        CORE_FUNC
+            .get_must()
+            .sys_set_domain_policy(domain_name, max_restarts, action)
+    }
+
+    pub fn set_panic_policy(domain_id: u64, policy: PanicPolicy) -> LinuxResult<()> {
+        CORE_FUNC
+            .get_must()
+            .sys_set_panic_policy(rref::domain_id(), domain_id, policy)
+    }
+
+    pub fn set_cache_mode(domain_name: &str, mode: CacheMode) -> LinuxResult<()> {
+        CORE_FUNC.get_must().sys_set_cache_mode(domain_name, mode)
+    }
+
+    pub fn set_queue_depth(domain_name: &str, depth: u32) -> LinuxResult<()> {
+        CORE_FUNC.get_must().sys_set_queue_depth(domain_name, depth)
+    }
+
+    pub fn inject_latency(domain_id: u64, us: u64) -> LinuxResult<()> {
+        CORE_FUNC
+            .get_must()
+            .sys_inject_latency(rref::domain_id(), domain_id, us)
+    }
+
+    pub fn block_domain_pause(domain_name: &str) -> LinuxResult<()> {
+        CORE_FUNC.get_must().sys_block_domain_pause(domain_name)
+    }
+
+    pub fn block_domain_resume(domain_name: &str) -> LinuxResult<()> {
+        CORE_FUNC.get_must().sys_block_domain_resume(domain_name)
+    }
+
+    pub fn set_upgrade_reserve(domain_name: &str, bytes: usize) -> LinuxResult<()> {
+        CORE_FUNC
+            .get_must()
+            .sys_set_upgrade_reserve(domain_name, bytes)
+    }
+
+    pub fn domain_metrics_reset(domain_name: &str, reset_panics: bool) -> LinuxResult<()> {
+        CORE_FUNC
+            .get_must()
+            .sys_domain_metrics_reset(domain_name, reset_panics)
+    }
+
+    pub fn domain_latency(domain_name: &str) -> LinuxResult<RRefVec<u8>> {
+        CORE_FUNC.get_must().sys_domain_latency(domain_name)
+    }
+
+    pub fn domain_call_counts(domain_name: &str) -> LinuxResult<RRefVec<u8>> {
+        CORE_FUNC.get_must().sys_domain_call_counts(domain_name)
+    }
+
+    pub fn domain_idle_ms(domain_name: &str) -> LinuxResult<u64> {
+        CORE_FUNC.get_must().sys_domain_idle_ms(domain_name)
+    }
+
+    pub fn bench_pinned_path(domain_name: &str, iterations: u64) -> LinuxResult<(u64, u64)> {
+        CORE_FUNC
+            .get_must()
+            .sys_bench_pinned_path(domain_name, iterations)
+    }
+
+    pub fn domain_touch(domain_id: u64) -> LinuxResult<()> {
+        CORE_FUNC.get_must().sys_domain_touch(domain_id)
+    }
+
+    pub fn domain_call(domain_id: u64, op: u32, in_buf: RRefVec<u8>) -> LinuxResult<RRefVec<u8>> {
+        CORE_FUNC.get_must().sys_domain_call(domain_id, op, in_buf)
+    }
+
+    pub fn domain_call_timeout(
+        domain_id: u64,
+        op: u32,
+        in_buf: RRefVec<u8>,
+        timeout_ms: u64,
+    ) -> LinuxResult<RRefVec<u8>> {
+        CORE_FUNC
+            .get_must()
+            .sys_domain_call_timeout(domain_id, op, in_buf, timeout_ms)
+    }
+
+    pub fn snapshot_domain(domain_name: &str) -> LinuxResult<RRefVec<u8>> {
+        CORE_FUNC.get_must().sys_snapshot_domain(domain_name)
+    }
+
+    pub fn restore_domain(domain_name: &str, state: &RRefVec<u8>) -> LinuxResult<()> {
+        CORE_FUNC.get_must().sys_restore_domain(domain_name, state)
+    }
+
+    pub fn clone_domain_with_state(src_name: &str, new_name: &str) -> LinuxResult<u64> {
+        CORE_FUNC
+            .get_must()
+            .sys_clone_domain_with_state(src_name, new_name)
+    }
+
+    pub fn domain_load_info(domain_name: &str) -> LinuxResult<RRefVec<u8>> {
+        CORE_FUNC.get_must().sys_domain_load_info(domain_name)
+    }
+
+    pub fn domain_describe(domain_name: &str) -> LinuxResult<RRefVec<u8>> {
+        CORE_FUNC.get_must().sys_domain_describe(domain_name)
+    }
+
+    pub fn list_domains_filtered(
+        ty: Option<DomainTypeRaw>,
+        name_prefix: &str,
+        offset: usize,
+        limit: usize,
+    ) -> LinuxResult<RRefVec<u8>> {
+        CORE_FUNC
+            .get_must()
+            .sys_list_domains_filtered(ty, name_prefix, offset, limit)
+    }
+
+    pub fn export_domain_graph() -> LinuxResult<RRefVec<u8>> {
+        CORE_FUNC.get_must().sys_export_domain_graph()
+    }
+
+    pub fn check_upgrade_compat(
+        old_domain_name: &str,
+        new_domain_name: &str,
+        ty: DomainTypeRaw,
+    ) -> LinuxResult<RRefVec<u8>> {
+        CORE_FUNC
+            .get_must()
+            .sys_check_upgrade_compat(old_domain_name, new_domain_name, ty)
+    }
+
+    pub fn audit() -> LinuxResult<RRefVec<u8>> {
+        CORE_FUNC.get_must().sys_audit()
+    }
+
+    pub fn upgrade_history(domain_name: &str) -> LinuxResult<RRefVec<u8>> {
+        CORE_FUNC.get_must().sys_upgrade_history(domain_name)
+    }
+
+    pub fn checkout_shared_data() -> LinuxResult<SharedDataReport> {
         CORE_FUNC.get_must().checkout_shared_data()
     }
 
+    pub fn compact_shared_heap() -> LinuxResult<usize> {
+        CORE_FUNC.get_must().sys_compact_shared_heap()
+    }
+
+    pub fn shared_data_owner(addr: usize) -> Option<u64> {
+        CORE_FUNC.get_must().sys_shared_data_owner(addr)
+    }
+
+    pub fn domain_memory_map(domain_id: u64) -> LinuxResult<RRefVec<u8>> {
+        CORE_FUNC.get_must().sys_domain_memory_map(domain_id)
+    }
+
     pub fn domain_info() -> LinuxResult<Arc<dyn Any + Send + Sync>> {
         CORE_FUNC.get_must().domain_info()
     }
@@ -345,10 +1112,45 @@ mod core_impl {
     pub(crate) fn sys_blk_queue_flag_clear(flag: core::ffi::c_uint, q: *mut request_queue) {
         CORE_FUNC.get_must().sys_blk_queue_flag_clear(flag, q)
     }
+    pub(crate) fn sys_blk_queue_max_hw_sectors(
+        q: *mut request_queue,
+        max_hw_sectors: core::ffi::c_uint,
+    ) {
+        CORE_FUNC
+            .get_must()
+            .sys_blk_queue_max_hw_sectors(q, max_hw_sectors)
+    }
+    pub(crate) fn sys_blk_queue_max_segments(
+        q: *mut request_queue,
+        max_segments: core::ffi::c_ushort,
+    ) {
+        CORE_FUNC
+            .get_must()
+            .sys_blk_queue_max_segments(q, max_segments)
+    }
+    pub(crate) fn sys_blk_queue_max_discard_sectors(
+        q: *mut request_queue,
+        max_discard_sectors: core::ffi::c_uint,
+    ) {
+        CORE_FUNC
+            .get_must()
+            .sys_blk_queue_max_discard_sectors(q, max_discard_sectors)
+    }
+    pub(crate) fn sys_blk_queue_write_cache(q: *mut request_queue, enabled: bool, fua: bool) {
+        CORE_FUNC
+            .get_must()
+            .sys_blk_queue_write_cache(q, enabled, fua)
+    }
     #[allow(unused)]
     pub(crate) fn sys_del_gendisk(disk: *mut gendisk) {
         CORE_FUNC.get_must().sys_del_gendisk(disk)
     }
+    pub(crate) fn sys_blk_mq_update_nr_requests(
+        q: *mut request_queue,
+        nr: core::ffi::c_uint,
+    ) -> core::ffi::c_int {
+        CORE_FUNC.get_must().sys_blk_mq_update_nr_requests(q, nr)
+    }
     pub(crate) fn sys_blk_mq_rq_to_pdu(rq: *mut request) -> *mut core::ffi::c_void {
         CORE_FUNC.get_must().sys_blk_mq_rq_to_pdu(rq)
     }
@@ -480,9 +1282,13 @@ mod core_impl {
         range_ns: u64_,
         mode: hrtimer_mode,
     ) {
-        CORE_FUNC
-            .get_must()
-            .sys_hrtimer_start_range_ns(timer, tim, range_ns, mode);
+        CORE_FUNC.get_must().sys_hrtimer_start_range_ns(
+            rref::domain_id(),
+            timer,
+            tim,
+            range_ns,
+            mode,
+        );
     }
 }
 
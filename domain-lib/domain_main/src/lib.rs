@@ -8,14 +8,24 @@ pub fn domain_main(
 ) -> proc_macro::TokenStream {
     let item = TokenStream::from(item);
     let panic = panic_impl();
+    let version = interface_version();
     quote! (
         #[no_mangle]
         #item
         #panic
+        #version
     )
     .into()
 }
 
+fn interface_version() -> TokenStream {
+    quote!(
+        #[used]
+        #[link_section = ".domain_interface"]
+        static DOMAIN_INTERFACE_VERSION: u32 = interface::INTERFACE_VERSION;
+    )
+}
+
 fn panic_impl() -> TokenStream {
     quote!(
         #[panic_handler]
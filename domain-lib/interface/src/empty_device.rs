@@ -1,14 +1,54 @@
 use downcast_rs::{impl_downcast, DowncastSync};
-use rref::RRefVec;
+use rref::{
+    wire::{Decode, Decoder, Encode, Encoder},
+    RRefVec,
+};
 
 use super::LinuxResult;
 use crate::Basic;
 
 // #[proxy(EmptyDeviceDomainProxy, SRCU)]
 pub trait EmptyDeviceDomain: Basic + DowncastSync {
-    fn init(&self) -> LinuxResult<()>;
+    fn init(&self, config: &EmptyDeviceConfig) -> LinuxResult<()>;
+    /// Fill `data` and return it, a short read sets `len()` of the buffer to the bytes
+    /// read, its `size()` is kept.
     fn read(&self, data: RRefVec<u8>) -> LinuxResult<RRefVec<u8>>;
     fn write(&self, data: &RRefVec<u8>) -> LinuxResult<usize>;
+    /// Write `data` and return the response of the device, the ownership of
+    /// both buffers is moved across the domain boundary like [`Self::read`].
+    fn write_read(&self, data: RRefVec<u8>) -> LinuxResult<RRefVec<u8>>;
+    /// Fill `data` like [`Self::read`] and return the bytes read, but return `EINTR` as
+    /// soon as the call `call_id` is canceled. The buffer is filled from the start, so
+    /// the bytes read before the cancellation are kept in it and `len()` is set to them.
+    ///
+    /// The domain should poll `call_canceled(call_id)` while it reads.
+    fn read_interruptible(&self, data: &mut RRefVec<u8>, call_id: u64) -> LinuxResult<usize>;
 }
 
 impl_downcast!(sync EmptyDeviceDomain);
+
+#[derive(Debug, Copy, Clone)]
+pub struct EmptyDeviceConfig {
+    // Max bytes of one read/write request
+    pub buffer_size: usize,
+}
+
+impl Default for EmptyDeviceConfig {
+    fn default() -> Self {
+        Self { buffer_size: 4096 }
+    }
+}
+
+impl Encode for EmptyDeviceConfig {
+    fn encode_to(&self, encoder: &mut Encoder) {
+        encoder.put(&self.buffer_size);
+    }
+}
+
+impl Decode for EmptyDeviceConfig {
+    fn decode_from(decoder: &mut Decoder) -> LinuxResult<Self> {
+        Ok(Self {
+            buffer_size: decoder.get()?,
+        })
+    }
+}
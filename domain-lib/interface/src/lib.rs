@@ -10,13 +10,70 @@ use alloc::sync::Arc;
 use core::{any::Any, fmt::Debug};
 
 pub use pconst::LinuxErrno;
+use rref::RRefVec;
 
 use crate::{empty_device::EmptyDeviceDomain, logger::LogDomain, null_block::BlockDeviceDomain};
 
 type LinuxResult<T> = Result<T, LinuxErrno>;
 
+/// The version of the interface between the kernel and the domains.
+///
+/// It must be bumped whenever a trait or a type shared with the domains changes its layout.
+pub const INTERFACE_VERSION: u32 = 10;
+/// The elf section where a domain records the [INTERFACE_VERSION] it is built against.
+pub const INTERFACE_VERSION_SECTION: &str = ".domain_interface";
+
 pub trait Basic: Send + Sync + Debug + Any {
     fn domain_id(&self) -> u64;
+    /// Called when the shared heap is under pressure, `level` grows with the usage.
+    ///
+    /// The domain should release the memory it caches and return the bytes it freed.
+    fn on_memory_pressure(&self, _level: u8) -> LinuxResult<usize> {
+        Ok(0)
+    }
+    /// A generic call into the domain, `op` selects the operation and `buf` carries its
+    /// arguments. The result is returned in a buffer owned by the domain.
+    ///
+    /// It lets a domain offer new operations without a new syscall for each of them.
+    fn invoke(&self, _op: u32, _buf: RRefVec<u8>) -> LinuxResult<RRefVec<u8>> {
+        Err(LinuxErrno::ENOSYS)
+    }
+    /// Call [Basic::invoke], but return `EINTR` as soon as the call `call_id` is canceled.
+    ///
+    /// The domain should poll `call_canceled(call_id)` in an operation which may take long.
+    /// The default implementation never polls, the call runs until it completes.
+    fn invoke_interruptible(
+        &self,
+        op: u32,
+        buf: RRefVec<u8>,
+        _call_id: u64,
+    ) -> LinuxResult<RRefVec<u8>> {
+        self.invoke(op, buf)
+    }
+    /// Serialize the state of the domain, the result is owned by the domain.
+    ///
+    /// The domain is not changed, the state can be restored by [Basic::import_state].
+    fn export_state(&self) -> LinuxResult<RRefVec<u8>> {
+        Err(LinuxErrno::ENOSYS)
+    }
+    /// Replace the state of the domain with a state returned by [Basic::export_state]
+    fn import_state(&self, _state: &RRefVec<u8>) -> LinuxResult<()> {
+        Err(LinuxErrno::ENOSYS)
+    }
+    /// Called once before the domain is torn down at shutdown, while the block layer still
+    /// accepts its I/O.
+    ///
+    /// The domain should flush the data it buffers, e.g. write back the dirty cache.
+    fn on_shutdown(&self) -> LinuxResult<()> {
+        Ok(())
+    }
+    /// The generation of the domain behind a proxy, it grows with every hot upgrade.
+    ///
+    /// A caller keeping a [DomainType] can compare it to find out that the domain has been
+    /// replaced meanwhile. A domain which is not behind a proxy is always at epoch 0.
+    fn epoch(&self) -> u64 {
+        0
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -42,6 +99,94 @@ impl DomainType {
         }
     }
 
+    pub fn on_memory_pressure(&self, level: u8) -> LinuxResult<usize> {
+        match self {
+            DomainType::EmptyDeviceDomain(d) => d.on_memory_pressure(level),
+            DomainType::LogDomain(d) => d.on_memory_pressure(level),
+            DomainType::BlockDeviceDomain(d) => d.on_memory_pressure(level),
+        }
+    }
+
+    pub fn epoch(&self) -> u64 {
+        match self {
+            DomainType::EmptyDeviceDomain(d) => d.epoch(),
+            DomainType::LogDomain(d) => d.epoch(),
+            DomainType::BlockDeviceDomain(d) => d.epoch(),
+        }
+    }
+
+    pub fn on_shutdown(&self) -> LinuxResult<()> {
+        match self {
+            DomainType::EmptyDeviceDomain(d) => d.on_shutdown(),
+            DomainType::LogDomain(d) => d.on_shutdown(),
+            DomainType::BlockDeviceDomain(d) => d.on_shutdown(),
+        }
+    }
+
+    /// Downcast an empty device domain to its concrete type `T`
+    ///
+    /// Return `None` if the domain is of another variant or is not a `T`.
+    pub fn as_empty_device<T: EmptyDeviceDomain>(&self) -> Option<Arc<T>> {
+        match self {
+            DomainType::EmptyDeviceDomain(d) => d.clone().downcast_arc::<T>().ok(),
+            _ => None,
+        }
+    }
+
+    /// Downcast a log domain to its concrete type `T`, see [DomainType::as_empty_device]
+    pub fn as_logger<T: LogDomain>(&self) -> Option<Arc<T>> {
+        match self {
+            DomainType::LogDomain(d) => d.clone().downcast_arc::<T>().ok(),
+            _ => None,
+        }
+    }
+
+    /// Downcast a block device domain to its concrete type `T`, see
+    /// [DomainType::as_empty_device]
+    pub fn as_block_device<T: BlockDeviceDomain>(&self) -> Option<Arc<T>> {
+        match self {
+            DomainType::BlockDeviceDomain(d) => d.clone().downcast_arc::<T>().ok(),
+            _ => None,
+        }
+    }
+
+    pub fn invoke(&self, op: u32, buf: RRefVec<u8>) -> LinuxResult<RRefVec<u8>> {
+        match self {
+            DomainType::EmptyDeviceDomain(d) => d.invoke(op, buf),
+            DomainType::LogDomain(d) => d.invoke(op, buf),
+            DomainType::BlockDeviceDomain(d) => d.invoke(op, buf),
+        }
+    }
+
+    pub fn invoke_interruptible(
+        &self,
+        op: u32,
+        buf: RRefVec<u8>,
+        call_id: u64,
+    ) -> LinuxResult<RRefVec<u8>> {
+        match self {
+            DomainType::EmptyDeviceDomain(d) => d.invoke_interruptible(op, buf, call_id),
+            DomainType::LogDomain(d) => d.invoke_interruptible(op, buf, call_id),
+            DomainType::BlockDeviceDomain(d) => d.invoke_interruptible(op, buf, call_id),
+        }
+    }
+
+    pub fn export_state(&self) -> LinuxResult<RRefVec<u8>> {
+        match self {
+            DomainType::EmptyDeviceDomain(d) => d.export_state(),
+            DomainType::LogDomain(d) => d.export_state(),
+            DomainType::BlockDeviceDomain(d) => d.export_state(),
+        }
+    }
+
+    pub fn import_state(&self, state: &RRefVec<u8>) -> LinuxResult<()> {
+        match self {
+            DomainType::EmptyDeviceDomain(d) => d.import_state(state),
+            DomainType::LogDomain(d) => d.import_state(state),
+            DomainType::BlockDeviceDomain(d) => d.import_state(state),
+        }
+    }
+
     pub fn ref_count(&self) -> usize {
         match self {
             DomainType::EmptyDeviceDomain(d) => Arc::strong_count(d),
@@ -71,3 +216,319 @@ impl TryFrom<u8> for DomainTypeRaw {
         }
     }
 }
+
+impl DomainTypeRaw {
+    /// Whether a domain of this type can be hot-upgraded to a domain of type `new`
+    ///
+    /// The proxy of the old domain keeps serving the calls after the upgrade, so the
+    /// interface of `new` must be a superset of this one. No domain type extends
+    /// another one yet, so a domain can only be upgraded to the same type.
+    pub fn can_upgrade_to(self, new: DomainTypeRaw) -> bool {
+        self == new
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use alloc::{boxed::Box, collections::BTreeMap};
+    use core::{
+        alloc::Layout,
+        any::TypeId,
+        sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+    };
+    use std::sync::Mutex;
+
+    use rref::{RRefVec, SharedHeapAlloc, SharedHeapAllocation, SharedHeapHeader};
+
+    use super::*;
+    use crate::{
+        empty_device::EmptyDeviceConfig,
+        logger::{Level, LevelFilter},
+    };
+
+    #[derive(Debug)]
+    struct Device;
+
+    impl Basic for Device {
+        fn domain_id(&self) -> u64 {
+            1
+        }
+    }
+
+    impl EmptyDeviceDomain for Device {
+        fn init(&self, _config: &EmptyDeviceConfig) -> LinuxResult<()> {
+            Ok(())
+        }
+        fn read(&self, data: RRefVec<u8>) -> LinuxResult<RRefVec<u8>> {
+            Ok(data)
+        }
+        fn write(&self, data: &RRefVec<u8>) -> LinuxResult<usize> {
+            Ok(data.len())
+        }
+        fn write_read(&self, data: RRefVec<u8>) -> LinuxResult<RRefVec<u8>> {
+            Ok(data)
+        }
+        fn read_interruptible(&self, _data: &mut RRefVec<u8>, _call_id: u64) -> LinuxResult<usize> {
+            Ok(0)
+        }
+    }
+
+    #[derive(Debug)]
+    struct Logger;
+
+    impl Basic for Logger {
+        fn domain_id(&self) -> u64 {
+            2
+        }
+    }
+
+    impl LogDomain for Logger {
+        fn init(&self) -> LinuxResult<()> {
+            Ok(())
+        }
+        fn log(&self, _level: Level, _msg: &RRefVec<u8>) -> LinuxResult<()> {
+            Ok(())
+        }
+        fn set_max_level(&self, _level: LevelFilter) -> LinuxResult<()> {
+            Ok(())
+        }
+    }
+
+    #[derive(Debug)]
+    struct OtherLogger;
+
+    impl Basic for OtherLogger {
+        fn domain_id(&self) -> u64 {
+            3
+        }
+    }
+
+    impl LogDomain for OtherLogger {
+        fn init(&self) -> LinuxResult<()> {
+            Ok(())
+        }
+        fn log(&self, _level: Level, _msg: &RRefVec<u8>) -> LinuxResult<()> {
+            Ok(())
+        }
+        fn set_max_level(&self, _level: LevelFilter) -> LinuxResult<()> {
+            Ok(())
+        }
+    }
+
+    /// A logger which buffers the messages until it is shut down
+    #[derive(Debug, Default)]
+    struct BufferedLogger {
+        buffered: AtomicUsize,
+        written: AtomicUsize,
+    }
+
+    impl Basic for BufferedLogger {
+        fn domain_id(&self) -> u64 {
+            4
+        }
+        fn on_shutdown(&self) -> LinuxResult<()> {
+            let buffered = self.buffered.swap(0, Ordering::Relaxed);
+            self.written.fetch_add(buffered, Ordering::Relaxed);
+            Ok(())
+        }
+    }
+
+    impl LogDomain for BufferedLogger {
+        fn init(&self) -> LinuxResult<()> {
+            Ok(())
+        }
+        fn log(&self, _level: Level, _msg: &RRefVec<u8>) -> LinuxResult<()> {
+            self.buffered.fetch_add(1, Ordering::Relaxed);
+            Ok(())
+        }
+        fn set_max_level(&self, _level: LevelFilter) -> LinuxResult<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_domain_type_downcast() {
+        let device = DomainType::EmptyDeviceDomain(Arc::new(Device));
+        let logger = DomainType::LogDomain(Arc::new(Logger));
+        assert_eq!(device.as_empty_device::<Device>().unwrap().domain_id(), 1);
+        assert_eq!(logger.as_logger::<Logger>().unwrap().domain_id(), 2);
+        // a mismatched variant or type is not a panic
+        assert!(logger.as_empty_device::<Device>().is_none());
+        assert!(device.as_logger::<Logger>().is_none());
+        assert!(logger.as_logger::<OtherLogger>().is_none());
+    }
+
+    #[test]
+    fn test_domain_state_unsupported() {
+        // a domain which does not migrate its state can not be snapshotted
+        let device = DomainType::EmptyDeviceDomain(Arc::new(Device));
+        assert_eq!(device.export_state().err(), Some(LinuxErrno::ENOSYS));
+    }
+
+    #[test]
+    fn test_domain_shutdown_flush() {
+        let logger = Arc::new(BufferedLogger::default());
+        let domain = DomainType::LogDomain(logger.clone());
+        // three messages are logged and buffered
+        logger.buffered.store(3, Ordering::Relaxed);
+        assert_eq!(logger.written.load(Ordering::Relaxed), 0);
+        // the buffered messages would be lost with the domain without the hook
+        domain.on_shutdown().unwrap();
+        assert_eq!(logger.written.load(Ordering::Relaxed), 3);
+        assert_eq!(logger.buffered.load(Ordering::Relaxed), 0);
+        // a domain which buffers nothing has nothing to do
+        let device = DomainType::EmptyDeviceDomain(Arc::new(Device));
+        assert!(device.on_shutdown().is_ok());
+    }
+
+    /// A proxy whose domain can be replaced
+    #[derive(Debug, Default)]
+    struct UpgradableDevice {
+        epoch: AtomicU64,
+    }
+
+    impl Basic for UpgradableDevice {
+        fn domain_id(&self) -> u64 {
+            5
+        }
+        fn epoch(&self) -> u64 {
+            self.epoch.load(Ordering::Acquire)
+        }
+    }
+
+    impl EmptyDeviceDomain for UpgradableDevice {
+        fn init(&self, _config: &EmptyDeviceConfig) -> LinuxResult<()> {
+            Ok(())
+        }
+        fn read(&self, data: RRefVec<u8>) -> LinuxResult<RRefVec<u8>> {
+            Ok(data)
+        }
+        fn write(&self, data: &RRefVec<u8>) -> LinuxResult<usize> {
+            Ok(data.len())
+        }
+        fn write_read(&self, data: RRefVec<u8>) -> LinuxResult<RRefVec<u8>> {
+            Ok(data)
+        }
+        fn read_interruptible(&self, _data: &mut RRefVec<u8>, _call_id: u64) -> LinuxResult<usize> {
+            Ok(0)
+        }
+    }
+
+    #[test]
+    fn test_domain_epoch() {
+        let proxy = Arc::new(UpgradableDevice::default());
+        let cached = DomainType::EmptyDeviceDomain(proxy.clone());
+        let seen = cached.epoch();
+        assert_eq!(seen, 0);
+        // the upgrade replaces the domain behind the same proxy
+        proxy.epoch.fetch_add(1, Ordering::Release);
+        assert_eq!(cached.epoch(), seen + 1);
+        assert_eq!(DomainType::EmptyDeviceDomain(Arc::new(Device)).epoch(), 0);
+    }
+
+    /// A shared heap of [BoundedHeap::LIMIT] bytes, the allocations over it fail
+    struct BoundedHeap {
+        sizes: Mutex<BTreeMap<usize, usize>>,
+    }
+
+    impl BoundedHeap {
+        const LIMIT: usize = 64;
+    }
+
+    impl SharedHeapAlloc for BoundedHeap {
+        unsafe fn alloc(
+            &self,
+            layout: Layout,
+            type_id: TypeId,
+            drop_fn: fn(TypeId, *mut u8),
+        ) -> Option<SharedHeapAllocation> {
+            let mut sizes = self.sizes.lock().unwrap();
+            if sizes.values().sum::<usize>() + layout.size() > Self::LIMIT {
+                return None;
+            }
+            let value_pointer = alloc::alloc::alloc(layout);
+            sizes.insert(value_pointer as usize, layout.size());
+            Some(SharedHeapAllocation {
+                value_pointer,
+                domain_id_pointer: Box::into_raw(Box::new(SharedHeapHeader::default())) as *mut u64,
+                layout,
+                type_id,
+                drop_fn,
+            })
+        }
+        unsafe fn dealloc(&self, ptr: *mut u8) {
+            self.sizes.lock().unwrap().remove(&(ptr as usize));
+        }
+        unsafe fn retype(&self, _ptr: *mut u8, _type_id: TypeId) {}
+    }
+
+    static HEAP: BoundedHeap = BoundedHeap {
+        sizes: Mutex::new(BTreeMap::new()),
+    };
+
+    /// A device which caches data in the shared heap until the heap is under pressure
+    #[derive(Debug, Default)]
+    struct CachingDevice {
+        cache: Mutex<Option<RRefVec<u8>>>,
+    }
+
+    impl Basic for CachingDevice {
+        fn domain_id(&self) -> u64 {
+            6
+        }
+        fn on_memory_pressure(&self, _level: u8) -> LinuxResult<usize> {
+            Ok(self
+                .cache
+                .lock()
+                .unwrap()
+                .take()
+                .map_or(0, |cache| cache.size()))
+        }
+    }
+
+    impl EmptyDeviceDomain for CachingDevice {
+        fn init(&self, _config: &EmptyDeviceConfig) -> LinuxResult<()> {
+            Ok(())
+        }
+        fn read(&self, data: RRefVec<u8>) -> LinuxResult<RRefVec<u8>> {
+            Ok(data)
+        }
+        fn write(&self, data: &RRefVec<u8>) -> LinuxResult<usize> {
+            Ok(data.len())
+        }
+        fn write_read(&self, data: RRefVec<u8>) -> LinuxResult<RRefVec<u8>> {
+            Ok(data)
+        }
+        fn read_interruptible(&self, _data: &mut RRefVec<u8>, _call_id: u64) -> LinuxResult<usize> {
+            Ok(0)
+        }
+    }
+
+    #[test]
+    fn test_domain_memory_pressure() {
+        rref::init(&HEAP, 6);
+        let device = Arc::new(CachingDevice::default());
+        let domain = DomainType::EmptyDeviceDomain(device.clone());
+        *device.cache.lock().unwrap() = Some(RRefVec::new(1, 40));
+        // the cache leaves no room for 32 bytes
+        let layout = Layout::array::<u8>(32).unwrap();
+        assert!(rref::share_heap_alloc(layout, TypeId::of::<u8>(), |_, _| {}).is_none());
+        // the domain frees its cache under pressure, then the allocation succeeds
+        assert_eq!(domain.on_memory_pressure(1), Ok(40));
+        let data = RRefVec::new(0u8, 32);
+        assert_eq!(data.size(), 32);
+        // there is nothing left to free
+        assert_eq!(domain.on_memory_pressure(2), Ok(0));
+    }
+
+    #[test]
+    fn test_domain_type_upgrade() {
+        assert!(DomainTypeRaw::EmptyDeviceDomain.can_upgrade_to(DomainTypeRaw::EmptyDeviceDomain));
+        assert!(DomainTypeRaw::LogDomain.can_upgrade_to(DomainTypeRaw::LogDomain));
+        // the interfaces of the other types are not supersets of each other
+        assert!(!DomainTypeRaw::EmptyDeviceDomain.can_upgrade_to(DomainTypeRaw::BlockDeviceDomain));
+        assert!(!DomainTypeRaw::BlockDeviceDomain.can_upgrade_to(DomainTypeRaw::LogDomain));
+    }
+}
@@ -36,11 +36,25 @@ pub trait BlockDeviceDomain: Basic + DowncastSync {
     ) -> LinuxResult<()>;
     fn commit_rqs(&self, hctx_ptr: SafePtr, hctx_driver_data_ptr: SafePtr) -> LinuxResult<()>;
     fn complete_request(&self, rq_ptr: SafePtr) -> LinuxResult<()>;
+    /// Set how the writes are completed, switching to [CacheMode::WriteThrough] flushes the
+    /// cached writes first
+    fn set_cache_mode(&self, mode: CacheMode) -> LinuxResult<()>;
     fn exit(&self) -> LinuxResult<()>;
 }
 
 impl_downcast!(sync BlockDeviceDomain);
 
+/// How a block domain with a write cache completes the writes
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum CacheMode {
+    /// A write is completed after it reaches the backing store
+    #[default]
+    WriteThrough,
+    /// A write is completed once it is cached, the cache is written back by a flush
+    /// request, so the writes after the last flush are lost if the domain crashes
+    WriteBack,
+}
+
 #[derive(Debug, Copy, Clone)]
 pub struct BlockArgs {
     // Use memory backing
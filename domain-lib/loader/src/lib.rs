@@ -17,7 +17,8 @@ use core::{
     ops::Range,
 };
 
-use corelib::domain_info::DomainFileInfo;
+use corelib::domain_info::{DomainFileInfo, DomainLoadInfo};
+use interface::{INTERFACE_VERSION, INTERFACE_VERSION_SECTION};
 use log::{debug, trace};
 use memory_addr::VirtAddr;
 use storage::StorageArg;
@@ -83,6 +84,34 @@ impl<V: DomainVmOps> DomainLoader<V> {
         }
     }
 
+    /// The ELF image this loader has loaded
+    ///
+    /// A clone of the loader is not loaded, so it reports 0 as the base and the entry.
+    pub fn domain_load_info(&self) -> DomainLoadInfo {
+        DomainLoadInfo {
+            name: self.ident.clone(),
+            size: self.data.len(),
+            base: self.virt_start,
+            entry: self.entry_point,
+        }
+    }
+
+    /// Take over the image loaded by `old`, so this loader describes the running domain with
+    /// its own ELF data, e.g. one with richer debug info.
+    ///
+    /// `old` is left unloaded and can be dropped without unmapping the domain. Return an
+    /// error if this loader is loaded, its image would be unmapped.
+    pub fn take_over(&mut self, old: &mut Self) -> Result<()> {
+        if self.module_area.is_some() {
+            return Err("the loader is loaded");
+        }
+        self.module_area = old.module_area.take();
+        self.entry_point = old.entry_point;
+        self.virt_start = old.virt_start;
+        self.text_section = old.text_section.clone();
+        Ok(())
+    }
+
     pub fn empty() -> Self {
         Self::new(Arc::new(vec![]), "empty_loader")
     }
@@ -181,6 +210,9 @@ impl<V: DomainVmOps> DomainLoader<V> {
         debug!("Domain address:{:p}", elf_binary.as_ptr());
         let elf = ElfFile::new(elf_binary)?;
         debug!("Domain type:{:?}", elf.header.pt2.type_().as_type());
+        if elf_interface_version(&elf) != Some(INTERFACE_VERSION) {
+            return Err("interface version mismatch");
+        }
         let end_paddr = elf
             .program_iter()
             .filter(|ph| ph.get_type() == Ok(Type::Load))
@@ -223,6 +255,22 @@ impl<V: DomainVmOps> Drop for DomainLoader<V> {
     }
 }
 
+/// Read the interface version recorded in the domain elf data.
+///
+/// Return `None` if the data is not an elf file or the domain is built without the version.
+pub fn interface_version(elf_binary: &[u8]) -> Option<u32> {
+    let elf = ElfFile::new(elf_binary).ok()?;
+    elf_interface_version(&elf)
+}
+
+fn elf_interface_version(elf: &ElfFile) -> Option<u32> {
+    let data = elf
+        .find_section_by_name(INTERFACE_VERSION_SECTION)?
+        .raw_data(elf);
+    let version = data.get(..4)?.try_into().ok()?;
+    Some(u32::from_le_bytes(version))
+}
+
 fn relocate_dyn(elf: &ElfFile, region_start: usize) -> Result<Vec<(usize, usize)>> {
     let data = elf
         .find_section_by_name(".rela.dyn")
@@ -256,3 +304,169 @@ const RELATIVE: u32 = R_RISCV_RELATIVE;
 
 #[cfg(target_arch = "x86_64")]
 const RELATIVE: u32 = R_X86_64_RELATIVE;
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    /// Build a minimal elf file which only has the interface version section.
+    fn stamped_elf(version: u32) -> Vec<u8> {
+        const SHSTRTAB: &[u8] = b"\0.domain_interface\0.shstrtab\0";
+        let shstrtab_off = 72;
+        let shdr_off = (shstrtab_off + SHSTRTAB.len() + 7) & !7;
+        let mut elf = Vec::new();
+        // elf header
+        elf.extend_from_slice(&[0x7f, b'E', b'L', b'F', 2, 1, 1, 0]);
+        elf.extend_from_slice(&[0; 8]);
+        elf.extend_from_slice(&3u16.to_le_bytes()); // e_type: ET_DYN
+        elf.extend_from_slice(&62u16.to_le_bytes()); // e_machine: x86_64
+        elf.extend_from_slice(&1u32.to_le_bytes()); // e_version
+        elf.extend_from_slice(&0u64.to_le_bytes()); // e_entry
+        elf.extend_from_slice(&0u64.to_le_bytes()); // e_phoff
+        elf.extend_from_slice(&(shdr_off as u64).to_le_bytes()); // e_shoff
+        elf.extend_from_slice(&0u32.to_le_bytes()); // e_flags
+        elf.extend_from_slice(&64u16.to_le_bytes()); // e_ehsize
+        elf.extend_from_slice(&56u16.to_le_bytes()); // e_phentsize
+        elf.extend_from_slice(&0u16.to_le_bytes()); // e_phnum
+        elf.extend_from_slice(&64u16.to_le_bytes()); // e_shentsize
+        elf.extend_from_slice(&3u16.to_le_bytes()); // e_shnum
+        elf.extend_from_slice(&2u16.to_le_bytes()); // e_shstrndx
+                                                    // .domain_interface
+        elf.extend_from_slice(&version.to_le_bytes());
+        elf.resize(shstrtab_off, 0);
+        // .shstrtab
+        elf.extend_from_slice(SHSTRTAB);
+        elf.resize(shdr_off, 0);
+        // section headers: null, .domain_interface, .shstrtab
+        let mut section = |name: u32, ty: u32, offset: usize, size: usize| {
+            elf.extend_from_slice(&name.to_le_bytes());
+            elf.extend_from_slice(&ty.to_le_bytes());
+            elf.extend_from_slice(&0u64.to_le_bytes()); // sh_flags
+            elf.extend_from_slice(&0u64.to_le_bytes()); // sh_addr
+            elf.extend_from_slice(&(offset as u64).to_le_bytes());
+            elf.extend_from_slice(&(size as u64).to_le_bytes());
+            elf.extend_from_slice(&0u32.to_le_bytes()); // sh_link
+            elf.extend_from_slice(&0u32.to_le_bytes()); // sh_info
+            elf.extend_from_slice(&1u64.to_le_bytes()); // sh_addralign
+            elf.extend_from_slice(&0u64.to_le_bytes()); // sh_entsize
+        };
+        section(0, 0, 0, 0);
+        section(1, 1, 64, 4);
+        section(19, 3, shstrtab_off, SHSTRTAB.len());
+        elf
+    }
+
+    /// Map an empty area and do nothing else
+    struct NoVmOps;
+
+    impl DomainVmOps for NoVmOps {
+        fn map_domain_area(_size: usize) -> Box<dyn DomainArea> {
+            Box::new(TestArea)
+        }
+        fn unmap_domain_area(_area: Box<dyn DomainArea>) {}
+        fn set_memory_x(_start: usize, _pages: usize) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    static UNMAPPED: AtomicUsize = AtomicUsize::new(0);
+
+    #[derive(Debug)]
+    struct TestArea;
+
+    impl DomainArea for TestArea {
+        fn as_slice(&self) -> &[u8] {
+            &[]
+        }
+        fn as_mut_slice(&self) -> &mut [u8] {
+            &mut []
+        }
+        fn start_virtual_address(&self) -> VirtAddr {
+            VirtAddr::from(0x1000)
+        }
+        fn any(self: Box<Self>) -> Box<dyn core::any::Any> {
+            self
+        }
+    }
+
+    struct CountVmOps;
+
+    impl DomainVmOps for CountVmOps {
+        fn map_domain_area(_size: usize) -> Box<dyn DomainArea> {
+            Box::new(TestArea)
+        }
+        fn unmap_domain_area(_area: Box<dyn DomainArea>) {
+            UNMAPPED.fetch_add(1, Ordering::Relaxed);
+        }
+        fn set_memory_x(_start: usize, _pages: usize) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn take_over_keeps_the_image() {
+        let mut old = DomainLoader::<CountVmOps>::new(Arc::new(vec![1, 2, 3]), "null");
+        old.module_area = Some(CountVmOps::map_domain_area(0x1000));
+        old.virt_start = 0x1000;
+        old.entry_point = 0x1010;
+        let mut new = DomainLoader::<CountVmOps>::new(Arc::new(vec![1, 2, 3, 4]), "null_v2");
+        new.take_over(&mut old).unwrap();
+        drop(old);
+        assert_eq!(UNMAPPED.load(Ordering::Relaxed), 0);
+
+        // the image is described with the name and the data of the new elf
+        let info = new.domain_load_info();
+        assert_eq!(info.name, "null_v2");
+        assert_eq!((info.base, info.entry, info.size), (0x1000, 0x1010, 4));
+        // a loaded loader cannot take over another image
+        let mut other = DomainLoader::<CountVmOps>::new(Arc::new(vec![]), "null");
+        assert!(new.take_over(&mut other).is_err());
+        drop(new);
+        assert_eq!(UNMAPPED.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn unloaded_domain_load_info() {
+        let elf = stamped_elf(INTERFACE_VERSION);
+        let len = elf.len();
+        let loader = DomainLoader::<NoVmOps>::new(Arc::new(elf), "null");
+        let info = loader.domain_load_info();
+        assert_eq!(info.name, "null");
+        assert_eq!(info.size, len);
+        assert_eq!((info.base, info.entry), (0, 0));
+    }
+
+    #[test]
+    fn upgraded_domain_load_info() {
+        let mut current = DomainLoader::<NoVmOps>::new(Arc::new(vec![0; 16]), "null");
+        current.module_area = Some(NoVmOps::map_domain_area(0x1000));
+        current.virt_start = 0x1000;
+        current.entry_point = 0x1010;
+        let mut new = DomainLoader::<NoVmOps>::new(Arc::new(vec![0; 32]), "null_v2");
+        new.module_area = Some(NoVmOps::map_domain_area(0x1000));
+        new.virt_start = 0x3000;
+        new.entry_point = 0x3020;
+        // an upgrade swaps the loader of the proxy, the old one is dropped with the old domain
+        let old = core::mem::replace(&mut current, new);
+        drop(old);
+        let info = current.domain_load_info();
+        assert_eq!(info.name, "null_v2");
+        assert_eq!((info.base, info.entry, info.size), (0x3000, 0x3020, 32));
+    }
+
+    #[test]
+    fn stamped_interface_version() {
+        let elf = stamped_elf(INTERFACE_VERSION);
+        assert_eq!(interface_version(&elf), Some(INTERFACE_VERSION));
+    }
+
+    #[test]
+    fn mismatched_interface_version() {
+        let elf = stamped_elf(INTERFACE_VERSION + 1);
+        assert_ne!(interface_version(&elf), Some(INTERFACE_VERSION));
+        assert_eq!(interface_version(b"not a elf file"), None);
+    }
+}
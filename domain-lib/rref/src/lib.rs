@@ -5,16 +5,19 @@
 #![no_std]
 mod rref;
 mod rvec;
+pub mod wire;
 
 extern crate alloc;
+use alloc::vec::Vec;
 use core::{
     alloc::Layout,
     any::{type_name_of_val, TypeId},
+    sync::atomic::{AtomicUsize, Ordering},
 };
 
 pub use rref::RRef;
-pub use rvec::RRefVec;
-use spin::Once;
+pub use rvec::{RRefVec, DMA_ALIGN};
+use spin::{Mutex, Once};
 /// A trait for types that can be shared between domains.
 ///
 /// # Safety
@@ -43,7 +46,7 @@ pub trait CustomDrop {
 
 impl<T: RRefable> CustomDrop for T {
     default fn custom_drop(&mut self) {
-        log::warn!("default for {}", type_name_of_val(self));
+        log::trace!("default for {}", type_name_of_val(self));
     }
 }
 impl<T: RRefable> CustomDrop for Option<T> {
@@ -101,6 +104,26 @@ impl_shared_data!((0, T), (1, T), (2, T), (3, T), (4, T));
 impl_shared_data!((0, T), (1, T), (2, T), (3, T), (4, T), (5, T));
 impl_shared_data!((0, T), (1, T), (2, T), (3, T), (4, T), (5, T), (6, T));
 
+/// The header of a shared heap allocation, `domain_id_pointer` points to it.
+///
+/// The owner is the first field, so the header can be used as a pointer to the owner.
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct SharedHeapHeader {
+    pub domain_id: u64,
+    /// The id set by `RRef::set_trace_id`, 0 if the data is not traced
+    pub trace_id: u64,
+    /// The class set by `RRef::set_resource_class`, 0 if the data has no class
+    pub resource_class: u64,
+}
+
+impl SharedHeapHeader {
+    /// The layout the allocator must use for the header behind `domain_id_pointer`
+    pub const fn layout() -> Layout {
+        Layout::new::<SharedHeapHeader>()
+    }
+}
+
 #[derive(Copy, Clone)]
 pub struct SharedHeapAllocation {
     pub value_pointer: *mut u8,
@@ -122,6 +145,12 @@ impl SharedHeapAllocation {
             *self.domain_id_pointer = domain_id;
         }
     }
+    pub fn trace_id(&self) -> u64 {
+        unsafe { (*(self.domain_id_pointer as *mut SharedHeapHeader)).trace_id }
+    }
+    pub fn resource_class(&self) -> u64 {
+        unsafe { (*(self.domain_id_pointer as *mut SharedHeapHeader)).resource_class }
+    }
 }
 
 unsafe impl Send for SharedHeapAllocation {}
@@ -129,6 +158,8 @@ unsafe impl Send for SharedHeapAllocation {}
 pub trait SharedHeapAlloc: Send + Sync {
     /// Allocates a new heap allocation with the given layout, type_id, and drop function.
     ///
+    /// `domain_id_pointer` of the allocation must point to a [SharedHeapHeader].
+    ///
     /// # Safety
     ///
     /// The caller must ensure that the layout is valid and that the drop function is correct.
@@ -144,12 +175,45 @@ pub trait SharedHeapAlloc: Send + Sync {
     ///
     /// The caller must ensure that the pointer is valid and that the allocation was not already deallocated.
     unsafe fn dealloc(&self, ptr: *mut u8);
+    /// Deallocates the heap allocation at the given pointer unless the allocator is busy.
+    ///
+    /// Return false without touching the allocation if the allocator lock is held, e.g. by
+    /// the frame which is panicking, so the caller can retry later instead of deadlocking.
+    /// It is only used while [SharedHeapAlloc::unwinding]. The default implementation never
+    /// reports the allocator busy.
+    ///
+    /// # Safety
+    ///
+    /// The same as [SharedHeapAlloc::dealloc].
+    unsafe fn try_dealloc(&self, ptr: *mut u8) -> bool {
+        self.dealloc(ptr);
+        true
+    }
+    /// Whether the current task is unwinding from a panic, only then a deallocation may find
+    /// the allocator lock held by the frame which is panicking. The default implementation
+    /// never unwinds.
+    fn unwinding(&self) -> bool {
+        false
+    }
+    /// Changes the type of the heap allocation at the given pointer, so it is dropped with
+    /// `type_id` afterwards.
+    ///
+    /// # Safety
+    ///
+    /// The caller must ensure that the pointer is valid and that the allocation holds a value
+    /// of the new type before it is dropped.
+    unsafe fn retype(&self, ptr: *mut u8, type_id: TypeId);
 }
 
 static SHARED_HEAP: Once<&'static dyn SharedHeapAlloc> = Once::new();
 
 static CRATE_DOMAIN_ID: Once<u64> = Once::new();
 
+/// The allocations whose deallocation found the allocator busy, see [process_deferred_dealloc]
+static DEFERRED_DEALLOC: Mutex<Vec<usize>> = Mutex::new(Vec::new());
+/// The length of [DEFERRED_DEALLOC], so the deallocations need not lock it when it is empty
+static DEFERRED_COUNT: AtomicUsize = AtomicUsize::new(0);
+
 pub fn init(allocator: &'static dyn SharedHeapAlloc, domain_id: u64) {
     SHARED_HEAP.call_once(|| allocator);
     CRATE_DOMAIN_ID.call_once(|| domain_id);
@@ -163,8 +227,51 @@ pub fn share_heap_alloc(
     unsafe { SHARED_HEAP.get_unchecked().alloc(layout, type_id, drop_fn) }
 }
 
+/// Deallocate the allocation, or defer it if the allocator is busy while unwinding.
+///
+/// An RRef dropped while unwinding may find the allocator lock held by the panicking
+/// frame, waiting for it would never return. The deferred allocations are freed by the
+/// next deallocation, or by [process_deferred_dealloc]. Otherwise the deallocation waits
+/// for the allocator as usual.
 pub(crate) fn share_heap_dealloc(ptr: *mut u8) {
-    unsafe { SHARED_HEAP.get_unchecked().dealloc(ptr) }
+    let heap = unsafe { SHARED_HEAP.get_unchecked() };
+    if !heap.unwinding() {
+        unsafe { heap.dealloc(ptr) };
+    } else if !unsafe { heap.try_dealloc(ptr) } {
+        log::warn!(
+            "<rref> dealloc {:p}: the shared heap is busy, defer it",
+            ptr
+        );
+        DEFERRED_DEALLOC.lock().push(ptr as usize);
+        DEFERRED_COUNT.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+    if DEFERRED_COUNT.load(Ordering::Relaxed) != 0 {
+        process_deferred_dealloc();
+    }
+}
+
+/// Deallocate the allocations deferred because the allocator was busy.
+///
+/// It should be called once the unwinding is over. Return the number of the allocations
+/// freed, the ones which still find the allocator busy stay deferred.
+pub fn process_deferred_dealloc() -> usize {
+    let pending = core::mem::take(&mut *DEFERRED_DEALLOC.lock());
+    DEFERRED_COUNT.fetch_sub(pending.len(), Ordering::Relaxed);
+    let mut freed = 0;
+    for ptr in pending {
+        if unsafe { SHARED_HEAP.get_unchecked().try_dealloc(ptr as *mut u8) } {
+            freed += 1;
+        } else {
+            DEFERRED_DEALLOC.lock().push(ptr);
+            DEFERRED_COUNT.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+    freed
+}
+
+pub(crate) fn share_heap_retype(ptr: *mut u8, type_id: TypeId) {
+    unsafe { SHARED_HEAP.get_unchecked().retype(ptr, type_id) }
 }
 
 #[inline]
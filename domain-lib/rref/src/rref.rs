@@ -7,17 +7,20 @@
 //! 4. 类型安全的接口
 //!
 //! Reference: https://std-dev-guide.rust-lang.org/policy/specialization.html
-use alloc::collections::BTreeMap;
+use alloc::{boxed::Box, collections::BTreeMap};
 use core::{
     alloc::Layout,
     any::TypeId,
     fmt::{Debug, Formatter},
+    hash::{Hash, Hasher},
+    mem::{align_of, size_of, ManuallyDrop, MaybeUninit},
     ops::{Deref, DerefMut},
+    sync::atomic::{fence, AtomicPtr, Ordering},
 };
 
 use spin::Mutex;
 
-use super::{CustomDrop, RRefable, SharedData, TypeIdentifiable};
+use super::{CustomDrop, RRefable, SharedData, SharedHeapHeader, TypeIdentifiable};
 
 /// RRef<T> - 远程引用类型
 /// 
@@ -70,63 +73,213 @@ pub fn drop_domain_share_data(id: TypeId, ptr: *mut u8) {
     drop_fn(ptr);
 }
 
+/// DROP_CACHE: 已经注册到DROP中的类型
+///
+/// 一个类型的drop fn只需要注册一次，之后再分配这个类型时只需要查这个无锁的缓存，
+/// 不需要再获取DROP的锁。缓存满了以后退化为每次都获取锁。
+///
+/// 每一项指向一个泄漏的TypeId，查找时比较完整的TypeId，不同的类型即使哈希相同也不会
+/// 被当成已经注册。缓存最多泄漏DROP_CACHE_SIZE个TypeId。
+const DROP_CACHE_SIZE: usize = 64;
+static DROP_CACHE: [AtomicPtr<TypeId>; DROP_CACHE_SIZE] =
+    [const { AtomicPtr::new(core::ptr::null_mut()) }; DROP_CACHE_SIZE];
+
+/// 测试中每个类型获取DROP的锁的次数，按类型统计，不受并行的其他测试影响
+#[cfg(test)]
+static DROP_LOCK_COUNT: Mutex<BTreeMap<TypeId, usize>> = Mutex::new(BTreeMap::new());
+
+/// FNV-1a，用于计算TypeId在缓存中的起始位置
+struct Fnv64(u64);
+
+impl Hasher for Fnv64 {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= *byte as u64;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+}
+
+/// TypeId在DROP_CACHE中开始探测的位置
+fn drop_cache_start(type_id: TypeId) -> usize {
+    let mut hasher = Fnv64(0xcbf29ce484222325);
+    type_id.hash(&mut hasher);
+    hasher.finish() as usize % DROP_CACHE_SIZE
+}
+
+/// 从`start`开始线性探测，type_id是否已经在缓存中
+fn drop_cache_contains(type_id: TypeId, start: usize) -> bool {
+    for i in 0..DROP_CACHE_SIZE {
+        let ptr = DROP_CACHE[(start + i) % DROP_CACHE_SIZE].load(Ordering::Acquire);
+        if ptr.is_null() {
+            return false;
+        }
+        // SAFETY: 缓存中的TypeId被泄漏，永远不会释放
+        if unsafe { *ptr } == type_id {
+            return true;
+        }
+    }
+    false
+}
+
+/// 从`start`开始把type_id放入第一个空槽，已经在缓存中或者缓存满了时什么都不做
+fn drop_cache_insert(type_id: TypeId, start: usize) {
+    let new = Box::into_raw(Box::new(type_id));
+    for i in 0..DROP_CACHE_SIZE {
+        match DROP_CACHE[(start + i) % DROP_CACHE_SIZE].compare_exchange(
+            core::ptr::null_mut(),
+            new,
+            Ordering::Release,
+            Ordering::Acquire,
+        ) {
+            Ok(_) => return,
+            // SAFETY: 同上
+            Err(ptr) if unsafe { *ptr } == type_id => break,
+            Err(_) => {}
+        }
+    }
+    // SAFETY: new没有放入缓存，没有其他引用
+    drop(unsafe { Box::from_raw(new) });
+}
+
+/// 注册类型T的drop fn，同一类型只在第一次注册时获取DROP的锁
+fn register_drop_fn<T: RRefable + TypeIdentifiable>(type_id: TypeId) {
+    let start = drop_cache_start(type_id);
+    if drop_cache_contains(type_id, start) {
+        return;
+    }
+
+    #[cfg(test)]
+    {
+        *DROP_LOCK_COUNT.lock().entry(type_id).or_default() += 1;
+    }
+    DROP.lock().entry(type_id).or_insert(drop_no_type::<T>);
+
+    // 注册完成后才放入缓存，其他CPU在缓存中看到它时DROP中一定已经有这个类型
+    drop_cache_insert(type_id, start);
+}
+
 impl<T: RRefable> RRef<T>
 where
     T: TypeIdentifiable,
 {
-    pub(crate) unsafe fn new_with_layout(value: T, layout: Layout, init: bool) -> RRef<T> {
+    /// alloc_with_layout - 在共享堆中分配一块`layout`大小的内存，不写入任何值
+    ///
+    /// 分配以类型T注册drop fn，所以domain被回收时这块内存会被当作T释放。未初始化的分配以
+    /// `MaybeUninit<T>`分配，它的drop什么都不做。
+    ///
+    /// # Safety
+    ///
+    /// 调用者必须在读取之前初始化数据，`layout`必须能容纳T
+    pub(crate) unsafe fn alloc_with_layout(layout: Layout) -> RRef<T> {
         let type_id = T::type_id();
-        let mut drop_guard = DROP.lock();
-        drop_guard.entry(type_id).or_insert(drop_no_type::<T>);
-        drop(drop_guard);
+        register_drop_fn::<T>(type_id);
 
         let allocation = match crate::share_heap_alloc(layout, type_id, drop_domain_share_data) {
             Some(allocation) => allocation,
             None => panic!("Shared heap allocation failed"),
         };
-        let value_pointer = allocation.value_pointer as *mut T;
-        *allocation.domain_id_pointer = crate::domain_id();
-        if init {
-            core::ptr::write(value_pointer, value);
-        }
+        // 缓存中复用的header可能还带着上一个数据的追踪id和资源类别
+        *(allocation.domain_id_pointer as *mut SharedHeapHeader) = SharedHeapHeader {
+            domain_id: crate::domain_id(),
+            trace_id: 0,
+            resource_class: 0,
+        };
         RRef {
             domain_id_pointer: allocation.domain_id_pointer,
-            value_pointer,
+            value_pointer: allocation.value_pointer as *mut T,
             exist: false,
         }
     }
 
+    /// new_with_layout - 分配并写入数据
+    ///
+    /// 发布顺序：数据和header写完之后才有release fence，RRef被返回后才可能通过
+    /// move_to或者指针传递发布给其他domain，所以其他CPU上的domain只要以acquire
+    /// （或者锁）观察到这个指针或新的domain id，就一定能看到完整初始化的数据。
+    unsafe fn new_with_layout(value: T, layout: Layout) -> RRef<T> {
+        let rref = Self::alloc_with_layout(layout);
+        core::ptr::write(rref.value_pointer, value);
+        fence(Ordering::Release);
+        rref
+    }
+
     pub fn new(value: T) -> RRef<T> {
         let layout = Layout::new::<T>();
-        unsafe { Self::new_with_layout(value, layout, true) }
+        unsafe { Self::new_with_layout(value, layout) }
     }
 
     pub fn new_aligned(value: T, align: usize) -> RRef<T> {
-        let size = core::mem::size_of::<T>();
-        let layout = unsafe { Layout::from_size_align_unchecked(size, align) };
-        unsafe { Self::new_with_layout(value, layout, true) }
+        let layout = Self::aligned_layout(align);
+        unsafe { Self::new_with_layout(value, layout) }
     }
 
-    pub fn new_uninit() -> RRef<T> {
+    /// new_uninit - 分配一个未初始化的RRef，调用者写入数据后通过[RRef::assume_init]得到RRef<T>
+    ///
+    /// 在初始化之前drop不会对数据调用T的drop
+    pub fn new_uninit() -> RRef<MaybeUninit<T>> {
         let layout = Layout::new::<T>();
-        unsafe {
-            Self::new_with_layout(
-                core::mem::MaybeUninit::uninit().assume_init(),
-                layout,
-                false,
-            )
-        }
+        unsafe { RRef::<MaybeUninit<T>>::alloc_with_layout(layout) }
     }
 
-    pub fn new_uninit_aligned(align: usize) -> RRef<T> {
-        let size = core::mem::size_of::<T>();
-        let layout = unsafe { Layout::from_size_align_unchecked(size, align) };
-        unsafe {
-            Self::new_with_layout(
-                core::mem::MaybeUninit::uninit().assume_init(),
-                layout,
-                false,
-            )
+    pub fn new_uninit_aligned(align: usize) -> RRef<MaybeUninit<T>> {
+        let layout = Self::aligned_layout(align);
+        unsafe { RRef::<MaybeUninit<T>>::alloc_with_layout(layout) }
+    }
+
+    /// `align`必须是2的幂，并且不小于T本身的对齐要求
+    fn aligned_layout(align: usize) -> Layout {
+        Layout::new::<T>()
+            .align_to(align)
+            .expect("invalid alignment for RRef")
+    }
+
+    /// map - 用f将数据转换为U，复用同一块共享堆内存，不重新分配
+    ///
+    /// 这块内存之后以类型U注册drop fn。U的大小不能超过T，并且这块内存的地址必须满足U的对齐要求，
+    /// 否则panic。如果f panic，这块内存会被直接释放，不会再对数据调用T的drop。
+    pub fn map<U, F>(self, f: F) -> RRef<U>
+    where
+        U: RRefable + TypeIdentifiable,
+        F: FnOnce(T) -> U,
+    {
+        assert!(
+            size_of::<U>() <= size_of::<T>(),
+            "RRef::map: the new type is larger than the old one"
+        );
+        assert_eq!(
+            self.value_pointer as usize % align_of::<U>(),
+            0,
+            "RRef::map: the allocation is not aligned for the new type"
+        );
+
+        /// f panic时释放这块内存，数据已经被移出，不能再调用T的drop
+        struct DeallocGuard(*mut u8);
+        impl Drop for DeallocGuard {
+            fn drop(&mut self) {
+                crate::share_heap_dealloc(self.0);
+            }
+        }
+
+        let this = ManuallyDrop::new(self);
+        let ptr = this.value_pointer as *mut u8;
+        let guard = DeallocGuard(ptr);
+        let value = unsafe { core::ptr::read(this.value_pointer) };
+        let value = f(value);
+        core::mem::forget(guard);
+
+        let type_id = U::type_id();
+        register_drop_fn::<U>(type_id);
+        crate::share_heap_retype(ptr, type_id);
+        unsafe { core::ptr::write(ptr as *mut U, value) };
+        RRef {
+            domain_id_pointer: this.domain_id_pointer,
+            value_pointer: ptr as *mut U,
+            exist: this.exist,
         }
     }
 
@@ -135,6 +288,61 @@ where
     }
 }
 
+impl<T: RRefable> RRef<T> {
+    /// set_trace_id - 给数据打上追踪id，0表示不追踪
+    ///
+    /// 追踪id保存在共享堆的header中，move_to不会改变它。开启`debug_rref`特性时，
+    /// move_to会记录被追踪的数据在domain之间的每次转移。
+    pub fn set_trace_id(&mut self, trace_id: u64) {
+        unsafe { (*self.header()).trace_id = trace_id }
+    }
+
+    /// trace_id - 数据的追踪id，没有追踪时为0
+    pub fn trace_id(&self) -> u64 {
+        unsafe { (*self.header()).trace_id }
+    }
+
+    /// set_resource_class - 设置数据的资源类别，0表示没有类别
+    ///
+    /// 热升级按资源类别选择释放哪些共享数据而不迁移到新domain，见corelib的
+    /// `ReplaceOptions`。资源类别和追踪id互不影响，move_to不会改变它。
+    pub fn set_resource_class(&mut self, class: u64) {
+        unsafe { (*self.header()).resource_class = class }
+    }
+
+    /// resource_class - 数据的资源类别，没有类别时为0
+    pub fn resource_class(&self) -> u64 {
+        unsafe { (*self.header()).resource_class }
+    }
+
+    fn header(&self) -> *mut SharedHeapHeader {
+        self.domain_id_pointer as *mut SharedHeapHeader
+    }
+}
+
+impl<T: RRefable + TypeIdentifiable> RRef<MaybeUninit<T>> {
+    /// assume_init - 将已经初始化的RRef<MaybeUninit<T>>转换为RRef<T>
+    ///
+    /// 这块内存从这时起以类型T注册drop fn，domain被回收时才会对数据调用T的drop。
+    ///
+    /// # Safety
+    ///
+    /// 调用者必须保证数据已经被完整地初始化
+    pub unsafe fn assume_init(self) -> RRef<T> {
+        let this = ManuallyDrop::new(self);
+        let type_id = T::type_id();
+        register_drop_fn::<T>(type_id);
+        crate::share_heap_retype(this.value_pointer as *mut u8, type_id);
+        // 与new_with_layout相同，保证发布之前数据的写入已经完成
+        fence(Ordering::Release);
+        RRef {
+            domain_id_pointer: this.domain_id_pointer,
+            value_pointer: this.value_pointer as *mut T,
+            exist: this.exist,
+        }
+    }
+}
+
 impl<T: RRefable> Deref for RRef<T> {
     type Target = T;
     fn deref(&self) -> &T {
@@ -153,7 +361,7 @@ impl<T: RRefable> Drop for RRef<T> {
         if self.exist {
             return;
         }
-        log::warn!("<drop> for RRef {:#x}", self.value_pointer as usize);
+        log::trace!("<drop> for RRef {:#x}", self.value_pointer as usize);
         self.custom_drop();
     }
 }
@@ -163,7 +371,7 @@ impl<T: RRefable> CustomDrop for RRef<T> {
         if self.exist {
             return;
         }
-        log::warn!("<custom_drop> for RRef {:#x}", self.value_pointer as usize);
+        log::trace!("<custom_drop> for RRef {:#x}", self.value_pointer as usize);
         let value = unsafe { &mut *self.value_pointer };
         value.custom_drop();
         crate::share_heap_dealloc(self.value_pointer as *mut u8);
@@ -203,9 +411,431 @@ impl<T: RRefable> SharedData for RRef<T> {
             // 步骤2: 原子地更新domain ID指针
             // 注意：这里不是原子操作，但在热升级流程中由锁保护
             *self.domain_id_pointer = new_domain_id;
+
+            #[cfg(feature = "debug_rref")]
+            if self.trace_id() != 0 {
+                log::info!(
+                    "<rref trace> {:#x}: domain {} -> {}",
+                    self.trace_id(),
+                    old_domain_id,
+                    new_domain_id
+                );
+            }
             
             // 步骤3: 返回旧的domain ID
             old_domain_id
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use alloc::{
+        alloc::{alloc, dealloc},
+        boxed::Box,
+        collections::BTreeSet,
+    };
+    use core::sync::atomic::AtomicUsize;
+
+    use pconst::LinuxErrno;
+
+    use super::*;
+    use crate::{SharedHeapAlloc, SharedHeapAllocation};
+
+    /// 测试用的共享堆，直接使用全局分配器
+    struct TestHeap;
+
+    static TEST_HEAP_LAYOUT: Mutex<BTreeMap<usize, Layout>> = Mutex::new(BTreeMap::new());
+    static TEST_HEAP_TYPE: Mutex<BTreeMap<usize, TypeId>> = Mutex::new(BTreeMap::new());
+    /// 释放时堆被视为忙的分配，模拟分配器的锁被正在panic的栈帧持有
+    static TEST_HEAP_BUSY: Mutex<BTreeSet<usize>> = Mutex::new(BTreeSet::new());
+    std::thread_local! {
+        /// 当前测试线程是否正在panic展开
+        static TEST_UNWINDING: core::cell::Cell<bool> = const { core::cell::Cell::new(false) };
+    }
+
+    impl SharedHeapAlloc for TestHeap {
+        unsafe fn alloc(
+            &self,
+            layout: Layout,
+            type_id: TypeId,
+            drop_fn: fn(TypeId, *mut u8),
+        ) -> Option<SharedHeapAllocation> {
+            let value_pointer = alloc(layout);
+            TEST_HEAP_LAYOUT
+                .lock()
+                .insert(value_pointer as usize, layout);
+            TEST_HEAP_TYPE
+                .lock()
+                .insert(value_pointer as usize, type_id);
+            Some(SharedHeapAllocation {
+                value_pointer,
+                domain_id_pointer: Box::into_raw(Box::new(SharedHeapHeader::default())) as *mut u64,
+                layout,
+                type_id,
+                drop_fn,
+            })
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8) {
+            let layout = TEST_HEAP_LAYOUT.lock().remove(&(ptr as usize)).unwrap();
+            TEST_HEAP_TYPE.lock().remove(&(ptr as usize));
+            dealloc(ptr, layout);
+        }
+
+        unsafe fn try_dealloc(&self, ptr: *mut u8) -> bool {
+            if TEST_HEAP_BUSY.lock().contains(&(ptr as usize)) {
+                return false;
+            }
+            self.dealloc(ptr);
+            true
+        }
+
+        unsafe fn retype(&self, ptr: *mut u8, type_id: TypeId) {
+            *TEST_HEAP_TYPE.lock().get_mut(&(ptr as usize)).unwrap() = type_id;
+        }
+
+        fn unwinding(&self) -> bool {
+            TEST_UNWINDING.get()
+        }
+    }
+
+    static DROP_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+    struct Tracked(u32);
+
+    impl CustomDrop for Tracked {
+        fn custom_drop(&mut self) {
+            DROP_COUNT.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn new_uninit_then_write() {
+        crate::init(&TestHeap, 1);
+        let before = DROP_COUNT.load(Ordering::Relaxed);
+
+        // 未初始化的数据被drop或者被domain回收时都不会调用T的drop
+        let uninit = RRef::<Tracked>::new_uninit();
+        let ptr = uninit.value_pointer as *mut u8;
+        let type_id = TEST_HEAP_TYPE.lock()[&(ptr as usize)];
+        assert_eq!(type_id, TypeId::of::<MaybeUninit<Tracked>>());
+        drop_domain_share_data(type_id, ptr);
+        drop(uninit);
+        assert_eq!(DROP_COUNT.load(Ordering::Relaxed), before);
+
+        let mut uninit = RRef::<Tracked>::new_uninit_aligned(64);
+        assert_eq!(uninit.value_pointer as usize % 64, 0);
+        uninit.write(Tracked(7));
+        let rref = unsafe { uninit.assume_init() };
+        assert_eq!(rref.0, 7);
+        let ptr = rref.value_pointer as usize;
+        assert_eq!(TEST_HEAP_TYPE.lock()[&ptr], TypeId::of::<Tracked>());
+        assert_eq!(rref.domain_id(), 1);
+        drop(rref);
+        assert_eq!(DROP_COUNT.load(Ordering::Relaxed), before + 1);
+    }
+
+    #[test]
+    fn map_bytes_to_struct_and_back() {
+        #[repr(C)]
+        struct Header {
+            len: u32,
+            flags: u32,
+        }
+
+        crate::init(&TestHeap, 1);
+        let bytes = RRef::new_aligned([1u8, 0, 0, 0, 2, 0, 0, 0], 4);
+        let ptr = bytes.value_pointer as usize;
+
+        let header = bytes.map(|b| Header {
+            len: u32::from_le_bytes([b[0], b[1], b[2], b[3]]),
+            flags: u32::from_le_bytes([b[4], b[5], b[6], b[7]]),
+        });
+        assert_eq!(header.value_pointer as usize, ptr);
+        assert_eq!((header.len, header.flags), (1, 2));
+        assert_eq!(TEST_HEAP_TYPE.lock()[&ptr], TypeId::of::<Header>());
+        assert!(DROP.lock().contains_key(&TypeId::of::<Header>()));
+
+        let bytes = header.map(|h| {
+            let mut b = [0u8; 8];
+            b[..4].copy_from_slice(&h.len.to_le_bytes());
+            b[4..].copy_from_slice(&h.flags.to_le_bytes());
+            b
+        });
+        assert_eq!(bytes.value_pointer as usize, ptr);
+        assert_eq!(*bytes, [1, 0, 0, 0, 2, 0, 0, 0]);
+        assert_eq!(TEST_HEAP_TYPE.lock()[&ptr], TypeId::of::<[u8; 8]>());
+        drop(bytes);
+        assert!(!TEST_HEAP_TYPE.lock().contains_key(&ptr));
+    }
+
+    #[test]
+    fn rvec_fill_and_zero() {
+        crate::init(&TestHeap, 1);
+        let mut vec = crate::RRefVec::new(1u8, 512);
+        vec.fill(0xAB);
+        assert_eq!(vec.len(), 512);
+        assert!(vec.iter().all(|&b| b == 0xAB));
+        vec.zero();
+        assert!(vec.iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn rvec_short_read_len() {
+        crate::init(&TestHeap, 1);
+        let mut vec = crate::RRefVec::new(0u8, 16);
+        assert_eq!((vec.len(), vec.size()), (16, 16));
+        // 模拟domain只读到5个字节
+        vec.as_mut_slice()[..5].fill(1);
+        vec.set_len(5);
+        assert_eq!((vec.len(), vec.size()), (5, 16));
+        assert_eq!(vec.as_slice(), &[1; 5]);
+        vec.set_len(16);
+        assert_eq!(vec.as_slice()[5..], [0; 11]);
+    }
+
+    #[test]
+    fn rvec_call_moved_round_trip() {
+        crate::init(&TestHeap, 1);
+        let request = crate::RRefVec::from_slice(b"ping");
+        let response = request
+            .call_moved(2, |request| {
+                // the callee owns the request and answers with its own data
+                assert_eq!(request.domain_id(), 2);
+                assert_eq!(request.as_slice(), b"ping");
+                let response = crate::RRefVec::from_slice(b"pong");
+                response.move_to(2);
+                Ok(response)
+            })
+            .unwrap();
+        assert_eq!(response.domain_id(), 1);
+        assert_eq!(response.as_slice(), b"pong");
+
+        // the echoed request comes back to the caller as well
+        let echoed = crate::RRefVec::from_slice(b"echo")
+            .call_moved(2, Ok)
+            .unwrap();
+        assert_eq!((echoed.domain_id(), echoed.as_slice()), (1, &b"echo"[..]));
+        let res = crate::RRefVec::<u8>::from_slice(b"fail").call_moved(2, |_| Err(LinuxErrno::EIO));
+        assert_eq!(res.err(), Some(LinuxErrno::EIO));
+    }
+
+    #[test]
+    fn rvec_call_moved_foreign() {
+        crate::init(&TestHeap, 1);
+        // the buffer of domain 3, still in use there
+        let foreign = crate::RRefVec::from_slice(b"data of 3");
+        foreign.move_to(3);
+        let ptr = foreign.as_slice().as_ptr() as usize;
+        let foreign = core::cell::Cell::new(Some(foreign));
+        let res =
+            crate::RRefVec::from_slice(b"read").call_moved(2, |_| Ok(foreign.take().unwrap()));
+        assert_eq!(res.err(), Some(LinuxErrno::EPROTO));
+        // it is left to its owner, not freed by the caller
+        assert!(TEST_HEAP_TYPE.lock().contains_key(&ptr));
+        let data = unsafe { core::slice::from_raw_parts(ptr as *const u8, 9) };
+        assert_eq!(data, b"data of 3");
+    }
+
+    #[test]
+    fn rvec_call_moved_short_read() {
+        crate::init(&TestHeap, 1);
+        // 和EmptyDeviceDomainProxy::read一样，缓冲区迁移到domain 2再迁移回来
+        let data = crate::RRefVec::new(0u8, 16)
+            .call_moved(2, |mut data| {
+                assert_eq!(data.domain_id(), 2);
+                // domain只读到5个字节
+                data.as_mut_slice()[..5].fill(1);
+                data.set_len(5);
+                Ok(data)
+            })
+            .unwrap();
+        assert_eq!((data.domain_id(), data.len(), data.size()), (1, 5, 16));
+        assert_eq!(data.as_slice(), &[1; 5]);
+    }
+
+    #[test]
+    fn rvec_dma_aligned() {
+        crate::init(&TestHeap, 1);
+        for len in [1, 100, 4096] {
+            let vec = crate::RRefVec::new_dma(len);
+            assert_eq!(vec.as_slice().as_ptr() as usize % crate::DMA_ALIGN, 0);
+            assert_eq!(vec.len(), len);
+            assert!(vec.as_slice().iter().all(|&b| b == 0));
+        }
+    }
+
+    #[test]
+    fn trace_id_survives_move() {
+        crate::init(&TestHeap, 1);
+        let mut rref = RRef::new(42u32);
+        assert_eq!(rref.trace_id(), 0);
+        rref.set_trace_id(0x1234);
+        assert_eq!(rref.move_to(2), 1);
+        assert_eq!(rref.trace_id(), 0x1234);
+        assert_eq!(rref.move_to(1), 2);
+        assert_eq!((rref.domain_id(), rref.trace_id(), *rref), (1, 0x1234, 42));
+    }
+
+    #[test]
+    fn resource_class_is_not_trace_id() {
+        crate::init(&TestHeap, 1);
+        let mut rref = RRef::new(42u32);
+        rref.set_resource_class(2);
+        assert_eq!((rref.trace_id(), rref.resource_class()), (0, 2));
+        rref.set_trace_id(0x1234);
+        assert_eq!(rref.move_to(2), 1);
+        assert_eq!((rref.trace_id(), rref.resource_class()), (0x1234, 2));
+    }
+
+    /// 只是冒烟测试：x86是TSO，即使没有fence这个测试也不会失败，只有在弱内存序的
+    /// 架构上才能检测到缺失的fence
+    #[test]
+    fn new_publishes_initialized_value() {
+        extern crate std;
+        use core::sync::atomic::AtomicBool;
+
+        crate::init(&TestHeap, 1);
+        // 只用relaxed发布指针，依赖RRef::new中的release fence
+        let published = AtomicUsize::new(0);
+        let done = AtomicBool::new(false);
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                for _ in 0..1000 {
+                    let ptr = loop {
+                        let ptr = published.swap(0, Ordering::Acquire);
+                        if ptr != 0 {
+                            break ptr as *const [u64; 8];
+                        }
+                        core::hint::spin_loop();
+                    };
+                    assert_eq!(unsafe { *ptr }, [0x5a5a_5a5a_5a5a_5a5a; 8]);
+                    done.store(true, Ordering::Release);
+                }
+            });
+            for _ in 0..1000 {
+                let rref = RRef::new([0x5a5a_5a5a_5a5a_5a5au64; 8]);
+                published.store(rref.value_pointer as usize, Ordering::Relaxed);
+                while !done.swap(false, Ordering::Acquire) {
+                    core::hint::spin_loop();
+                }
+                drop(rref);
+            }
+        });
+    }
+
+    #[test]
+    fn bulk_drop_logs_nothing() {
+        extern crate std;
+        use core::cell::Cell;
+
+        std::thread_local! {
+            static LOUD: Cell<usize> = const { Cell::new(0) };
+        }
+
+        /// 统计当前线程info及以上级别的日志
+        struct CountingLogger;
+        impl log::Log for CountingLogger {
+            fn enabled(&self, _: &log::Metadata) -> bool {
+                true
+            }
+            fn log(&self, record: &log::Record) {
+                if record.level() <= log::Level::Info {
+                    LOUD.with(|loud| loud.set(loud.get() + 1));
+                }
+            }
+            fn flush(&self) {}
+        }
+
+        crate::init(&TestHeap, 1);
+        let _ = log::set_logger(&CountingLogger);
+        log::set_max_level(log::LevelFilter::Trace);
+        // 批量释放只由共享堆打印一行汇总，每个RRef的drop不能打印日志
+        let rrefs = (0..1000)
+            .map(|i| RRef::new(i as u64))
+            .collect::<alloc::vec::Vec<_>>();
+        drop(rrefs);
+        assert_eq!(LOUD.with(|loud| loud.get()), 0);
+    }
+
+    #[test]
+    fn drop_fn_registered_once() {
+        struct Foo;
+        struct Bar;
+        for _ in 0..10 {
+            register_drop_fn::<Foo>(TypeId::of::<Foo>());
+            register_drop_fn::<Bar>(TypeId::of::<Bar>());
+        }
+        let counts = DROP_LOCK_COUNT.lock();
+        assert_eq!(counts.get(&TypeId::of::<Foo>()), Some(&1));
+        assert_eq!(counts.get(&TypeId::of::<Bar>()), Some(&1));
+        drop(counts);
+        assert!(DROP.lock().contains_key(&TypeId::of::<Foo>()));
+        assert!(DROP.lock().contains_key(&TypeId::of::<Bar>()));
+    }
+
+    #[test]
+    fn drop_cache_compares_type_ids() {
+        struct Foo;
+        struct Bar;
+        struct Baz;
+        // 所有类型从同一个位置开始探测，模拟哈希冲突
+        let start = 7;
+        drop_cache_insert(TypeId::of::<Foo>(), start);
+        assert!(drop_cache_contains(TypeId::of::<Foo>(), start));
+        assert!(!drop_cache_contains(TypeId::of::<Bar>(), start));
+        drop_cache_insert(TypeId::of::<Bar>(), start);
+        drop_cache_insert(TypeId::of::<Foo>(), start);
+        assert!(drop_cache_contains(TypeId::of::<Foo>(), start));
+        assert!(drop_cache_contains(TypeId::of::<Bar>(), start));
+        assert!(!drop_cache_contains(TypeId::of::<Baz>(), start));
+        let cached = DROP_CACHE
+            .iter()
+            .filter(|slot| {
+                let ptr = slot.load(Ordering::Acquire);
+                !ptr.is_null() && unsafe { *ptr } == TypeId::of::<Foo>()
+            })
+            .count();
+        assert_eq!(cached, 1);
+    }
+
+    #[test]
+    fn drop_while_unwinding_is_deferred() {
+        crate::init(&TestHeap, 1);
+        let before = DROP_COUNT.load(Ordering::Relaxed);
+        let ptr = AtomicUsize::new(0);
+        // RRef在panic展开时被drop，此时分配器忙，释放被推迟而不是死锁
+        let res = std::panic::catch_unwind(|| {
+            let rref = RRef::new(Tracked(1));
+            ptr.store(rref.value_pointer as usize, Ordering::Relaxed);
+            TEST_HEAP_BUSY.lock().insert(rref.value_pointer as usize);
+            TEST_UNWINDING.set(true);
+            panic!("simulated domain panic");
+        });
+        TEST_UNWINDING.set(false);
+        assert!(res.is_err());
+        let ptr = ptr.load(Ordering::Relaxed);
+        assert!(DROP_COUNT.load(Ordering::Relaxed) > before);
+        assert!(TEST_HEAP_LAYOUT.lock().contains_key(&ptr));
+
+        // 展开结束后分配器空闲，推迟的释放被处理
+        TEST_HEAP_BUSY.lock().remove(&ptr);
+        crate::process_deferred_dealloc();
+        assert!(!TEST_HEAP_LAYOUT.lock().contains_key(&ptr));
+    }
+
+    #[test]
+    fn drop_outside_unwinding_is_not_deferred() {
+        crate::init(&TestHeap, 1);
+        // 没有panic时即使分配器忙也等待它，而不是推迟释放
+        let rref = RRef::new(Tracked(1));
+        let ptr = rref.value_pointer as usize;
+        TEST_HEAP_BUSY.lock().insert(ptr);
+        drop(rref);
+        assert!(!TEST_HEAP_LAYOUT.lock().contains_key(&ptr));
+        TEST_HEAP_BUSY.lock().remove(&ptr);
+    }
+}
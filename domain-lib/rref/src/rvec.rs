@@ -2,18 +2,26 @@ use alloc::boxed::Box;
 use core::{
     alloc::Layout,
     fmt::{Debug, Formatter},
-    mem::MaybeUninit,
     ops::{Deref, DerefMut, Index, IndexMut},
+    sync::atomic::{fence, Ordering},
 };
 
-use super::{CustomDrop, RRef, RRefable, SharedData, TypeIdentifiable};
+use pconst::LinuxErrno;
+
+use super::{CustomDrop, RRef, RRefable, SharedData, SharedHeapHeader, TypeIdentifiable};
+
+/// The alignment of the buffers made by [RRefVec::new_dma], the size of a cache line
+pub const DMA_ALIGN: usize = 64;
 
 pub struct RRefVec<T>
 where
     T: 'static + RRefable + Copy + TypeIdentifiable,
 {
     data: RRef<T>,
+    /// The number of the elements allocated
     size: usize,
+    /// The number of the elements filled, it is `size` unless [RRefVec::set_len] is called
+    len: usize,
     exist: bool,
 }
 unsafe impl<T> RRefable for RRefVec<T> where T: 'static + RRefable + Copy + TypeIdentifiable {}
@@ -25,63 +33,144 @@ where
 {
     pub fn new(initial_value: T, size: usize) -> Self {
         let layout = Layout::array::<T>(size).unwrap();
-        let data = unsafe { RRef::new_with_layout(initial_value, layout, false) };
+        let data = unsafe { RRef::alloc_with_layout(layout) };
         let mut vec = Self {
             data,
             size,
+            len: size,
             exist: false,
         };
         vec.as_mut_slice().fill(initial_value);
+        // 与RRef::new相同，发布之前数据的写入必须完成
+        fence(Ordering::Release);
         vec
     }
 
     pub fn new_uninit(size: usize) -> Self {
         let layout = Layout::array::<T>(size).unwrap();
-        let data =
-            unsafe { RRef::new_with_layout(MaybeUninit::uninit().assume_init(), layout, false) };
+        let data = unsafe { RRef::alloc_with_layout(layout) };
+        // 数据未初始化，但header的写入同样要在发布之前完成
+        fence(Ordering::Release);
         Self {
             data,
             size,
+            len: size,
             exist: false,
         }
     }
 
-    #[allow(clippy::uninit_assumed_init)]
     pub fn from_slice(slice: &[T]) -> Self {
         let size = slice.len();
         let layout = Layout::array::<T>(size).unwrap();
-        let data =
-            unsafe { RRef::new_with_layout(MaybeUninit::uninit().assume_init(), layout, false) };
+        let data = unsafe { RRef::alloc_with_layout(layout) };
         let mut vec = Self {
             data,
             size,
+            len: size,
             exist: false,
         };
         vec.as_mut_slice().copy_from_slice(slice);
+        // 与RRef::new相同，发布之前数据的写入必须完成
+        fence(Ordering::Release);
         vec
     }
+    /// The `len()` elements filled
     pub fn as_slice(&self) -> &[T] {
-        unsafe { core::slice::from_raw_parts(&*self.data, self.size) }
+        unsafe { core::slice::from_raw_parts(&*self.data, self.len) }
     }
     pub fn as_mut_slice(&mut self) -> &mut [T] {
-        unsafe { core::slice::from_raw_parts_mut(&mut *self.data, self.size) }
+        unsafe { core::slice::from_raw_parts_mut(&mut *self.data, self.len) }
     }
+    /// The number of the elements allocated, `len()` can not exceed it
     pub fn size(&self) -> usize {
         self.size
     }
+    /// The number of the elements filled
     pub fn len(&self) -> usize {
-        self.size
+        self.len
+    }
+
+    /// Set the number of the elements filled, e.g. a domain which reads fewer bytes than
+    /// the buffer holds sets it to the bytes read before returning the buffer.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `len` is larger than `size()`.
+    pub fn set_len(&mut self, len: usize) {
+        assert!(len <= self.size, "RRefVec::set_len beyond the allocation");
+        self.len = len;
     }
 
     pub fn is_empty(&self) -> bool {
-        self.size == 0
+        self.len == 0
+    }
+
+    /// The domain owning the data
+    pub fn domain_id(&self) -> u64 {
+        self.data.domain_id()
+    }
+
+    /// Tag the data with `trace_id`, see [RRef::set_trace_id]
+    pub fn set_trace_id(&mut self, trace_id: u64) {
+        self.data.set_trace_id(trace_id);
+    }
+
+    pub fn trace_id(&self) -> u64 {
+        self.data.trace_id()
+    }
+
+    /// Set the resource class of the data, see [RRef::set_resource_class]
+    pub fn set_resource_class(&mut self, class: u64) {
+        self.data.set_resource_class(class);
+    }
+
+    pub fn resource_class(&self) -> u64 {
+        self.data.resource_class()
+    }
+
+    /// Move the data to the domain `callee` and pass it to `call`, then move the data `call`
+    /// returns back to the old owner, the ownership is migrated like the `read` of the
+    /// proxies.
+    ///
+    /// The returned data must be owned by `callee`. A domain may return the data of another
+    /// domain: moving it to the caller would cross the isolation boundary, and dropping it
+    /// would free the data the other domain still uses, so it is forgotten and `EPROTO` is
+    /// returned.
+    pub fn call_moved(
+        self,
+        callee: u64,
+        call: impl FnOnce(Self) -> Result<Self, LinuxErrno>,
+    ) -> Result<Self, LinuxErrno> {
+        let owner = self.move_to(callee);
+        let res = call(self)?;
+        let res_owner = res.domain_id();
+        if res_owner != callee {
+            log::warn!(
+                "domain {} returned data owned by domain {}, reject it",
+                callee,
+                res_owner
+            );
+            core::mem::forget(res);
+            return Err(LinuxErrno::EPROTO);
+        }
+        res.move_to(owner);
+        Ok(res)
+    }
+
+    /// Set all the `len()` elements to `value`
+    pub fn fill(&mut self, value: T) {
+        self.as_mut_slice().fill(value);
     }
 
     /// # WARNING
     /// This is a super dangerous function, it will return a slice of the data without checking the domain id
     pub fn from_other_rvec_slice(slice: &[T]) -> Self {
-        let id = Box::new(crate::domain_id());
-        let ptr = Box::into_raw(id);
+        let id = Box::new(SharedHeapHeader {
+            domain_id: crate::domain_id(),
+            trace_id: 0,
+            resource_class: 0,
+        });
+        let ptr = Box::into_raw(id) as *mut u64;
         let rref = RRef {
             domain_id_pointer: ptr,
             value_pointer: slice.as_ptr() as *mut T,
@@ -90,11 +179,38 @@ where
         Self {
             data: rref,
             size: slice.len(),
+            len: slice.len(),
             exist: true,
         }
     }
 }
 
+impl RRefVec<u8> {
+    /// Allocate a zeroed buffer of `len` bytes aligned to [DMA_ALIGN], for the device
+    /// buffers which should not share a cache line with other data.
+    ///
+    /// The shared heap keeps the layout of each allocation, so the buffer is freed with
+    /// the same alignment.
+    pub fn new_dma(len: usize) -> Self {
+        let layout = Layout::from_size_align(len, DMA_ALIGN).unwrap();
+        let data = unsafe { RRef::alloc_with_layout(layout) };
+        let mut vec = Self {
+            data,
+            size: len,
+            len,
+            exist: false,
+        };
+        vec.zero();
+        fence(Ordering::Release);
+        vec
+    }
+
+    /// Set all the `len()` bytes to 0, like `memset`
+    pub fn zero(&mut self) {
+        self.fill(0);
+    }
+}
+
 impl<T: RRefable + Copy + TypeIdentifiable> Index<usize> for RRefVec<T> {
     type Output = T;
     fn index(&self, index: usize) -> &Self::Output {
@@ -116,6 +232,7 @@ where
         f.debug_struct("RRefVec")
             .field("data", &self.data)
             .field("size", &self.size)
+            .field("len", &self.len)
             .finish()
     }
 }
@@ -124,12 +241,12 @@ impl<T: RRefable + Copy + TypeIdentifiable> Drop for RRefVec<T> {
     fn drop(&mut self) {
         unsafe {
             if self.exist {
-                let id = self.data.domain_id_pointer;
+                let id = self.data.domain_id_pointer as *mut SharedHeapHeader;
                 let _d = Box::from_raw(id);
                 return;
             }
         }
-        log::warn!("<drop> for RRefVec");
+        log::trace!("<drop> for RRefVec");
     }
 }
 
@@ -138,7 +255,7 @@ impl<T: RRefable + Copy + TypeIdentifiable> CustomDrop for RRefVec<T> {
         if self.exist {
             return;
         }
-        log::warn!("<custom_drop> for RRefVec");
+        log::trace!("<custom_drop> for RRefVec");
         self.data.custom_drop();
     }
 }
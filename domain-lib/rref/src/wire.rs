@@ -0,0 +1,286 @@
+//! A small, versioned wire format for passing structured data across the
+//! domain boundary in a [`RRefVec<u8>`].
+//!
+//! Layout of an encoded buffer:
+//!
+//! ```text
+//! +---------+----------------------------+-----+
+//! | version | field 0                    | ... |
+//! |   u8    | len: u32 LE | payload      |     |
+//! +---------+----------------------------+-----+
+//! ```
+//!
+//! Every field is length-prefixed and all integers are little-endian, so a
+//! decoder can reject truncated or malformed buffers instead of reading past
+//! the end. `usize` is always encoded as `u64`.
+use alloc::{string::String, vec, vec::Vec};
+
+use pconst::LinuxErrno;
+
+use crate::RRefVec;
+
+type LinuxResult<T> = Result<T, LinuxErrno>;
+
+/// Current version of the wire format.
+pub const WIRE_VERSION: u8 = 1;
+
+pub trait Encode {
+    fn encode_to(&self, encoder: &mut Encoder);
+
+    /// Encode `self` into a buffer on the shared heap.
+    fn encode(&self) -> RRefVec<u8> {
+        RRefVec::from_slice(&self.encode_to_vec())
+    }
+
+    /// Encode `self` into a local buffer.
+    fn encode_to_vec(&self) -> Vec<u8> {
+        let mut encoder = Encoder::new();
+        self.encode_to(&mut encoder);
+        encoder.finish()
+    }
+}
+
+pub trait Decode: Sized {
+    fn decode_from(decoder: &mut Decoder) -> LinuxResult<Self>;
+
+    /// Decode a value from a buffer produced by [`Encode::encode`].
+    fn decode(data: &RRefVec<u8>) -> LinuxResult<Self> {
+        Self::decode_from_slice(data.as_slice())
+    }
+
+    /// Decode a value from a local buffer produced by [`Encode::encode_to_vec`].
+    fn decode_from_slice(data: &[u8]) -> LinuxResult<Self> {
+        let mut decoder = Decoder::new(data)?;
+        let value = Self::decode_from(&mut decoder)?;
+        if !decoder.is_empty() {
+            return Err(LinuxErrno::EINVAL);
+        }
+        Ok(value)
+    }
+}
+
+/// Encode `value` into a buffer on the shared heap.
+pub fn encode<T: Encode>(value: &T) -> RRefVec<u8> {
+    value.encode()
+}
+
+/// Decode a `T` from a buffer on the shared heap.
+pub fn decode<T: Decode>(data: &RRefVec<u8>) -> LinuxResult<T> {
+    T::decode(data)
+}
+
+pub struct Encoder {
+    buf: Vec<u8>,
+}
+
+impl Default for Encoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Encoder {
+    pub fn new() -> Self {
+        Self {
+            buf: vec![WIRE_VERSION],
+        }
+    }
+
+    pub fn put_bytes(&mut self, bytes: &[u8]) {
+        let len = u32::try_from(bytes.len()).expect("wire field too large");
+        self.buf.extend_from_slice(&len.to_le_bytes());
+        self.buf.extend_from_slice(bytes);
+    }
+
+    pub fn put<T: Encode + ?Sized>(&mut self, value: &T) {
+        value.encode_to(self);
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+pub struct Decoder<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+    /// Create a decoder, checking the version byte of `buf`.
+    pub fn new(buf: &'a [u8]) -> LinuxResult<Self> {
+        match buf.split_first() {
+            Some((&WIRE_VERSION, rest)) => Ok(Self { buf: rest }),
+            _ => Err(LinuxErrno::EINVAL),
+        }
+    }
+
+    pub fn get_bytes(&mut self) -> LinuxResult<&'a [u8]> {
+        if self.buf.len() < 4 {
+            return Err(LinuxErrno::EINVAL);
+        }
+        let (len, rest) = self.buf.split_at(4);
+        let len = u32::from_le_bytes(len.try_into().unwrap()) as usize;
+        if rest.len() < len {
+            return Err(LinuxErrno::EINVAL);
+        }
+        let (field, rest) = rest.split_at(len);
+        self.buf = rest;
+        Ok(field)
+    }
+
+    pub fn get<T: Decode>(&mut self) -> LinuxResult<T> {
+        T::decode_from(self)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+}
+
+macro_rules! impl_wire_int {
+    ($($t:ty),*) => {
+        $(
+            impl Encode for $t {
+                fn encode_to(&self, encoder: &mut Encoder) {
+                    encoder.put_bytes(&self.to_le_bytes());
+                }
+            }
+
+            impl Decode for $t {
+                fn decode_from(decoder: &mut Decoder) -> LinuxResult<Self> {
+                    let bytes = decoder.get_bytes()?;
+                    let bytes = bytes.try_into().map_err(|_| LinuxErrno::EINVAL)?;
+                    Ok(<$t>::from_le_bytes(bytes))
+                }
+            }
+        )*
+    };
+}
+
+impl_wire_int!(u8, u16, u32, u64, i8, i16, i32, i64);
+
+impl Encode for usize {
+    fn encode_to(&self, encoder: &mut Encoder) {
+        (*self as u64).encode_to(encoder);
+    }
+}
+
+impl Decode for usize {
+    fn decode_from(decoder: &mut Decoder) -> LinuxResult<Self> {
+        let value = u64::decode_from(decoder)?;
+        usize::try_from(value).map_err(|_| LinuxErrno::EINVAL)
+    }
+}
+
+impl Encode for bool {
+    fn encode_to(&self, encoder: &mut Encoder) {
+        (*self as u8).encode_to(encoder);
+    }
+}
+
+impl Decode for bool {
+    fn decode_from(decoder: &mut Decoder) -> LinuxResult<Self> {
+        match u8::decode_from(decoder)? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(LinuxErrno::EINVAL),
+        }
+    }
+}
+
+impl Encode for str {
+    fn encode_to(&self, encoder: &mut Encoder) {
+        encoder.put_bytes(self.as_bytes());
+    }
+}
+
+impl Encode for String {
+    fn encode_to(&self, encoder: &mut Encoder) {
+        self.as_str().encode_to(encoder);
+    }
+}
+
+impl Decode for String {
+    fn decode_from(decoder: &mut Decoder) -> LinuxResult<Self> {
+        let bytes = decoder.get_bytes()?;
+        core::str::from_utf8(bytes)
+            .map(String::from)
+            .map_err(|_| LinuxErrno::EINVAL)
+    }
+}
+
+impl<T: Encode> Encode for Vec<T> {
+    fn encode_to(&self, encoder: &mut Encoder) {
+        self.len().encode_to(encoder);
+        for item in self.iter() {
+            item.encode_to(encoder);
+        }
+    }
+}
+
+impl<T: Decode> Decode for Vec<T> {
+    fn decode_from(decoder: &mut Decoder) -> LinuxResult<Self> {
+        let len = usize::decode_from(decoder)?;
+        // every element takes at least a length prefix, so a bogus length
+        // cannot make us allocate more than the buffer could hold
+        if len > decoder.buf.len() / 4 {
+            return Err(LinuxErrno::EINVAL);
+        }
+        let mut vec = Vec::with_capacity(len);
+        for _ in 0..len {
+            vec.push(T::decode_from(decoder)?);
+        }
+        Ok(vec)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let data = encode_to_vec_of(&(String::from("null"), 3u64, vec![1u32, 2, 3]));
+        let (name, id, list) = decode_from_vec_of(&data).unwrap();
+        assert_eq!(name, "null");
+        assert_eq!(id, 3);
+        assert_eq!(list, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn truncated_buffer() {
+        let data = encode_to_vec_of(&(String::from("null"), 3u64, vec![1u32, 2, 3]));
+        for len in 0..data.len() {
+            assert!(matches!(
+                decode_from_vec_of(&data[..len]),
+                Err(LinuxErrno::EINVAL)
+            ));
+        }
+    }
+
+    #[test]
+    fn bad_version() {
+        let mut data = 7u32.encode_to_vec();
+        data[0] = WIRE_VERSION + 1;
+        assert!(u32::decode_from_slice(&data).is_err());
+    }
+
+    fn encode_to_vec_of(value: &(String, u64, Vec<u32>)) -> Vec<u8> {
+        let mut encoder = Encoder::new();
+        encoder.put(&value.0);
+        encoder.put(&value.1);
+        encoder.put(&value.2);
+        encoder.finish()
+    }
+
+    fn decode_from_vec_of(data: &[u8]) -> LinuxResult<(String, u64, Vec<u32>)> {
+        let mut decoder = Decoder::new(data)?;
+        let value = (decoder.get()?, decoder.get()?, decoder.get()?);
+        if !decoder.is_empty() {
+            return Err(LinuxErrno::EINVAL);
+        }
+        Ok(value)
+    }
+}
@@ -5,13 +5,33 @@ extern crate alloc;
 use alloc::boxed::Box;
 use alloc::string::String;
 use core::fmt::Debug;
-use core::sync::atomic::AtomicBool;
-use basic::{println, LinuxResult};
-use interface::{empty_device::EmptyDeviceDomain, Basic};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use basic::{println, LinuxError, LinuxResult};
+use interface::{
+    empty_device::{EmptyDeviceConfig, EmptyDeviceDomain},
+    Basic,
+};
 use rref::RRefVec;
 
 #[derive(Debug)]
-pub struct NullDeviceDomainImpl;
+pub struct NullDeviceDomainImpl {
+    /// The most bytes returned by one read, a larger buffer gets a short read
+    buffer_size: AtomicUsize,
+}
+
+impl NullDeviceDomainImpl {
+    pub fn new() -> Self {
+        Self {
+            buffer_size: AtomicUsize::new(EmptyDeviceConfig::default().buffer_size),
+        }
+    }
+}
+
+impl Default for NullDeviceDomainImpl {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 impl Basic for NullDeviceDomainImpl {
     fn domain_id(&self) -> u64 {
@@ -20,12 +40,20 @@ impl Basic for NullDeviceDomainImpl {
 }
 
 impl EmptyDeviceDomain for NullDeviceDomainImpl {
-    fn init(&self) -> LinuxResult<()> {
+    fn init(&self, config: &EmptyDeviceConfig) -> LinuxResult<()> {
+        println!(
+            "NullDeviceDomainImpl init, buffer size: {}",
+            config.buffer_size
+        );
+        self.buffer_size
+            .store(config.buffer_size, Ordering::Relaxed);
         Ok(())
     }
 
     fn read(&self, mut data: RRefVec<u8>) -> LinuxResult<RRefVec<u8>> {
-        data.as_mut_slice().fill(1);
+        let filled = data.len().min(self.buffer_size.load(Ordering::Relaxed));
+        data.as_mut_slice()[..filled].fill(1);
+        data.set_len(filled);
         Ok(data)
     }
     fn write(&self, data: &RRefVec<u8>) -> LinuxResult<usize> {
@@ -37,6 +65,28 @@ impl EmptyDeviceDomain for NullDeviceDomainImpl {
         }
         Ok(data.len())
     }
+
+    fn write_read(&self, data: RRefVec<u8>) -> LinuxResult<RRefVec<u8>> {
+        // the null device echoes the request as the response
+        Ok(data)
+    }
+
+    fn read_interruptible(&self, data: &mut RRefVec<u8>, call_id: u64) -> LinuxResult<usize> {
+        // check the cancellation between the chunks, the chunks already filled are kept
+        let mut filled = 0;
+        for chunk in data.as_mut_slice().chunks_mut(512) {
+            if basic::call_canceled(call_id) {
+                break;
+            }
+            chunk.fill(1);
+            filled += chunk.len();
+        }
+        if filled < data.len() {
+            data.set_len(filled);
+            return Err(LinuxError::EINTR);
+        }
+        Ok(filled)
+    }
 }
 #[derive(Debug)]
 pub struct UnwindWrap(NullDeviceDomainImpl);
@@ -52,8 +102,8 @@ impl Basic for UnwindWrap {
     }
 }
 impl EmptyDeviceDomain for UnwindWrap {
-    fn init(&self) -> LinuxResult<()> {
-        self.0.init()
+    fn init(&self, config: &EmptyDeviceConfig) -> LinuxResult<()> {
+        self.0.init(config)
     }
     fn read(&self, data: RRefVec<u8>) -> LinuxResult<RRefVec<u8>> {
         basic::catch_unwind(|| self.0.read(data))
@@ -61,10 +111,16 @@ impl EmptyDeviceDomain for UnwindWrap {
     fn write(&self, data: &RRefVec<u8>) -> LinuxResult<usize> {
         basic::catch_unwind(|| self.0.write(data))
     }
+    fn write_read(&self, data: RRefVec<u8>) -> LinuxResult<RRefVec<u8>> {
+        basic::catch_unwind(|| self.0.write_read(data))
+    }
+    fn read_interruptible(&self, data: &mut RRefVec<u8>, call_id: u64) -> LinuxResult<usize> {
+        basic::catch_unwind(|| self.0.read_interruptible(data, call_id))
+    }
 }
 
 pub fn main() -> Box<dyn EmptyDeviceDomain> {
-    Box::new(UnwindWrap::new(NullDeviceDomainImpl))
+    Box::new(UnwindWrap::new(NullDeviceDomainImpl::new()))
 }
 
 
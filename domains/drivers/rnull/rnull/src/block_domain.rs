@@ -5,8 +5,9 @@ use basic::{impl_has_timer, kernel::{
     block,
     block::{
         bio::Segment,
+        cache::{CachedStore, SparePages},
         mq,
-        mq::{GenDisk, Operations, TagSet},
+        mq::{GenDisk, Operations, QueueLimits, TagSet},
     },
     error,
     error::{Error, KernelResult},
@@ -17,7 +18,7 @@ use basic::{impl_has_timer, kernel::{
     time::hrtimer::{RawTimer, TimerCallback},
     types::ForeignOwnable,
 }, new_mutex, new_spinlock, println, SafePtr};
-use interface::null_block::BlockArgs;
+use interface::null_block::{BlockArgs, CacheMode};
 use kmacro::vtable;
 use pinned_init::{pin_data, pin_init, InPlaceInit, PinInit};
 
@@ -63,6 +64,45 @@ impl NullBlkDomain {
             args: args.clone(),
         })
     }
+    /// Free all the pages indexed by the memory backed tree, return the number of pages freed
+    pub fn free_pages(&self) -> usize {
+        let disk = self.disk.lock();
+        // SAFETY: The queue data is created by `add_disk` with `ForeignOwnable::into_foreign()`
+        // and it lives as long as the disk.
+        let queue_data = unsafe {
+            <Pin<Box<QueueData>> as ForeignOwnable>::borrow(disk.queue_data_ptr().raw_ptr())
+        };
+        queue_data.store.lock_irqsave().drain()
+    }
+
+    /// Set how the writes of the memory backed device are completed
+    ///
+    /// The cached pages are written back before switching to [CacheMode::WriteThrough].
+    pub fn set_cache_mode(&self, mode: CacheMode) -> KernelResult {
+        let disk = self.disk.lock();
+        // SAFETY: The queue data is created by `add_disk` with `ForeignOwnable::into_foreign()`
+        // and it lives as long as the disk.
+        let queue_data = unsafe {
+            <Pin<Box<QueueData>> as ForeignOwnable>::borrow(disk.queue_data_ptr().raw_ptr())
+        };
+        queue_data
+            .store
+            .lock_irqsave()
+            .set_write_back(mode == CacheMode::WriteBack)
+    }
+
+    /// Write back the cached pages and keep the cache mode, return the number of pages
+    /// written back
+    pub fn flush_cache(&self) -> KernelResult<usize> {
+        let disk = self.disk.lock();
+        // SAFETY: The queue data is created by `add_disk` with `ForeignOwnable::into_foreign()`
+        // and it lives as long as the disk.
+        let queue_data = unsafe {
+            <Pin<Box<QueueData>> as ForeignOwnable>::borrow(disk.queue_data_ptr().raw_ptr())
+        };
+        queue_data.store.lock_irqsave().flush()
+    }
+
     pub fn tag_set_with_queue_data(&self) -> KernelResult<(SafePtr, SafePtr)> {
         let disk = self.disk.lock();
         Ok((disk.tagset_ptr(), disk.queue_data_ptr()))
@@ -76,7 +116,15 @@ impl NullBlkDomain {
         disk.set_capacity(self.args.param_capacity_mib << 11);
         disk.set_queue_logical_block_size(4096);
         disk.set_queue_physical_block_size(4096);
+        // a discard request has no data, it is completed like the other requests
+        disk.set_queue_limits(&QueueLimits {
+            max_hw_sectors: 1024,
+            max_segments: 128,
+            max_discard_sectors: u32::MAX >> 9,
+        })?;
         disk.set_rotational(false);
+        // the cached writes are written back by the flush requests
+        disk.set_queue_write_cache(true);
         Ok(())
     }
 }
@@ -92,8 +140,10 @@ type Tree = RadixTree<Box<Pages<0>>>;
 
 #[pin_data]
 pub struct QueueData {
+    /// The pages of the device, and the pages written in [CacheMode::WriteBack] mode and
+    /// not flushed yet
     #[pin]
-    tree: SpinLock<Tree>,
+    store: SpinLock<CachedStore<Tree>>,
     completion_time_nsec: u64,
     irq_mode: IRQMode,
     memory_backed: bool,
@@ -103,11 +153,11 @@ fn add_disk(
     tagset: Arc<TagSet<NullBlkDevice>>,
     args: &BlockArgs,
 ) -> KernelResult<GenDisk<NullBlkDevice>> {
-    let tree = RadixTree::new()?;
+    let store = CachedStore::new(RadixTree::new()?, RadixTree::new()?);
     let mode = args.param_irq_mode.try_into()?;
     let queue_data = Box::pin_init(pin_init!(
     QueueData {
-        tree <- new_spinlock!(tree, "rnullb:mem"),
+        store <- new_spinlock!(store, "rnullb:mem"),
         completion_time_nsec: args.param_completion_time_nsec,
         irq_mode: mode,
         memory_backed: args.param_memory_backed,
@@ -118,24 +168,23 @@ fn add_disk(
 
 impl NullBlkDevice {
     #[inline(always)]
-    fn write(tree: &mut Tree, sector: usize, segment: &Segment<'_>) -> KernelResult {
+    fn write(
+        store: &mut CachedStore<Tree>,
+        spare: &mut SparePages<Tree>,
+        sector: usize,
+        segment: &Segment<'_>,
+    ) -> KernelResult {
         let idx = sector >> 3; // TODO: PAGE_SECTOR_SHIFT
-        let mut page = if let Some(page) = tree.get_mut(idx as u64) {
-            page
-        } else {
-            tree.try_insert(idx as u64, Box::try_new(Pages::new()?)?)?;
-            tree.get_mut(idx as u64).unwrap()
-        };
-
-        segment.copy_to_page_atomic(&mut page)?;
+        let page = store.write_page(idx as u64, spare)?;
+        segment.copy_to_page_atomic(page)?;
 
         Ok(())
     }
 
     #[inline(always)]
-    fn read(tree: &mut Tree, sector: usize, segment: &mut Segment<'_>) -> KernelResult {
+    fn read(store: &CachedStore<Tree>, sector: usize, segment: &mut Segment<'_>) -> KernelResult {
         let idx = sector >> 3; // TODO: PAGE_SECTOR_SHIFT
-        if let Some(page) = tree.get(idx as u64) {
+        if let Some(page) = store.read_page(idx as u64) {
             segment.copy_from_page_atomic(page)?;
         }
 
@@ -145,13 +194,14 @@ impl NullBlkDevice {
     #[inline(never)]
     fn transfer(
         command: block::req_op,
-        tree: &mut Tree,
+        store: &mut CachedStore<Tree>,
+        spare: &mut SparePages<Tree>,
         sector: usize,
         segment: &mut Segment<'_>,
     ) -> KernelResult {
         match command {
-            block::req_op_REQ_OP_WRITE => Self::write(tree, sector, segment)?,
-            block::req_op_REQ_OP_READ => Self::read(tree, sector, segment)?,
+            block::req_op_REQ_OP_WRITE => Self::write(store, spare, sector, segment)?,
+            block::req_op_REQ_OP_READ => Self::read(store, sector, segment)?,
             _ => (),
         }
         Ok(())
@@ -201,13 +251,22 @@ impl Operations for NullBlkDevice {
     ) -> KernelResult {
         rq.start();
         if queue_data.memory_backed {
-            let mut tree = queue_data.tree.lock_irqsave();
-
-            let mut sector = rq.sector();
-            for bio in rq.bio_iter() {
-                for mut segment in bio.segment_iter() {
-                    Self::transfer(rq.command(), &mut tree, sector, &mut segment)?;
-                    sector += segment.len() >> 9; // TODO: SECTOR_SHIFT
+            if rq.command() == block::req_op_REQ_OP_FLUSH {
+                queue_data.store.lock_irqsave().flush()?;
+            } else {
+                let mut spare = SparePages::new();
+                let mut sector = rq.sector();
+                for bio in rq.bio_iter() {
+                    for mut segment in bio.segment_iter() {
+                        // the pages are allocated before the spinlock is taken
+                        if rq.command() == block::req_op_REQ_OP_WRITE {
+                            spare.fill()?;
+                        }
+                        let mut store = queue_data.store.lock_irqsave();
+                        Self::transfer(rq.command(), &mut store, &mut spare, sector, &mut segment)?;
+                        drop(store);
+                        sector += segment.len() >> 9; // TODO: SECTOR_SHIFT
+                    }
                 }
             }
         }
@@ -9,7 +9,7 @@ use core::fmt::Debug;
 
 use basic::{kernel::block::mq::OperationsConverter, println, LinuxError, LinuxResult, SafePtr};
 use interface::{
-    null_block::{BlockArgs, BlockDeviceDomain},
+    null_block::{BlockArgs, BlockDeviceDomain, CacheMode},
     Basic,
 };
 use spin::Mutex;
@@ -33,6 +33,20 @@ impl Basic for NullDeviceDomainImpl {
     fn domain_id(&self) -> u64 {
         rref::domain_id()
     }
+
+    fn on_shutdown(&self) -> LinuxResult<()> {
+        let blk = self.block.lock();
+        let Some(blk) = blk.as_ref() else {
+            return Ok(());
+        };
+        // the cached writes would be lost with the domain
+        let pages = blk.flush_cache().map_err(|e| {
+            println!("NullBlkModule flush_cache error: {:?}", e);
+            LinuxError::EIO
+        })?;
+        println!("NullBlkModule wrote back {} pages at shutdown", pages);
+        Ok(())
+    }
 }
 
 impl BlockDeviceDomain for NullDeviceDomainImpl {
@@ -128,8 +142,21 @@ impl BlockDeviceDomain for NullDeviceDomainImpl {
         })
     }
 
+    fn set_cache_mode(&self, mode: CacheMode) -> LinuxResult<()> {
+        let blk = self.block.lock();
+        let blk = blk.as_ref().ok_or(LinuxError::EINVAL)?;
+        blk.set_cache_mode(mode).map_err(|e| {
+            println!("NullBlkModule set_cache_mode error: {:?}", e);
+            LinuxError::EINVAL
+        })
+    }
+
     fn exit(&self) -> LinuxResult<()> {
         let v = self.block.lock().take();
+        if let Some(block) = &v {
+            let pages = block.free_pages();
+            println!("NullDeviceDomainImpl free {} pages", pages);
+        }
         drop(v);
         println!("NullDeviceDomainImpl exit");
         Ok(())
@@ -143,6 +170,10 @@ impl Basic for UnwindWrap {
     fn domain_id(&self) -> u64 {
         self.0.domain_id()
     }
+
+    fn on_shutdown(&self) -> LinuxResult<()> {
+        basic::catch_unwind(|| self.0.on_shutdown())
+    }
 }
 
 impl BlockDeviceDomain for UnwindWrap{
@@ -194,6 +225,10 @@ impl BlockDeviceDomain for UnwindWrap{
         basic::catch_unwind(|| self.0.complete_request(rq_ptr))
     }
 
+    fn set_cache_mode(&self, mode: CacheMode) -> LinuxResult<()> {
+        basic::catch_unwind(|| self.0.set_cache_mode(mode))
+    }
+
     fn exit(&self) -> LinuxResult<()> {
         basic::catch_unwind(|| self.0.exit())
     }
@@ -21,6 +21,8 @@
 #include <linux/blkdev.h>
 #include <linux/pagemap.h>
 #include <linux/srcu.h>
+#include <linux/wait.h>
+#include <linux/sched.h>
 // Bindgen gets confused at certain things
 //
 const gfp_t BINDINGS_GFP_KERNEL = GFP_KERNEL;
@@ -21,6 +21,9 @@ extern "C" {
 
     pub fn rust_helper_rcu_read_lock();
     pub fn rust_helper_synchronize_rcu();
+    pub fn rust_helper_start_poll_synchronize_rcu() -> core::ffi::c_ulong;
+    pub fn rust_helper_poll_state_synchronize_rcu(cookie: core::ffi::c_ulong) -> bool;
+    pub fn rust_helper_rcu_barrier();
     pub fn rust_helper_rcu_assign_pointer(
         rcu_data: *const CRcuData,
         new_ptr: *const core::ffi::c_void,
@@ -28,12 +31,29 @@ extern "C" {
     pub fn rust_helper_rcu_dereference(rcu_data: *const CRcuData) -> *const core::ffi::c_void;
 
     // srcu
-    // void * rust_helper_srcu_dereference(struct rcudata *p,const struct srcu_struct *ssp) {
-    #[link_name = "rust_helper_srcu_dereference"]
-    pub fn srcu_dereference(
-        p: *const CRcuData,
-        ssp: *const srcu_struct,
-    ) -> *const core::ffi::c_void;
+    #[link_name = "rust_helper_start_poll_synchronize_srcu"]
+    pub fn start_poll_synchronize_srcu(ssp: *mut srcu_struct) -> core::ffi::c_ulong;
+    #[link_name = "rust_helper_poll_state_synchronize_srcu"]
+    pub fn poll_state_synchronize_srcu(ssp: *mut srcu_struct, cookie: core::ffi::c_ulong) -> bool;
+
+    // context
+    #[link_name = "rust_helper_in_atomic"]
+    pub fn in_atomic() -> core::ffi::c_int;
+    #[link_name = "rust_helper_irqs_disabled"]
+    pub fn irqs_disabled() -> core::ffi::c_int;
+    #[link_name = "rust_helper_in_task"]
+    pub fn in_task() -> core::ffi::c_int;
+    // sleep
+    #[link_name = "rust_helper_msleep"]
+    pub fn msleep(msecs: core::ffi::c_uint);
+
+    // workqueue
+    #[link_name = "rust_helper_init_work"]
+    pub fn init_work(work: *mut work_struct, func: work_func_t);
+    #[link_name = "rust_helper_schedule_work"]
+    pub fn schedule_work(work: *mut work_struct) -> bool;
+    #[link_name = "rust_helper_schedule_work_on"]
+    pub fn schedule_work_on(cpu: core::ffi::c_int, work: *mut work_struct) -> bool;
 
     #[link_name = "rust_helper_spin_lock_init"]
     pub fn spin_lock_init(
@@ -50,6 +70,9 @@ extern "C" {
     #[link_name = "rust_helper_spin_lock_irqsave"]
     pub fn spin_lock_irqsave(lock: *mut spinlock_t) -> core::ffi::c_ulong;
 
+    #[link_name = "rust_helper_lockdep_assert_not_held"]
+    pub fn lockdep_assert_not_held(lock: *mut mutex);
+
     #[link_name = "rust_helper_get_current"]
     pub fn get_current() -> *mut task_struct;
     #[link_name = "rust_helper_get_task_struct"]
@@ -58,6 +81,8 @@ extern "C" {
     pub fn put_task_struct(t: *mut task_struct);
     #[link_name = "rust_helper_signal_pending"]
     pub fn signal_pending(t: *mut task_struct) -> core::ffi::c_int;
+    #[link_name = "rust_helper_task_nice"]
+    pub fn task_nice(t: *const task_struct) -> core::ffi::c_int;
 
     // error
     #[link_name = "rust_helper_IS_ERR"]
@@ -82,6 +107,11 @@ extern "C" {
         p: *mut core::ffi::c_longlong,
         cpu: core::ffi::c_int,
     ) -> *mut core::ffi::c_longlong;
+    /// The first word of `cpu_online_mask`, bit `i` is CPU `i`
+    #[link_name = "rust_helper_cpu_online_mask"]
+    pub fn cpu_online_mask() -> core::ffi::c_ulong;
+    #[link_name = "rust_helper_cpu_to_node"]
+    pub fn cpu_to_node(cpu: core::ffi::c_int) -> core::ffi::c_int;
     // Per-cpu end
 
     // Page
@@ -102,6 +132,11 @@ extern "C" {
     pub fn blk_mq_rq_to_pdu(rq: *mut request) -> *mut core::ffi::c_void;
     #[link_name = "rust_helper_blk_mq_rq_from_pdu"]
     pub fn blk_mq_rq_from_pdu(pdu: *mut core::ffi::c_void) -> *mut request;
+    #[link_name = "rust_helper_blk_mq_update_nr_requests"]
+    pub fn blk_mq_update_nr_requests(
+        q: *mut request_queue,
+        nr: core::ffi::c_uint,
+    ) -> core::ffi::c_int;
     // Block device end
 
     // #[link_name="rust_helper_slab_is_available"]
@@ -196,8 +231,32 @@ extern "C" {
     pub fn memalloc_nofs_restore(flags: core::ffi::c_uint);
 }
 
+/// The pointer published by RCU, it has the layout of `struct rcudata` in helpers.c
+///
+/// The pointer is only accessed atomically: [CRcuData::load] is an acquire load which
+/// pairs with the release store of [CRcuData::store] or `rcu_assign_pointer` in C, so a
+/// reader never sees a torn pointer, and sees the data as it was when it was published.
 #[repr(C)]
 #[derive(Debug)]
 pub struct CRcuData {
-    pub data_ptr: *mut core::ffi::c_void,
+    data_ptr: core::sync::atomic::AtomicPtr<core::ffi::c_void>,
+}
+
+impl CRcuData {
+    pub const fn new(ptr: *mut core::ffi::c_void) -> Self {
+        Self {
+            data_ptr: core::sync::atomic::AtomicPtr::new(ptr),
+        }
+    }
+
+    /// Load the pointer with acquire ordering
+    pub fn load(&self) -> *mut core::ffi::c_void {
+        self.data_ptr.load(core::sync::atomic::Ordering::Acquire)
+    }
+
+    /// Publish `ptr` with release ordering, the data it points to must be initialized
+    pub fn store(&self, ptr: *mut core::ffi::c_void) {
+        self.data_ptr
+            .store(ptr, core::sync::atomic::Ordering::Release)
+    }
 }
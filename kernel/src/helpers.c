@@ -15,12 +15,19 @@
 #include <linux/blk_types.h>
 #include <linux/blkdev.h>
 #include <linux/percpu.h>
+#include <linux/cpumask.h>
+#include <linux/topology.h>
+#include <linux/smp.h>
 #include <linux/bio.h>
+#include <linux/sbitmap.h>
 #include <linux/slab.h>
 #include <linux/radix-tree.h>
 #include <linux/fs.h>
 #include <linux/pagemap.h>
 #include <linux/srcu.h>
+#include <linux/preempt.h>
+#include <linux/irqflags.h>
+#include <linux/delay.h>
 
 
 void bug_helper(void) { BUG(); }
@@ -49,6 +56,9 @@ _Static_assert(__builtin_types_compatible_p(size_t, uintptr_t),
 void rust_helper_rcu_read_unlock(void) { rcu_read_unlock(); }
 void rust_helper_rcu_read_lock(void) { rcu_read_lock(); }
 void rust_helper_synchronize_rcu(void) { synchronize_rcu(); }
+unsigned long rust_helper_start_poll_synchronize_rcu(void) { return start_poll_synchronize_rcu(); }
+bool rust_helper_poll_state_synchronize_rcu(unsigned long cookie) { return poll_state_synchronize_rcu(cookie); }
+void rust_helper_rcu_barrier(void) { rcu_barrier(); }
 
 struct rcudata {
     void *a;
@@ -84,6 +94,7 @@ unsigned long rust_helper_spin_lock_irqsave(spinlock_t *lock)
 void rust_helper_mutex_init(struct mutex *lock) { mutex_init(lock); }
 void rust_helper_mutex_lock(struct mutex *lock) { mutex_lock(lock); }
 void rust_helper_mutex_unlock(struct mutex *lock) { mutex_unlock(lock); }
+void rust_helper_lockdep_assert_not_held(struct mutex *lock) { lockdep_assert_not_held(lock); }
 
 
 // task
@@ -91,6 +102,7 @@ struct task_struct *rust_helper_get_current(void){ return current; }
 void rust_helper_get_task_struct(struct task_struct *t){ get_task_struct(t); }
 void rust_helper_put_task_struct(struct task_struct *t){ put_task_struct(t); }
 int rust_helper_signal_pending(struct task_struct *t){ return signal_pending(t); }
+int rust_helper_task_nice(const struct task_struct *t){ return task_nice(t); }
 
 
 // err
@@ -106,6 +118,8 @@ void rust_helper_free_percpu_longlong(long long *p){ free_percpu(p); }
 int rust_helper_get_cpu(void){ return get_cpu(); }
 void rust_helper_put_cpu(void){ put_cpu(); }
 long long *rust_helper_per_cpu_ptr(long long *p, int cpu){ return per_cpu_ptr(p, cpu); }
+unsigned long rust_helper_cpu_online_mask(void){ return cpumask_bits(cpu_online_mask)[0]; }
+int rust_helper_cpu_to_node(int cpu){ return cpu_to_node(cpu); }
 
 
 // Page
@@ -123,6 +137,36 @@ void rust_helper_bio_advance_iter_single(const struct bio *bio,
 }
 void *rust_helper_blk_mq_rq_to_pdu(struct request *rq){ return blk_mq_rq_to_pdu(rq); }
 struct request *rust_helper_blk_mq_rq_from_pdu(void *pdu) { return blk_mq_rq_from_pdu(pdu);}
+/*
+ * blk_mq_update_nr_requests() is not exported, this is its path for a queue without an
+ * I/O scheduler. The queue is frozen and quiesced around the update, so no request is
+ * in flight while the tags are resized.
+ */
+int rust_helper_blk_mq_update_nr_requests(struct request_queue *q, unsigned int nr)
+{
+    struct blk_mq_tag_set *set = q->tag_set;
+    struct blk_mq_hw_ctx *hctx;
+    unsigned long i;
+    int ret = 0;
+
+    if (nr <= set->reserved_tags || nr > set->queue_depth)
+        return -EINVAL;
+    blk_mq_freeze_queue(q);
+    blk_mq_quiesce_queue(q);
+    if (q->elevator) {
+        ret = -EBUSY;
+        goto out;
+    }
+    queue_for_each_hw_ctx(q, hctx, i) {
+        if (hctx->tags)
+            sbitmap_queue_resize(&hctx->tags->bitmap_tags, nr - set->reserved_tags);
+    }
+    q->nr_requests = nr;
+out:
+    blk_mq_unquiesce_queue(q);
+    blk_mq_unfreeze_queue(q);
+    return ret;
+}
 
 //bool rust_helper_slab_is_available(void) { return slab_is_available(); }
 
@@ -279,6 +323,20 @@ void rust_helper_memalloc_nofs_restore(unsigned int flags)
 
 // srcu
 
-void * rust_helper_srcu_dereference(struct rcudata *p,const struct srcu_struct *ssp) {
-    return srcu_dereference(p->a, ssp);
-}
\ No newline at end of file
+unsigned long rust_helper_start_poll_synchronize_srcu(struct srcu_struct *ssp) {
+    return start_poll_synchronize_srcu(ssp);
+}
+bool rust_helper_poll_state_synchronize_srcu(struct srcu_struct *ssp, unsigned long cookie) {
+    return poll_state_synchronize_srcu(ssp, cookie);
+}
+
+// context
+int rust_helper_in_atomic(void) { return in_atomic(); }
+int rust_helper_irqs_disabled(void) { return irqs_disabled(); }
+int rust_helper_in_task(void) { return in_task(); }
+// sleep
+void rust_helper_msleep(unsigned int msecs) { msleep(msecs); }
+// workqueue
+void rust_helper_init_work(struct work_struct *work, work_func_t func) { INIT_WORK(work, func); }
+bool rust_helper_schedule_work(struct work_struct *work) { return schedule_work(work); }
+bool rust_helper_schedule_work_on(int cpu, struct work_struct *work) { return schedule_work_on(cpu, work); }
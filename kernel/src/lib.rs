@@ -28,6 +28,7 @@ pub mod sysctl;
 mod task;
 pub mod time;
 pub mod types;
+pub mod workqueue;
 
 use alloc::boxed::Box;
 
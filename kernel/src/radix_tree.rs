@@ -92,16 +92,17 @@ impl<V: ForeignOwnable> RadixTree<V> {
         // because this function takes a `&mut self`.
         Some(unsafe { ForeignOwnable::from_foreign(item.as_ptr()) })
     }
-}
 
-impl<V: ForeignOwnable> Drop for RadixTree<V> {
-    fn drop(&mut self) {
+    /// Remove all entries from the tree and drop their values, leaving the
+    /// tree empty. Returns the number of entries removed.
+    pub fn drain(&mut self) -> usize {
         let mut iter = bindings::radix_tree_iter {
             index: 0,
             next_index: 0,
             tags: 0,
             node: core::ptr::null_mut(),
         };
+        let mut count = 0;
 
         // SAFETY: Iter is valid as we allocated it on the stack above
         let mut slot = unsafe { bindings::radix_tree_iter_init(&mut iter, 0) };
@@ -122,12 +123,20 @@ impl<V: ForeignOwnable> Drop for RadixTree<V> {
 
             // SAFETY: All items in the tree are created by a call to
             // `ForeignOwnable::into_foreign()`.
-            let _ = unsafe { V::from_foreign(item) };
+            drop(unsafe { V::from_foreign(item) });
+            count += 1;
 
             // SAFETY: `self.tree` is valid and iter is managed by
             // `radix_tree_next_chunk()` and `radix_tree_next_slot()`. Slot is
             // not null.
             slot = unsafe { bindings::radix_tree_next_slot(slot, &mut iter, 0) };
         }
+        count
+    }
+}
+
+impl<V: ForeignOwnable> Drop for RadixTree<V> {
+    fn drop(&mut self) {
+        self.drain();
     }
 }
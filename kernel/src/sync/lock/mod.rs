@@ -174,6 +174,22 @@ impl<T: ?Sized, B: Backend> Lock<T, B> {
     }
 }
 
+impl<T: ?Sized, B: Backend<GuardState = ()>> Lock<T, B> {
+    /// Releases the lock without a guard.
+    ///
+    /// This is the counterpart of leaking a guard with [`core::mem::forget`], for callers that
+    /// need to keep the lock held beyond the scope that acquired it.
+    ///
+    /// # Safety
+    ///
+    /// The caller must own the lock, i.e. the guard returned by [`Lock::lock`] was forgotten
+    /// and not dropped.
+    pub unsafe fn force_unlock(&self) {
+        // SAFETY: The safety requirements of this function ensure that the lock is held.
+        unsafe { B::unlock(self.state.get(), &()) };
+    }
+}
+
 impl<T: Debug, B: Backend + Debug> Debug for Lock<T, B> {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         f.debug_struct("Lock").field("data", &self.data).finish()
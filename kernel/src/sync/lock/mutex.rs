@@ -90,6 +90,16 @@ macro_rules! new_mutex {
 /// [`struct mutex`]: ../../../../include/linux/mutex.h
 pub type Mutex<T> = super::Lock<T, MutexBackend>;
 
+impl<T: ?Sized> Mutex<T> {
+    /// Warns if the current task holds the mutex.
+    ///
+    /// It is checked by lockdep, so it does nothing if `CONFIG_LOCKDEP` is disabled.
+    pub fn assert_not_held(&self) {
+        // SAFETY: The mutex is initialised because `self` is pinned and initialised.
+        unsafe { bindings::lockdep_assert_not_held(self.state.get()) };
+    }
+}
+
 /// A kernel `struct mutex` lock backend.
 #[derive(Debug)]
 pub struct MutexBackend;
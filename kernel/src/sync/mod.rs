@@ -11,12 +11,14 @@ mod locked_by;
 mod per_cpu;
 mod rcu;
 mod srcu;
+mod wait;
 
 pub use lock::{mutex::Mutex, spinlock::SpinLock};
 pub use locked_by::LockedBy;
 pub use per_cpu::*;
 pub use rcu::RcuData;
-pub use srcu::SRcuData;
+pub use srcu::{ClassicRcu, RcuBackend, SRcuData, Srcu};
+pub use wait::WaitQueue;
 
 use crate::bindings;
 /// Represents a lockdep class. It's a wrapper around C's `lock_class_key`.
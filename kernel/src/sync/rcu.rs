@@ -15,9 +15,7 @@ impl<T> RcuData<T> {
     pub fn new(data: T) -> RcuData<T> {
         let v = Box::into_raw(Box::new(data));
         RcuData {
-            crcu_data: CRcuData {
-                data_ptr: v as *mut core::ffi::c_void,
-            },
+            crcu_data: CRcuData::new(v as *mut core::ffi::c_void),
             _marker: core::marker::PhantomData,
         }
     }
@@ -39,7 +37,7 @@ impl<T> RcuData<T> {
     /// You therefore still need to use locking (or something similar) to keep concurrent updates from interfering
     /// with each other.
     pub fn update(&self, data: T) -> Box<T> {
-        let old_ptr = self.crcu_data.data_ptr;
+        let old_ptr = self.crcu_data.load();
         let new_ptr = Box::into_raw(Box::new(data));
         rcu_assign_pointer(&self.crcu_data, new_ptr);
         pr_warn!("before synchronize_rcu");
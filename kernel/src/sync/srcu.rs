@@ -1,17 +1,137 @@
 use alloc::boxed::Box;
 
-use kbind
+use kbind::srcu_struct;
 
-use crate::{bindings, bindings::CRcuData, pr_warn};
+use crate::{
+    bindings,
+    bindings::CRcuData,
+    code,
+    error::KernelResult,
+    pr_err, pr_warn,
+    time::{ktime_ms_delta, Ktime},
+};
 
+/// RcuBackend - SRcuData使用的读写原语
+///
+/// SRcuData默认使用SRCU（[Srcu]），读者可以在读临界区中睡眠。
+/// 如果读临界区从不睡眠，可以使用开销更小的经典RCU（[ClassicRcu]）。
+pub trait RcuBackend {
+    /// 进入读临界区，返回值需要传给read_unlock
+    fn read_lock(&self) -> core::ffi::c_int;
+    /// 离开读临界区
+    fn read_unlock(&self, idx: core::ffi::c_int);
+    /// 等待所有已经开始的读者离开读临界区，会睡眠
+    fn synchronize(&self);
+    /// 开始一个宽限期，返回传给poll的cookie，不睡眠
+    fn start_poll(&self) -> core::ffi::c_ulong;
+    /// cookie对应的宽限期是否已经结束，不睡眠
+    fn poll(&self, cookie: core::ffi::c_ulong) -> bool;
+    /// 等待所有已经提交的回调（call_srcu/call_rcu）执行完毕，会睡眠
+    fn barrier(&self);
+}
+
+/// Srcu - 可睡眠的RCU后端，SRcuData::new默认使用
 #[derive(Debug)]
-pub struct SRcuData<T> {
-    crcu_data: CRcuData,
+pub struct Srcu {
     ssp: *mut srcu_struct,
+}
+
+impl Srcu {
+    pub fn new() -> Self {
+        // SRCU结构体分配在堆上，在Srcu被drop时释放
+        let ssp = Box::into_raw(Box::new(srcu_struct::default()));
+        unsafe {
+            bindings::init_srcu_struct(ssp);
+        }
+        Srcu { ssp }
+    }
+}
+
+impl Default for Srcu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RcuBackend for Srcu {
+    fn read_lock(&self) -> core::ffi::c_int {
+        unsafe { bindings::__srcu_read_lock(self.ssp) }
+    }
+
+    fn read_unlock(&self, idx: core::ffi::c_int) {
+        unsafe { bindings::__srcu_read_unlock(self.ssp, idx) }
+    }
+
+    fn synchronize(&self) {
+        unsafe { bindings::synchronize_srcu(self.ssp) }
+    }
+
+    fn start_poll(&self) -> core::ffi::c_ulong {
+        unsafe { bindings::start_poll_synchronize_srcu(self.ssp) }
+    }
+
+    fn poll(&self, cookie: core::ffi::c_ulong) -> bool {
+        unsafe { bindings::poll_state_synchronize_srcu(self.ssp, cookie) }
+    }
+
+    fn barrier(&self) {
+        unsafe { bindings::srcu_barrier(self.ssp) }
+    }
+}
+
+impl Drop for Srcu {
+    fn drop(&mut self) {
+        unsafe {
+            bindings::cleanup_srcu_struct(self.ssp);
+            let _v = Box::from_raw(self.ssp);
+        }
+    }
+}
+
+/// ClassicRcu - 经典RCU后端
+///
+/// 读临界区由rcu_read_lock/rcu_read_unlock保护，开销比SRCU小。
+///
+/// 警告：读临界区中禁止睡眠，即传给SRcuData::read的闭包不能睡眠，
+/// 否则会破坏RCU的宽限期语义。
+#[derive(Debug, Default)]
+pub struct ClassicRcu;
+
+impl RcuBackend for ClassicRcu {
+    fn read_lock(&self) -> core::ffi::c_int {
+        unsafe { bindings::rust_helper_rcu_read_lock() };
+        0
+    }
+
+    fn read_unlock(&self, _idx: core::ffi::c_int) {
+        unsafe { bindings::rust_helper_rcu_read_unlock() }
+    }
+
+    fn synchronize(&self) {
+        unsafe { bindings::rust_helper_synchronize_rcu() }
+    }
+
+    fn start_poll(&self) -> core::ffi::c_ulong {
+        unsafe { bindings::rust_helper_start_poll_synchronize_rcu() }
+    }
+
+    fn poll(&self, cookie: core::ffi::c_ulong) -> bool {
+        unsafe { bindings::rust_helper_poll_state_synchronize_rcu(cookie) }
+    }
+
+    fn barrier(&self) {
+        unsafe { bindings::rust_helper_rcu_barrier() }
+    }
+}
+
+#[derive(Debug)]
+pub struct SRcuData<T, B: RcuBackend = Srcu> {
+    crcu_data: CRcuData,
+    backend: B,
     _marker: core::marker::PhantomData<T>,
 }
-unsafe impl<T> Sync for SRcuData<T> {}
-unsafe impl<T> Send for SRcuData<T> {}
+unsafe impl<T, B: RcuBackend> Sync for SRcuData<T, B> {}
+unsafe impl<T, B: RcuBackend> Send for SRcuData<T, B> {}
 
 impl<T> SRcuData<T> {
     /// new - 创建新的SRcuData实例
@@ -26,34 +146,62 @@ impl<T> SRcuData<T> {
     /// - SRCU结构体也分配在堆上
     /// - 这些内存在SRcuData被drop时释放
     pub fn new(data: T) -> SRcuData<T> {
+        // SRCU (Sleepable Read-Copy-Update) 是Linux内核的RCU变体
+        // 允许读者在持有引用时睡眠
+        Self::new_with_backend(data, Srcu::new())
+    }
+
+    /// empty - 创建没有数据的SRcuData实例，见empty_with_backend
+    ///
+    /// # Safety
+    ///
+    /// 同empty_with_backend。
+    pub unsafe fn empty() -> SRcuData<T> {
+        unsafe { Self::empty_with_backend(Srcu::new()) }
+    }
+}
+
+impl<T, B: RcuBackend> SRcuData<T, B> {
+    /// new_with_backend - 使用指定的RCU后端创建SRcuData实例
+    ///
+    /// 读临界区从不睡眠时可以使用[ClassicRcu]，否则应使用[Srcu]。
+    pub fn new_with_backend(data: T, backend: B) -> SRcuData<T, B> {
         // 步骤1: 将数据分配到堆上，获取原始指针
         // Box::into_raw将Box转换为原始指针，转移所有权给调用者
         let v = Box::into_raw(Box::new(data));
-        
-        // 步骤2: 创建SRCU结构体
-        // SRCU (Sleepable Read-Copy-Update) 是Linux内核的RCU变体
-        // 允许读者在持有引用时睡眠
-        let ssp = Box::into_raw(Box::new(srcu_struct::default()));
-        
-        // 步骤3: 初始化SRCU结构体
-        // 这是内核函数，设置SRCU的内部状态
-        unsafe {
-            bindings::init_srcu_struct(ssp);
-        }
-        
-        // 步骤4: 构建SRcuData实例
+
+        // 步骤2: 构建SRcuData实例
         SRcuData {
             // CRcuData是内核RCU数据结构，存储数据指针
-            crcu_data: CRcuData {
-                data_ptr: v as *mut core::ffi::c_void,
-            },
-            // ssp: SRCU结构体指针，用于管理读者计数
-            ssp,
+            crcu_data: CRcuData::new(v as *mut core::ffi::c_void),
+            // backend: RCU后端，提供读锁和宽限期等待
+            backend,
             // PhantomData: 类型标记，确保类型安全
             _marker: core::marker::PhantomData,
         }
     }
 
+    /// empty_with_backend - 创建没有数据的SRcuData实例
+    ///
+    /// 数据指针为空，在try_update_directly填入数据之前不能读取。
+    ///
+    /// # Safety
+    ///
+    /// 调用者必须保证在try_update_directly填入数据之前，除is_empty和
+    /// try_update_directly之外不调用其他方法，它们会解引用空指针或者从空指针重建Box。
+    pub unsafe fn empty_with_backend(backend: B) -> SRcuData<T, B> {
+        SRcuData {
+            crcu_data: CRcuData::new(core::ptr::null_mut()),
+            backend,
+            _marker: core::marker::PhantomData,
+        }
+    }
+
+    /// is_empty - 是否还没有数据，即由empty_with_backend创建且还没有更新
+    pub fn is_empty(&self) -> bool {
+        self.crcu_data.load().is_null()
+    }
+
     /// read - 在RCU保护下读取数据
     /// 
     /// 这是标准的RCU读取操作，特点：
@@ -70,14 +218,17 @@ impl<T> SRcuData<T> {
     /// - 确保读者看到一致的数据视图
     /// - 防止编译器重排和CPU乱序执行
     pub fn read<R>(&self, f: impl FnOnce(&T) -> R) -> R {
-        // 步骤1: 获取SRCU读锁
-        // __srcu_read_lock返回一个索引，用于后续解锁
+        // 步骤1: 获取读锁
+        // SRCU后端的__srcu_read_lock返回一个索引，用于后续解锁
         // 这个调用会递增当前CPU的读者计数
-        let idx = unsafe { bindings::__srcu_read_lock(self.ssp) };
+        let idx = self.backend.read_lock();
+        // debug_rcu：记录当前任务正在读这个SRcuData，见check_not_reading
+        #[cfg(feature = "debug_rcu")]
+        let marker = read_marker::ReadMarker::enter(self.marker_key());
         
         // 步骤2: 在RCU保护下获取数据指针
-        // srcu_dereference确保内存屏障，防止乱序执行
-        let ptr = srcu_defererence::<T>(&self.crcu_data, self.ssp);
+        // srcu_dereference使用acquire读取指针，与srcu_assign_pointer配对
+        let ptr = srcu_dereference::<T>(&self.crcu_data);
         
         // 步骤3: 将原始指针转换为引用
         // 这里假设指针有效，因为RCU机制保证在读者持有锁期间数据不会被释放
@@ -86,12 +237,12 @@ impl<T> SRcuData<T> {
         // 步骤4: 执行用户提供的函数
         // 用户可以在安全的环境中访问数据
         let r = f(v);
+        #[cfg(feature = "debug_rcu")]
+        drop(marker);
         
         // 步骤5: 释放SRCU读锁
         // 递减读者计数，如果这是最后一个读者，可能会唤醒等待的写者
-        unsafe {
-            bindings::__srcu_read_unlock(self.ssp, idx);
-        }
+        self.backend.read_unlock(idx);
         
         // 步骤6: 返回结果
         r
@@ -112,7 +263,7 @@ impl<T> SRcuData<T> {
     pub fn read_directly<R>(&self, f: impl FnOnce(&T) -> R) -> R {
         // 步骤1: 直接获取数据指针
         // 不获取SRCU锁，因此没有读者计数保护
-        let ptr = srcu_defererence::<T>(&self.crcu_data, self.ssp);
+        let ptr = srcu_dereference::<T>(&self.crcu_data);
         
         // 步骤2: 将原始指针转换为引用
         let v = unsafe { &*ptr };
@@ -140,7 +291,7 @@ impl<T> SRcuData<T> {
     pub fn update_directly(&self, data: T) -> Box<T> {
         // 步骤1: 保存旧数据指针
         // 这个指针可能还在被现有读者使用
-        let old_ptr = self.crcu_data.data_ptr;
+        let old_ptr = self.crcu_data.load();
         
         // 步骤2: 创建新数据并获取指针
         // Box::into_raw转移所有权，避免立即释放
@@ -159,6 +310,24 @@ impl<T> SRcuData<T> {
         old_data
     }
 
+    /// try_update_directly - update_directly的可失败版本
+    ///
+    /// 与update_directly相同，但不假设旧数据指针有效：
+    /// - 新数据分配失败时返回ENOMEM，原数据不变
+    /// - 旧数据指针为空（SRcuData由empty_with_backend创建）时返回Ok(None)，
+    ///   不会从空指针重建Box
+    /// - 否则返回Ok(Some(旧数据))，调用者负责释放
+    pub fn try_update_directly(&self, data: T) -> KernelResult<Option<Box<T>>> {
+        let old_ptr = self.crcu_data.load();
+        let new_ptr = Box::into_raw(Box::try_new(data)?);
+        srcu_assign_pointer(&self.crcu_data, new_ptr);
+        if old_ptr.is_null() {
+            return Ok(None);
+        }
+        // 旧指针由new_with_backend或之前的更新通过Box::into_raw得到
+        Ok(Some(unsafe { Box::from_raw(old_ptr as *mut T) }))
+    }
+
     /// update - 更新数据并等待现有读者完成
     /// 
     /// 这是标准的RCU更新操作，特点：
@@ -175,8 +344,22 @@ impl<T> SRcuData<T> {
     /// - 确保旧数据的内存可以安全释放
     /// - 这是RCU的"宽限期"概念
     pub fn update(&self, data: T) -> Box<T> {
+        // synchronize_srcu会睡眠，在原子上下文中调用会导致死锁
+        if !can_synchronize() {
+            pr_err!(
+                "SRcuData::update called in atomic context, synchronize_srcu may deadlock, \
+                 use try_update or update_directly instead"
+            );
+        }
+        if self.check_not_reading().is_err() {
+            pr_err!(
+                "SRcuData::update called in a read section of the same data, \
+                 synchronize_srcu will never return"
+            );
+        }
+
         // 步骤1: 保存旧数据指针
-        let old_ptr = self.crcu_data.data_ptr;
+        let old_ptr = self.crcu_data.load();
         
         // 步骤2: 创建新数据并获取指针
         let new_ptr = Box::into_raw(Box::new(data));
@@ -190,7 +373,7 @@ impl<T> SRcuData<T> {
         // 步骤4: 等待所有现有读者完成
         // synchronize_srcu会阻塞，直到所有在更新前开始的读者释放了锁
         // 这确保了旧数据不再被任何读者使用
-        synchronize_srcu(self.ssp);
+        self.backend.synchronize();
         
         // 调试信息：更新完成
         pr_warn!("after synchronize_srcu");
@@ -202,28 +385,283 @@ impl<T> SRcuData<T> {
         // 步骤6: 返回旧数据
         old_data
     }
-}
 
-impl<T> Drop for SRcuData<T> {
-    fn drop(&mut self) {
-        unsafe {
-            bindings::cleanup_srcu_struct(self.ssp);
-            let _v = Box::from_raw(self.ssp);
+    /// update_no_sleep - 更新数据，忙等宽限期结束而不睡眠
+    ///
+    /// 用于关机等调度器可能已经停止的场景：update中的synchronize_srcu可能永远
+    /// 不返回，这里轮询宽限期的状态，最多忙等timeout_ms毫秒。
+    ///
+    /// 超时后新数据已经发布，但旧数据可能还有读者，不能释放，只能泄漏，
+    /// 返回ETIMEDOUT。
+    pub fn update_no_sleep(&self, data: T, timeout_ms: u64) -> KernelResult<Box<T>> {
+        let old_ptr = self.crcu_data.load();
+        let new_ptr = Box::into_raw(Box::new(data));
+        srcu_assign_pointer(&self.crcu_data, new_ptr);
+        let cookie = self.backend.start_poll();
+        let start = Ktime::ktime_get();
+        while !self.backend.poll(cookie) {
+            if ktime_ms_delta(Ktime::ktime_get(), start) >= timeout_ms as i64 {
+                pr_err!("SRcuData::update_no_sleep: grace period timed out, old data leaked");
+                return Err(code::ETIMEDOUT);
+            }
+            core::hint::spin_loop();
         }
+        Ok(unsafe { Box::from_raw(old_ptr as *mut T) })
+    }
+
+    /// try_update - 检查上下文后再更新数据
+    ///
+    /// 与update()相同，但在原子上下文（持有自旋锁、关闭中断等）中
+    /// synchronize_srcu无法安全调用，此时不替换数据，直接返回EDEADLK，
+    /// `data`会被丢弃。
+    ///
+    /// 开启debug_rcu时，在同一个SRcuData的读闭包中调用也返回EDEADLK。
+    ///
+    /// 原子上下文中的调用者应使用update_directly，并自行保证旧数据没有读者。
+    pub fn try_update(&self, data: T) -> KernelResult<Box<T>> {
+        if !can_synchronize() {
+            pr_err!("SRcuData::try_update called in atomic context");
+            return Err(code::EDEADLK);
+        }
+        if self.check_not_reading().is_err() {
+            pr_err!("SRcuData::try_update called in a read section of the same data");
+            return Err(code::EDEADLK);
+        }
+        Ok(self.update(data))
+    }
+
+    /// barrier - 等待所有已经提交的延迟回收回调执行完毕
+    ///
+    /// 卸载时调用，保证之后没有还在等待宽限期的延迟释放。会睡眠，
+    /// 在原子上下文中不等待，直接返回EDEADLK。
+    pub fn barrier(&self) -> KernelResult<()> {
+        if !can_synchronize() {
+            pr_err!("SRcuData::barrier called in atomic context");
+            return Err(code::EDEADLK);
+        }
+        self.backend.barrier();
+        Ok(())
     }
-}
 
-fn srcu_defererence<T>(crcu_data: &CRcuData, ssp: *const srcu_struct) -> *const T {
-    unsafe {
-        let ptr = bindings::srcu_dereference(crcu_data, ssp);
-        ptr as *const T
+    /// check_not_reading - 检查当前任务是否正在读这个SRcuData
+    ///
+    /// 读闭包中调用update时，synchronize会等待自己的读临界区结束，永远不会返回。
+    /// 开启debug_rcu时这种情况返回EDEADLK；没有开启时不记录读者，总是返回Ok。
+    pub fn check_not_reading(&self) -> KernelResult<()> {
+        #[cfg(feature = "debug_rcu")]
+        if read_marker::is_reading(self.marker_key()) {
+            return Err(code::EDEADLK);
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "debug_rcu")]
+    fn marker_key(&self) -> usize {
+        self as *const Self as usize
     }
 }
 
+/// srcu_dereference - 读取数据指针
+///
+/// 指针只通过原子操作访问，读者不会看到被撕裂的指针。这里的acquire读取与
+/// srcu_assign_pointer的release写入配对：读到新指针的读者一定能看到发布之前
+/// 对新数据的所有写入。read_directly也经过这里，但它不在读临界区中，
+/// 指针指向的数据在读取之后是否仍然有效由调用者保证。
+fn srcu_dereference<T>(crcu_data: &CRcuData) -> *const T {
+    crcu_data.load() as *const T
+}
+
+/// srcu_assign_pointer - 发布新的数据指针
+///
+/// release写入，与srcu_dereference的acquire读取配对，新数据必须在调用之前完成初始化
 fn srcu_assign_pointer<T>(crcu_data: &CRcuData, new_ptr: *const T) {
-    unsafe { bindings::rust_helper_rcu_assign_pointer(crcu_data, new_ptr as _) }
+    crcu_data.store(new_ptr as *mut core::ffi::c_void)
+}
+
+/// 当前上下文是否允许睡眠，即可以调用synchronize_srcu
+#[cfg(not(test))]
+fn can_synchronize() -> bool {
+    unsafe { bindings::in_atomic() == 0 && bindings::irqs_disabled() == 0 }
+}
+
+/// 测试中由tests::IN_ATOMIC模拟原子上下文
+#[cfg(test)]
+fn can_synchronize() -> bool {
+    !tests::IN_ATOMIC.with(|in_atomic| in_atomic.get())
 }
 
-fn synchronize_srcu(ssp: *const srcu_struct) {
-    unsafe { bindings::synchronize_srcu(ssp as *mut srcu_struct) }
+/// read_marker - debug_rcu：记录每个任务正在读的SRcuData
+///
+/// SRCU的读者可以睡眠并迁移到其他CPU，所以按任务而不是按CPU记录。
+/// 记录保存在固定大小的表中，不分配内存也不加锁；表满时不再记录，只会漏报。
+#[cfg(feature = "debug_rcu")]
+mod read_marker {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    const SLOTS: usize = 64;
+
+    /// 每一项是(任务, SRcuData)，任务为0的项是空闲的
+    static READERS: [(AtomicUsize, AtomicUsize); SLOTS] =
+        [const { (AtomicUsize::new(0), AtomicUsize::new(0)) }; SLOTS];
+
+    #[cfg(not(test))]
+    fn current_context() -> usize {
+        unsafe { crate::bindings::get_current() as usize }
+    }
+
+    #[cfg(test)]
+    fn current_context() -> usize {
+        extern crate std;
+        std::thread_local!(static CONTEXT: u8 = const { 0 });
+        CONTEXT.with(|c| c as *const u8 as usize)
+    }
+
+    /// 读临界区的记录，drop时清除
+    pub(super) struct ReadMarker(Option<usize>);
+
+    impl ReadMarker {
+        pub(super) fn enter(key: usize) -> Self {
+            let task = current_context();
+            let slot = READERS.iter().position(|(t, _)| {
+                t.compare_exchange(0, task, Ordering::Acquire, Ordering::Relaxed)
+                    .is_ok()
+            });
+            if let Some(i) = slot {
+                READERS[i].1.store(key, Ordering::Release);
+            }
+            ReadMarker(slot)
+        }
+    }
+
+    impl Drop for ReadMarker {
+        fn drop(&mut self) {
+            if let Some(i) = self.0 {
+                READERS[i].1.store(0, Ordering::Relaxed);
+                READERS[i].0.store(0, Ordering::Release);
+            }
+        }
+    }
+
+    /// 当前任务是否正在读key对应的SRcuData
+    pub(super) fn is_reading(key: usize) -> bool {
+        let task = current_context();
+        READERS
+            .iter()
+            .any(|(t, k)| t.load(Ordering::Relaxed) == task && k.load(Ordering::Acquire) == key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use alloc::{boxed::Box, vec::Vec};
+    use core::cell::Cell;
+
+    use super::*;
+
+    std::thread_local! {
+        /// 当前线程是否模拟原子上下文，见can_synchronize
+        pub(super) static IN_ATOMIC: Cell<bool> = const { Cell::new(false) };
+    }
+
+    /// 不调用内核的后端，只用来测试SRcuData自己的逻辑
+    #[derive(Debug)]
+    struct NoopRcu;
+
+    impl RcuBackend for NoopRcu {
+        fn read_lock(&self) -> core::ffi::c_int {
+            0
+        }
+        fn read_unlock(&self, _idx: core::ffi::c_int) {}
+        fn synchronize(&self) {}
+        fn start_poll(&self) -> core::ffi::c_ulong {
+            0
+        }
+        fn poll(&self, _cookie: core::ffi::c_ulong) -> bool {
+            true
+        }
+        fn barrier(&self) {}
+    }
+
+    #[cfg(feature = "debug_rcu")]
+    #[test]
+    fn update_in_read_is_flagged() {
+        let data = SRcuData::new_with_backend(1u32, NoopRcu);
+        let other = SRcuData::new_with_backend(2u32, NoopRcu);
+        assert!(data.check_not_reading().is_ok());
+        // 读闭包中更新同一个SRcuData会死锁
+        assert!(data.read(|_| data.check_not_reading()).is_err());
+        // 更新其他SRcuData不会
+        assert!(data.read(|_| other.check_not_reading()).is_ok());
+        // 读临界区结束后记录被清除
+        assert!(data.check_not_reading().is_ok());
+        // 其他任务的读者不影响当前任务
+        data.read(|_| {
+            let flagged = std::thread::scope(|s| {
+                s.spawn(|| data.check_not_reading().is_err())
+                    .join()
+                    .unwrap()
+            });
+            assert!(!flagged);
+        });
+    }
+
+    #[test]
+    fn update_refused_in_atomic_context() {
+        let data = SRcuData::new_with_backend(1u32, NoopRcu);
+        IN_ATOMIC.with(|in_atomic| in_atomic.set(true));
+        let err = data.try_update(2).err().map(|e| e.to_errno());
+        assert_eq!(err, Some(code::EDEADLK.to_errno()));
+        assert!(data.barrier().is_err());
+        // 数据没有被替换，读者仍然看到旧数据
+        assert_eq!(data.read(|v| *v), 1);
+        // 离开原子上下文之后可以正常更新
+        IN_ATOMIC.with(|in_atomic| in_atomic.set(false));
+        assert_eq!(*data.try_update(2).unwrap(), 1);
+        assert_eq!(data.read(|v| *v), 2);
+        assert!(data.barrier().is_ok());
+    }
+
+    #[test]
+    fn update_empty_data() {
+        // SAFETY: 填入数据之前只调用is_empty和try_update_directly
+        let data = unsafe { SRcuData::<u32, NoopRcu>::empty_with_backend(NoopRcu) };
+        assert!(data.is_empty());
+        // 没有旧数据，不会从空指针重建Box
+        assert!(data.try_update_directly(1).unwrap().is_none());
+        assert!(!data.is_empty());
+        assert_eq!(data.read(|v| *v), 1);
+        assert_eq!(
+            data.try_update_directly(2).unwrap().map(|old| *old),
+            Some(1)
+        );
+        assert_eq!(*data.update_directly(3), 2);
+        assert_eq!(data.read(|v| *v), 3);
+    }
+
+    #[test]
+    fn dereference_sees_published_data() {
+        // 两个字段总是相同，读者看到撕裂的指针或者没有发布完成的数据时会不同
+        let crcu_data = CRcuData::new(Box::into_raw(Box::new((0u64, 0u64))) as _);
+        let old = std::thread::scope(|s| {
+            let writer = s.spawn(|| {
+                let mut old = Vec::new();
+                for i in 1..10_000u64 {
+                    old.push(crcu_data.load() as usize);
+                    srcu_assign_pointer(&crcu_data, Box::into_raw(Box::new((i, i))));
+                }
+                old
+            });
+            for _ in 0..10_000 {
+                // 旧数据在写者结束之后才释放
+                let v = unsafe { &*srcu_dereference::<(u64, u64)>(&crcu_data) };
+                assert_eq!(v.0, v.1);
+            }
+            writer.join().unwrap()
+        });
+        for ptr in old.into_iter().chain([crcu_data.load() as usize]) {
+            drop(unsafe { Box::from_raw(ptr as *mut (u64, u64)) });
+        }
+    }
 }
@@ -0,0 +1,109 @@
+// SPDX-License-Identifier: GPL-2.0
+
+//! A kernel wait queue.
+//!
+//! C header: [`include/linux/wait.h`](srctree/include/linux/wait.h).
+
+use alloc::boxed::Box;
+use core::mem::MaybeUninit;
+
+use crate::{bindings, static_lock_class, time::msecs_to_jiffies, types::Opaque};
+
+/// A queue of tasks sleeping until a condition becomes true
+///
+/// The waiters check the condition after they are queued, so a waker must make the condition
+/// true before it calls [`WaitQueue::wake_up_all`], otherwise the wakeup may be lost.
+pub struct WaitQueue {
+    // the waiters link themselves into the head, it must not move
+    head: Box<Opaque<bindings::wait_queue_head>>,
+}
+
+// SAFETY: the wait queue head is protected by its own spinlock.
+unsafe impl Send for WaitQueue {}
+// SAFETY: the wait queue head is protected by its own spinlock.
+unsafe impl Sync for WaitQueue {}
+
+impl WaitQueue {
+    pub fn new() -> Self {
+        let head = Box::new(Opaque::uninit());
+        // SAFETY: the head is allocated and never moves, the name and the lock class are
+        // static.
+        unsafe {
+            bindings::__init_waitqueue_head(
+                head.get(),
+                crate::optional_name!().as_char_ptr(),
+                static_lock_class!().as_ptr(),
+            )
+        };
+        Self { head }
+    }
+
+    /// Sleep until `cond` returns `true`
+    pub fn wait(&self, cond: impl FnMut() -> bool) {
+        self.wait_jiffies(core::ffi::c_long::MAX, cond);
+    }
+
+    /// Sleep until `cond` returns `true` or `timeout_ms` milliseconds have passed, return the
+    /// last result of `cond`
+    pub fn wait_timeout(&self, timeout_ms: u64, cond: impl FnMut() -> bool) -> bool {
+        let ms = timeout_ms.min(core::ffi::c_uint::MAX as u64) as core::ffi::c_uint;
+        let jiffies = msecs_to_jiffies(ms).min(core::ffi::c_long::MAX as _);
+        self.wait_jiffies(jiffies as _, cond)
+    }
+
+    fn wait_jiffies(&self, mut timeout: core::ffi::c_long, mut cond: impl FnMut() -> bool) -> bool {
+        if cond() {
+            return true;
+        }
+        let mut entry = MaybeUninit::<bindings::wait_queue_entry>::uninit();
+        // SAFETY: the entry lives on the stack until `finish_wait` unlinks it.
+        unsafe { bindings::init_wait_entry(entry.as_mut_ptr(), 0) };
+        let done = loop {
+            // SAFETY: the head and the entry are initialized.
+            unsafe {
+                bindings::prepare_to_wait_event(
+                    self.head.get(),
+                    entry.as_mut_ptr(),
+                    bindings::TASK_UNINTERRUPTIBLE as _,
+                )
+            };
+            // the task is queued before `cond` is checked, so a wakeup after it is not lost
+            if cond() {
+                break true;
+            }
+            if timeout == 0 {
+                break false;
+            }
+            // SAFETY: the task state was set by `prepare_to_wait_event`.
+            timeout = unsafe { bindings::schedule_timeout(timeout) };
+        };
+        // SAFETY: the entry was queued by `prepare_to_wait_event`.
+        unsafe { bindings::finish_wait(self.head.get(), entry.as_mut_ptr()) };
+        done
+    }
+
+    /// Wake up all the tasks waiting on the queue
+    pub fn wake_up_all(&self) {
+        // SAFETY: the head is initialized.
+        unsafe {
+            bindings::__wake_up(
+                self.head.get(),
+                bindings::TASK_INTERRUPTIBLE | bindings::TASK_UNINTERRUPTIBLE,
+                0,
+                core::ptr::null_mut(),
+            )
+        };
+    }
+}
+
+impl Default for WaitQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl core::fmt::Debug for WaitQueue {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("WaitQueue").finish()
+    }
+}
@@ -29,6 +29,15 @@ pub fn msecs_to_jiffies(msecs: Msecs) -> Jiffies {
     unsafe { bindings::__msecs_to_jiffies(msecs) }
 }
 
+/// Sleeps for at least `msecs` milliseconds.
+///
+/// It must be called in process context, where it may sleep.
+#[inline]
+pub fn msleep(msecs: Msecs) {
+    // SAFETY: `msleep` only sleeps, the caller is in process context.
+    unsafe { bindings::msleep(msecs) }
+}
+
 /// A Rust wrapper around a `ktime_t`.
 #[repr(transparent)]
 #[derive(Copy, Clone)]
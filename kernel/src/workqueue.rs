@@ -0,0 +1,81 @@
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use crate::{bindings, types::Opaque};
+
+const UNINIT: u8 = 0;
+const INITIALIZING: u8 = 1;
+const READY: u8 = 2;
+
+/// A work item in a static which runs `func` in process context on the system workqueue
+///
+/// It is used to move work out of a context which must not sleep or re-enter the caller,
+/// e.g. an allocator. Scheduling it again before it runs is a no-op, so `func` must check
+/// the current state instead of relying on one run per [StaticWork::schedule].
+#[repr(C)]
+pub struct StaticWork {
+    // must be the first field, see `StaticWork::run`
+    work: Opaque<bindings::work_struct>,
+    func: fn(),
+    state: AtomicU8,
+}
+
+// SAFETY: the `work_struct` is only touched through the workqueue API, which is thread safe
+// once it is initialized, and the initialization is guarded by `state`.
+unsafe impl Sync for StaticWork {}
+
+impl StaticWork {
+    pub const fn new(func: fn()) -> Self {
+        Self {
+            work: Opaque::uninit(),
+            func,
+            state: AtomicU8::new(UNINIT),
+        }
+    }
+
+    /// Queue the work on the system workqueue, return `false` if it is already queued
+    ///
+    /// It does not sleep, so it can be called with a spinlock held.
+    pub fn schedule(&'static self) -> bool {
+        self.init();
+        // SAFETY: the work is initialized and lives forever.
+        unsafe { bindings::schedule_work(self.work.get()) }
+    }
+
+    /// Queue the work on the system workqueue to run on `cpu`, return `false` if it is
+    /// already queued, it then runs on the CPU it was queued for
+    ///
+    /// It does not sleep, so it can be called with a spinlock held.
+    pub fn schedule_on(&'static self, cpu: u32) -> bool {
+        self.init();
+        // SAFETY: the work is initialized and lives forever.
+        unsafe { bindings::schedule_work_on(cpu as core::ffi::c_int, self.work.get()) }
+    }
+
+    fn init(&self) {
+        match self.state.compare_exchange(
+            UNINIT,
+            INITIALIZING,
+            Ordering::Acquire,
+            Ordering::Acquire,
+        ) {
+            Ok(_) => {
+                // SAFETY: only the winner of the exchange initializes the work, before it is
+                // queued.
+                unsafe { bindings::init_work(self.work.get(), Some(Self::run)) };
+                self.state.store(READY, Ordering::Release);
+            }
+            Err(_) => {
+                while self.state.load(Ordering::Acquire) != READY {
+                    core::hint::spin_loop();
+                }
+            }
+        }
+    }
+
+    unsafe extern "C" fn run(work: *mut bindings::work_struct) {
+        // SAFETY: `work` is the first field of a `#[repr(C)]` `StaticWork`, and
+        // `Opaque` is transparent.
+        let this = unsafe { &*(work as *const StaticWork) };
+        (this.func)();
+    }
+}
@@ -0,0 +1,101 @@
+use pconst::LinuxErrno;
+
+/// Check the cpumask of `sys_domain_set_affinity` against the `online` CPUs, bit `i` is
+/// CPU `i`
+///
+/// Return `EINVAL` if the mask is empty or has a CPU which is not online.
+pub fn check_affinity(cpumask: u64, online: u64) -> Result<(), LinuxErrno> {
+    if cpumask == 0 || cpumask & !online != 0 {
+        return Err(LinuxErrno::EINVAL);
+    }
+    Ok(())
+}
+
+/// The range of the nice values, see `include/linux/sched/prio.h`
+pub const MIN_NICE: i32 = -20;
+pub const MAX_NICE: i32 = 19;
+
+/// Check the nice value of `sys_set_domain_nice`, return `EINVAL` if it is not in
+/// `[MIN_NICE, MAX_NICE]`
+pub fn check_nice(nice: i32) -> Result<(), LinuxErrno> {
+    if !(MIN_NICE..=MAX_NICE).contains(&nice) {
+        return Err(LinuxErrno::EINVAL);
+    }
+    Ok(())
+}
+
+/// Run `work` of a domain at the nice value `nice` of the domain, `None` runs it unchanged
+///
+/// The work runs on a shared worker, so the nice value `get_nice` returns before it is
+/// set again by `set_nice` after the work.
+pub fn run_with_nice<R>(
+    nice: Option<i32>,
+    get_nice: impl FnOnce() -> i32,
+    mut set_nice: impl FnMut(i32),
+    work: impl FnOnce() -> R,
+) -> R {
+    let Some(nice) = nice else {
+        return work();
+    };
+    let old = get_nice();
+    set_nice(nice);
+    let r = work();
+    set_nice(old);
+    r
+}
+
+/// The CPU in `cpumask` the work of a domain runs on, the `current` CPU if it is in the
+/// mask so that the work is not sent to another CPU, otherwise the first CPU of the mask
+pub fn affinity_cpu(cpumask: u64, current: u32) -> u32 {
+    if current < u64::BITS && cpumask & (1 << current) != 0 {
+        current
+    } else {
+        cpumask.trailing_zeros()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{collections::BTreeMap, vec::Vec};
+
+    use super::*;
+
+    #[test]
+    fn test_affinity() {
+        let online = 0b1111;
+        assert_eq!(check_affinity(0b0110, online), Ok(()));
+        assert_eq!(check_affinity(0, online), Err(LinuxErrno::EINVAL));
+        assert_eq!(check_affinity(0b1_0010, online), Err(LinuxErrno::EINVAL));
+        assert_eq!(check_affinity(1 << 63, u64::MAX), Ok(()));
+        // the work stays on the current CPU if it is in the mask
+        assert_eq!(affinity_cpu(0b0110, 2), 2);
+        assert_eq!(affinity_cpu(0b0110, 0), 1);
+        assert_eq!(affinity_cpu(0b0110, 3), 1);
+        assert_eq!(affinity_cpu(1 << 63, 100), 63);
+    }
+
+    #[test]
+    fn test_nice() {
+        assert_eq!(check_nice(MIN_NICE), Ok(()));
+        assert_eq!(check_nice(MAX_NICE), Ok(()));
+        assert_eq!(check_nice(MIN_NICE - 1), Err(LinuxErrno::EINVAL));
+        assert_eq!(check_nice(MAX_NICE + 1), Err(LinuxErrno::EINVAL));
+
+        // the queued work of two domains runs on one worker, only one has a nice value
+        let worker = core::cell::Cell::new(0);
+        let nices = BTreeMap::from([("low", 10)]);
+        let mut seen = Vec::new();
+        for name in ["low", "normal", "low"] {
+            let nice = nices.get(name).copied();
+            run_with_nice(
+                nice,
+                || worker.get(),
+                |n| worker.set(n),
+                || seen.push(worker.get()),
+            );
+            // the worker is left as it was
+            assert_eq!(worker.get(), 0);
+        }
+        assert_eq!(seen, [10, 0, 10]);
+    }
+}
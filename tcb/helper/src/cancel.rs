@@ -0,0 +1,188 @@
+use alloc::collections::BTreeMap;
+
+use pconst::LinuxErrno;
+
+#[derive(Debug)]
+struct InterruptibleCall {
+    domain_id: u64,
+    canceled: bool,
+    /// The time in nanoseconds after which the call is canceled, `u64::MAX` if it never
+    /// times out
+    deadline: u64,
+}
+
+/// The interruptible calls in flight into the domains, see `sys_cancel_call`
+///
+/// A call is armed once it is canceled or if it has a deadline. The domains poll
+/// [InterruptibleCalls::canceled] while they run, the caller can skip the poll while
+/// [InterruptibleCalls::armed] is 0 because no call can be canceled.
+#[derive(Debug, Default)]
+pub struct InterruptibleCalls {
+    next_id: u64,
+    calls: BTreeMap<u64, InterruptibleCall>,
+    armed: usize,
+}
+
+impl InterruptibleCalls {
+    pub const fn new() -> Self {
+        Self {
+            next_id: 0,
+            calls: BTreeMap::new(),
+            armed: 0,
+        }
+    }
+
+    /// Start a call into the domain `domain_id` which is canceled at `deadline` in
+    /// nanoseconds, `u64::MAX` for none, and return its call id
+    pub fn begin(&mut self, domain_id: u64, deadline: u64) -> u64 {
+        let call_id = self.next_id;
+        self.next_id += 1;
+        if deadline != u64::MAX {
+            self.armed += 1;
+        }
+        self.calls.insert(
+            call_id,
+            InterruptibleCall {
+                domain_id,
+                canceled: false,
+                deadline,
+            },
+        );
+        call_id
+    }
+
+    /// Forget the call `call_id` started by [InterruptibleCalls::begin]
+    pub fn end(&mut self, call_id: u64) {
+        if let Some(call) = self.calls.remove(&call_id) {
+            if call.canceled || call.deadline != u64::MAX {
+                self.armed -= 1;
+            }
+        }
+    }
+
+    /// Cancel all the calls in flight into the domain `domain_id`, return how many are
+    /// canceled
+    pub fn cancel(&mut self, domain_id: u64) -> usize {
+        let mut n = 0;
+        for call in self.calls.values_mut() {
+            if call.domain_id == domain_id && !call.canceled {
+                if call.deadline == u64::MAX {
+                    self.armed += 1;
+                }
+                call.canceled = true;
+                n += 1;
+            }
+        }
+        n
+    }
+
+    /// Whether the call `call_id` has been canceled or has timed out at `now_ns`, an
+    /// unknown call is never canceled
+    pub fn canceled(&self, call_id: u64, now_ns: u64) -> bool {
+        self.calls
+            .get(&call_id)
+            .is_some_and(|call| call.canceled || now_ns >= call.deadline)
+    }
+
+    /// Whether the deadline of the call `call_id` has passed at `now_ns`
+    pub fn timed_out(&self, call_id: u64, now_ns: u64) -> bool {
+        self.calls
+            .get(&call_id)
+            .is_some_and(|call| now_ns >= call.deadline)
+    }
+
+    /// The number of the calls which are canceled or have a deadline
+    pub fn armed(&self) -> usize {
+        self.armed
+    }
+}
+
+/// The result of an interruptible call which returned `res`, `timed_out` tells whether its
+/// deadline has passed
+///
+/// A call which stopped because it was canceled returns `EINTR`, which becomes `ETIMEDOUT`
+/// once the deadline has passed. A call which completed keeps its result even if it is
+/// late, as its side effects are done.
+pub fn timeout_result<R>(res: Result<R, LinuxErrno>, timed_out: bool) -> Result<R, LinuxErrno> {
+    match res {
+        Err(LinuxErrno::EINTR) if timed_out => Err(LinuxErrno::ETIMEDOUT),
+        res => res,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_interruptible_calls() {
+        let mut calls = InterruptibleCalls::new();
+        let a = calls.begin(1, u64::MAX);
+        let b = calls.begin(1, u64::MAX);
+        let c = calls.begin(2, u64::MAX);
+        assert_eq!(calls.armed(), 0);
+        assert!(!calls.canceled(a, u64::MAX - 1));
+        // only the calls into the domain are canceled, once
+        assert_eq!(calls.cancel(1), 2);
+        assert_eq!(calls.cancel(1), 0);
+        assert_eq!(calls.armed(), 2);
+        assert!(calls.canceled(a, 0) && calls.canceled(b, 0));
+        assert!(!calls.canceled(c, 0));
+        calls.end(a);
+        assert!(!calls.canceled(a, 0));
+        assert_eq!(calls.armed(), 1);
+        // a call with a deadline is armed until it ends, even once canceled
+        let d = calls.begin(2, 100);
+        assert_eq!(calls.armed(), 2);
+        assert!(!calls.canceled(d, 99));
+        assert!(calls.canceled(d, 100));
+        assert_eq!(calls.cancel(2), 2);
+        assert_eq!(calls.armed(), 3);
+        calls.end(d);
+        calls.end(d);
+        calls.end(b);
+        assert_eq!(calls.armed(), 1);
+        calls.end(c);
+        assert_eq!(calls.armed(), 0);
+    }
+
+    #[test]
+    fn test_call_timeout() {
+        const STEP_NS: u64 = 10;
+        let mut calls = InterruptibleCalls::new();
+        let now = core::cell::Cell::new(0);
+        // an operation of `steps` steps which polls for the cancellation at each step
+        let op = |calls: &InterruptibleCalls, call_id, steps| {
+            for _ in 0..steps {
+                if calls.canceled(call_id, now.get()) {
+                    return Err(LinuxErrno::EINTR);
+                }
+                now.set(now.get() + STEP_NS);
+            }
+            Ok(steps)
+        };
+        let call = |calls: &mut InterruptibleCalls, steps, timeout| {
+            let call_id = calls.begin(1, now.get() + timeout);
+            let res = op(calls, call_id, steps);
+            let timed_out = calls.timed_out(call_id, now.get());
+            calls.end(call_id);
+            timeout_result(res, timed_out)
+        };
+
+        // the slow operation is stopped at the deadline, the fast one completes
+        assert_eq!(call(&mut calls, 100, 50), Err(LinuxErrno::ETIMEDOUT));
+        assert_eq!(now.get(), 50);
+        assert_eq!(call(&mut calls, 3, 50), Ok(3));
+        assert_eq!(calls.armed(), 0);
+
+        // a canceled call is not reported as timed out, and a completed one keeps its result
+        let call_id = calls.begin(1, now.get() + 50);
+        calls.cancel(1);
+        let res = op(&calls, call_id, 3);
+        assert_eq!(
+            timeout_result(res, calls.timed_out(call_id, now.get())),
+            Err(LinuxErrno::EINTR)
+        );
+        assert_eq!(timeout_result(Ok(1), true), Ok(1));
+    }
+}
@@ -0,0 +1,176 @@
+use alloc::{
+    collections::{BTreeMap, BTreeSet},
+    string::{String, ToString},
+};
+
+use pconst::LinuxErrno;
+
+/// Set the tag `key` of a domain to `value`, replacing its old value, an empty `value`
+/// removes the tag
+///
+/// Return `EINVAL` if `key` is empty or contains `=` or a newline, or `value` contains a
+/// newline, and `ENOSPC` if the keys and values would take more than `limit` bytes. The
+/// tags are not changed on error.
+pub fn set_domain_tag(
+    tags: &mut BTreeMap<String, String>,
+    key: &str,
+    value: &str,
+    limit: usize,
+) -> Result<(), LinuxErrno> {
+    if key.is_empty() || key.contains(['=', '\n']) || value.contains('\n') {
+        return Err(LinuxErrno::EINVAL);
+    }
+    if value.is_empty() {
+        tags.remove(key);
+        return Ok(());
+    }
+    let old = tags.get(key).map_or(0, |old| key.len() + old.len());
+    let size: usize = tags.iter().map(|(k, v)| k.len() + v.len()).sum();
+    if size - old + key.len() + value.len() > limit {
+        return Err(LinuxErrno::ENOSPC);
+    }
+    tags.insert(key.to_string(), value.to_string());
+    Ok(())
+}
+
+/// Format the tags one `key=value` per line, sorted by key
+pub fn format_domain_tags(tags: &BTreeMap<String, String>) -> String {
+    tags.iter()
+        .map(|(k, v)| alloc::format!("{}={}\n", k, v))
+        .collect()
+}
+
+/// Move the value of `old_name` in `map` to `new_name`, see `sys_rename_domain`
+///
+/// Return `EEXIST` if `new_name` is already used and `EINVAL` if `old_name` is unknown, the
+/// map is left untouched on error.
+pub fn rename_key<'a, V>(
+    map: &'a mut BTreeMap<String, V>,
+    old_name: &str,
+    new_name: &str,
+) -> Result<&'a mut V, LinuxErrno> {
+    if map.contains_key(new_name) {
+        return Err(LinuxErrno::EEXIST);
+    }
+    let value = map.remove(old_name).ok_or(LinuxErrno::EINVAL)?;
+    Ok(map.entry(new_name.to_string()).or_insert(value))
+}
+
+/// The domain ids allocated by `sys_reserve_domain_id` which no domain uses yet
+#[derive(Debug, Default)]
+pub struct ReservedIds(BTreeSet<u64>);
+
+impl ReservedIds {
+    pub const fn new() -> Self {
+        Self(BTreeSet::new())
+    }
+
+    pub fn reserve(&mut self, domain_id: u64) {
+        self.0.insert(domain_id);
+    }
+
+    /// Take the reserved id `domain_id` to create a domain with it, `live` tells whether a
+    /// domain already uses it
+    ///
+    /// Return `EEXIST` if a domain already uses it, and `EINVAL` if it was not reserved or
+    /// is already taken.
+    pub fn take(&mut self, domain_id: u64, live: bool) -> Result<(), LinuxErrno> {
+        if live {
+            return Err(LinuxErrno::EEXIST);
+        }
+        if !self.0.remove(&domain_id) {
+            return Err(LinuxErrno::EINVAL);
+        }
+        Ok(())
+    }
+
+    /// Give back the id taken by [ReservedIds::take] whose domain could not be created, so
+    /// that the creation can be retried
+    pub fn give_back(&mut self, domain_id: u64) {
+        self.0.insert(domain_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{collections::BTreeSet, string::ToString};
+
+    use super::*;
+
+    #[test]
+    fn test_rename_key() {
+        let mut map = BTreeMap::new();
+        map.insert("null".to_string(), 1);
+        map.insert("logger".to_string(), 2);
+        assert_eq!(rename_key(&mut map, "null", "null_v2"), Ok(&mut 1));
+        assert_eq!(map.get("null"), None);
+        assert_eq!(map.get("null_v2"), Some(&1));
+        // the new name is used, nothing moves
+        assert_eq!(
+            rename_key(&mut map, "null_v2", "logger"),
+            Err(LinuxErrno::EEXIST)
+        );
+        assert_eq!(
+            rename_key(&mut map, "null_v2", "null_v2"),
+            Err(LinuxErrno::EEXIST)
+        );
+        assert_eq!(rename_key(&mut map, "null", "x"), Err(LinuxErrno::EINVAL));
+        assert_eq!(map.len(), 2);
+        assert_eq!(map.get("logger"), Some(&2));
+        assert_eq!(map.get("null_v2"), Some(&1));
+    }
+
+    #[test]
+    fn test_domain_tags() {
+        let mut tags = BTreeMap::new();
+        assert_eq!(set_domain_tag(&mut tags, "tenant", "acme", 24), Ok(()));
+        assert_eq!(set_domain_tag(&mut tags, "env", "staging", 24), Ok(()));
+        assert_eq!(format_domain_tags(&tags), "env=staging\ntenant=acme\n");
+        // overwriting only counts the new value
+        assert_eq!(set_domain_tag(&mut tags, "env", "production", 24), Ok(()));
+        assert_eq!(tags["env"], "production");
+        assert_eq!(
+            set_domain_tag(&mut tags, "region", "eu", 24),
+            Err(LinuxErrno::ENOSPC)
+        );
+        assert_eq!(
+            set_domain_tag(&mut tags, "a=b", "c", 24),
+            Err(LinuxErrno::EINVAL)
+        );
+        assert_eq!(
+            set_domain_tag(&mut tags, "", "c", 24),
+            Err(LinuxErrno::EINVAL)
+        );
+        assert_eq!(tags.len(), 2);
+        // an empty value removes the tag
+        assert_eq!(set_domain_tag(&mut tags, "env", "", 24), Ok(()));
+        assert_eq!(format_domain_tags(&tags), "tenant=acme\n");
+    }
+
+    #[test]
+    fn test_reserved_ids() {
+        let mut reserved = ReservedIds::new();
+        let mut live = BTreeSet::new();
+        let mut create = |reserved: &mut ReservedIds, id, ok: bool| {
+            reserved.take(id, live.contains(&id))?;
+            if !ok {
+                reserved.give_back(id);
+                return Err(LinuxErrno::ENOENT);
+            }
+            live.insert(id);
+            Ok(())
+        };
+        assert_eq!(create(&mut reserved, 7, true), Err(LinuxErrno::EINVAL));
+
+        // a failed creation can be retried with the same id
+        reserved.reserve(7);
+        assert_eq!(create(&mut reserved, 7, false), Err(LinuxErrno::ENOENT));
+        assert_eq!(create(&mut reserved, 7, true), Ok(()));
+        // the id is used once
+        assert_eq!(create(&mut reserved, 7, true), Err(LinuxErrno::EEXIST));
+        reserved.reserve(8);
+        assert_eq!(reserved.take(8, true), Err(LinuxErrno::EEXIST));
+        assert_eq!(reserved.take(8, false), Ok(()));
+        assert_eq!(reserved.take(8, false), Err(LinuxErrno::EINVAL));
+    }
+}
@@ -0,0 +1,19 @@
+//! The bookkeeping of the tcb which does not touch the kernel, e.g. the counters and
+//! flags of the proxies, the watchdog and the reports of the syscalls. It is kept apart
+//! from the tcb so that it can be tested on the host, and out of corelib so that it is not
+//! linked into the domains.
+#![no_std]
+extern crate alloc;
+
+pub mod affinity;
+pub mod cancel;
+pub mod domain;
+pub mod loader;
+pub mod log_sink;
+pub mod proxy;
+pub mod report;
+pub mod resource;
+pub mod sheap;
+pub mod teardown;
+pub mod upgrade;
+pub mod watchdog;
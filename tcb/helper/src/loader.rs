@@ -0,0 +1,192 @@
+use alloc::{string::String, sync::Arc, vec::Vec};
+
+use pconst::LinuxErrno;
+
+/// The domain elf data which is received chunk by chunk, see `sys_register_domain_begin`
+#[derive(Debug)]
+pub struct ChunkedElf {
+    total_len: usize,
+    data: Vec<u8>,
+}
+
+impl ChunkedElf {
+    /// Start to receive `total_len` bytes, return `EINVAL` if it is 0
+    pub fn new(total_len: usize) -> Result<Self, LinuxErrno> {
+        if total_len == 0 {
+            return Err(LinuxErrno::EINVAL);
+        }
+        Ok(Self {
+            total_len,
+            data: Vec::new(),
+        })
+    }
+
+    pub fn total_len(&self) -> usize {
+        self.total_len
+    }
+
+    /// The bytes received so far, which is the offset of the next chunk
+    pub fn received(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Append the chunk `data` at `offset`
+    ///
+    /// Return `EINVAL` if the chunk does not start at [ChunkedElf::received] or ends past
+    /// the total length, and `ENOMEM` if it cannot be stored. The data received so far is
+    /// kept on error.
+    pub fn append(&mut self, offset: usize, data: &[u8]) -> Result<(), LinuxErrno> {
+        if offset != self.data.len() {
+            return Err(LinuxErrno::EINVAL);
+        }
+        let end = offset.checked_add(data.len()).ok_or(LinuxErrno::EINVAL)?;
+        if end > self.total_len {
+            return Err(LinuxErrno::EINVAL);
+        }
+        self.data
+            .try_reserve(data.len())
+            .map_err(|_| LinuxErrno::ENOMEM)?;
+        self.data.extend_from_slice(data);
+        Ok(())
+    }
+
+    /// Take the data, return `EINVAL` if it is incomplete
+    pub fn finish(self) -> Result<Vec<u8>, LinuxErrno> {
+        if self.data.len() != self.total_len {
+            return Err(LinuxErrno::EINVAL);
+        }
+        Ok(self.data)
+    }
+}
+
+/// The FNV-1a hash of the domain elf data, used to find the identical data
+pub fn blob_hash(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &b| {
+        (hash ^ b as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+/// Store the domain elf `data` whose [blob_hash] is `hash`, see `sys_register_domain`
+///
+/// `registered` yields the identifier, the hash and the data of each registered elf. If one
+/// of them has the same bytes its buffer is shared and returned with its identifier,
+/// otherwise `data` is stored in a new buffer.
+pub fn share_blob<'a>(
+    registered: impl IntoIterator<Item = (&'a String, u64, &'a Arc<Vec<u8>>)>,
+    hash: u64,
+    data: Vec<u8>,
+) -> (Arc<Vec<u8>>, Option<&'a String>) {
+    registered
+        .into_iter()
+        .find(|(_, h, blob)| *h == hash && ***blob == data)
+        .map(|(name, _, blob)| (blob.clone(), Some(name)))
+        .unwrap_or_else(|| (Arc::new(data), None))
+}
+
+/// Copy the identifier of a new domain into the buffer `buf` of the caller, the rest of the
+/// buffer is zeroed
+///
+/// Return the length of the identifier, or `ENOSPC` without touching the buffer if it does
+/// not fit.
+pub fn write_identifier(identifier: &str, buf: &mut [u8]) -> Result<usize, LinuxErrno> {
+    let len = identifier.len();
+    if len > buf.len() {
+        return Err(LinuxErrno::ENOSPC);
+    }
+    buf[..len].copy_from_slice(identifier.as_bytes());
+    buf[len..].fill(0);
+    Ok(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{collections::BTreeMap, string::ToString, vec};
+
+    use corelib::domain_info::{check_identifier_buf, IDENTIFIER_BUF_MIN};
+
+    use super::*;
+
+    #[test]
+    fn test_chunked_elf() {
+        assert_eq!(ChunkedElf::new(0).unwrap_err(), LinuxErrno::EINVAL);
+        let mut elf = ChunkedElf::new(10).unwrap();
+        elf.append(0, &[1, 2, 3, 4]).unwrap();
+        // a chunk which is resent, skips data or overruns the total is refused
+        assert_eq!(elf.append(0, &[1, 2]), Err(LinuxErrno::EINVAL));
+        assert_eq!(elf.append(6, &[7]), Err(LinuxErrno::EINVAL));
+        assert_eq!(elf.append(4, &[0; 7]), Err(LinuxErrno::EINVAL));
+        assert_eq!(elf.append(usize::MAX, &[0]), Err(LinuxErrno::EINVAL));
+        assert_eq!(elf.received(), 4);
+        elf.append(4, &[]).unwrap();
+        elf.append(4, &[5, 6, 7]).unwrap();
+        assert_eq!(elf.received(), 7);
+        let mut incomplete = ChunkedElf::new(3).unwrap();
+        incomplete.append(0, &[1]).unwrap();
+        assert_eq!(incomplete.finish(), Err(LinuxErrno::EINVAL));
+        elf.append(7, &[8, 9, 10]).unwrap();
+        assert_eq!(elf.finish().unwrap(), (1..=10).collect::<Vec<u8>>());
+    }
+
+    #[test]
+    fn test_share_blob() {
+        let mut registry: BTreeMap<String, (u64, Arc<Vec<u8>>)> = BTreeMap::new();
+        let mut register = |name: &str, data: Vec<u8>| {
+            let hash = blob_hash(&data);
+            let (blob, shared) = share_blob(
+                registry.iter().map(|(k, (h, blob))| (k, *h, blob)),
+                hash,
+                data,
+            );
+            let shared = shared.cloned();
+            registry.insert(name.to_string(), (hash, blob));
+            shared
+        };
+        assert_eq!(register("null", vec![1, 2, 3]), None);
+        assert_eq!(register("null_b", vec![1, 2, 3]), Some("null".to_string()));
+        assert_eq!(register("logger", vec![4, 5]), None);
+        // the same bytes are stored once
+        let (null, null_b) = (&registry["null"].1, &registry["null_b"].1);
+        assert!(Arc::ptr_eq(null, null_b));
+        assert_eq!(Arc::strong_count(null), 2);
+        assert!(!Arc::ptr_eq(null, &registry["logger"].1));
+        // the other identifier keeps the buffer when one is unregistered
+        let (_, null) = registry.remove("null").unwrap();
+        drop(null);
+        assert_eq!(Arc::strong_count(&registry["null_b"].1), 1);
+        assert_eq!(*registry["null_b"].1, vec![1, 2, 3]);
+        // a hash collision with other bytes is not shared
+        let (h, _) = registry["logger"].clone();
+        let (blob, shared) = share_blob(
+            registry.iter().map(|(k, (h, blob))| (k, *h, blob)),
+            h,
+            vec![6],
+        );
+        assert_eq!(shared, None);
+        assert_eq!(*blob, vec![6]);
+    }
+
+    #[test]
+    fn test_write_identifier() {
+        let mut buf = [0xffu8; 8];
+        assert_eq!(write_identifier("null_1", &mut buf), Ok(6));
+        assert_eq!(&buf, b"null_1\0\0");
+        let mut small = [0xffu8; 4];
+        assert_eq!(
+            write_identifier("null_1", &mut small),
+            Err(LinuxErrno::ENOSPC)
+        );
+        assert_eq!(small, [0xff; 4]);
+        assert_eq!(check_identifier_buf(&small), Err(LinuxErrno::EINVAL));
+        // a buffer big enough to be accepted may still be too short for the identifier
+        let mut buf = [0xffu8; IDENTIFIER_BUF_MIN];
+        assert_eq!(check_identifier_buf(&buf), Ok(()));
+        let long = "a".repeat(IDENTIFIER_BUF_MIN + 1);
+        assert_eq!(write_identifier(&long, &mut buf), Err(LinuxErrno::ENOSPC));
+        assert_eq!(buf, [0xff; IDENTIFIER_BUF_MIN]);
+        assert_eq!(
+            write_identifier(&long[1..], &mut buf),
+            Ok(IDENTIFIER_BUF_MIN)
+        );
+        assert_eq!(&buf[..], &long.as_bytes()[1..]);
+    }
+}
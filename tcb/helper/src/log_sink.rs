@@ -0,0 +1,158 @@
+use alloc::{
+    collections::VecDeque,
+    string::{String, ToString},
+};
+
+use corelib::domain_info::{LogEntry, LogTail};
+
+/// Make the console prefix of a domain safe to print: the control characters, which could
+/// start an escape sequence, are removed, and it is cut to at most `limit` bytes on a char
+/// boundary
+pub fn sanitize_log_prefix(prefix: &str, limit: usize) -> String {
+    let mut sanitized = String::new();
+    for c in prefix.chars().filter(|c| !c.is_control()) {
+        if sanitized.len() + c.len_utf8() > limit {
+            break;
+        }
+        sanitized.push(c);
+    }
+    sanitized
+}
+
+/// Replace the `[0][Domain:<id>]` tag which the print macros of the domain `domain_id` put
+/// in front of a line with `[prefix]`
+///
+/// Return `None` if `s` does not start with the tag of the domain, e.g. the rest of a line
+/// split by a long write.
+pub fn retag_console_output(s: &str, domain_id: u64, prefix: &str) -> Option<String> {
+    let (id, rest) = s.strip_prefix("[0][Domain:")?.split_once(']')?;
+    if id.parse::<u64>().ok()? != domain_id {
+        return None;
+    }
+    Some(alloc::format!("[{}]{}", prefix, rest))
+}
+
+/// A ring which keeps the latest outputs of a domain, at most `capacity` bytes of text
+///
+/// The entries are not consumed by the readers, so several followers can read the same
+/// ring. The oldest entries are overwritten when the ring is full.
+#[derive(Debug)]
+pub struct LogRing {
+    entries: VecDeque<LogEntry>,
+    /// The bytes of the text of `entries`
+    len: usize,
+    capacity: usize,
+    next_seq: u64,
+    dropped: u64,
+}
+
+impl LogRing {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            entries: VecDeque::new(),
+            len: 0,
+            capacity,
+            next_seq: 0,
+            dropped: 0,
+        }
+    }
+
+    /// Append an output of the domain, only its tail is kept if it is larger than the ring
+    pub fn write(&mut self, s: &str) {
+        let mut start = s.len().saturating_sub(self.capacity);
+        while !s.is_char_boundary(start) {
+            start += 1;
+        }
+        let text = s[start..].to_string();
+        self.len += text.len();
+        self.entries.push_back(LogEntry {
+            seq: self.next_seq,
+            text,
+        });
+        self.next_seq += 1;
+        // the new entry alone always fits
+        while self.len > self.capacity {
+            let old = self.entries.pop_front().unwrap();
+            self.len -= old.text.len();
+            self.dropped += 1;
+        }
+    }
+
+    /// Read the entries from the sequence number `from_seq`, the ring is not changed
+    pub fn read_from(&self, from_seq: u64) -> LogTail {
+        let first = self.entries.front().map_or(self.next_seq, |e| e.seq);
+        LogTail {
+            entries: self
+                .entries
+                .iter()
+                .filter(|e| e.seq >= from_seq)
+                .cloned()
+                .collect(),
+            next_seq: self.next_seq,
+            missed: first.saturating_sub(from_seq),
+        }
+    }
+
+    /// How many entries have been overwritten since the ring was created
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_log_ring() {
+        let mut ring = LogRing::new(8);
+        ring.write("abc");
+        ring.write("def");
+        let tail = ring.read_from(0);
+        assert_eq!(tail.entries.len(), 2);
+        assert_eq!((tail.next_seq, tail.missed), (2, 0));
+        // a follower only gets the new entries
+        ring.write("gh");
+        assert_eq!(ring.read_from(2).entries[0].text, "gh");
+        assert!(ring.read_from(3).entries.is_empty());
+
+        // "abc" and "def" are overwritten
+        ring.write("ijklmn");
+        assert_eq!(ring.dropped(), 2);
+        let tail = ring.read_from(1);
+        assert_eq!(tail.missed, 1);
+        assert_eq!(tail.entries[0].seq, 2);
+        assert_eq!(tail.next_seq, 4);
+        // only the tail of an entry larger than the ring is kept
+        ring.write("0123456789");
+        assert_eq!(ring.read_from(0).entries[0].text, "23456789");
+        assert_eq!(ring.read_from(0).missed, 4);
+    }
+
+    #[test]
+    fn test_log_prefix() {
+        let prefix = sanitize_log_prefix("tenant-a", 16);
+        assert_eq!(
+            retag_console_output("[0][Domain:7] hello\n", 7, &prefix).as_deref(),
+            Some("[tenant-a] hello\n")
+        );
+        assert_eq!(
+            retag_console_output("[0][Domain:7][WARN]  disk\n", 7, &prefix).as_deref(),
+            Some("[tenant-a][WARN]  disk\n")
+        );
+        // only the tag of the domain is replaced
+        assert_eq!(
+            retag_console_output("[0][Domain:8] hello\n", 7, &prefix),
+            None
+        );
+        assert_eq!(retag_console_output("hello\n", 7, &prefix), None);
+        // the escape sequences and the other control characters are stripped
+        assert_eq!(
+            sanitize_log_prefix("\u{1B}[2Jevil\r\n\u{9B}31m", 16),
+            "[2Jevil31m"
+        );
+        // the prefix is bounded on a char boundary
+        assert_eq!(sanitize_log_prefix("abcdefgh", 4), "abcd");
+        assert_eq!(sanitize_log_prefix("ab\u{e9}\u{e9}", 4), "ab\u{e9}");
+    }
+}
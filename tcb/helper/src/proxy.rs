@@ -0,0 +1,732 @@
+use alloc::{string::ToString, vec::Vec};
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+
+use corelib::domain_info::{MethodCount, LATENCY_BUCKETS_NS};
+use pconst::LinuxErrno;
+
+/// The number of buckets of a domain call latency histogram
+pub const LATENCY_BUCKETS: usize = LATENCY_BUCKETS_NS.len() + 1;
+
+/// The bucket of a domain call latency histogram which counts a call of `ns` nanoseconds
+pub fn latency_bucket(ns: u64) -> usize {
+    LATENCY_BUCKETS_NS
+        .iter()
+        .position(|&bound| ns < bound)
+        .unwrap_or(LATENCY_BUCKETS_NS.len())
+}
+
+/// The number of the calls through a proxy, by method
+///
+/// It belongs to the proxy rather than the domain, so it keeps counting across the hot
+/// upgrades. A method is identified by its index in `methods`, counting a call is a
+/// relaxed increment.
+#[derive(Debug)]
+pub struct CallCounts<const N: usize> {
+    methods: [&'static str; N],
+    counts: [AtomicU64; N],
+}
+
+impl<const N: usize> CallCounts<N> {
+    pub fn new(methods: [&'static str; N]) -> Self {
+        Self {
+            methods,
+            counts: core::array::from_fn(|_| AtomicU64::new(0)),
+        }
+    }
+
+    /// Count a call of the method `method`
+    #[inline]
+    pub fn count(&self, method: usize) {
+        self.counts[method].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Zero all the counters, an increment racing with the reset may be lost
+    pub fn reset(&self) {
+        self.counts
+            .iter()
+            .for_each(|count| count.store(0, Ordering::Relaxed));
+    }
+
+    /// The number of the calls of each method, in the order of `methods`
+    pub fn counts(&self) -> Vec<MethodCount> {
+        self.methods
+            .iter()
+            .zip(self.counts.iter())
+            .map(|(method, count)| MethodCount {
+                method: method.to_string(),
+                calls: count.load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+}
+
+/// When the last call through a proxy was made, see `sys_domain_idle_ms`
+///
+/// It belongs to the proxy rather than the domain, so an upgrade does not make the domain
+/// look idle. Recording a call is a relaxed `fetch_max`, so a late store from another CPU
+/// never moves the time back.
+#[derive(Debug)]
+pub struct LastActive {
+    ns: AtomicU64,
+}
+
+impl LastActive {
+    /// A domain created at `now_ns`, it is idle from then until its first call
+    pub const fn new(now_ns: u64) -> Self {
+        Self {
+            ns: AtomicU64::new(now_ns),
+        }
+    }
+
+    /// Record a call at `now_ns`
+    pub fn touch(&self, now_ns: u64) {
+        self.ns.fetch_max(now_ns, Ordering::Relaxed);
+    }
+
+    /// The milliseconds from the last call to `now_ns`
+    pub fn idle_ms(&self, now_ns: u64) -> u64 {
+        now_ns.saturating_sub(self.ns.load(Ordering::Relaxed)) / 1_000_000
+    }
+}
+
+/// Poll `done` until it returns true, or return `ETIMEDOUT` once `elapsed_ms` reaches
+/// `timeout_ms`
+///
+/// `pause` runs between two polls, e.g. to spin or to sleep.
+pub fn wait_until(
+    timeout_ms: u64,
+    mut elapsed_ms: impl FnMut() -> u64,
+    mut pause: impl FnMut(),
+    mut done: impl FnMut() -> bool,
+) -> Result<(), LinuxErrno> {
+    loop {
+        if done() {
+            return Ok(());
+        }
+        if elapsed_ms() >= timeout_ms {
+            return Err(LinuxErrno::ETIMEDOUT);
+        }
+        pause();
+    }
+}
+
+/// The proxies a task is calling through, see [TaskCalls]
+struct TaskSlot<const D: usize> {
+    /// The task owning the slot, 0 if the slot is free
+    task: AtomicUsize,
+    /// The number of the calls in `proxies`
+    depth: AtomicUsize,
+    /// The proxies of the nested calls of the task, the outermost first
+    proxies: [AtomicUsize; D],
+}
+
+impl<const D: usize> TaskSlot<D> {
+    const fn new() -> Self {
+        Self {
+            task: AtomicUsize::new(0),
+            depth: AtomicUsize::new(0),
+            proxies: [const { AtomicUsize::new(0) }; D],
+        }
+    }
+}
+
+/// The proxies the tasks in a domain call are calling through, for at most `N` tasks with
+/// at most `D` nested calls each
+///
+/// A task takes a free slot when it enters its outermost call and frees it when it leaves
+/// that call, only the task changes its slot in between. The slots are atomics, so neither
+/// a lock nor the CPU of the task is needed: a task which sleeps and migrates in a call
+/// still finds its own slot and never touches the slot of another task.
+pub struct TaskCalls<const N: usize, const D: usize> {
+    slots: [TaskSlot<D>; N],
+}
+
+impl<const N: usize, const D: usize> TaskCalls<N, D> {
+    pub const fn new() -> Self {
+        Self {
+            slots: [const { TaskSlot::new() }; N],
+        }
+    }
+
+    /// Run `f` as a call of `task` through the proxy `proxy`, `task` must not be 0
+    ///
+    /// Return `EDEADLK` without running `f` if `task` is already in a call through `proxy`,
+    /// and `ELOOP` if the calls of `task` are already nested `D` deep. The call is not
+    /// tracked if all the slots are taken by other tasks.
+    pub fn call<R>(
+        &self,
+        task: usize,
+        proxy: usize,
+        f: impl FnOnce() -> Result<R, LinuxErrno>,
+    ) -> Result<R, LinuxErrno> {
+        self.enter(task, proxy, true, f)
+    }
+
+    /// Like [TaskCalls::call], but `task` may call through `proxy` again in the call, only
+    /// the depth is checked
+    pub fn call_reentrant<R>(
+        &self,
+        task: usize,
+        proxy: usize,
+        f: impl FnOnce() -> Result<R, LinuxErrno>,
+    ) -> Result<R, LinuxErrno> {
+        self.enter(task, proxy, false, f)
+    }
+
+    fn enter<R>(
+        &self,
+        task: usize,
+        proxy: usize,
+        check_reentry: bool,
+        f: impl FnOnce() -> Result<R, LinuxErrno>,
+    ) -> Result<R, LinuxErrno> {
+        let Some(slot) = self.slot(task) else {
+            return f();
+        };
+        let depth = slot.depth.load(Ordering::Relaxed);
+        let reentrant = check_reentry
+            && slot.proxies[..depth]
+                .iter()
+                .any(|p| p.load(Ordering::Relaxed) == proxy);
+        let r = if reentrant {
+            Err(LinuxErrno::EDEADLK)
+        } else if depth == D {
+            Err(LinuxErrno::ELOOP)
+        } else {
+            slot.proxies[depth].store(proxy, Ordering::Relaxed);
+            slot.depth.store(depth + 1, Ordering::Relaxed);
+            let r = f();
+            slot.depth.store(depth, Ordering::Relaxed);
+            r
+        };
+        if depth == 0 {
+            slot.task.store(0, Ordering::Release);
+        }
+        r
+    }
+
+    /// The number of the calls `task` is in
+    pub fn depth(&self, task: usize) -> usize {
+        self.slots
+            .iter()
+            .find(|s| s.task.load(Ordering::Acquire) == task)
+            .map_or(0, |s| s.depth.load(Ordering::Relaxed))
+    }
+
+    /// The slot of `task`, a free slot is taken if it has none
+    fn slot(&self, task: usize) -> Option<&TaskSlot<D>> {
+        if let Some(slot) = self
+            .slots
+            .iter()
+            .find(|s| s.task.load(Ordering::Acquire) == task)
+        {
+            return Some(slot);
+        }
+        self.slots.iter().find(|s| {
+            s.task
+                .compare_exchange(0, task, Ordering::Acquire, Ordering::Relaxed)
+                .is_ok()
+        })
+    }
+}
+
+impl<const N: usize, const D: usize> Default for TaskCalls<N, D> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Whether a proxy is frozen by `sys_freeze_domain`
+///
+/// The proxies only freeze with the lock of their lock path held and check the flag after
+/// they take that lock, so that no call runs between `freeze` and `thaw`.
+#[derive(Debug, Default)]
+pub struct FreezeFlag(AtomicBool);
+
+impl FreezeFlag {
+    pub const fn new() -> Self {
+        Self(AtomicBool::new(false))
+    }
+
+    pub fn is_frozen(&self) -> bool {
+        self.0.load(Ordering::Acquire)
+    }
+
+    /// Return `EBUSY` if it is already frozen
+    pub fn freeze(&self) -> Result<(), LinuxErrno> {
+        self.0
+            .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+            .map(|_| ())
+            .map_err(|_| LinuxErrno::EBUSY)
+    }
+
+    /// Return `EINVAL` if it is not frozen
+    pub fn thaw(&self) -> Result<(), LinuxErrno> {
+        self.0
+            .compare_exchange(true, false, Ordering::AcqRel, Ordering::Acquire)
+            .map(|_| ())
+            .map_err(|_| LinuxErrno::EINVAL)
+    }
+}
+
+/// Whether the block queue of a proxy is paused by `sys_block_domain_pause`
+///
+/// The queue is only reported as paused once it is quiesced, and a resume can not
+/// unquiesce it while a pause is still quiescing it.
+#[derive(Debug, Default)]
+pub struct IoPause(AtomicU8);
+
+impl IoPause {
+    const RUNNING: u8 = 0;
+    /// The queue is being quiesced or unquiesced
+    const BUSY: u8 = 1;
+    const PAUSED: u8 = 2;
+
+    pub const fn new() -> Self {
+        Self(AtomicU8::new(Self::RUNNING))
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.0.load(Ordering::Acquire) == Self::PAUSED
+    }
+
+    fn transition(
+        &self,
+        from: u8,
+        to: u8,
+        run: impl FnOnce(),
+        err: LinuxErrno,
+    ) -> Result<(), LinuxErrno> {
+        self.0
+            .compare_exchange(from, Self::BUSY, Ordering::AcqRel, Ordering::Acquire)
+            .map_err(|_| err)?;
+        run();
+        self.0.store(to, Ordering::Release);
+        Ok(())
+    }
+
+    /// Run `quiesce`, then mark the queue paused
+    ///
+    /// Return `EBUSY` if the queue is already paused, or is being paused or resumed.
+    pub fn pause(&self, quiesce: impl FnOnce()) -> Result<(), LinuxErrno> {
+        self.transition(Self::RUNNING, Self::PAUSED, quiesce, LinuxErrno::EBUSY)
+    }
+
+    /// Run `unquiesce`, then mark the queue running
+    ///
+    /// Return `EINVAL` if the queue is not paused, which includes a pause in progress.
+    pub fn resume(&self, unquiesce: impl FnOnce()) -> Result<(), LinuxErrno> {
+        self.transition(Self::PAUSED, Self::RUNNING, unquiesce, LinuxErrno::EINVAL)
+    }
+}
+
+const NSEC_PER_SEC: u64 = 1_000_000_000;
+
+/// A token bucket which admits `rate` calls per second on average and at most `burst`
+/// calls at once, see `sys_set_domain_rate_limit`
+///
+/// It is kept by a proxy and taken on its submission paths, which may run in interrupt
+/// context, so it is lock-free. The bucket is tracked as the time at which it would be
+/// full again: a call moves it one interval further, and is refused if that is more than
+/// `burst` intervals ahead of now. The limit is changed by [RateLimiter::set] without
+/// stopping the calls; a call racing with it may use the old or the new limit.
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    /// The nanoseconds between two calls at the average rate, 0 if there is no limit
+    interval_ns: AtomicU64,
+    /// `burst` intervals
+    limit_ns: AtomicU64,
+    /// When the bucket is full again, in nanoseconds
+    full_at_ns: AtomicU64,
+}
+
+impl RateLimiter {
+    /// A limiter without a limit
+    pub const fn new() -> Self {
+        Self {
+            interval_ns: AtomicU64::new(0),
+            limit_ns: AtomicU64::new(0),
+            full_at_ns: AtomicU64::new(0),
+        }
+    }
+
+    /// Limit the calls to `rate` per second and `burst` at once, a `rate` of 0 removes the
+    /// limit. The bucket starts full at `now_ns`.
+    ///
+    /// Return `EINVAL` if `burst` is 0 while `rate` is not.
+    pub fn set(&self, rate: u64, burst: u64, now_ns: u64) -> Result<(), LinuxErrno> {
+        if rate == 0 {
+            self.interval_ns.store(0, Ordering::Release);
+            return Ok(());
+        }
+        if burst == 0 {
+            return Err(LinuxErrno::EINVAL);
+        }
+        let interval = (NSEC_PER_SEC / rate).max(1);
+        self.limit_ns
+            .store(interval.saturating_mul(burst), Ordering::Relaxed);
+        self.full_at_ns.store(now_ns, Ordering::Relaxed);
+        self.interval_ns.store(interval, Ordering::Release);
+        Ok(())
+    }
+
+    /// Whether the calls are limited, the callers skip reading the clock if they are not
+    #[inline]
+    pub fn is_limited(&self) -> bool {
+        self.interval_ns.load(Ordering::Relaxed) != 0
+    }
+
+    /// Take a token at `now_ns`, return `false` if the bucket is empty
+    pub fn try_take(&self, now_ns: u64) -> bool {
+        let interval = self.interval_ns.load(Ordering::Acquire);
+        if interval == 0 {
+            return true;
+        }
+        let limit = self.limit_ns.load(Ordering::Relaxed);
+        let mut full_at = self.full_at_ns.load(Ordering::Relaxed);
+        loop {
+            let next = full_at.max(now_ns).saturating_add(interval);
+            if next - now_ns > limit {
+                return false;
+            }
+            match self.full_at_ns.compare_exchange_weak(
+                full_at,
+                next,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return true,
+                Err(current) => full_at = current,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rref::wire::{Decode, Encode};
+
+    use super::*;
+
+    #[test]
+    fn test_last_active() {
+        const MS: u64 = 1_000_000;
+        let last_active = LastActive::new(10 * MS);
+        assert_eq!(last_active.idle_ms(10 * MS), 0);
+        // the idle time grows while the domain is quiet
+        assert_eq!(last_active.idle_ms(25 * MS), 15);
+        assert_eq!(last_active.idle_ms(40 * MS), 30);
+        // a call resets it
+        last_active.touch(40 * MS);
+        assert_eq!(last_active.idle_ms(40 * MS), 0);
+        assert_eq!(last_active.idle_ms(45 * MS + MS / 2), 5);
+        // a late call from another CPU does not move the time back
+        last_active.touch(35 * MS);
+        assert_eq!(last_active.idle_ms(45 * MS), 5);
+        // a clock read before the last call is not idle
+        assert_eq!(last_active.idle_ms(30 * MS), 0);
+    }
+
+    #[test]
+    fn test_rate_limiter() {
+        // 10 calls per second, 3 at once
+        let limiter = RateLimiter::new();
+        assert!(!limiter.is_limited());
+        assert!((0..100).all(|_| limiter.try_take(0)));
+        assert_eq!(limiter.set(10, 0, 0), Err(LinuxErrno::EINVAL));
+        limiter.set(10, 3, 0).unwrap();
+        assert!((0..3).all(|_| limiter.try_take(0)));
+        assert!(!limiter.try_take(0));
+        // a token is refilled every 100ms
+        assert!(!limiter.try_take(99_999_999));
+        assert!(limiter.try_take(100_000_000));
+        assert!(!limiter.try_take(100_000_000));
+        // the bucket never holds more than the burst
+        assert!((0..3).all(|_| limiter.try_take(10 * NSEC_PER_SEC)));
+        assert!(!limiter.try_take(10 * NSEC_PER_SEC));
+        // the clock going backwards refills nothing
+        assert!(!limiter.try_take(0));
+        // removing the limit admits all the calls
+        limiter.set(0, 0, 0).unwrap();
+        assert!(limiter.try_take(0));
+    }
+
+    #[test]
+    fn test_rate_limiter_concurrent() {
+        extern crate std;
+
+        // the calls racing for the tokens take exactly the burst
+        let limiter = RateLimiter::new();
+        limiter.set(1, 64, 0).unwrap();
+        let taken = AtomicUsize::new(0);
+        std::thread::scope(|s| {
+            for _ in 0..8 {
+                s.spawn(|| {
+                    let n = (0..100).filter(|_| limiter.try_take(0)).count();
+                    taken.fetch_add(n, Ordering::Relaxed);
+                });
+            }
+        });
+        assert_eq!(taken.load(Ordering::Relaxed), 64);
+    }
+
+    #[test]
+    fn test_call_counts() {
+        const READ: usize = 0;
+        const WRITE: usize = 1;
+        let calls = CallCounts::new(["read", "write", "invoke"]);
+        (0..5).for_each(|_| calls.count(READ));
+        (0..3).for_each(|_| calls.count(WRITE));
+        let count = |method: &str, calls: u64| MethodCount {
+            method: method.into(),
+            calls,
+        };
+        let counts = calls.counts();
+        assert_eq!(
+            counts,
+            [count("read", 5), count("write", 3), count("invoke", 0)]
+        );
+        // the counts survive the wire format
+        let decoded = Vec::<MethodCount>::decode_from_slice(&counts.encode_to_vec()).unwrap();
+        assert_eq!(decoded, counts);
+        calls.reset();
+        assert!(calls.counts().iter().all(|c| c.calls == 0));
+    }
+
+    #[test]
+    fn test_call_counts_reset_in_flight() {
+        extern crate std;
+
+        // the calls keep counting while the counters are reset, an increment racing with
+        // the reset may be lost but no count is ever made up
+        let calls = CallCounts::new(["read", "write"]);
+        std::thread::scope(|s| {
+            s.spawn(|| (0..10000).for_each(|_| calls.count(0)));
+            s.spawn(|| (0..100).for_each(|_| calls.reset()));
+        });
+        let counts = calls.counts();
+        assert!(counts[0].calls <= 10000);
+        assert_eq!(counts[1].calls, 0);
+        calls.count(1);
+        calls.reset();
+        assert!(calls.counts().iter().all(|c| c.calls == 0));
+    }
+
+    #[test]
+    fn test_wait_until() {
+        let now = core::cell::Cell::new(0);
+        let pauses = core::cell::Cell::new(0);
+        let elapsed = || now.get();
+        let pause = || {
+            pauses.set(pauses.get() + 1);
+            now.set(now.get() + 1);
+        };
+
+        // an idle domain returns at once
+        let counter = [1i64, -1, 0, 0];
+        let idle = || counter.iter().sum::<i64>() <= 0;
+        assert_eq!(wait_until(100, elapsed, pause, idle), Ok(()));
+        assert_eq!(pauses.get(), 0);
+
+        // a domain which becomes idle while waiting
+        let busy = core::cell::Cell::new(3);
+        let drains = || {
+            busy.set(busy.get() - 1);
+            busy.get() <= 0
+        };
+        assert_eq!(wait_until(100, elapsed, pause, drains), Ok(()));
+        assert_eq!(pauses.get(), 2);
+
+        // a continuously busy domain times out
+        now.set(0);
+        pauses.set(0);
+        let counter = [1i64, 0, 0, 0];
+        let busy = || counter.iter().sum::<i64>() <= 0;
+        assert_eq!(
+            wait_until(10, elapsed, pause, busy),
+            Err(LinuxErrno::ETIMEDOUT)
+        );
+        assert_eq!((now.get(), pauses.get()), (10, 10));
+    }
+
+    #[test]
+    fn test_wait_ready() {
+        let ready = AtomicBool::new(false);
+        let now = core::cell::Cell::new(0);
+        // the proxy is initialized while the caller sleeps
+        let sleep = || {
+            now.set(now.get() + 1);
+            if now.get() == 3 {
+                ready.store(true, Ordering::Release);
+            }
+        };
+        let is_ready = || ready.load(Ordering::Acquire);
+        assert_eq!(wait_until(100, || now.get(), sleep, is_ready), Ok(()));
+        assert_eq!(now.get(), 3);
+
+        // a proxy which is never initialized times out
+        ready.store(false, Ordering::Release);
+        now.set(10);
+        assert_eq!(
+            wait_until(5, || now.get() - 10, || now.set(now.get() + 1), is_ready),
+            Err(LinuxErrno::ETIMEDOUT)
+        );
+        assert_eq!(now.get(), 15);
+    }
+
+    #[test]
+    fn test_task_calls_reentry() {
+        let calls = TaskCalls::<4, 4>::new();
+        // a task calling back through the proxy it is in is rejected
+        let r = calls.call(1, 0xa, || calls.call(1, 0xa, || Ok(1)));
+        assert_eq!(r, Err(LinuxErrno::EDEADLK));
+        let r = calls.call(1, 0xa, || {
+            calls.call(1, 0xb, || calls.call(1, 0xa, || Ok(1)))
+        });
+        assert_eq!(r, Err(LinuxErrno::EDEADLK));
+        // nested calls through other proxies and other tasks in the same proxy are not
+        let r = calls.call(1, 0xa, || calls.call(1, 0xb, || Ok(2)));
+        assert_eq!(r, Ok(2));
+        let r = calls.call(1, 0xa, || calls.call(2, 0xa, || Ok(3)));
+        assert_eq!(r, Ok(3));
+        // the same call is fine again once the outer call has returned
+        assert_eq!(calls.call(1, 0xa, || Ok(4)), Ok(4));
+        assert!(calls
+            .slots
+            .iter()
+            .all(|s| s.task.load(Ordering::Relaxed) == 0));
+
+        // without a free slot the call is not tracked
+        let calls = TaskCalls::<1, 4>::new();
+        let r = calls.call(1, 0xa, || {
+            calls.call(2, 0xa, || calls.call(2, 0xa, || Ok(5)))
+        });
+        assert_eq!(r, Ok(5));
+    }
+
+    #[test]
+    fn test_task_calls_depth() {
+        fn nest(calls: &TaskCalls<4, 3>, task: usize, depth: usize) -> Result<usize, LinuxErrno> {
+            if depth == 0 {
+                return Ok(calls.depth(task));
+            }
+            calls.call(task, depth, || nest(calls, task, depth - 1))
+        }
+
+        let calls = TaskCalls::<4, 3>::new();
+        assert_eq!(nest(&calls, 1, 3), Ok(3));
+        assert_eq!(nest(&calls, 1, 4), Err(LinuxErrno::ELOOP));
+        // the depth is counted per task, a deep task does not limit the others
+        let r = calls.call(1, 0xa, || calls.call(1, 0xb, || nest(&calls, 2, 3)));
+        assert_eq!(r, Ok(3));
+        let r = calls.call(1, 0xa, || calls.call(1, 0xb, || nest(&calls, 1, 2)));
+        assert_eq!(r, Err(LinuxErrno::ELOOP));
+
+        // a reentrant call is allowed where asked for, but still counted
+        let r = calls.call_reentrant(1, 0xa, || {
+            calls.call_reentrant(1, 0xa, || Ok(calls.depth(1)))
+        });
+        assert_eq!(r, Ok(2));
+        let r = calls.call_reentrant(1, 0xa, || {
+            calls.call_reentrant(1, 0xa, || {
+                calls.call_reentrant(1, 0xa, || nest(&calls, 1, 1))
+            })
+        });
+        assert_eq!(r, Err(LinuxErrno::ELOOP));
+        assert_eq!(calls.depth(1), 0);
+    }
+
+    #[test]
+    fn test_task_calls_migrate() {
+        extern crate std;
+        use std::sync::Barrier;
+
+        // the tasks sleep and run on other threads in their calls, the slots need no CPU
+        let calls = TaskCalls::<8, 4>::new();
+        let barrier = Barrier::new(4);
+        std::thread::scope(|s| {
+            for task in 1..=4usize {
+                let (calls, barrier) = (&calls, &barrier);
+                s.spawn(move || {
+                    for _ in 0..1000 {
+                        let r = calls.call(task, 0xa, || {
+                            barrier.wait();
+                            calls.call(task, 0xb, || Ok(calls.depth(task)))
+                        });
+                        assert_eq!(r, Ok(2));
+                    }
+                });
+            }
+        });
+        assert!(calls
+            .slots
+            .iter()
+            .all(|s| s.task.load(Ordering::Relaxed) == 0));
+    }
+
+    #[test]
+    fn test_io_pause() {
+        let pause = IoPause::new();
+        assert!(!pause.is_paused());
+        assert_eq!(pause.resume(|| unreachable!()), Err(LinuxErrno::EINVAL));
+        // the queue is quiesced before it is reported as paused, and it can not be resumed
+        // or paused again until then
+        pause
+            .pause(|| {
+                assert!(!pause.is_paused());
+                assert_eq!(pause.resume(|| unreachable!()), Err(LinuxErrno::EINVAL));
+                assert_eq!(pause.pause(|| unreachable!()), Err(LinuxErrno::EBUSY));
+            })
+            .unwrap();
+        assert!(pause.is_paused());
+        assert_eq!(pause.pause(|| unreachable!()), Err(LinuxErrno::EBUSY));
+        let mut unquiesced = false;
+        pause
+            .resume(|| {
+                assert!(!pause.is_paused());
+                unquiesced = true;
+            })
+            .unwrap();
+        assert!(unquiesced);
+        assert!(!pause.is_paused());
+    }
+
+    #[test]
+    fn test_freeze_flag() {
+        extern crate std;
+
+        let flag = FreezeFlag::new();
+        assert!(!flag.is_frozen());
+        assert_eq!(flag.thaw(), Err(LinuxErrno::EINVAL));
+        flag.freeze().unwrap();
+        assert!(flag.is_frozen());
+        assert_eq!(flag.freeze(), Err(LinuxErrno::EBUSY));
+        flag.thaw().unwrap();
+        assert!(!flag.is_frozen());
+        assert_eq!(flag.thaw(), Err(LinuxErrno::EINVAL));
+        // concurrent freezes, only one of them wins
+        let won = std::thread::scope(|s| {
+            let threads = (0..8)
+                .map(|_| s.spawn(|| flag.freeze().is_ok()))
+                .collect::<Vec<_>>();
+            threads
+                .into_iter()
+                .map(|t| t.join().unwrap())
+                .filter(|&won| won)
+                .count()
+        });
+        assert_eq!(won, 1);
+        assert!(flag.is_frozen());
+    }
+
+    #[test]
+    fn test_latency_bucket() {
+        assert_eq!(latency_bucket(0), 0);
+        assert_eq!(latency_bucket(999), 0);
+        assert_eq!(latency_bucket(1_000), 1);
+        assert_eq!(latency_bucket(20_000), 3);
+        assert_eq!(latency_bucket(3_999_999), 6);
+        assert_eq!(latency_bucket(4_000_000), LATENCY_BUCKETS - 1);
+        assert_eq!(latency_bucket(u64::MAX), LATENCY_BUCKETS - 1);
+    }
+}
@@ -0,0 +1,409 @@
+use alloc::{
+    collections::{BTreeMap, BTreeSet},
+    string::String,
+    vec::Vec,
+};
+use core::fmt::Display;
+
+use corelib::domain_info::{DomainLoadInfo, UpgradeRecord};
+use interface::DomainTypeRaw;
+
+/// What a domain is doing, as seen by its proxy
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DomainState {
+    /// The real domain has not finished its initialization, the calls return `EAGAIN`
+    NotReady,
+    /// The calls go through the lock path, the domain is being replaced or is frozen
+    Upgrading,
+    Running,
+}
+
+impl DomainState {
+    pub fn new(ready: bool, upgrading: bool) -> Self {
+        match (ready, upgrading) {
+            (false, _) => DomainState::NotReady,
+            (true, true) => DomainState::Upgrading,
+            (true, false) => DomainState::Running,
+        }
+    }
+}
+
+impl Display for DomainState {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let state = match self {
+            DomainState::NotReady => "not ready",
+            DomainState::Upgrading => "upgrading",
+            DomainState::Running => "running",
+        };
+        f.write_str(state)
+    }
+}
+
+/// A domain of the graph exported by `sys_export_domain_graph`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DomainNode {
+    pub name: String,
+    pub ty: DomainTypeRaw,
+    pub state: DomainState,
+}
+
+/// All the domains and the dependencies between them, formatted as a DOT digraph
+///
+/// An edge `(from, to)` means the domain `from` got the domain `to` through
+/// `sys_get_domain`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DomainGraph {
+    pub nodes: Vec<DomainNode>,
+    pub edges: Vec<(String, String)>,
+}
+
+impl Display for DomainGraph {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        writeln!(f, "digraph domains {{")?;
+        for node in self.nodes.iter() {
+            writeln!(
+                f,
+                "    \"{}\" [type=\"{:?}\", state=\"{}\"];",
+                node.name, node.ty, node.state
+            )?;
+        }
+        for (from, to) in self.edges.iter() {
+            writeln!(f, "    \"{}\" -> \"{}\";", from, to)?;
+        }
+        writeln!(f, "}}")
+    }
+}
+
+/// Everything known about a domain, formatted by `sys_domain_describe` as a readable
+/// multi-line report
+#[derive(Debug, Clone)]
+pub struct DomainReport {
+    pub id: u64,
+    pub name: String,
+    pub ty: DomainTypeRaw,
+    /// The ELF image the domain is running
+    pub load_info: DomainLoadInfo,
+    pub ready: bool,
+    /// Whether the calls go through the lock path of the proxy
+    pub upgrading: bool,
+    /// The calls in flight on the lock-free path, `None` if the proxy does not count them
+    pub readers: Option<i64>,
+    /// The number of the live shared heap allocations owned by the domain
+    pub shared_data: usize,
+    pub panic_count: usize,
+    /// How many times the domain has been restarted by the watchdog
+    pub restarts: usize,
+    pub last_upgrade: Option<UpgradeRecord>,
+}
+
+impl Display for DomainReport {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        writeln!(f, "Domain ID: {}", self.id)?;
+        writeln!(f, "  - Name: {}", self.name)?;
+        writeln!(f, "  - Type: {:?}", self.ty)?;
+        writeln!(
+            f,
+            "  - File: {} ({} bytes, base {:#x})",
+            self.load_info.name, self.load_info.size, self.load_info.base
+        )?;
+        writeln!(
+            f,
+            "  - State: {}",
+            DomainState::new(self.ready, self.upgrading)
+        )?;
+        match self.readers {
+            Some(readers) => writeln!(f, "  - Readers: {}", readers)?,
+            None => writeln!(f, "  - Readers: unknown")?,
+        }
+        writeln!(f, "  - Shared data: {} allocations", self.shared_data)?;
+        writeln!(f, "  - Panic count: {}", self.panic_count)?;
+        writeln!(f, "  - Restarts: {}", self.restarts)?;
+        match &self.last_upgrade {
+            Some(record) => writeln!(
+                f,
+                "  - Last upgrade: {} -> {} at {}ns, {}",
+                record.from,
+                record.to,
+                record.timestamp_ns,
+                if record.success {
+                    "succeeded"
+                } else {
+                    "failed"
+                }
+            ),
+            None => writeln!(f, "  - Last upgrade: never"),
+        }
+    }
+}
+
+/// The state of the isolation subsystem collected by `sys_audit`
+///
+/// It is read from the tables of the kernel only, no domain is called.
+#[derive(Debug, Clone, Default)]
+pub struct AuditInput {
+    /// The domains in `DOMAIN_INFO`, by id
+    pub domains: BTreeMap<u64, String>,
+    /// The names the proxies are registered with
+    pub proxies: BTreeSet<String>,
+    /// The number of the live shared heap allocations, by owner
+    pub shared_data: BTreeMap<u64, usize>,
+    /// The number of the pages allocated by `sys_alloc_pages`, by domain
+    pub pages: BTreeMap<u64, usize>,
+    /// The use of the limited resources of the domains
+    pub quotas: Vec<QuotaUsage>,
+}
+
+/// The use of a limited resource by a domain
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QuotaUsage {
+    pub domain_id: u64,
+    pub resource: &'static str,
+    pub used: usize,
+    pub limit: usize,
+}
+
+/// An invariant of the isolation subsystem which does not hold
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuditViolation {
+    /// A domain in `DOMAIN_INFO` has no registered proxy
+    MissingProxy { id: u64, name: String },
+    /// A proxy is registered with a name which no domain in `DOMAIN_INFO` has
+    UnknownProxy { name: String },
+    /// Several domains in `DOMAIN_INFO` have the same name, e.g. the old domain of an
+    /// upgrade was not removed
+    DuplicateName { name: String, ids: Vec<u64> },
+    /// Shared heap allocations are owned by a domain which is not known
+    UnknownOwner { owner: u64, count: usize },
+    /// Pages are still recorded for a domain which is not known
+    OrphanedPages { domain_id: u64, pages: usize },
+    /// A domain uses more of a resource than its limit
+    OverQuota(QuotaUsage),
+}
+
+impl Display for AuditViolation {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            AuditViolation::MissingProxy { id, name } => {
+                write!(f, "domain {} ({}) has no proxy", id, name)
+            }
+            AuditViolation::UnknownProxy { name } => {
+                write!(
+                    f,
+                    "proxy {} is registered, but no domain has its name",
+                    name
+                )
+            }
+            AuditViolation::DuplicateName { name, ids } => {
+                write!(f, "domains {:?} are all named {}", ids, name)
+            }
+            AuditViolation::UnknownOwner { owner, count } => write!(
+                f,
+                "{} shared heap allocations are owned by unknown domain {}",
+                count, owner
+            ),
+            AuditViolation::OrphanedPages { domain_id, pages } => {
+                write!(
+                    f,
+                    "{} pages are recorded for unknown domain {}",
+                    pages, domain_id
+                )
+            }
+            AuditViolation::OverQuota(usage) => write!(
+                f,
+                "domain {} uses {} {}, over its limit of {}",
+                usage.domain_id, usage.used, usage.resource, usage.limit
+            ),
+        }
+    }
+}
+
+/// The violations found by `sys_audit`, formatted one per line
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AuditReport {
+    pub violations: Vec<AuditViolation>,
+}
+
+impl AuditReport {
+    /// Check the invariants on `input`, the domain `kernel_id` is the kernel which is
+    /// never in `DOMAIN_INFO`
+    pub fn check(input: &AuditInput, kernel_id: u64) -> Self {
+        let known = |id: u64| id == kernel_id || input.domains.contains_key(&id);
+        let mut violations = Vec::new();
+        let mut names = BTreeMap::<&str, Vec<u64>>::new();
+        for (&id, name) in input.domains.iter() {
+            names.entry(name).or_default().push(id);
+            if !input.proxies.contains(name) {
+                violations.push(AuditViolation::MissingProxy {
+                    id,
+                    name: name.clone(),
+                });
+            }
+        }
+        for (name, ids) in names.into_iter().filter(|(_, ids)| ids.len() > 1) {
+            violations.push(AuditViolation::DuplicateName {
+                name: name.into(),
+                ids,
+            });
+        }
+        for name in input.proxies.iter() {
+            if !input.domains.values().any(|n| n == name) {
+                violations.push(AuditViolation::UnknownProxy { name: name.clone() });
+            }
+        }
+        for (&owner, &count) in input.shared_data.iter().filter(|(&id, _)| !known(id)) {
+            violations.push(AuditViolation::UnknownOwner { owner, count });
+        }
+        for (&domain_id, &pages) in input.pages.iter().filter(|(&id, _)| !known(id)) {
+            violations.push(AuditViolation::OrphanedPages { domain_id, pages });
+        }
+        for usage in input.quotas.iter().filter(|usage| usage.used > usage.limit) {
+            violations.push(AuditViolation::OverQuota(usage.clone()));
+        }
+        Self { violations }
+    }
+
+    pub fn is_clean(&self) -> bool {
+        self.violations.is_empty()
+    }
+}
+
+impl Display for AuditReport {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        if self.is_clean() {
+            return writeln!(f, "no violations");
+        }
+        for violation in self.violations.iter() {
+            writeln!(f, "{}", violation)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::string::ToString;
+
+    use super::*;
+
+    #[test]
+    fn test_domain_report() {
+        let report = DomainReport {
+            id: 3,
+            name: "null_block".into(),
+            ty: DomainTypeRaw::BlockDeviceDomain,
+            load_info: DomainLoadInfo {
+                name: "null_block_v2".into(),
+                size: 4096,
+                base: 0,
+                entry: 0,
+            },
+            ready: true,
+            upgrading: false,
+            readers: Some(0),
+            shared_data: 2,
+            panic_count: 0,
+            restarts: 0,
+            last_upgrade: None,
+        };
+        let text = alloc::format!("{}", report);
+        assert!(text.contains("Name: null_block\n"));
+        assert!(text.contains("Type: BlockDeviceDomain"));
+        assert!(text.contains("State: running"));
+        assert!(text.contains("Last upgrade: never"));
+    }
+
+    #[test]
+    fn test_domain_graph() {
+        let node = |name: &str, ty, state| DomainNode {
+            name: name.into(),
+            ty,
+            state,
+        };
+        let graph = DomainGraph {
+            nodes: alloc::vec![
+                node("logger", DomainTypeRaw::LogDomain, DomainState::Running),
+                node(
+                    "null",
+                    DomainTypeRaw::EmptyDeviceDomain,
+                    DomainState::Upgrading
+                ),
+                node(
+                    "rnull",
+                    DomainTypeRaw::BlockDeviceDomain,
+                    DomainState::NotReady
+                ),
+            ],
+            edges: alloc::vec![
+                ("null".into(), "logger".into()),
+                ("rnull".into(), "logger".into()),
+            ],
+        };
+        let text = alloc::format!("{}", graph);
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                "digraph domains {",
+                "    \"logger\" [type=\"LogDomain\", state=\"running\"];",
+                "    \"null\" [type=\"EmptyDeviceDomain\", state=\"upgrading\"];",
+                "    \"rnull\" [type=\"BlockDeviceDomain\", state=\"not ready\"];",
+                "    \"null\" -> \"logger\";",
+                "    \"rnull\" -> \"logger\";",
+                "}",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_audit_report() {
+        let mut input = AuditInput::default();
+        input.domains.insert(1, "null".into());
+        input.domains.insert(2, "rnull".into());
+        input.proxies.insert("null".into());
+        input.proxies.insert("rnull".into());
+        input.shared_data.insert(0, 3);
+        input.shared_data.insert(2, 1);
+        input.pages.insert(1, 4);
+        let local_keys = |domain_id, used| QuotaUsage {
+            domain_id,
+            resource: "local keys",
+            used,
+            limit: 64,
+        };
+        input.quotas.push(local_keys(1, 64));
+        let report = AuditReport::check(&input, 0);
+        assert!(report.is_clean());
+        assert_eq!(alloc::format!("{}", report), "no violations\n");
+
+        // rnull was unloaded, but its page map entry and its shared data were left behind
+        input.domains.remove(&2);
+        input.proxies.remove("rnull");
+        input.pages.insert(2, 8);
+        // null was upgraded to 5, but its old entry was not removed
+        input.domains.insert(5, "null".into());
+        // a proxy was registered without its DOMAIN_INFO entry
+        input.proxies.insert("logger".into());
+        // null has more local areas than allowed
+        input.quotas.push(local_keys(5, 65));
+        let report = AuditReport::check(&input, 0);
+        assert_eq!(
+            report.violations,
+            [
+                AuditViolation::DuplicateName {
+                    name: "null".into(),
+                    ids: alloc::vec![1, 5],
+                },
+                AuditViolation::UnknownProxy {
+                    name: "logger".into(),
+                },
+                AuditViolation::UnknownOwner { owner: 2, count: 1 },
+                AuditViolation::OrphanedPages {
+                    domain_id: 2,
+                    pages: 8,
+                },
+                AuditViolation::OverQuota(local_keys(5, 65)),
+            ]
+        );
+        assert_eq!(report.to_string().lines().count(), 5);
+    }
+}
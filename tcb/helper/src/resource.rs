@@ -0,0 +1,133 @@
+use alloc::{boxed::Box, collections::BTreeMap};
+
+use pconst::LinuxErrno;
+
+/// Round the page count of `sys_alloc_pages`/`sys_free_pages` up to a power of two
+///
+/// Return `None` if `n` is 0 or larger than `max`, so that a huge count neither overflows
+/// nor reaches the frame allocator.
+pub fn alloc_page_count(n: usize, max: usize) -> Option<usize> {
+    if n == 0 || n > max {
+        return None;
+    }
+    n.checked_next_power_of_two()
+}
+
+/// Call `touch` with the number of each page in the ranges `(first page, count)` recorded
+/// for a domain, return the number of the pages touched
+pub fn warmup_pages(pages: &[(usize, usize)], mut touch: impl FnMut(usize)) -> usize {
+    let mut touched = 0;
+    for &(first, n) in pages {
+        (first..first + n).for_each(&mut touch);
+        touched += n;
+    }
+    touched
+}
+
+/// The scratch areas of a domain allocated by `sys_domain_local_alloc`, indexed by key
+///
+/// The areas persist across the calls into the domain and are freed with it.
+#[derive(Debug, Default)]
+pub struct DomainLocal {
+    areas: BTreeMap<u64, Box<[u8]>>,
+}
+
+impl DomainLocal {
+    /// Allocate a zeroed area of `size` bytes under `key`, the area already under `key` is
+    /// returned if its size is `size`
+    ///
+    /// Return `EINVAL` if `size` is 0 or larger than `max_size`, `EEXIST` if the area under
+    /// `key` has another size and `ENOSPC` if the domain already has `max_keys` areas.
+    pub fn alloc(
+        &mut self,
+        key: u64,
+        size: usize,
+        max_size: usize,
+        max_keys: usize,
+    ) -> Result<*mut u8, LinuxErrno> {
+        if size == 0 || size > max_size {
+            return Err(LinuxErrno::EINVAL);
+        }
+        if let Some(data) = self.areas.get_mut(&key) {
+            if data.len() != size {
+                return Err(LinuxErrno::EEXIST);
+            }
+            return Ok(data.as_mut_ptr());
+        }
+        if self.areas.len() >= max_keys {
+            return Err(LinuxErrno::ENOSPC);
+        }
+        let data = self
+            .areas
+            .entry(key)
+            .or_insert(alloc::vec![0u8; size].into_boxed_slice());
+        Ok(data.as_mut_ptr())
+    }
+
+    /// The area under `key` and its size
+    pub fn get(&mut self, key: u64) -> Option<(*mut u8, usize)> {
+        self.areas
+            .get_mut(&key)
+            .map(|data| (data.as_mut_ptr(), data.len()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.areas.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.areas.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use super::*;
+
+    #[test]
+    fn test_alloc_page_count() {
+        assert_eq!(alloc_page_count(0, 1 << 16), None);
+        assert_eq!(alloc_page_count(usize::MAX, usize::MAX), None);
+        assert_eq!(alloc_page_count(usize::MAX, 1 << 16), None);
+        assert_eq!(alloc_page_count((1 << 16) + 1, 1 << 16), None);
+        assert_eq!(alloc_page_count(1, 1 << 16), Some(1));
+        assert_eq!(alloc_page_count(5, 1 << 16), Some(8));
+        assert_eq!(alloc_page_count(1 << 16, 1 << 16), Some(1 << 16));
+    }
+
+    #[test]
+    fn test_warmup_pages() {
+        let mut touched = Vec::new();
+        assert_eq!(
+            warmup_pages(&[(16, 4), (3, 1), (32, 2)], |p| touched.push(p)),
+            7
+        );
+        assert_eq!(touched, [16, 17, 18, 19, 3, 32, 33]);
+        assert_eq!(warmup_pages(&[], |_| panic!()), 0);
+    }
+
+    #[test]
+    fn test_domain_local() {
+        let mut local = DomainLocal::default();
+        assert_eq!(local.alloc(1, 0, 64, 2), Err(LinuxErrno::EINVAL));
+        assert_eq!(local.alloc(1, 65, 64, 2), Err(LinuxErrno::EINVAL));
+        assert!(local.get(1).is_none());
+
+        // the first call stores a value
+        let ptr = local.alloc(1, 8, 64, 2).unwrap();
+        unsafe { ptr.cast::<u64>().write_unaligned(0xdead_beef) };
+        // the second call gets it back, by alloc or by get
+        assert_eq!(local.alloc(1, 8, 64, 2), Ok(ptr));
+        let (got, len) = local.get(1).unwrap();
+        assert_eq!((got, len), (ptr, 8));
+        assert_eq!(unsafe { got.cast::<u64>().read_unaligned() }, 0xdead_beef);
+
+        assert_eq!(local.alloc(1, 16, 64, 2), Err(LinuxErrno::EEXIST));
+        let other = local.alloc(2, 16, 64, 2).unwrap();
+        assert_eq!(unsafe { *other.add(15) }, 0);
+        assert_eq!(local.alloc(3, 8, 64, 2), Err(LinuxErrno::ENOSPC));
+        assert_eq!(local.len(), 2);
+    }
+}
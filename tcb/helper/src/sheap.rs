@@ -0,0 +1,209 @@
+use alloc::collections::BTreeMap;
+use core::fmt::Display;
+
+/// The shared heap allocations freed in bulk, e.g. when a domain is unloaded.
+///
+/// A domain may own thousands of RRefs, so a bulk free is reported as one summary line
+/// instead of a line for every allocation.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FreeSummary {
+    /// The number of the allocations freed
+    pub count: usize,
+    /// The bytes freed
+    pub bytes: usize,
+}
+
+impl FreeSummary {
+    /// Count an allocation of `bytes` bytes
+    pub fn add(&mut self, bytes: usize) {
+        self.count += 1;
+        self.bytes += bytes;
+    }
+}
+
+impl Display for FreeSummary {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{} RRefs ({} bytes)", self.count, self.bytes)
+    }
+}
+
+/// The memory pressure level of the shared heap at `usage` bytes, given the level `old` the
+/// domains were last notified of
+///
+/// The level is the number of `thresholds` reached. It rises as soon as a threshold is
+/// reached, but it only falls once the usage is `hysteresis` bytes below the threshold, so
+/// that an allocation and a free around a threshold do not notify the domains again and again.
+pub fn pressure_level(thresholds: &[usize], hysteresis: usize, old: u8, usage: usize) -> u8 {
+    let rise = thresholds.iter().filter(|&&t| usage >= t).count() as u8;
+    if rise >= old {
+        return rise;
+    }
+    thresholds
+        .iter()
+        .take(old as usize)
+        .filter(|&&t| usage.saturating_add(hysteresis) >= t)
+        .count() as u8
+}
+
+/// The bytes of the shared heap against its limit, see `sys_set_upgrade_reserve`
+///
+/// The bytes of a reservation can only be used by the allocations of its owner, e.g. the
+/// task upgrading a domain while the new domain runs its `init`. The owner draws from its
+/// reservation first, and all the other allocations must fit in the bytes which are
+/// neither used nor reserved.
+#[derive(Debug)]
+pub struct HeapBudget {
+    limit: usize,
+    used: usize,
+    /// The bytes left in the reservation of each owner
+    reserves: BTreeMap<usize, usize>,
+    reserved: usize,
+}
+
+impl HeapBudget {
+    pub const fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            used: 0,
+            reserves: BTreeMap::new(),
+            reserved: 0,
+        }
+    }
+
+    /// The bytes of the live allocations
+    pub fn used(&self) -> usize {
+        self.used
+    }
+
+    /// The bytes reserved and not used yet
+    pub fn reserved(&self) -> usize {
+        self.reserved
+    }
+
+    fn available(&self) -> usize {
+        self.limit.saturating_sub(self.used + self.reserved)
+    }
+
+    /// Reserve `bytes` for `owner`, return `false` if they are not available
+    pub fn reserve(&mut self, owner: usize, bytes: usize) -> bool {
+        if bytes > self.available() {
+            return false;
+        }
+        *self.reserves.entry(owner).or_default() += bytes;
+        self.reserved += bytes;
+        true
+    }
+
+    /// Release up to `bytes` of what is left of the reservation of `owner`
+    pub fn release(&mut self, owner: usize, bytes: usize) {
+        let Some(left) = self.reserves.get_mut(&owner) else {
+            return;
+        };
+        let released = bytes.min(*left);
+        *left -= released;
+        self.reserved -= released;
+        if *left == 0 {
+            self.reserves.remove(&owner);
+        }
+    }
+
+    /// Account an allocation of `size` bytes by `owner`, drawn from its reservation first
+    ///
+    /// Return `false` without changing anything if it does not fit.
+    pub fn alloc(&mut self, owner: usize, size: usize) -> bool {
+        let left = self.reserves.get(&owner).copied().unwrap_or(0);
+        let drawn = size.min(left);
+        if size - drawn > self.available() {
+            return false;
+        }
+        self.release(owner, drawn);
+        self.used += size;
+        true
+    }
+
+    /// Account the free of an allocation of `size` bytes
+    pub fn free(&mut self, size: usize) {
+        self.used -= size;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::string::ToString;
+
+    use super::*;
+
+    #[test]
+    fn test_free_summary() {
+        let mut summary = FreeSummary::default();
+        for i in 0..1000 {
+            summary.add(16 + i % 2 * 16);
+        }
+        assert_eq!(
+            summary,
+            FreeSummary {
+                count: 1000,
+                bytes: 24000,
+            }
+        );
+        // the bulk free is reported as one line
+        let line = summary.to_string();
+        assert_eq!(line, "1000 RRefs (24000 bytes)");
+        assert_eq!(line.lines().count(), 1);
+    }
+
+    #[test]
+    fn test_pressure_level() {
+        let thresholds = [100, 200, 300];
+        assert_eq!(pressure_level(&thresholds, 10, 0, 50), 0);
+        assert_eq!(pressure_level(&thresholds, 10, 0, 100), 1);
+        assert_eq!(pressure_level(&thresholds, 10, 1, 250), 2);
+        assert_eq!(pressure_level(&thresholds, 10, 0, 1000), 3);
+        // within the hysteresis below a threshold, the level stays
+        assert_eq!(pressure_level(&thresholds, 10, 1, 95), 1);
+        assert_eq!(pressure_level(&thresholds, 10, 2, 190), 2);
+        assert_eq!(pressure_level(&thresholds, 10, 3, 291), 3);
+        // far enough below, it falls
+        assert_eq!(pressure_level(&thresholds, 10, 1, 89), 0);
+        assert_eq!(pressure_level(&thresholds, 10, 3, 195), 2);
+        assert_eq!(pressure_level(&thresholds, 10, 3, 150), 1);
+        assert_eq!(pressure_level(&thresholds, 10, 3, 0), 0);
+        // an allocation and a free around a threshold do not flap
+        let mut level = 0;
+        let mut rises = 0;
+        for usage in [99, 100, 99, 100, 98, 101, 95] {
+            let new = pressure_level(&thresholds, 10, level, usage);
+            rises += (new > level) as u32;
+            level = new;
+        }
+        assert_eq!((level, rises), (1, 1));
+    }
+
+    #[test]
+    fn test_heap_budget() {
+        let mut budget = HeapBudget::new(100);
+        assert!(budget.alloc(1, 30));
+        // the upgrade fails early if its reservation cannot be met
+        assert!(!budget.reserve(2, 80));
+        assert_eq!(budget.reserved(), 0);
+        assert!(budget.reserve(2, 50));
+        // the other allocations cannot eat the reservation
+        assert!(!budget.alloc(1, 21));
+        assert!(budget.alloc(1, 20));
+        assert!(!budget.alloc(1, 1));
+        // the owner draws from its reservation
+        assert!(budget.alloc(2, 40));
+        assert_eq!((budget.used(), budget.reserved()), (90, 10));
+        assert!(!budget.alloc(2, 11));
+        assert!(budget.alloc(2, 10));
+        assert_eq!((budget.used(), budget.reserved()), (100, 0));
+        // a reservation which is used up releases nothing
+        budget.release(2, 50);
+        assert_eq!(budget.reserved(), 0);
+        budget.free(40);
+        assert!(budget.reserve(2, 30));
+        budget.release(2, 30);
+        assert_eq!((budget.used(), budget.reserved()), (60, 0));
+        assert!(budget.alloc(1, 40));
+    }
+}
@@ -0,0 +1,174 @@
+use pconst::LinuxErrno;
+
+use crate::proxy::wait_until;
+
+/// Wait until at most `max_refs` handles of a domain are alive, `refs` returns the number of
+/// its handles or `None` if the domain is unknown
+///
+/// It polls like [wait_until]. Return `ENOENT` if the domain is unknown, and `ETIMEDOUT` if
+/// the handles are still held after `timeout_ms`.
+pub fn wait_handles_dropped(
+    max_refs: usize,
+    timeout_ms: u64,
+    elapsed_ms: impl FnMut() -> u64,
+    pause: impl FnMut(),
+    mut refs: impl FnMut() -> Option<usize>,
+) -> Result<(), LinuxErrno> {
+    let mut unknown = false;
+    wait_until(timeout_ms, elapsed_ms, pause, || match refs() {
+        Some(refs) => refs <= max_refs,
+        None => {
+            unknown = true;
+            true
+        }
+    })?;
+    if unknown {
+        return Err(LinuxErrno::ENOENT);
+    }
+    Ok(())
+}
+
+/// How the teardown of a domain waits for its handles and its calls in flight
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TeardownMode {
+    /// The normal teardown, it may sleep
+    Sleep,
+    /// Never sleep, only busy-wait with a hard timeout. It is used late in shutdown, when
+    /// the scheduler may be winding down and a sleep could never return.
+    NoSleep,
+}
+
+/// The steps of the teardown of a domain, run by [teardown_domain]
+pub trait Teardown {
+    /// Wait until the domain has no handle but the ones of the registry, return `ENOENT`
+    /// if the domain is unknown
+    fn wait_unused(&mut self, mode: TeardownMode) -> Result<(), LinuxErrno>;
+    /// Busy-wait until no call runs in the domain, then swap it for an empty domain and
+    /// busy-wait for the grace period of its readers
+    fn retire_no_sleep(&mut self) -> Result<(), LinuxErrno>;
+    /// Remove the domain from the registry
+    fn unregister(&mut self) -> Result<(), LinuxErrno>;
+}
+
+/// Tear a domain down with `steps` in the mode `mode`
+///
+/// Return `EBUSY` if handles of the domain are still held. In [TeardownMode::NoSleep] the
+/// domain is retired first, which never sleeps, and `EBUSY` is also returned if its calls
+/// do not drain or its grace period does not end in time. The domain then stays
+/// registered, as unloading it could free the memory of a running call.
+pub fn teardown_domain(steps: &mut impl Teardown, mode: TeardownMode) -> Result<(), LinuxErrno> {
+    match steps.wait_unused(mode) {
+        Ok(()) => {}
+        Err(LinuxErrno::ENOENT) => return Err(LinuxErrno::ENOENT),
+        Err(_) => return Err(LinuxErrno::EBUSY),
+    }
+    if mode == TeardownMode::NoSleep {
+        steps.retire_no_sleep().map_err(|_| LinuxErrno::EBUSY)?;
+    }
+    steps.unregister()
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::sync::Arc;
+
+    use super::*;
+
+    #[test]
+    fn test_teardown_domain() {
+        #[derive(Default)]
+        struct Steps {
+            handles: usize,
+            in_flight: usize,
+            retired: bool,
+            registered: bool,
+        }
+        impl Teardown for Steps {
+            fn wait_unused(&mut self, _mode: TeardownMode) -> Result<(), LinuxErrno> {
+                match self.handles {
+                    0 => Ok(()),
+                    _ => Err(LinuxErrno::ETIMEDOUT),
+                }
+            }
+            fn retire_no_sleep(&mut self) -> Result<(), LinuxErrno> {
+                if self.in_flight != 0 {
+                    return Err(LinuxErrno::ETIMEDOUT);
+                }
+                self.retired = true;
+                Ok(())
+            }
+            fn unregister(&mut self) -> Result<(), LinuxErrno> {
+                self.registered = false;
+                Ok(())
+            }
+        }
+        let steps = |handles, in_flight| Steps {
+            handles,
+            in_flight,
+            registered: true,
+            ..Default::default()
+        };
+
+        // a domain which does not drain is not unloaded
+        let mut busy = steps(0, 1);
+        assert_eq!(
+            teardown_domain(&mut busy, TeardownMode::NoSleep),
+            Err(LinuxErrno::EBUSY)
+        );
+        assert!(busy.registered && !busy.retired);
+        let mut held = steps(1, 0);
+        assert_eq!(
+            teardown_domain(&mut held, TeardownMode::NoSleep),
+            Err(LinuxErrno::EBUSY)
+        );
+        assert!(held.registered && !held.retired);
+
+        let mut idle = steps(0, 0);
+        assert_eq!(teardown_domain(&mut idle, TeardownMode::NoSleep), Ok(()));
+        assert!(idle.retired && !idle.registered);
+        // the sleeping teardown does not retire the domain
+        let mut idle = steps(0, 1);
+        assert_eq!(teardown_domain(&mut idle, TeardownMode::Sleep), Ok(()));
+        assert!(!idle.retired && !idle.registered);
+    }
+
+    #[test]
+    fn test_teardown_waits_for_handle() {
+        use core::cell::{Cell, RefCell};
+
+        // the registry and the kernel shim keep a handle each
+        let registry = Arc::new(());
+        let shim = registry.clone();
+        let handle = RefCell::new(Some(registry.clone()));
+        let pauses = Cell::new(0);
+        let refs = || Some(Arc::strong_count(&registry));
+        // the holder drops its handle after the third poll
+        let pause = || {
+            pauses.set(pauses.get() + 1);
+            if pauses.get() == 3 {
+                handle.borrow_mut().take();
+            }
+        };
+        assert_eq!(wait_handles_dropped(2, 10, || 0, pause, refs), Ok(()));
+        assert_eq!(pauses.get(), 3);
+        assert!(handle.borrow().is_none());
+
+        // a handle held across the whole wait makes the teardown fail
+        let held = registry.clone();
+        let elapsed = Cell::new(0);
+        let res = wait_handles_dropped(
+            2,
+            10,
+            || elapsed.get(),
+            || elapsed.set(elapsed.get() + 1),
+            refs,
+        );
+        assert_eq!(res, Err(LinuxErrno::ETIMEDOUT));
+        assert_eq!(elapsed.get(), 10);
+        drop((held, shim));
+        assert_eq!(
+            wait_handles_dropped(2, 10, || 0, || {}, || None),
+            Err(LinuxErrno::ENOENT)
+        );
+    }
+}
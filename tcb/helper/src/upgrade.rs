@@ -0,0 +1,272 @@
+use alloc::{collections::VecDeque, string::String, vec::Vec};
+use core::{
+    fmt::Display,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use corelib::domain_info::UpgradeRecord;
+use interface::DomainTypeRaw;
+use pconst::LinuxErrno;
+
+/// The recent upgrade records of a domain, the oldest first
+#[derive(Debug, Clone, Default)]
+pub struct UpgradeHistory {
+    records: VecDeque<UpgradeRecord>,
+}
+
+impl UpgradeHistory {
+    /// Append a record, the oldest record is dropped if there are already `max` records
+    pub fn record(&mut self, record: UpgradeRecord, max: usize) {
+        while self.records.len() >= max.max(1) {
+            self.records.pop_front();
+        }
+        self.records.push_back(record);
+    }
+
+    pub fn records(&self) -> Vec<UpgradeRecord> {
+        self.records.iter().cloned().collect()
+    }
+
+    /// Drop the records, the new upgrades are still recorded
+    pub fn clear(&mut self) {
+        self.records.clear();
+    }
+}
+
+/// Whether the upgrades of all domains are forbidden by `sys_set_upgrade_freeze`
+///
+/// It is only checked when an upgrade starts, so an upgrade in progress always finishes.
+#[derive(Debug, Default)]
+pub struct UpgradeFreeze(AtomicBool);
+
+impl UpgradeFreeze {
+    pub const fn new() -> Self {
+        Self(AtomicBool::new(false))
+    }
+
+    pub fn set(&self, frozen: bool) {
+        self.0.store(frozen, Ordering::SeqCst);
+    }
+
+    /// Return `EAGAIN` if the upgrades are frozen
+    pub fn check(&self) -> Result<(), LinuxErrno> {
+        if self.0.load(Ordering::SeqCst) {
+            return Err(LinuxErrno::EAGAIN);
+        }
+        Ok(())
+    }
+}
+
+/// What a domain which depends on another domain requires of it, see
+/// `sys_check_upgrade_compat`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UpgradeRequirement {
+    /// The name of the dependent domain
+    pub dependent: String,
+    /// The type of the proxy the dependent got through `sys_get_domain`
+    pub ty: DomainTypeRaw,
+    /// The interface version the dependent is built against
+    pub interface_version: u32,
+}
+
+/// A requirement of a dependent which the new domain does not satisfy
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CompatIssue {
+    /// The new domain has another type, so it can not be installed behind the proxy the
+    /// dependent holds
+    TypeMismatch {
+        dependent: String,
+        expected: DomainTypeRaw,
+        found: DomainTypeRaw,
+    },
+    /// The new domain is built against another interface version, `None` if it records
+    /// no version
+    InterfaceMismatch {
+        dependent: String,
+        expected: u32,
+        found: Option<u32>,
+    },
+}
+
+impl Display for CompatIssue {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            CompatIssue::TypeMismatch {
+                dependent,
+                expected,
+                found,
+            } => write!(
+                f,
+                "{} requires type {:?}, but the new domain is {:?}",
+                dependent, expected, found
+            ),
+            CompatIssue::InterfaceMismatch {
+                dependent,
+                expected,
+                found,
+            } => write!(
+                f,
+                "{} requires interface version {}, but the new domain has {:?}",
+                dependent, expected, found
+            ),
+        }
+    }
+}
+
+/// The requirements violated by a new domain, formatted one per line
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct UpgradeCompatReport {
+    pub issues: Vec<CompatIssue>,
+}
+
+impl UpgradeCompatReport {
+    /// Check the new domain of type `ty` built against `interface_version` against the
+    /// requirements of the dependents
+    pub fn check(
+        requirements: &[UpgradeRequirement],
+        ty: DomainTypeRaw,
+        interface_version: Option<u32>,
+    ) -> Self {
+        let mut issues = Vec::new();
+        for req in requirements.iter() {
+            if !req.ty.can_upgrade_to(ty) {
+                issues.push(CompatIssue::TypeMismatch {
+                    dependent: req.dependent.clone(),
+                    expected: req.ty,
+                    found: ty,
+                });
+            }
+            if interface_version != Some(req.interface_version) {
+                issues.push(CompatIssue::InterfaceMismatch {
+                    dependent: req.dependent.clone(),
+                    expected: req.interface_version,
+                    found: interface_version,
+                });
+            }
+        }
+        Self { issues }
+    }
+
+    pub fn is_compatible(&self) -> bool {
+        self.issues.is_empty()
+    }
+}
+
+impl Display for UpgradeCompatReport {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        if self.is_compatible() {
+            return writeln!(f, "compatible");
+        }
+        for issue in self.issues.iter() {
+            writeln!(f, "{}", issue)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rref::wire::{Decode, Encode};
+
+    use super::*;
+
+    #[test]
+    fn test_upgrade_compat_report() {
+        let requirements = [
+            UpgradeRequirement {
+                dependent: "rnull".into(),
+                ty: DomainTypeRaw::EmptyDeviceDomain,
+                interface_version: 6,
+            },
+            UpgradeRequirement {
+                dependent: "logger".into(),
+                ty: DomainTypeRaw::EmptyDeviceDomain,
+                interface_version: 6,
+            },
+        ];
+        let report =
+            UpgradeCompatReport::check(&requirements, DomainTypeRaw::EmptyDeviceDomain, Some(6));
+        assert!(report.is_compatible());
+        assert_eq!(alloc::format!("{}", report), "compatible\n");
+        assert!(UpgradeCompatReport::check(&[], DomainTypeRaw::LogDomain, None).is_compatible());
+
+        // the new domain is built against another interface and has another type
+        let report =
+            UpgradeCompatReport::check(&requirements[..1], DomainTypeRaw::LogDomain, Some(7));
+        assert!(!report.is_compatible());
+        assert_eq!(
+            report.issues,
+            [
+                CompatIssue::TypeMismatch {
+                    dependent: "rnull".into(),
+                    expected: DomainTypeRaw::EmptyDeviceDomain,
+                    found: DomainTypeRaw::LogDomain,
+                },
+                CompatIssue::InterfaceMismatch {
+                    dependent: "rnull".into(),
+                    expected: 6,
+                    found: Some(7),
+                },
+            ]
+        );
+        assert_eq!(
+            alloc::format!("{}", report),
+            "rnull requires type EmptyDeviceDomain, but the new domain is LogDomain\n\
+             rnull requires interface version 6, but the new domain has Some(7)\n"
+        );
+    }
+
+    #[test]
+    fn test_upgrade_history() {
+        let upgrade = |to: &str, timestamp_ns| UpgradeRecord {
+            timestamp_ns,
+            from: "gnull".into(),
+            to: to.into(),
+            success: true,
+            drain_iterations: 1,
+        };
+        let mut history = UpgradeHistory::default();
+        history.record(upgrade("gnull_v2", 10), 2);
+        history.record(upgrade("gnull_v3", 20), 2);
+        // both upgrades are reported, the oldest first
+        let records =
+            Vec::<UpgradeRecord>::decode_from_slice(&history.records().encode_to_vec()).unwrap();
+        let to = records.iter().map(|r| r.to.as_str()).collect::<Vec<_>>();
+        assert_eq!(to, ["gnull_v2", "gnull_v3"]);
+        assert_eq!(records[1].timestamp_ns, 20);
+
+        history.record(upgrade("gnull_v4", 30), 2);
+        let to = history
+            .records()
+            .into_iter()
+            .map(|r| r.to)
+            .collect::<Vec<_>>();
+        assert_eq!(to, ["gnull_v3", "gnull_v4"]);
+        history.clear();
+        assert!(history.records().is_empty());
+    }
+
+    #[test]
+    fn test_upgrade_freeze() {
+        let freeze = UpgradeFreeze::new();
+        let upgrade = |freeze: &UpgradeFreeze, upgraded: &mut usize| {
+            freeze.check()?;
+            // a freeze during the upgrade does not stop it
+            freeze.set(true);
+            *upgraded += 1;
+            Ok::<_, LinuxErrno>(())
+        };
+        let mut upgraded = 0;
+        assert_eq!(upgrade(&freeze, &mut upgraded), Ok(()));
+        assert_eq!(upgraded, 1);
+        // the upgrades are rejected while frozen
+        assert_eq!(upgrade(&freeze, &mut upgraded), Err(LinuxErrno::EAGAIN));
+        freeze.set(true);
+        assert_eq!(freeze.check(), Err(LinuxErrno::EAGAIN));
+        assert_eq!(upgraded, 1);
+        // and resume once unfrozen
+        freeze.set(false);
+        assert_eq!(upgrade(&freeze, &mut upgraded), Ok(()));
+        assert_eq!(upgraded, 2);
+    }
+}
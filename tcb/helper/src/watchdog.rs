@@ -0,0 +1,121 @@
+use corelib::domain_info::PanicAction;
+
+/// The watchdog policy of a domain set by `sys_set_domain_policy`, and its restarts
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Watchdog {
+    max_restarts: usize,
+    action: PanicAction,
+    /// The restarts which succeeded
+    restarts: usize,
+    /// A restart decided by [Watchdog::on_panic] has not finished yet
+    restarting: bool,
+}
+
+impl Watchdog {
+    pub fn new(max_restarts: usize, action: PanicAction) -> Self {
+        Self {
+            max_restarts,
+            action,
+            restarts: 0,
+            restarting: false,
+        }
+    }
+
+    /// The number of the restarts which succeeded
+    pub fn restarts(&self) -> usize {
+        self.restarts
+    }
+
+    pub fn reset_restarts(&mut self) {
+        self.restarts = 0;
+    }
+
+    /// What to do with the domain whose call has just panicked
+    ///
+    /// A `Restart` policy becomes `Disable` once the domain has been restarted
+    /// `max_restarts` times. Only one restart runs at a time: while it is pending, the
+    /// calls which crashed the same domain are ignored, so the domain is restarted once.
+    /// The restart must be finished by [Watchdog::restart_done].
+    pub fn on_panic(&mut self) -> PanicAction {
+        match self.action {
+            PanicAction::Restart if self.restarting => PanicAction::Ignore,
+            PanicAction::Restart if self.restarts >= self.max_restarts => PanicAction::Disable,
+            PanicAction::Restart => {
+                self.restarting = true;
+                PanicAction::Restart
+            }
+            action => action,
+        }
+    }
+
+    /// What to do with the domain which is never upgraded whose call has just panicked
+    ///
+    /// The domain can not be replaced behind its proxy, so a `Restart` policy disables it
+    /// at once and no restart is pending.
+    pub fn on_panic_pinned(&self) -> PanicAction {
+        match self.action {
+            PanicAction::Restart => PanicAction::Disable,
+            action => action,
+        }
+    }
+
+    /// Finish the pending restart, it is only counted if it succeeded
+    pub fn restart_done(&mut self, ok: bool) {
+        if core::mem::take(&mut self.restarting) && ok {
+            self.restarts += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use corelib::domain_info::PanicPolicy;
+
+    use super::*;
+
+    #[test]
+    fn test_watchdog_pinned() {
+        let mut watchdog = Watchdog::new(2, PanicAction::Restart);
+        // a pinned domain is disabled instead of restarted
+        assert_eq!(watchdog.on_panic_pinned(), PanicAction::Disable);
+        assert_eq!(
+            PanicPolicy::Fence.action(Some(watchdog.on_panic_pinned())),
+            PanicAction::Disable
+        );
+        // no restart is pending, so the next unpinned panic still restarts it
+        assert_eq!(watchdog.on_panic(), PanicAction::Restart);
+        assert_eq!(watchdog.restarts(), 0);
+        let watchdog = Watchdog::new(2, PanicAction::Ignore);
+        assert_eq!(watchdog.on_panic_pinned(), PanicAction::Ignore);
+    }
+
+    #[test]
+    fn test_watchdog() {
+        let mut watchdog = Watchdog::new(2, PanicAction::Restart);
+        // the first panic restarts the domain, a concurrent one does not restart it again
+        assert_eq!(watchdog.on_panic(), PanicAction::Restart);
+        assert_eq!(watchdog.on_panic(), PanicAction::Ignore);
+        watchdog.restart_done(true);
+        assert_eq!(watchdog.restarts(), 1);
+
+        // a failed restart is not counted
+        assert_eq!(watchdog.on_panic(), PanicAction::Restart);
+        watchdog.restart_done(false);
+        assert_eq!(watchdog.restarts(), 1);
+        assert_eq!(watchdog.on_panic(), PanicAction::Restart);
+        watchdog.restart_done(true);
+        assert_eq!(watchdog.restarts(), 2);
+
+        // restarted twice, the third panic disables it
+        assert_eq!(watchdog.on_panic(), PanicAction::Disable);
+        watchdog.restart_done(true);
+        assert_eq!(watchdog.restarts(), 2);
+        watchdog.reset_restarts();
+        assert_eq!(watchdog.on_panic(), PanicAction::Restart);
+
+        let mut watchdog = Watchdog::new(2, PanicAction::Ignore);
+        assert_eq!(watchdog.on_panic(), PanicAction::Ignore);
+        watchdog.restart_done(true);
+        assert_eq!(watchdog.restarts(), 0);
+    }
+}
@@ -144,7 +144,8 @@ impl SysctlStorage for CommandChannel {
             }
             Some(Command::Unload(ref unload_command)) => {
                 println!("Command: {:?}", command);
-                let res = super::unload_domain(unload_command.domain_ident);
+                let res =
+                    super::unload_domain(unload_command.domain_ident, super::TeardownMode::Sleep);
                 if res.is_err() {
                     return (0, Err(linux_err::EINVAL));
                 }
@@ -13,11 +13,14 @@ pub use command::CommandChannel;
 use corelib::{LinuxError, LinuxResult};
 use interface::{null_block::BlockArgs, DomainType, DomainTypeRaw};
 use kernel::{error::KernelResult, types::Mode};
+pub use tcb_helper::teardown::TeardownMode;
+use tcb_helper::teardown::{teardown_domain, Teardown};
 
 use crate::{
+    config::{TEARDOWN_HANDLE_TIMEOUT_MS, TEARDOWN_SPIN_TIMEOUT_MS},
     create_domain,
-    domain_helper::{domain_ref_count, unregister_domain, DOMAIN_SYS},
-    domain_proxy::{block_device::BlockDeviceDomainProxy, ProxyBuilder},
+    domain_helper::{domain_ref_count, unregister_unused_domain, wait_domain_unused, DOMAIN_SYS},
+    domain_proxy::{block_device::BlockDeviceDomainProxy, with_proxy, ProxyBuilder},
     kshim::{BlockDeviceShim, KernelShim},
     register_domain,
 };
@@ -34,7 +37,7 @@ pub fn init_domain_channel() -> KernelResult<Sysctl<CommandChannel>> {
 }
 
 fn register_domain(ident: &str, elf: Vec<u8>, ty: DomainTypeRaw) -> LinuxResult<()> {
-    crate::domain_loader::creator::register_domain_elf(ident, elf, ty);
+    crate::domain_loader::creator::register_domain_elf(ident, elf, ty)?;
     println!("Register domain: {} ({:?})", ident, ty);
     Ok(())
 }
@@ -84,23 +87,51 @@ pub fn load_domain(
     Ok(())
 }
 
-pub fn unload_domain(domain_ident: &str) -> LinuxResult<()> {
-    println!("Unload domain: {}", domain_ident);
-    let ref_count = domain_ref_count(domain_ident);
-    if ref_count.is_none() {
-        println!("[unload_domain] Domain {} not found", domain_ident);
-        return Err(LinuxError::ENOENT);
+/// The teardown of the domain registered as `.0`
+///
+/// The registry and the kernel shim keep a handle each, the handles got by other domains
+/// must be dropped before the domain is freed.
+struct DomainTeardown<'a>(&'a str);
+
+impl Teardown for DomainTeardown<'_> {
+    fn wait_unused(&mut self, mode: TeardownMode) -> LinuxResult<()> {
+        let timeout_ms = match mode {
+            TeardownMode::Sleep => TEARDOWN_HANDLE_TIMEOUT_MS,
+            TeardownMode::NoSleep => TEARDOWN_SPIN_TIMEOUT_MS,
+        };
+        let res = wait_domain_unused(self.0, 2, timeout_ms, mode);
+        match res {
+            Ok(()) => {}
+            Err(LinuxError::ENOENT) => println!("[unload_domain] Domain {} not found", self.0),
+            Err(_) => println!(
+                "[unload_domain] Domain {} is still in use, it has {:?} references",
+                self.0,
+                domain_ref_count(self.0)
+            ),
+        }
+        res
     }
-    let ref_count = ref_count.unwrap();
-    if ref_count > 2 {
-        println!(
-            "[unload_domain] Domain {} is still in use, it has {} references",
-            domain_ident, ref_count
-        );
-        return Err(LinuxError::EBUSY);
+
+    fn retire_no_sleep(&mut self) -> LinuxResult<()> {
+        with_proxy(self.0, |p| p.retire_no_sleep(TEARDOWN_SPIN_TIMEOUT_MS)).inspect_err(|e| {
+            println!(
+                "[unload_domain] Domain {} did not drain: {:?}, keep it loaded",
+                self.0, e
+            )
+        })
     }
-    unregister_domain(domain_ident);
-    KSHIM_OBJ.write().remove(domain_ident);
+
+    fn unregister(&mut self) -> LinuxResult<()> {
+        // a handle may have been handed out while waiting
+        unregister_unused_domain(self.0, 2)?;
+        KSHIM_OBJ.write().remove(self.0);
+        Ok(())
+    }
+}
+
+pub fn unload_domain(domain_ident: &str, mode: TeardownMode) -> LinuxResult<()> {
+    println!("Unload domain: {} ({:?})", domain_ident, mode);
+    teardown_domain(&mut DomainTeardown(domain_ident), mode)?;
     println!("Domain {} unloaded", domain_ident);
     Ok(())
 }
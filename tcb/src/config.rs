@@ -5,6 +5,45 @@ use kernel::error::{Error, KernelResult};
 pub const FRAME_SIZE: usize = 0x1000;
 /// 物理页大小的位数
 pub const FRAME_BITS: usize = 12;
+// page map按FRAME_BITS移位，domain通过sys_frame_bits/sys_frame_size看到的两个值必须一致
+const _: () = assert!(FRAME_SIZE == 1 << FRAME_BITS);
+/// domain单次sys_alloc_pages可以申请的最大页数
+pub const MAX_DOMAIN_ALLOC_PAGES: usize = 1 << 16;
+/// domain本地存储的最大key数量
+pub const MAX_DOMAIN_LOCAL_KEYS: usize = 64;
+/// domain本地存储单个区域的最大字节数
+pub const MAX_DOMAIN_LOCAL_SIZE: usize = 64 * 1024;
+/// 每个domain保留的热升级记录数量
+pub const MAX_UPGRADE_HISTORY: usize = 16;
+/// 每个task上domain调用嵌套的最大深度，超过时返回ELOOP，防止内核栈溢出
+pub const MAX_DOMAIN_CALL_DEPTH: usize = 8;
+/// 同时在domain调用中的task数量上限，更多的task的调用不做重入检测
+pub const MAX_DOMAIN_CALL_TASKS: usize = 256;
+/// 共享堆内存压力等级的阈值（字节），超过第i个阈值时压力等级为i+1
+pub const SHARED_HEAP_PRESSURE_THRESHOLDS: [usize; 3] = [16 << 20, 32 << 20, 64 << 20];
+/// 共享堆内存压力等级下降的滞后量（字节），使用量低于阈值这么多之后压力等级才下降
+pub const SHARED_HEAP_PRESSURE_HYSTERESIS: usize = 2 << 20;
+/// 共享堆预留的上限（字节），已使用和已预留的共享堆之和不能超过它
+pub const SHARED_HEAP_LIMIT: usize = 128 << 20;
+/// 不睡眠的卸载中忙等读者退出和宽限期结束的上限（毫秒），超时后卸载返回EBUSY，domain不被卸载
+pub const TEARDOWN_SPIN_TIMEOUT_MS: u64 = 100;
+/// 卸载等待domain的句柄被释放的上限（毫秒），超时后卸载返回EBUSY
+pub const TEARDOWN_HANDLE_TIMEOUT_MS: u64 = 1000;
+/// 从不热升级的domain的ELF名称，默认为空，由集成者按需填写
+/// 只有空设备代理有固定路径：调用不检查flag，但仍然更新计数器，卸载时可以等待读者退出。
+/// 热升级返回EPERM，Restart的panic策略变为Disable，每次调用省下的时间见sys_bench_pinned_path
+pub const PINNED_DOMAINS: &[&str] = &[];
+/// 每个domain的标签的key和value的总字节数上限
+pub const MAX_DOMAIN_TAG_BYTES: usize = 1024;
+/// sys_set_log_prefix设置的控制台前缀的字节数上限，更长的前缀被截断
+pub const MAX_LOG_PREFIX_BYTES: usize = 32;
+/// sys_bind_domain_log绑定的日志环的字节数上限
+pub const MAX_LOG_SINK_BYTES: usize = 1 << 20;
+/// sys_domain_memory_map列出的共享堆分配的数量上限，其余的分配只被计数
+pub const MAX_MEMORY_MAP_ENTRIES: usize = 1024;
+/// 热升级和冻结等待无锁路径上的读者退出的上限（毫秒），超时后返回ETIMEDOUT，
+/// 旧domain继续服务，冻结不生效
+pub const DRAIN_TIMEOUT_MS: u64 = 1000;
 
 pub fn to_kresult<T>(err: LinuxResult<T>) -> KernelResult<T> {
     match err {
@@ -1,7 +1,7 @@
 use alloc::boxed::Box;
 
 use corelib::LinuxResult;
-use interface::{DomainType, DomainTypeRaw};
+use interface::{empty_device::EmptyDeviceConfig, DomainType, DomainTypeRaw};
 use kernel::env;
 
 use crate::{
@@ -34,7 +34,7 @@ pub fn init_domain_system() -> LinuxResult<()> {
         DomainTypeRaw::EmptyDeviceDomain,
         "empty_device"
     )?;
-    null_device.init_by_box(Box::new(()))?;
+    null_device.init_by_box(Box::new(EmptyDeviceConfig::default()))?;
     register_domain!(
         "empty_device",
         domain_file_info,
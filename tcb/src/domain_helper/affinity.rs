@@ -0,0 +1,95 @@
+use alloc::{collections::BTreeMap, string::String};
+
+use corelib::LinuxResult;
+use ksync::Mutex;
+use tcb_helper::{
+    affinity::{check_affinity, check_nice, run_with_nice},
+    domain::rename_key,
+};
+
+use super::DOMAIN_INFO;
+
+/// The CPUs the work of each domain is pinned to, bit `i` is CPU `i`, by domain name
+///
+/// It is keyed by name so that the affinity is kept when the domain is upgraded or
+/// restarted, which gives it a new id.
+static AFFINITY: Mutex<BTreeMap<String, u64>> = Mutex::new(BTreeMap::new());
+/// The nice value of the work of each domain, by domain name like [AFFINITY]
+static NICE: Mutex<BTreeMap<String, i32>> = Mutex::new(BTreeMap::new());
+
+/// The online CPUs, bit `i` is CPU `i`
+///
+/// Only the first 64 CPUs can be set in an affinity.
+fn online_cpus() -> u64 {
+    unsafe { kernel::bindings::cpu_online_mask() as u64 }
+}
+
+/// Pin the work of the domain `name` to the CPUs in `cpumask`, see [check_affinity]
+pub fn set_domain_affinity(name: &str, cpumask: u64) -> LinuxResult<()> {
+    check_affinity(cpumask, online_cpus())?;
+    AFFINITY.lock().insert(name.into(), cpumask);
+    Ok(())
+}
+
+/// Get the CPUs the work of the domain `name` is pinned to
+pub fn domain_affinity_by_name(name: &str) -> Option<u64> {
+    AFFINITY.lock().get(name).copied()
+}
+
+/// Get the CPUs the work of the domain `domain_id` is pinned to
+pub fn domain_affinity(domain_id: u64) -> Option<u64> {
+    let name = DOMAIN_INFO
+        .lock()
+        .domain_list
+        .get(&domain_id)
+        .map(|data| data.name.clone())?;
+    domain_affinity_by_name(&name)
+}
+
+/// Set the nice value of the work of the domain `name`, see [check_nice]
+///
+/// It is applied by [run_with_domain_nice] while the work of the domain runs.
+pub fn set_domain_nice(name: &str, nice: i32) -> LinuxResult<()> {
+    check_nice(nice)?;
+    NICE.lock().insert(name.into(), nice);
+    Ok(())
+}
+
+/// Get the nice value of the work of the domain `name`
+pub fn domain_nice_by_name(name: &str) -> Option<i32> {
+    NICE.lock().get(name).copied()
+}
+
+/// Get the nice value of the work of the domain `domain_id`
+pub fn domain_nice(domain_id: u64) -> Option<i32> {
+    let name = DOMAIN_INFO
+        .lock()
+        .domain_list
+        .get(&domain_id)
+        .map(|data| data.name.clone())?;
+    domain_nice_by_name(&name)
+}
+
+/// Run `work` of the domain `name` from a workqueue at the nice value of the domain, see
+/// [run_with_nice]
+pub fn run_with_domain_nice<R>(name: &str, work: impl FnOnce() -> R) -> R {
+    let current = unsafe { kernel::bindings::get_current() };
+    run_with_nice(
+        domain_nice_by_name(name),
+        || unsafe { kernel::bindings::task_nice(current) },
+        |nice| unsafe { kernel::bindings::set_user_nice(current, nice as _) },
+        work,
+    )
+}
+
+/// Move the affinity and the nice value of the domain `old_name` to `new_name`
+pub fn rename_affinity(old_name: &str, new_name: &str) {
+    let _ = rename_key(&mut AFFINITY.lock(), old_name, new_name);
+    let _ = rename_key(&mut NICE.lock(), old_name, new_name);
+}
+
+/// Forget the affinity and the nice value of the domain `name`
+pub fn remove_affinity(name: &str) {
+    AFFINITY.lock().remove(name);
+    NICE.lock().remove(name);
+}
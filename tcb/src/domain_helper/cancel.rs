@@ -0,0 +1,65 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use kernel::time::Ktime;
+use ksync::Mutex;
+use tcb_helper::cancel::InterruptibleCalls;
+
+/// The interruptible calls in flight
+static INTERRUPTIBLE_CALLS: Mutex<InterruptibleCalls> = Mutex::new(InterruptibleCalls::new());
+/// The [InterruptibleCalls::armed] calls, updated under the lock
+///
+/// The domains poll [call_canceled] in their loops, it only takes the lock while a call
+/// may be canceled.
+static ARMED_CALLS: AtomicUsize = AtomicUsize::new(0);
+
+fn now_ns() -> u64 {
+    Ktime::ktime_get().to_ns() as u64
+}
+
+fn update_calls<R>(f: impl FnOnce(&mut InterruptibleCalls) -> R) -> R {
+    let mut calls = INTERRUPTIBLE_CALLS.lock();
+    let r = f(&mut calls);
+    ARMED_CALLS.store(calls.armed(), Ordering::Release);
+    r
+}
+
+/// Start an interruptible call into the domain `domain_id`, return its call id
+///
+/// The call id is passed to the domain, which polls [call_canceled] to find out whether
+/// it should stop. [end_call] must be called when the call returns.
+pub fn begin_call(domain_id: u64) -> u64 {
+    update_calls(|calls| calls.begin(domain_id, u64::MAX))
+}
+
+/// Start an interruptible call like [begin_call], which is also canceled once `timeout_ms`
+/// milliseconds have passed
+pub fn begin_call_timeout(domain_id: u64, timeout_ms: u64) -> u64 {
+    let deadline = now_ns().saturating_add(timeout_ms.saturating_mul(1_000_000));
+    update_calls(|calls| calls.begin(domain_id, deadline))
+}
+
+/// Whether the deadline of the call `call_id` started by [begin_call_timeout] has passed
+pub fn call_timed_out(call_id: u64) -> bool {
+    INTERRUPTIBLE_CALLS.lock().timed_out(call_id, now_ns())
+}
+
+/// Forget the call `call_id` started by [begin_call]
+pub fn end_call(call_id: u64) {
+    update_calls(|calls| calls.end(call_id))
+}
+
+/// Cancel all the interruptible calls in flight into the domain `domain_id`
+///
+/// Return the number of the calls canceled.
+pub fn cancel_calls(domain_id: u64) -> usize {
+    update_calls(|calls| calls.cancel(domain_id))
+}
+
+/// Whether the call `call_id` has been canceled or has timed out, an unknown call is never
+/// canceled
+pub fn call_canceled(call_id: u64) -> bool {
+    if ARMED_CALLS.load(Ordering::Acquire) == 0 {
+        return false;
+    }
+    INTERRUPTIBLE_CALLS.lock().canceled(call_id, now_ns())
+}
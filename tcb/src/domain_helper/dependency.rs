@@ -0,0 +1,73 @@
+use alloc::{
+    collections::{BTreeMap, BTreeSet},
+    string::{String, ToString},
+    vec::Vec,
+};
+
+use ksync::Mutex;
+use tcb_helper::domain::rename_key;
+
+use crate::domain_helper::DOMAIN_INFO;
+
+/// The domains each domain got through `sys_get_domain`, indexed by domain name
+///
+/// The names are kept instead of the ids, so the edges survive the upgrades.
+static DOMAIN_DEPENDENCIES: Mutex<BTreeMap<String, BTreeSet<String>>> = Mutex::new(BTreeMap::new());
+
+/// Record that the domain `caller_id` got the domain `target`
+///
+/// A caller which is not a registered domain, e.g. the kernel, is ignored.
+pub fn record_dependency(caller_id: u64, target: &str) {
+    let Some(caller) = DOMAIN_INFO
+        .lock()
+        .domain_list
+        .get(&caller_id)
+        .map(|data| data.name.clone())
+    else {
+        return;
+    };
+    DOMAIN_DEPENDENCIES
+        .lock()
+        .entry(caller)
+        .or_default()
+        .insert(target.to_string());
+}
+
+/// All the dependency edges `(from, to)`, sorted by name
+pub fn domain_dependencies() -> Vec<(String, String)> {
+    DOMAIN_DEPENDENCIES
+        .lock()
+        .iter()
+        .flat_map(|(from, to)| to.iter().map(move |to| (from.clone(), to.clone())))
+        .collect()
+}
+
+/// The domains which got the domain `name`, sorted by name
+pub fn domain_dependents(name: &str) -> Vec<String> {
+    DOMAIN_DEPENDENCIES
+        .lock()
+        .iter()
+        .filter(|(_, to)| to.contains(name))
+        .map(|(from, _)| from.clone())
+        .collect()
+}
+
+/// Rename the domain `old_name` to `new_name` in all the edges
+pub fn rename_dependency(old_name: &str, new_name: &str) {
+    let mut deps = DOMAIN_DEPENDENCIES.lock();
+    let _ = rename_key(&mut deps, old_name, new_name);
+    for to in deps.values_mut() {
+        if to.remove(old_name) {
+            to.insert(new_name.to_string());
+        }
+    }
+}
+
+/// Forget all the edges from or to the domain `name`
+pub fn remove_dependency(name: &str) {
+    let mut deps = DOMAIN_DEPENDENCIES.lock();
+    deps.remove(name);
+    deps.values_mut().for_each(|to| {
+        to.remove(name);
+    });
+}
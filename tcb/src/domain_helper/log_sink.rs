@@ -0,0 +1,87 @@
+use alloc::{collections::BTreeMap, string::String};
+
+use corelib::{domain_info::LogTail, LinuxError, LinuxResult};
+use ksync::Mutex;
+use tcb_helper::log_sink::{retag_console_output, sanitize_log_prefix, LogRing};
+
+use crate::config::{MAX_LOG_PREFIX_BYTES, MAX_LOG_SINK_BYTES};
+
+/// The log sinks bound to the domains, indexed by domain id
+static DOMAIN_LOG_SINK: Mutex<BTreeMap<u64, LogRing>> = Mutex::new(BTreeMap::new());
+
+/// The console prefixes set by `sys_set_log_prefix`, indexed by domain id
+static DOMAIN_LOG_PREFIX: Mutex<BTreeMap<u64, String>> = Mutex::new(BTreeMap::new());
+
+/// Bind a log sink of `capacity` bytes to the domain, the output of the domain will not go
+/// to the console any more.
+///
+/// Return `EINVAL` if `capacity` is 0 or larger than `MAX_LOG_SINK_BYTES` and `EEXIST` if
+/// the domain already has a sink.
+pub fn bind_log_sink(domain_id: u64, capacity: usize) -> LinuxResult<()> {
+    if capacity == 0 || capacity > MAX_LOG_SINK_BYTES {
+        return Err(LinuxError::EINVAL);
+    }
+    let mut sinks = DOMAIN_LOG_SINK.lock();
+    if sinks.contains_key(&domain_id) {
+        return Err(LinuxError::EEXIST);
+    }
+    sinks.insert(domain_id, LogRing::new(capacity));
+    Ok(())
+}
+
+/// Remove the log sink of the domain, the output in it is discarded.
+pub fn unbind_log_sink(domain_id: u64) {
+    DOMAIN_LOG_SINK.lock().remove(&domain_id);
+}
+
+/// Set the prefix which replaces the default tag of the console output of the domain, an
+/// empty prefix restores the default tag.
+///
+/// The control characters are removed and the prefix is cut to `MAX_LOG_PREFIX_BYTES`.
+pub fn set_log_prefix(domain_id: u64, prefix: &str) {
+    let prefix = sanitize_log_prefix(prefix, MAX_LOG_PREFIX_BYTES);
+    let mut prefixes = DOMAIN_LOG_PREFIX.lock();
+    if prefix.is_empty() {
+        prefixes.remove(&domain_id);
+    } else {
+        prefixes.insert(domain_id, prefix);
+    }
+}
+
+/// Forget the console prefix of the domain
+pub fn remove_log_prefix(domain_id: u64) {
+    DOMAIN_LOG_PREFIX.lock().remove(&domain_id);
+}
+
+/// The output of the domain with its default tag replaced by its prefix.
+///
+/// Return `None` if the domain has no prefix or the output has no tag.
+pub fn apply_log_prefix(domain_id: u64, s: &str) -> Option<String> {
+    let prefixes = DOMAIN_LOG_PREFIX.lock();
+    retag_console_output(s, domain_id, prefixes.get(&domain_id)?)
+}
+
+/// Write the output of the domain to its log sink.
+///
+/// Return `false` if the domain has no sink.
+pub fn write_domain_log(domain_id: u64, s: &str) -> bool {
+    match DOMAIN_LOG_SINK.lock().get_mut(&domain_id) {
+        Some(ring) => {
+            ring.write(s);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Read the outputs of the domain from the sequence number `from_seq`, they are not
+/// consumed, so a follower passes the `next_seq` it got last time.
+///
+/// Return `ENOENT` if the domain has no sink.
+pub fn read_domain_log(domain_id: u64, from_seq: u64) -> LinuxResult<LogTail> {
+    DOMAIN_LOG_SINK
+        .lock()
+        .get(&domain_id)
+        .map(|ring| ring.read_from(from_seq))
+        .ok_or(LinuxError::ENOENT)
+}
@@ -1,30 +1,55 @@
+mod affinity;
+mod cancel;
+mod dependency;
+mod log_sink;
+mod pressure;
 mod resource;
 mod sheap;
 mod storage_heap;
 mod syscall;
+mod upgrade_history;
+mod watchdog;
 
 extern crate alloc;
 
 use alloc::{
     boxed::Box,
-    collections::BTreeMap,
+    collections::{BTreeMap, BTreeSet},
     format,
     string::{String, ToString},
     sync::Arc,
 };
 use core::sync::atomic::AtomicU64;
 
+pub use affinity::*;
 use basic::DomainInfoSet;
+pub use cancel::*;
 use corelib::{
     domain_info::{DomainDataInfo, DomainFileInfo, DomainInfo},
-    LinuxResult,
+    LinuxError, LinuxResult,
 };
+pub use dependency::*;
 pub use interface::DomainType;
+use interface::DomainTypeRaw;
+use kernel::time::{ktime_ms_delta, Ktime};
 use ksync::{Lazy, Mutex, Once};
+pub use log_sink::*;
 pub use resource::*;
-pub use sheap::{checkout_shared_data, FreeShared, SHARED_HEAP_ALLOCATOR};
+pub use sheap::{
+    begin_unwind, checkout_shared_data, compact_shared_heap, domain_memory_map, domain_shared_data,
+    end_unwind, remove_upgrade_reserve, rename_upgrade_reserve, reserve_shared_heap,
+    set_upgrade_reserve, shared_data_owner, shared_data_owners, upgrade_reserve, AllocScope,
+    FreeShared, SharedHeapReservation, SHARED_HEAP_ALLOCATOR,
+};
 pub use storage_heap::*;
+pub(crate) use syscall::create_domain_with_id;
 pub use syscall::DOMAIN_SYS;
+use tcb_helper::{
+    domain::{rename_key, ReservedIds},
+    teardown::{wait_handles_dropped, TeardownMode},
+};
+pub use upgrade_history::*;
+pub use watchdog::*;
 
 static DOMAIN_IDS: AtomicU64 = AtomicU64::new(0);
 
@@ -74,17 +99,44 @@ impl DomainContainer {
     fn ref_count(&self, name: &str) -> Option<usize> {
         self.domains.get(name).map(|domain| domain.ref_count())
     }
+
+    fn rename(&mut self, old_name: &str, new_name: &str) -> LinuxResult<u64> {
+        let domain_id = rename_key(&mut self.domains, old_name, new_name)?.domain_id();
+        println!("<rename domain>: {} -> {}", old_name, new_name);
+        Ok(domain_id)
+    }
 }
 
 static DOMAIN_CONTAINER: Mutex<DomainContainer> = Mutex::new(DomainContainer::new());
 static DOMAIN_CREATE: Once<Box<dyn DomainCreate>> = Once::new();
 pub static DOMAIN_INFO: Lazy<Arc<DomainInfoSet>> =
     Lazy::new(|| Arc::new(DomainInfoSet::new(DomainInfo::new())));
+static RESERVED_DOMAIN_IDS: Mutex<ReservedIds> = Mutex::new(ReservedIds::new());
+
 /// Allocate a domain id
 pub fn alloc_domain_id() -> u64 {
     DOMAIN_IDS.fetch_add(1, core::sync::atomic::Ordering::SeqCst)
 }
 
+/// Allocate a domain id for a domain which is created later by [take_reserved_domain_id]
+pub fn reserve_domain_id() -> u64 {
+    let id = alloc_domain_id();
+    RESERVED_DOMAIN_IDS.lock().reserve(id);
+    id
+}
+
+/// Take the reserved id `domain_id` to create a domain with it, see [ReservedIds::take]
+pub fn take_reserved_domain_id(domain_id: u64) -> LinuxResult<()> {
+    let live = DOMAIN_INFO.lock().domain_list.contains_key(&domain_id);
+    RESERVED_DOMAIN_IDS.lock().take(domain_id, live)
+}
+
+/// Give back the reserved id taken by [take_reserved_domain_id] whose domain could not be
+/// created, so that the creation can be retried
+pub fn return_reserved_domain_id(domain_id: u64) {
+    RESERVED_DOMAIN_IDS.lock().give_back(domain_id);
+}
+
 /// Initialize the domain creation function
 pub fn init_domain_create(domain_create: Box<dyn DomainCreate>) {
     DOMAIN_CREATE.call_once(|| domain_create);
@@ -95,6 +147,60 @@ pub fn query_domain(domain_identifier: &str) -> Option<DomainType> {
     DOMAIN_CONTAINER.lock().get(domain_identifier)
 }
 
+/// find the domain which id is `domain_id`
+pub fn query_domain_by_id(domain_id: u64) -> Option<DomainType> {
+    let name = DOMAIN_INFO
+        .lock()
+        .domain_list
+        .get(&domain_id)
+        .map(|data| data.name.clone())?;
+    query_domain(&name)
+}
+
+/// The names the proxies are registered with, no proxy is called
+pub fn registered_domains() -> BTreeSet<String> {
+    DOMAIN_CONTAINER.lock().domains.keys().cloned().collect()
+}
+
+/// Check whether the domain which name is `domain_identifier` exists
+///
+/// It only looks up [DOMAIN_INFO], so the proxy of the domain is not touched.
+pub fn domain_exists(domain_identifier: &str) -> bool {
+    DOMAIN_INFO
+        .lock()
+        .domain_list
+        .values()
+        .any(|data| data.name == domain_identifier)
+}
+
+/// Check whether `domain_id` is the id of a registered domain or of the kernel itself
+///
+/// Shared data moved to any other id is orphaned.
+pub fn domain_is_live(domain_id: u64) -> bool {
+    DOMAIN_INFO
+        .lock()
+        .is_move_target(domain_id, rref::domain_id())
+}
+
+/// Get the id of the domain which name is `domain_identifier`
+///
+/// Like [domain_exists], it only looks up [DOMAIN_INFO].
+pub fn domain_id_by_name(domain_identifier: &str) -> Option<u64> {
+    DOMAIN_INFO.lock().domain_id(domain_identifier)
+}
+
+/// Get the type of the domain which name is `domain_identifier`
+///
+/// Like [domain_exists], it only looks up [DOMAIN_INFO].
+pub fn domain_type(domain_identifier: &str) -> Option<DomainTypeRaw> {
+    DOMAIN_INFO
+        .lock()
+        .domain_list
+        .values()
+        .find(|data| data.name == domain_identifier)
+        .map(|data| data.ty)
+}
+
 /// Register a domain with a  identifier which may be unique
 pub fn register_domain(
     identifier: &str,
@@ -112,6 +218,7 @@ pub fn register_domain(
         ty,
         panic_count: 0,
         file_info: domain_file,
+        tags: BTreeMap::new(),
     };
 
     DOMAIN_INFO
@@ -127,9 +234,80 @@ pub fn register_domain(
 pub fn unregister_domain(identifier: &str) {
     let domain = DOMAIN_CONTAINER.lock().domains.remove(identifier);
     if let Some(domain) = domain {
-        let domain_id = domain.domain_id();
-        DOMAIN_INFO.lock().domain_list.remove(&domain_id);
+        forget_domain(identifier, domain.domain_id());
+    }
+}
+
+/// Unregister the domain like [unregister_domain] if at most `max_refs` handles of it are
+/// alive, the handle kept by the registry included.
+///
+/// The count is checked under the registry lock, so `sys_get_domain` cannot hand out a new
+/// handle between the check and the removal. Return `ENOENT` if the domain is unknown and
+/// `EBUSY` if it is still in use.
+pub fn unregister_unused_domain(identifier: &str, max_refs: usize) -> LinuxResult<()> {
+    let mut container = DOMAIN_CONTAINER.lock();
+    match container.ref_count(identifier) {
+        None => return Err(LinuxError::ENOENT),
+        Some(refs) if refs > max_refs => return Err(LinuxError::EBUSY),
+        Some(_) => {}
+    }
+    let domain = container.domains.remove(identifier).unwrap();
+    drop(container);
+    // a domain retired by the teardown reports the id of the empty domain
+    let domain_id = domain_id_by_name(identifier).unwrap_or_else(|| domain.domain_id());
+    forget_domain(identifier, domain_id);
+    Ok(())
+}
+
+/// Wait until at most `max_refs` handles of the domain are alive, or return `ETIMEDOUT`
+/// after `timeout_ms` milliseconds. Return `ENOENT` if the domain is unknown.
+///
+/// A handle is handed out by `sys_get_domain` and released when its holder drops it. It
+/// sleeps between the polls in [TeardownMode::Sleep] and spins in [TeardownMode::NoSleep].
+pub fn wait_domain_unused(
+    identifier: &str,
+    max_refs: usize,
+    timeout_ms: u64,
+    mode: TeardownMode,
+) -> LinuxResult<()> {
+    let start = Ktime::ktime_get();
+    wait_handles_dropped(
+        max_refs,
+        timeout_ms,
+        || ktime_ms_delta(Ktime::ktime_get(), start) as u64,
+        || match mode {
+            TeardownMode::Sleep => kernel::time::msleep(1),
+            TeardownMode::NoSleep => core::hint::spin_loop(),
+        },
+        || domain_ref_count(identifier),
+    )
+}
+
+/// Forget the bookkeeping of the domain removed from the registry
+fn forget_domain(identifier: &str, domain_id: u64) {
+    DOMAIN_INFO.lock().domain_list.remove(&domain_id);
+    remove_upgrade_history(identifier);
+    remove_dependency(identifier);
+    remove_watchdog(identifier);
+    remove_upgrade_reserve(identifier);
+    remove_affinity(identifier);
+}
+
+/// Rename the domain `old_name` to `new_name`
+///
+/// Return `EEXIST` if `new_name` is already used and `EINVAL` if `old_name` is unknown.
+pub fn rename_domain(old_name: &str, new_name: &str) -> LinuxResult<()> {
+    let mut container = DOMAIN_CONTAINER.lock();
+    let domain_id = container.rename(old_name, new_name)?;
+    if let Some(data) = DOMAIN_INFO.lock().domain_list.get_mut(&domain_id) {
+        data.name = new_name.to_string();
     }
+    rename_upgrade_history(old_name, new_name);
+    rename_dependency(old_name, new_name);
+    rename_watchdog(old_name, new_name);
+    rename_upgrade_reserve(old_name, new_name);
+    rename_affinity(old_name, new_name);
+    Ok(())
 }
 
 /// Get the reference count of the domain
@@ -155,6 +333,10 @@ macro_rules! register_domain {
 }
 
 pub trait DomainCreate: Send + Sync {
+    /// Create a domain from the ELF `domain_file_name`
+    ///
+    /// The identifier of the new domain is written with
+    /// [tcb_helper::loader::write_identifier], which never writes past `identifier`.
     fn create_domain(
         &self,
         domain_file_name: &str,
@@ -0,0 +1,83 @@
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+
+use kernel::workqueue::StaticWork;
+use tcb_helper::sheap::pressure_level;
+
+use crate::{
+    config::{SHARED_HEAP_PRESSURE_HYSTERESIS, SHARED_HEAP_PRESSURE_THRESHOLDS},
+    domain_helper::{run_with_domain_nice, DOMAIN_CONTAINER},
+};
+
+/// The current pressure level of the shared heap
+static PRESSURE_LEVEL: AtomicU8 = AtomicU8::new(0);
+/// The usage which raised the level, for the log of the notification
+static PRESSURE_USAGE: AtomicUsize = AtomicUsize::new(0);
+/// Notify the domains outside of the allocator, see [check_memory_pressure]
+static PRESSURE_WORK: StaticWork = StaticWork::new(notify_memory_pressure);
+
+/// Check the usage of the shared heap and notify the domains if it crosses a higher threshold.
+///
+/// It is called from the shared heap allocator, so it only updates the level: the domains
+/// are notified later from the system workqueue, where they can allocate and free shared
+/// data without re-entering the allocator of the caller. The level only falls once the usage
+/// is [SHARED_HEAP_PRESSURE_HYSTERESIS] below the threshold, so that a usage hovering around
+/// a threshold does not notify the domains on every allocation.
+pub fn check_memory_pressure(usage: usize) {
+    let mut old = PRESSURE_LEVEL.load(Ordering::Relaxed);
+    loop {
+        let level = pressure_level(
+            &SHARED_HEAP_PRESSURE_THRESHOLDS,
+            SHARED_HEAP_PRESSURE_HYSTERESIS,
+            old,
+            usage,
+        );
+        if level == old {
+            return;
+        }
+        match PRESSURE_LEVEL.compare_exchange(old, level, Ordering::SeqCst, Ordering::Relaxed) {
+            Ok(_) if level > old => {
+                PRESSURE_USAGE.store(usage, Ordering::Relaxed);
+                PRESSURE_WORK.schedule();
+                return;
+            }
+            Ok(_) => return,
+            Err(current) => old = current,
+        }
+    }
+}
+
+/// Notify the domains of the current pressure level in ascending domain id order
+///
+/// The level may have fallen again before the work runs, then there is nothing to do. A
+/// rise while the work runs queues it again.
+fn notify_memory_pressure() {
+    let level = PRESSURE_LEVEL.load(Ordering::SeqCst);
+    if level == 0 {
+        return;
+    }
+    let mut domains = DOMAIN_CONTAINER
+        .lock()
+        .domains
+        .iter()
+        .map(|(name, domain)| (name.clone(), domain.clone()))
+        .collect::<Vec<_>>();
+    domains.sort_by_key(|(_, domain)| domain.domain_id());
+    warn!(
+        "<memory pressure> shared heap usage: {} bytes, level: {}",
+        PRESSURE_USAGE.load(Ordering::Relaxed),
+        level
+    );
+    let mut freed = 0;
+    for (name, domain) in domains {
+        match run_with_domain_nice(&name, || domain.on_memory_pressure(level)) {
+            Ok(bytes) => freed += bytes,
+            Err(e) => warn!(
+                "[Domain: {}] on_memory_pressure failed: {:?}",
+                domain.domain_id(),
+                e
+            ),
+        }
+    }
+    warn!("<memory pressure> domains freed {} bytes", freed);
+}
@@ -1,10 +1,20 @@
-use alloc::{boxed::Box, collections::BTreeMap, vec::Vec};
+use alloc::{
+    boxed::Box,
+    collections::{BTreeMap, BTreeSet},
+    vec::Vec,
+};
 
+use corelib::{LinuxError, LinuxResult};
 use ksync::Mutex;
+use tcb_helper::{
+    report::QuotaUsage,
+    resource::{warmup_pages, DomainLocal},
+};
 
 use crate::{
-    config::FRAME_BITS,
+    config::{FRAME_BITS, MAX_DOMAIN_LOCAL_KEYS, MAX_DOMAIN_LOCAL_SIZE},
     domain_helper::{
+        log_sink::{remove_log_prefix, unbind_log_sink},
         sheap::{free_domain_shared_data, FreeShared},
         storage_heap::DomainDataMap,
     },
@@ -14,6 +24,9 @@ pub(super) static DOMAIN_RESOURCE: Mutex<DomainResource> = Mutex::new(DomainReso
 pub struct DomainResource {
     page_map: BTreeMap<u64, Vec<(usize, usize)>>,
     box_data: BTreeMap<u64, usize>,
+    local_data: BTreeMap<u64, DomainLocal>,
+    /// The hrtimers started by the domain and not canceled, they may still be armed
+    timers: BTreeMap<u64, BTreeSet<usize>>,
 }
 
 impl DomainResource {
@@ -21,6 +34,8 @@ impl DomainResource {
         Self {
             page_map: BTreeMap::new(),
             box_data: BTreeMap::new(),
+            local_data: BTreeMap::new(),
+            timers: BTreeMap::new(),
         }
     }
 
@@ -34,35 +49,222 @@ impl DomainResource {
         vec.retain(|(s, _)| *s != page);
     }
 
+    /// Remove all the page map entries of the domain, the caller frees the pages
+    fn take_page_map(&mut self, domain_id: u64) -> Vec<(usize, usize)> {
+        self.page_map.remove(&domain_id).unwrap_or_default()
+    }
+
     pub fn insert_box_data(&mut self, domain_id: u64, data: usize) {
         self.box_data.insert(domain_id, data);
     }
+
+    /// Allocate a zeroed scratch area of `size` bytes for the domain under `key`.
+    ///
+    /// If the key already exists, the old area is returned when the size matches.
+    pub fn alloc_local_data(
+        &mut self,
+        domain_id: u64,
+        key: u64,
+        size: usize,
+    ) -> LinuxResult<*mut u8> {
+        self.local_data.entry(domain_id).or_default().alloc(
+            key,
+            size,
+            MAX_DOMAIN_LOCAL_SIZE,
+            MAX_DOMAIN_LOCAL_KEYS,
+        )
+    }
+
+    /// Get the scratch area of the domain under `key`
+    pub fn get_local_data(&mut self, domain_id: u64, key: u64) -> LinuxResult<(*mut u8, usize)> {
+        self.local_data
+            .get_mut(&domain_id)
+            .and_then(|local| local.get(key))
+            .ok_or(LinuxError::ENOENT)
+    }
 }
 
 pub fn register_domain_resource(domain_id: u64, box_ptr: usize) {
     DOMAIN_RESOURCE.lock().insert_box_data(domain_id, box_ptr);
 }
 
-pub fn free_domain_resource(domain_id: u64, free_shared: FreeShared) {
+/// Allocate a scratch area of `size` bytes for the domain under `key`, it lives
+/// until the domain is freed.
+pub fn alloc_domain_local(domain_id: u64, key: u64, size: usize) -> LinuxResult<*mut u8> {
+    DOMAIN_RESOURCE
+        .lock()
+        .alloc_local_data(domain_id, key, size)
+}
+
+/// Get the scratch area and its size of the domain under `key`
+pub fn get_domain_local(domain_id: u64, key: u64) -> LinuxResult<(*mut u8, usize)> {
+    DOMAIN_RESOURCE.lock().get_local_data(domain_id, key)
+}
+
+/// The use of the domain local areas of each domain, against [MAX_DOMAIN_LOCAL_KEYS]
+pub fn local_data_quotas() -> Vec<QuotaUsage> {
+    DOMAIN_RESOURCE
+        .lock()
+        .local_data
+        .iter()
+        .map(|(&domain_id, local)| QuotaUsage {
+            domain_id,
+            resource: "local areas",
+            used: local.len(),
+            limit: MAX_DOMAIN_LOCAL_KEYS,
+        })
+        .collect()
+}
+
+/// The number of the pages allocated by `sys_alloc_pages` and not freed, by domain
+pub fn page_map_owners() -> BTreeMap<u64, usize> {
+    DOMAIN_RESOURCE
+        .lock()
+        .page_map
+        .iter()
+        .map(|(&id, pages)| (id, pages.iter().map(|(_, n)| n).sum()))
+        .filter(|&(_, n)| n != 0)
+        .collect()
+}
+
+/// Touch every page allocated by `sys_alloc_pages` and not freed by the domain, so the
+/// first call into the domain does not fault them in. Return the number of the pages touched.
+///
+/// The lock is held while touching, so the domain cannot free the pages meanwhile.
+pub fn warmup_domain_pages(domain_id: u64) -> usize {
+    let resource = DOMAIN_RESOURCE.lock();
+    let Some(pages) = resource.page_map.get(&domain_id) else {
+        return 0;
+    };
+    warmup_pages(pages, |page| unsafe {
+        core::ptr::read_volatile((page << FRAME_BITS) as *const u8);
+    })
+}
+
+/// Free all the pages the domain allocated by `sys_alloc_pages` and has not freed.
+///
+/// The entries are taken from the page map in one lock acquisition, and the pages are
+/// freed after the lock is released, so a domain with thousands of entries does not keep
+/// the other domains waiting. The contiguous entries are not coalesced: each one is a
+/// separate area from `alloc_frames`, which `free_frames` must free on its own.
+///
+/// Return the number of the pages freed and the entries `(start, count)` which could not
+/// be freed.
+pub fn free_all_pages(domain_id: u64) -> (usize, Vec<(usize, usize)>) {
+    let entries = DOMAIN_RESOURCE.lock().take_page_map(domain_id);
+    let mut freed = 0;
+    let mut leaked = Vec::new();
+    for (page_start, n) in entries {
+        // free_frames only accepts what alloc_frames returned
+        if page_start == 0 || !n.is_power_of_two() {
+            leaked.push((page_start, n));
+            continue;
+        }
+        crate::mem::free_frames((page_start << FRAME_BITS) as *mut u8, n);
+        freed += n;
+    }
+    // a domain may hold thousands of entries, so only a summary is printed
+    if freed != 0 {
+        warn!("[Domain: {}] freed {} pages", domain_id, freed);
+    }
+    (freed, leaked)
+}
+
+/// Record that the domain started the hrtimer `timer`, it is canceled by
+/// [free_domain_resource] if the domain does not cancel it
+pub fn record_domain_timer(domain_id: u64, timer: *mut kernel::bindings::hrtimer) {
+    DOMAIN_RESOURCE
+        .lock()
+        .timers
+        .entry(domain_id)
+        .or_default()
+        .insert(timer as usize);
+}
+
+/// Forget the hrtimer `timer` canceled by its domain
+pub fn forget_domain_timer(timer: *mut kernel::bindings::hrtimer) {
+    DOMAIN_RESOURCE
+        .lock()
+        .timers
+        .values_mut()
+        .for_each(|timers| {
+            timers.remove(&(timer as usize));
+        });
+}
+
+/// Cancel the hrtimers the domain left armed, return how many were canceled.
+///
+/// `hrtimer_cancel` waits for a running callback, which may take the resource lock, so
+/// the lock is not held while canceling. A callback which restarts its timer is handled
+/// by `hrtimer_cancel`, the timer is forgotten again afterwards.
+fn cancel_domain_timers(domain_id: u64) -> usize {
+    let timers = DOMAIN_RESOURCE
+        .lock()
+        .timers
+        .remove(&domain_id)
+        .unwrap_or_default();
+    for &timer in timers.iter() {
+        unsafe { kernel::bindings::hrtimer_cancel(timer as *mut kernel::bindings::hrtimer) };
+    }
+    DOMAIN_RESOURCE.lock().timers.remove(&domain_id);
+    timers.len()
+}
+
+/// What [free_domain_resource] freed and what it could not free
+#[derive(Debug, Default)]
+pub struct FreeReport {
+    /// The number of hrtimers canceled
+    pub timers: usize,
+    /// The number of pages freed
+    pub pages: usize,
+    /// The pages `(start, count)` which could not be freed, they are leaked
+    pub leaked_pages: Vec<(usize, usize)>,
+    /// The number of shared data freed or moved to the new domain
+    pub shared_data: usize,
+    /// Whether the DomainDataMap of the domain was freed
+    pub data_map: bool,
+    /// The number of domain local data freed
+    pub local_data: usize,
+}
+
+impl FreeReport {
+    /// Whether all the resources of the domain were freed
+    pub fn is_complete(&self) -> bool {
+        self.leaked_pages.is_empty()
+    }
+}
+
+/// Free all the resources of the domain.
+///
+/// The resources are freed in a fixed order, so that nothing the domain left running
+/// touches memory which is already freed:
+/// 1. the hrtimers the domain left armed are canceled, their callbacks may use any of the
+///    memory below
+/// 2. the work queued by the domain is flushed, no domain queues work yet
+/// 3. the shared data is freed or moved to the new domain, the `ReplaceOptions` in
+///    [FreeShared::NotFree] choose what is moved
+/// 4. the pages, the `DomainDataMap` and the domain local data are freed
+///
+/// Return `EINVAL` for the id of an empty domain, otherwise return what was freed, the
+/// resources which could not be freed are listed in [FreeReport::leaked_pages].
+pub fn free_domain_resource(domain_id: u64, free_shared: FreeShared) -> LinuxResult<FreeReport> {
     println!("free_domain_resource for domain_id: {}", domain_id);
+    // the empty domains have no resources
+    if domain_id == u64::MAX {
+        return Err(LinuxError::EINVAL);
+    }
+    let mut report = FreeReport::default();
+
+    // cancel timers before freeing anything they may touch
+    report.timers = cancel_domain_timers(domain_id);
 
     // free shared data
-    free_domain_shared_data(domain_id, free_shared);
+    report.shared_data = free_domain_shared_data(domain_id, free_shared);
 
-    let mut binding = DOMAIN_RESOURCE.lock();
     // free pages
-    if let Some(vec) = binding.page_map.remove(&domain_id) {
-        for (page_start, n) in vec {
-            let page_end = page_start + n;
-            warn!(
-                "[Domain: {}] free pages: [{:#x}-{:#x}]",
-                domain_id,
-                page_start << FRAME_BITS,
-                page_end << FRAME_BITS
-            );
-            crate::mem::free_frames((page_start << FRAME_BITS) as *mut u8, n);
-        }
-    }
+    (report.pages, report.leaked_pages) = free_all_pages(domain_id);
+
+    let mut binding = DOMAIN_RESOURCE.lock();
 
     // free Box<DomainDataMap>
     let ptr = binding.box_data.remove(&domain_id);
@@ -70,5 +272,17 @@ pub fn free_domain_resource(domain_id: u64, free_shared: FreeShared) {
         let data_map = unsafe { Box::from_raw(data_map_addr as *mut DomainDataMap) };
         drop(data_map);
         println_color!(31, "[Domain: {}] free DomainDataMap resource", domain_id);
+        report.data_map = true;
     }
+
+    // free domain local data
+    report.local_data = binding
+        .local_data
+        .remove(&domain_id)
+        .map_or(0, |map| map.len());
+    drop(binding);
+
+    unbind_log_sink(domain_id);
+    remove_log_prefix(domain_id);
+    Ok(report)
 }
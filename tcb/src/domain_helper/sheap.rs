@@ -1,21 +1,95 @@
 use alloc::{
     alloc::{alloc, dealloc},
-    collections::BTreeMap,
+    collections::{BTreeMap, BTreeSet},
+    format,
+    string::{String, ToString},
     sync::Arc,
     vec,
     vec::Vec,
 };
-use core::{alloc::Layout, any::TypeId};
+use core::{
+    alloc::Layout,
+    any::TypeId,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+};
 
+use corelib::{
+    domain_info::{ReplaceOptions, SharedAllocation, SharedDataReport, SharedMemoryMap},
+    LinuxError, LinuxResult,
+};
 use hashbrown::HashMap;
 use ksync::{Lazy, Mutex};
-use rref::{SharedHeapAlloc, SharedHeapAllocation};
+use rref::{SharedHeapAlloc, SharedHeapAllocation, SharedHeapHeader};
+use tcb_helper::{
+    domain::rename_key,
+    sheap::{FreeSummary, HeapBudget},
+};
 
-use crate::config::FRAME_SIZE;
+use crate::{
+    config::{FRAME_SIZE, SHARED_HEAP_LIMIT},
+    domain_helper::{domain_is_live, pressure::check_memory_pressure},
+};
 
-static SHARED_HEAP: Mutex<BTreeMap<usize, SharedHeapAllocation>> = Mutex::new(BTreeMap::new());
+static SHARED_HEAP: Mutex<BTreeMap<usize, HeapEntry>> = Mutex::new(BTreeMap::new());
+/// The sequence number of the next allocation of the shared heap, see [AllocScope]
+static SHARED_HEAP_SEQ: AtomicU64 = AtomicU64::new(0);
+/// The bytes of the live allocations and of the live [SharedHeapReservation]s, within
+/// [SHARED_HEAP_LIMIT]
+static SHARED_HEAP_BUDGET: Mutex<HeapBudget> = Mutex::new(HeapBudget::new(SHARED_HEAP_LIMIT));
+/// The shared heap budget reserved for the new domain when the domain is upgraded,
+/// indexed by domain name
+static UPGRADE_RESERVES: Mutex<BTreeMap<String, usize>> = Mutex::new(BTreeMap::new());
+/// The tasks unwinding from a panic of a domain, from `sys_backtrace` until the proxy gets
+/// the crash
+static UNWINDING_TASKS: Mutex<BTreeSet<usize>> = Mutex::new(BTreeSet::new());
+/// The length of [UNWINDING_TASKS], so the deallocations need not lock it when no task
+/// unwinds
+static UNWINDING_COUNT: AtomicUsize = AtomicUsize::new(0);
 pub static SHARED_HEAP_ALLOCATOR: &'static dyn SharedHeapAlloc = &SharedHeapAllocator;
 
+/// A live allocation of the shared heap and the call which made it
+#[derive(Clone, Copy)]
+struct HeapEntry {
+    allocation: SharedHeapAllocation,
+    /// The task which made the allocation
+    task: usize,
+    /// The sequence number of the allocation, it grows with every allocation
+    seq: u64,
+}
+unsafe impl Send for HeapEntry {}
+
+impl HeapEntry {
+    fn new(allocation: SharedHeapAllocation) -> Self {
+        Self {
+            allocation,
+            task: current_task(),
+            seq: SHARED_HEAP_SEQ.fetch_add(1, Ordering::Relaxed),
+        }
+    }
+}
+
+fn current_task() -> usize {
+    unsafe { kernel::bindings::get_current() as usize }
+}
+
+/// Mark the current task as unwinding from a panic, its deallocations of the shared heap
+/// do not wait for the heap lock, which the panicking frame may hold
+pub fn begin_unwind() {
+    let mut tasks = UNWINDING_TASKS.lock();
+    tasks.insert(current_task());
+    UNWINDING_COUNT.store(tasks.len(), Ordering::Release);
+}
+
+/// Mark the current task as done unwinding, see [begin_unwind]
+pub fn end_unwind() {
+    if UNWINDING_COUNT.load(Ordering::Acquire) == 0 {
+        return;
+    }
+    let mut tasks = UNWINDING_TASKS.lock();
+    tasks.remove(&current_task());
+    UNWINDING_COUNT.store(tasks.len(), Ordering::Release);
+}
+
 struct SharedHeapAllocationPart {
     value_pointer: *mut u8,
     domain_id_pointer: *mut u64,
@@ -52,6 +126,25 @@ impl SharedHeapCache {
         let mut vec = vec.lock();
         vec.push(part);
     }
+    /// Give all cached parts back to the kernel allocator and return the bytes released
+    fn drain(&self) -> usize {
+        let cache = core::mem::take(&mut *self.cache.lock());
+        let mut released = 0;
+        for (layout, vec) in cache {
+            let parts = core::mem::take(&mut *vec.lock());
+            for part in parts {
+                unsafe {
+                    dealloc(part.value_pointer, layout);
+                    dealloc(
+                        part.domain_id_pointer as *mut u8,
+                        SharedHeapHeader::layout(),
+                    );
+                }
+                released += layout.size();
+            }
+        }
+        released
+    }
 }
 
 static SHARED_HEAP_CACHE: Lazy<SharedHeapCache> = Lazy::new(SharedHeapCache::new);
@@ -93,7 +186,7 @@ impl SharedHeapAllocator {
             layout.size(),
             ptr as usize
         );
-        let domain_id_pointer = alloc(Layout::for_value(&0u64)) as *mut u64;
+        let domain_id_pointer = alloc(SharedHeapHeader::layout()) as *mut u64;
         let res = SharedHeapAllocation {
             value_pointer: ptr,
             domain_id_pointer,
@@ -112,10 +205,24 @@ impl SharedHeapAlloc for SharedHeapAllocator {
         type_id: TypeId,
         drop_fn: fn(TypeId, *mut u8),
     ) -> Option<SharedHeapAllocation> {
+        // the allocations of the task which holds a reservation draw from it
+        let mut budget = SHARED_HEAP_BUDGET.lock();
+        if !budget.alloc(current_task(), layout.size()) {
+            drop(budget);
+            warn!(
+                "<SharedHeap> alloc size: {} exceeds the limit of {} bytes",
+                layout.size(),
+                SHARED_HEAP_LIMIT
+            );
+            return None;
+        }
+        let usage = budget.used() + budget.reserved();
+        drop(budget);
+        check_memory_pressure(usage);
         if layout.size() > FRAME_SIZE {
             let (ptr, res) = SharedHeapAllocator::alloc_from_heap(layout, type_id, drop_fn)?;
             let mut shared_heap = SHARED_HEAP.lock();
-            shared_heap.insert(ptr as usize, res);
+            shared_heap.insert(ptr as usize, HeapEntry::new(res));
             return Some(res);
         }
         let mut shared_heap = SHARED_HEAP.lock();
@@ -125,22 +232,65 @@ impl SharedHeapAlloc for SharedHeapAllocator {
         } else {
             SharedHeapAllocator::alloc_from_heap(layout, type_id, drop_fn)?
         };
-        shared_heap.insert(ptr as usize, res);
+        shared_heap.insert(ptr as usize, HeapEntry::new(res));
         Some(res)
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8) {
+        log::error!("<SharedHeap> dealloc: {:p}", ptr);
+        SharedHeapAllocator::dealloc_allocation(ptr);
+    }
+
+    unsafe fn try_dealloc(&self, ptr: *mut u8) -> bool {
+        let Some(mut heap) = SHARED_HEAP.try_lock() else {
+            return false;
+        };
+        let allocation = heap.remove(&(ptr as usize)).map(|entry| entry.allocation);
+        drop(heap);
+        log::error!("<SharedHeap> dealloc: {:p}", ptr);
+        SharedHeapAllocator::free_allocation(ptr, allocation);
+        true
+    }
+
+    fn unwinding(&self) -> bool {
+        UNWINDING_COUNT.load(Ordering::Acquire) != 0
+            && UNWINDING_TASKS.lock().contains(&current_task())
+    }
+
+    unsafe fn retype(&self, ptr: *mut u8, type_id: TypeId) {
+        match SHARED_HEAP.lock().get_mut(&(ptr as usize)) {
+            Some(entry) => entry.allocation.type_id = type_id,
+            None => panic!(
+                "<SharedHeap> retype: {:#x}, but the data has been dropped",
+                ptr as usize
+            ),
+        }
+    }
+}
+
+impl SharedHeapAllocator {
+    /// Remove the allocation from the shared heap without logging it.
+    unsafe fn dealloc_allocation(ptr: *mut u8) {
         let mut heap = SHARED_HEAP.lock();
-        let allocation = heap.remove(&(ptr as usize));
+        let allocation = heap.remove(&(ptr as usize)).map(|entry| entry.allocation);
         drop(heap);
+        SharedHeapAllocator::free_allocation(ptr, allocation);
+    }
+
+    /// Free the allocation just removed from the shared heap, `None` if it was not found.
+    unsafe fn free_allocation(ptr: *mut u8, allocation: Option<SharedHeapAllocation>) {
         if let Some(allocation) = allocation {
-            log::error!("<SharedHeap> dealloc: {:p}", ptr);
+            let mut budget = SHARED_HEAP_BUDGET.lock();
+            budget.free(allocation.layout.size());
+            let usage = budget.used() + budget.reserved();
+            drop(budget);
+            check_memory_pressure(usage);
             assert_eq!(allocation.value_pointer, ptr);
             if allocation.layout.size() > FRAME_SIZE {
                 dealloc(allocation.value_pointer, allocation.layout);
                 dealloc(
                     allocation.domain_id_pointer as *mut u8,
-                    Layout::for_value(&0u64),
+                    SharedHeapHeader::layout(),
                 );
             } else {
                 let part = SharedHeapAllocationPart {
@@ -158,30 +308,240 @@ impl SharedHeapAlloc for SharedHeapAllocator {
     }
 }
 
-pub fn checkout_shared_data() {
+/// Count the live allocations of the shared heap by the domain owning them.
+///
+/// The allocations owned by a domain which is not live were moved to a wrong domain or
+/// not freed with their domain, they are reported as orphaned.
+pub fn checkout_shared_data() -> SharedDataReport {
+    let map = shared_data_owners();
+    for (id, count) in map.iter() {
+        if domain_is_live(*id) {
+            println_color!(34, "domain_id: {}, count: {}", id, count);
+        } else {
+            println_color!(31, "domain_id: {}, count: {} (orphaned)", id, count);
+        }
+    }
+    let report = SharedDataReport::from_owners(&map, domain_is_live);
+    println_color!(
+        34,
+        "<checkout_shared_data> shared heap size: {}, orphaned: {}",
+        report.total,
+        report.orphaned
+    );
+    report
+}
+
+/// Release the free blocks kept by the shared heap cache.
+///
+/// Small blocks freed by the domains are cached per layout and never given back, so after
+/// many upgrades with different buffer sizes the kernel allocator cannot coalesce them.
+/// The live allocations are not moved, because the heap does not know where the `RRef`s
+/// pointing to them are stored.
+///
+/// Return the bytes released.
+pub fn compact_shared_heap() -> usize {
+    let released = SHARED_HEAP_CACHE.drain();
+    println_color!(
+        34,
+        "<compact_shared_heap> release {} bytes from the cache",
+        released
+    );
+    released
+}
+
+/// A budget of the shared heap reserved by [reserve_shared_heap], what is left of it is
+/// released when dropped
+#[derive(Debug)]
+pub struct SharedHeapReservation {
+    owner: usize,
+    bytes: usize,
+}
+
+impl Drop for SharedHeapReservation {
+    fn drop(&mut self) {
+        SHARED_HEAP_BUDGET.lock().release(self.owner, self.bytes);
+    }
+}
+
+/// Reserve `bytes` of the shared heap for the current task, so that the used and the
+/// reserved bytes stay within [SHARED_HEAP_LIMIT].
+///
+/// The shared heap allocations of the current task draw from the reservation, and the
+/// allocations of the other tasks fail once they would eat it, see [HeapBudget]. If the
+/// budget is not available, the domains are notified of the memory pressure the
+/// reservation would cause and the heap cache is compacted before trying again. Return
+/// `ENOMEM` if it is still not available.
+///
+/// The reserved bytes count as used when the pressure level is computed, so the
+/// allocations of the other domains push them to free memory instead of eating the budget.
+pub fn reserve_shared_heap(bytes: usize) -> LinuxResult<SharedHeapReservation> {
+    let owner = current_task();
+    if !SHARED_HEAP_BUDGET.lock().reserve(owner, bytes) {
+        let budget = SHARED_HEAP_BUDGET.lock();
+        let usage = budget.used() + budget.reserved() + bytes;
+        drop(budget);
+        check_memory_pressure(usage);
+        compact_shared_heap();
+        if !SHARED_HEAP_BUDGET.lock().reserve(owner, bytes) {
+            return Err(LinuxError::ENOMEM);
+        }
+    }
+    Ok(SharedHeapReservation { owner, bytes })
+}
+
+/// Set the shared heap budget reserved for the new domain when the domain `name` is
+/// upgraded, 0 reserves nothing
+pub fn set_upgrade_reserve(name: &str, bytes: usize) {
+    let mut reserves = UPGRADE_RESERVES.lock();
+    if bytes == 0 {
+        reserves.remove(name);
+    } else {
+        reserves.insert(name.to_string(), bytes);
+    }
+}
+
+/// Get the shared heap budget reserved for the new domain when the domain `name` is upgraded
+pub fn upgrade_reserve(name: &str) -> usize {
+    UPGRADE_RESERVES.lock().get(name).copied().unwrap_or(0)
+}
+
+/// Move the upgrade reserve of the domain `old_name` to `new_name`
+pub fn rename_upgrade_reserve(old_name: &str, new_name: &str) {
+    let mut reserves = UPGRADE_RESERVES.lock();
+    let _ = rename_key(&mut reserves, old_name, new_name);
+}
+
+/// Forget the upgrade reserve of the domain `name`
+pub fn remove_upgrade_reserve(name: &str) {
+    UPGRADE_RESERVES.lock().remove(name);
+}
+
+/// Find the domain which owns the shared heap allocation containing `addr`.
+///
+/// `addr` may point into the middle of the allocation. Return `None` if it is not
+/// in any live allocation.
+pub fn shared_data_owner(addr: usize) -> Option<u64> {
+    let heap = SHARED_HEAP.lock();
+    let (&start, entry) = heap.range(..=addr).next_back()?;
+    if addr == start || addr - start < entry.allocation.layout.size() {
+        Some(entry.allocation.domain_id())
+    } else {
+        None
+    }
+}
+
+/// Count the live shared heap allocations by the domain owning them.
+pub fn shared_data_owners() -> BTreeMap<u64, usize> {
     let heap = SHARED_HEAP.lock();
     let mut map = BTreeMap::new();
     heap.iter().for_each(|(_, v)| {
-        let id = v.domain_id();
+        let id = v.allocation.domain_id();
         let count = map.get(&id).unwrap_or(&0) + 1;
         map.insert(id, count);
     });
-    for (id, count) in map {
-        println_color!(34, "domain_id: {}, count: {}", id, count);
+    map
+}
+
+/// Count the live shared heap allocations owned by the domain `domain_id`.
+pub fn domain_shared_data(domain_id: u64) -> usize {
+    SHARED_HEAP
+        .lock()
+        .values()
+        .filter(|v| v.allocation.domain_id() == domain_id)
+        .count()
+}
+
+/// The live shared heap allocations owned by the domain `domain_id`, sorted by address.
+///
+/// At most `limit` allocations are listed, the others are only counted.
+pub fn domain_memory_map(domain_id: u64, limit: usize) -> SharedMemoryMap {
+    let heap = SHARED_HEAP.lock();
+    let allocations = heap
+        .iter()
+        .filter(|(_, v)| v.allocation.domain_id() == domain_id)
+        .map(|(&addr, v)| SharedAllocation {
+            addr,
+            size: v.allocation.layout.size(),
+            type_id: format!("{:?}", v.allocation.type_id),
+            trace_id: v.allocation.trace_id(),
+        });
+    SharedMemoryMap::from_sorted(allocations, limit)
+}
+
+/// The shared heap allocations made by one call into a domain.
+///
+/// A call which panics loses the `RRef`s it was building, they are still owned by the
+/// domain and nothing frees them until the domain is unloaded. The scope remembers where
+/// the call started, so [AllocScope::reclaim] can find the allocations made since then by
+/// the same task and still owned by the domain, those were never returned to the caller.
+pub struct AllocScope {
+    domain_id: u64,
+    task: usize,
+    seq: u64,
+}
+
+impl AllocScope {
+    /// Start the scope of a call into the domain `domain_id` on the current task
+    pub fn begin(domain_id: u64) -> Self {
+        Self {
+            domain_id,
+            task: current_task(),
+            seq: SHARED_HEAP_SEQ.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn domain_id(&self) -> u64 {
+        self.domain_id
+    }
+
+    /// Free the allocations made in the scope which the domain still owns, return the
+    /// number of allocations freed.
+    ///
+    /// It must only be called once the call has panicked and the domain will never run
+    /// again, see [corelib::domain_info::PanicAction::reclaims_call_allocations]: the
+    /// allocations the domain kept in its own state are freed as well.
+    pub fn reclaim(&self) -> usize {
+        let heap = SHARED_HEAP.lock();
+        let data = heap
+            .values()
+            .filter(|v| {
+                v.task == self.task
+                    && v.seq >= self.seq
+                    && v.allocation.domain_id() == self.domain_id
+            })
+            .map(|v| v.allocation)
+            .collect::<Vec<_>>();
+        drop(heap);
+        let count = data.len();
+        data.into_iter().for_each(|v| unsafe {
+            v.drop_fn();
+            SharedHeapAllocator::dealloc_allocation(v.value_pointer);
+        });
+        count
     }
-    println_color!(
-        34,
-        "<checkout_shared_data> shared heap size: {}",
-        heap.len()
-    );
 }
 
 pub enum FreeShared {
     Free,
-    NotFree(u64),
+    /// Move the shared data to the domain, except what the options free
+    NotFree(u64, ReplaceOptions),
 }
 
-pub fn free_domain_shared_data(id: u64, free_shared: FreeShared) {
+/// Drop and free the shared data, return what is freed
+fn free_allocations(data: Vec<SharedHeapAllocation>) -> FreeSummary {
+    let mut summary = FreeSummary::default();
+    data.into_iter().for_each(|v| unsafe {
+        summary.add(v.layout.size());
+        v.drop_fn();
+        SharedHeapAllocator::dealloc_allocation(v.value_pointer);
+    });
+    summary
+}
+
+/// Free the shared data of the domain or move it to another domain.
+///
+/// Return the number of the shared data freed or moved.
+pub fn free_domain_shared_data(id: u64, free_shared: FreeShared) -> usize {
     checkout_shared_data();
     let mut data = vec![];
     let heap = SHARED_HEAP.lock();
@@ -191,25 +551,41 @@ pub fn free_domain_shared_data(id: u64, free_shared: FreeShared) {
         heap.len()
     );
     heap.iter().for_each(|(_, v)| {
-        if v.domain_id() == id {
-            data.push(*v);
+        if v.allocation.domain_id() == id {
+            data.push(v.allocation);
         }
     });
     drop(heap);
     println_color!(34, "<free_domain_shared_data> for domain_id: {}", id);
     println_color!(34, "domain has {} data", data.len());
 
+    let count = data.len();
     match free_shared {
         FreeShared::Free => {
-            println_color!(34, "free_shared is Free, free {} data", data.len());
-            data.into_iter().for_each(|v| unsafe {
-                v.drop_fn();
-                SharedHeapAllocator.dealloc(v.value_pointer);
-            });
+            let summary = free_allocations(data);
+            println_color!(
+                34,
+                "<free_domain_shared_data> freed {} for domain {}",
+                summary,
+                id
+            );
         }
-        FreeShared::NotFree(domain_id) => {
+        FreeShared::NotFree(domain_id, options) => {
             println_color!(34, "free_shared is NotFree, do not free data");
-            data.into_iter().for_each(|v| v.set_domain_id(domain_id));
+            let (freed, moved): (Vec<_>, Vec<_>) = data
+                .into_iter()
+                .partition(|v| options.frees(v.resource_class()));
+            if !freed.is_empty() {
+                let summary = free_allocations(freed);
+                println_color!(
+                    34,
+                    "<free_domain_shared_data> freed {} not moved to domain {}",
+                    summary,
+                    domain_id
+                );
+            }
+            moved.into_iter().for_each(|v| v.set_domain_id(domain_id));
         }
     }
+    count
 }
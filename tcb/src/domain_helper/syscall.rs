@@ -1,21 +1,43 @@
-use alloc::{string::ToString, sync::Arc};
+use alloc::{boxed::Box, string::ToString, sync::Arc, vec::Vec};
 use core::{
     any::Any,
-    ffi::{c_char, c_int, c_long, c_uint, c_ulong, c_void},
+    ffi::{c_char, c_int, c_long, c_uint, c_ulong, c_ushort, c_void},
     sync::atomic::AtomicBool,
 };
 
-use corelib::{domain_info::DomainDataInfo, CoreFunction, LinuxError, LinuxResult};
-use interface::*;
+use corelib::{
+    domain_info::{
+        DomainDataInfo, LogTail, Manifest, ManifestEntry, PanicAction, PanicPolicy, ReplaceOptions,
+        SharedDataReport, UpgradeRecord,
+    },
+    CoreFunction, LinuxError, LinuxResult,
+};
+use interface::{empty_device::EmptyDeviceConfig, null_block::CacheMode, *};
 use kernel::bindings::*;
+use rref::{
+    wire::{Decode, Encode},
+    RRefVec,
+};
+use tcb_helper::{
+    affinity::affinity_cpu,
+    cancel::timeout_result,
+    domain::{format_domain_tags, set_domain_tag},
+    report::{AuditInput, AuditReport, DomainGraph, DomainNode, DomainReport, DomainState},
+    resource::alloc_page_count,
+    upgrade::{UpgradeCompatReport, UpgradeFreeze, UpgradeRequirement},
+};
 
 use crate::{
-    config::FRAME_BITS,
+    channel::{load_domain, unload_domain, TeardownMode},
+    config::{
+        FRAME_BITS, FRAME_SIZE, MAX_DOMAIN_ALLOC_PAGES, MAX_DOMAIN_TAG_BYTES,
+        MAX_MEMORY_MAP_ENTRIES,
+    },
     domain_helper::{resource::DOMAIN_RESOURCE, DOMAIN_CREATE, DOMAIN_INFO},
     domain_loader::creator,
     domain_proxy::{
         block_device::BlockDeviceDomainProxy, empty_device::EmptyDeviceDomainProxy,
-        logger::LogDomainProxy,
+        logger::LogDomainProxy, with_proxy, with_proxy_by_id, Proxy,
     },
 };
 
@@ -25,7 +47,13 @@ pub struct DomainSyscall;
 
 impl CoreFunction for DomainSyscall {
     fn sys_alloc_pages(&self, domain_id: u64, n: usize) -> *mut u8 {
-        let n = n.next_power_of_two();
+        let Some(n) = alloc_page_count(n, MAX_DOMAIN_ALLOC_PAGES) else {
+            warn!(
+                "[Domain: {}] alloc pages: invalid page count {}",
+                domain_id, n
+            );
+            return core::ptr::null_mut();
+        };
         let page = crate::mem::alloc_frames(n);
         // info!(
         //     "[Domain: {}] alloc pages: {}, range:[{:#x}-{:#x}]",
@@ -41,7 +69,13 @@ impl CoreFunction for DomainSyscall {
     }
 
     fn sys_free_pages(&self, domain_id: u64, p: *mut u8, n: usize) {
-        let n = n.next_power_of_two();
+        let Some(n) = alloc_page_count(n, MAX_DOMAIN_ALLOC_PAGES) else {
+            warn!(
+                "[Domain: {}] free pages: invalid page count {}",
+                domain_id, n
+            );
+            return;
+        };
         debug!("[Domain: {}] free pages: {}, ptr: {:p}", domain_id, n, p);
         DOMAIN_RESOURCE
             .lock()
@@ -49,8 +83,146 @@ impl CoreFunction for DomainSyscall {
         crate::mem::free_frames(p, n);
     }
 
-    fn sys_write_console(&self, s: &str) {
-        print_raw!("{}", s);
+    fn sys_frame_bits(&self) -> u32 {
+        FRAME_BITS as u32
+    }
+
+    fn sys_frame_size(&self) -> usize {
+        FRAME_SIZE
+    }
+
+    fn sys_write_console(&self, caller: u64, s: &str) {
+        let retagged = super::apply_log_prefix(caller, s);
+        let s = retagged.as_deref().unwrap_or(s);
+        if !super::write_domain_log(caller, s) {
+            print_raw!("{}", s);
+        }
+    }
+
+    fn sys_set_log_prefix(&self, caller: u64, domain_id: u64, prefix: &str) -> LinuxResult<()> {
+        if caller != domain_id {
+            return Err(LinuxError::EPERM);
+        }
+        if !super::domain_is_live(domain_id) {
+            return Err(LinuxError::EINVAL);
+        }
+        super::set_log_prefix(domain_id, prefix);
+        Ok(())
+    }
+
+    fn sys_bind_domain_log(&self, caller: u64, domain_id: u64, capacity: usize) -> LinuxResult<()> {
+        if caller != domain_id {
+            return Err(LinuxError::EPERM);
+        }
+        if !super::domain_is_live(domain_id) {
+            return Err(LinuxError::EINVAL);
+        }
+        super::bind_log_sink(domain_id, capacity)
+    }
+
+    fn sys_read_domain_log(
+        &self,
+        caller: u64,
+        domain_id: u64,
+        from_seq: u64,
+    ) -> LinuxResult<LogTail> {
+        if caller != domain_id {
+            return Err(LinuxError::EPERM);
+        }
+        super::read_domain_log(domain_id, from_seq)
+    }
+
+    fn sys_domain_local_alloc(&self, caller: u64, key: u64, size: usize) -> LinuxResult<*mut u8> {
+        super::alloc_domain_local(caller, key, size)
+    }
+
+    fn sys_domain_local_get(&self, caller: u64, key: u64) -> LinuxResult<(*mut u8, usize)> {
+        super::get_domain_local(caller, key)
+    }
+
+    fn sys_domain_set_affinity(
+        &self,
+        caller: u64,
+        domain_id: u64,
+        cpumask: u64,
+    ) -> LinuxResult<()> {
+        if caller != domain_id {
+            return Err(LinuxError::EPERM);
+        }
+        let name = DOMAIN_INFO
+            .lock()
+            .domain_list
+            .get(&domain_id)
+            .map(|data| data.name.clone())
+            .ok_or(LinuxError::EINVAL)?;
+        super::set_domain_affinity(&name, cpumask)
+    }
+
+    fn sys_domain_affinity(&self, domain_id: u64) -> Option<u64> {
+        super::domain_affinity(domain_id)
+    }
+
+    fn sys_set_domain_nice(&self, caller: u64, domain_id: u64, nice: i32) -> LinuxResult<()> {
+        if caller != domain_id {
+            return Err(LinuxError::EPERM);
+        }
+        let name = DOMAIN_INFO
+            .lock()
+            .domain_list
+            .get(&domain_id)
+            .map(|data| data.name.clone())
+            .ok_or(LinuxError::EINVAL)?;
+        super::set_domain_nice(&name, nice)
+    }
+
+    fn sys_domain_nice(&self, domain_id: u64) -> Option<i32> {
+        super::domain_nice(domain_id)
+    }
+
+    fn sys_set_domain_rate_limit(
+        &self,
+        caller: u64,
+        domain_id: u64,
+        calls_per_sec: u64,
+        burst: u64,
+    ) -> LinuxResult<()> {
+        if caller != domain_id {
+            return Err(LinuxError::EPERM);
+        }
+        with_proxy_by_id(domain_id, |p| p.set_rate_limit(calls_per_sec, burst))
+    }
+
+    fn sys_set_domain_tag(
+        &self,
+        caller: u64,
+        domain_id: u64,
+        key: &str,
+        value: &str,
+    ) -> LinuxResult<()> {
+        if caller != domain_id {
+            return Err(LinuxError::EPERM);
+        }
+        let mut info = DOMAIN_INFO.lock();
+        let data = info
+            .domain_list
+            .get_mut(&domain_id)
+            .ok_or(LinuxError::EINVAL)?;
+        set_domain_tag(&mut data.tags, key, value, MAX_DOMAIN_TAG_BYTES)
+    }
+
+    fn sys_get_domain_tags(&self, domain_id: u64) -> LinuxResult<RRefVec<u8>> {
+        let info = DOMAIN_INFO.lock();
+        let data = info.domain_list.get(&domain_id).ok_or(LinuxError::EINVAL)?;
+        Ok(RRefVec::from_slice(
+            format_domain_tags(&data.tags).as_bytes(),
+        ))
+    }
+
+    fn sys_domain_numa_node(&self, domain_id: u64) -> c_int {
+        match super::domain_affinity(domain_id) {
+            Some(mask) => unsafe { kernel::bindings::cpu_to_node(mask.trailing_zeros() as c_int) },
+            None => kernel::bindings::NUMA_NO_NODE,
+        }
     }
 
     fn sys_backtrace(&self, domain_id: u64) {
@@ -58,6 +230,8 @@ impl CoreFunction for DomainSyscall {
         info.domain_list
             .get_mut(&domain_id)
             .map(|d| d.panic_count += 1);
+        drop(info);
+        super::begin_unwind();
         unwind();
     }
 
@@ -65,8 +239,22 @@ impl CoreFunction for DomainSyscall {
         BLK_CRASH.load(core::sync::atomic::Ordering::Relaxed)
     }
 
-    fn sys_get_domain(&self, name: &str) -> Option<DomainType> {
-        super::query_domain(name)
+    fn sys_get_domain(&self, caller: u64, name: &str) -> Option<DomainType> {
+        let domain = super::query_domain(name)?;
+        super::record_dependency(caller, name);
+        Some(domain)
+    }
+
+    fn sys_domain_exists(&self, name: &str) -> bool {
+        super::domain_exists(name)
+    }
+
+    fn sys_get_domain_id(&self, name: &str) -> Option<u64> {
+        super::domain_id_by_name(name)
+    }
+
+    fn sys_domain_type(&self, name: &str) -> Option<DomainTypeRaw> {
+        super::domain_type(name)
     }
 
     fn sys_create_domain(
@@ -80,128 +268,499 @@ impl CoreFunction for DomainSyscall {
             .create_domain(domain_file_name, identifier)
     }
 
-    fn sys_register_domain(&self, ident: &str, ty: DomainTypeRaw, data: &[u8]) -> LinuxResult<()> {
-        creator::register_domain_elf(ident, data.to_vec(), ty);
-        Ok(())
+    fn sys_create_domain_id(
+        &self,
+        domain_file_name: &str,
+        identifier: &mut [u8],
+    ) -> LinuxResult<u64> {
+        self.sys_create_domain(domain_file_name, identifier)
+            .map(|domain| domain.domain_id())
     }
 
-    /// sys_update_domain - 系统调用：更新domain（热升级入口点）
-    /// 
-    /// 这是热升级的主要入口，处理不同类型的domain升级：
-    /// 1. 查找旧domain
-    /// 2. 根据domain类型创建新domain
-    /// 3. 调用代理层的replace方法执行原子替换
-    /// 4. 更新domain信息表
-    fn sys_update_domain(
+    fn sys_reserve_domain_id(&self) -> u64 {
+        super::reserve_domain_id()
+    }
+
+    fn sys_create_domain_with_id(
         &self,
-        old_domain_name: &str,   // 旧domain名称
-        new_domain_name: &str,   // 新domain名称（ELF文件名）
-        ty: DomainTypeRaw,       // domain类型
+        domain_id: u64,
+        domain_file_name: &str,
+        identifier: &str,
     ) -> LinuxResult<()> {
-        // 步骤1: 查找旧domain
-        let old_domain = super::query_domain(old_domain_name);
-        let old_domain_id = old_domain.as_ref().map(|d| d.domain_id());
-        
-        // 步骤2: 根据domain类型执行不同的升级逻辑
-        let (domain_info, new_domain_id) = match old_domain {
-            // 情况1: LogDomain类型
-            Some(DomainType::LogDomain(logger)) => {
-                let old_domain_id = logger.domain_id();
-                // 创建新domain实例，传递旧domain ID用于状态迁移
-                let (id, new_domain, loader) = creator::create_domain_or_empty::<LogDomainProxy, _>(
-                    ty,
-                    new_domain_name,
-                    None,
-                    Some(old_domain_id),  // 传递旧domain ID
-                );
-                let logger_proxy = logger.downcast_arc::<LogDomainProxy>().unwrap();
-                let domain_info = loader.domain_file_info();
-                
-                // 关键步骤：调用代理层的replace方法执行原子替换
-                logger_proxy.replace(new_domain, loader)?;
-                
-                println!(
-                    "日志domain热升级成功: {} -> {}",
-                    old_domain_name, new_domain_name
+        if super::domain_exists(identifier) {
+            return Err(LinuxError::EEXIST);
+        }
+        super::take_reserved_domain_id(domain_id)?;
+        create_domain_with_id(domain_id, domain_file_name, identifier)
+            .inspect_err(|_| super::return_reserved_domain_id(domain_id))
+    }
+
+    fn sys_create_domains(&self, manifest: RRefVec<u8>) -> LinuxResult<RRefVec<u8>> {
+        let manifest = Manifest::decode(&manifest)?;
+        let outcomes = manifest.create_all(create_manifest_entry, |entry| {
+            let res = unload_domain(&entry.identifier, TeardownMode::Sleep);
+            if let Err(e) = res {
+                warn!(
+                    "<sys_create_domains> failed to remove {}: {:?}",
+                    entry.identifier, e
                 );
-                Ok((domain_info, id))
             }
-            
-            // 情况2: EmptyDeviceDomain类型
-            Some(DomainType::EmptyDeviceDomain(empty_device)) => {
-                let old_domain_id = empty_device.domain_id();
-                let (id, new_domain, loader) = creator::create_domain_or_empty::<
-                    EmptyDeviceDomainProxy,
-                    _,
-                >(
-                    ty, new_domain_name, None, Some(old_domain_id)
-                );
-                let empty_device = empty_device
-                    .downcast_arc::<EmptyDeviceDomainProxy>()
-                    .unwrap();
-                let domain_info = loader.domain_file_info();
-                
-                // 执行原子替换
-                empty_device.replace(new_domain, loader)?;
-                
-                println!(
-                    "空设备domain热升级成功: {} -> {}",
-                    old_domain_name, new_domain_name
-                );
-                Ok((domain_info, id))
+        })?;
+        Ok(outcomes.encode())
+    }
+
+    fn sys_rename_domain(&self, old_name: &str, new_name: &str) -> LinuxResult<()> {
+        super::rename_domain(old_name, new_name)
+    }
+
+    fn sys_register_domain(&self, ident: &str, ty: DomainTypeRaw, data: &[u8]) -> LinuxResult<()> {
+        creator::register_domain_elf(ident, data.to_vec(), ty)
+    }
+
+    fn sys_unregister_domain(&self, ident: &str) -> LinuxResult<usize> {
+        creator::unregister_domain_elf(ident)
+    }
+
+    fn sys_register_domain_begin(
+        &self,
+        ident: &str,
+        ty: DomainTypeRaw,
+        total_len: usize,
+    ) -> LinuxResult<()> {
+        creator::register_domain_elf_begin(ident, ty, total_len)
+    }
+
+    fn sys_register_domain_chunk(
+        &self,
+        ident: &str,
+        offset: usize,
+        data: &[u8],
+    ) -> LinuxResult<()> {
+        creator::register_domain_elf_chunk(ident, offset, data)
+    }
+
+    fn sys_register_domain_finish(&self, ident: &str) -> LinuxResult<()> {
+        creator::register_domain_elf_finish(ident)
+    }
+
+    fn sys_set_registry_reloadable(&self, ident: &str, reloadable: bool) -> LinuxResult<()> {
+        creator::set_domain_elf_reloadable(ident, reloadable)
+    }
+
+    fn sys_trim_registry(&self, ident: &str) -> LinuxResult<usize> {
+        creator::trim_domain_elf(ident)
+    }
+
+    fn sys_trim_registry_all(&self) -> LinuxResult<usize> {
+        Ok(creator::trim_domain_elf_all())
+    }
+
+    fn sys_freeze_domain(&self, domain_name: &str) -> LinuxResult<()> {
+        with_proxy(domain_name, |p| p.freeze())
+    }
+
+    fn sys_thaw_domain(&self, domain_name: &str) -> LinuxResult<()> {
+        with_proxy(domain_name, |p| p.thaw())
+    }
+
+    fn sys_domain_is_upgrading(&self, domain_name: &str) -> LinuxResult<bool> {
+        with_proxy(domain_name, |p| Ok(p.is_upgrading()))
+    }
+
+    fn sys_domain_yield(&self, domain_id: u64) -> LinuxResult<()> {
+        with_proxy_by_id(domain_id, |p| p.yield_point())
+    }
+
+    fn sys_domain_is_ready(&self, domain_name: &str) -> LinuxResult<bool> {
+        with_proxy(domain_name, |p| Ok(p.is_ready()))
+    }
+
+    fn sys_domain_unready_calls(&self, domain_name: &str) -> LinuxResult<u64> {
+        with_proxy(domain_name, |p| Ok(p.unready_calls()))
+    }
+
+    fn sys_wait_domain_ready(&self, domain_name: &str, timeout_ms: u64) -> LinuxResult<()> {
+        with_proxy(domain_name, |p| p.wait_ready(timeout_ms))
+    }
+
+    fn sys_wait_domain_quiescent(&self, domain_name: &str, timeout_ms: u64) -> LinuxResult<()> {
+        with_proxy(domain_name, |p| p.wait_quiescent(timeout_ms))
+    }
+
+    fn sys_domain_warmup(&self, domain_id: u64) -> LinuxResult<usize> {
+        if !super::domain_is_live(domain_id) {
+            return Err(LinuxError::EINVAL);
+        }
+        Ok(super::warmup_domain_pages(domain_id))
+    }
+
+    fn sys_shutdown_all(&self) -> LinuxResult<usize> {
+        let mut domains = super::DOMAIN_CONTAINER
+            .lock()
+            .domains
+            .iter()
+            .map(|(name, domain)| (name.clone(), domain.clone()))
+            .collect::<Vec<_>>();
+        domains.sort_by_key(|(_, domain)| domain.domain_id());
+        // flush before the kernel shims are removed, which quiesces the block layer
+        for (name, domain) in domains.iter() {
+            if let Err(e) = domain.on_shutdown() {
+                warn!("[Domain: {}] on_shutdown failed: {:?}", name, e);
             }
-            
-            // 情况3: BlockDeviceDomain类型
-            Some(DomainType::BlockDeviceDomain(block_device)) => {
-                let old_domain_id = block_device.domain_id();
-                let (id, new_domain, loader) = creator::create_domain_or_empty::<
-                    BlockDeviceDomainProxy,
-                    _,
-                >(
-                    ty, new_domain_name, None, Some(old_domain_id)
-                );
-                let block_device = block_device
-                    .downcast_arc::<BlockDeviceDomainProxy>()
-                    .unwrap();
-                let domain_info = loader.domain_file_info();
-                
-                // 执行原子替换
-                block_device.replace(new_domain, loader)?;
-                
-                println!(
-                    "块设备domain热升级成功: {} -> {}",
-                    old_domain_name, new_domain_name
-                );
-                Ok((domain_info, id))
+        }
+        // the handles are dropped first, the unload waits for them
+        let names = domains
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect::<Vec<_>>();
+        let mut unloaded = 0;
+        for name in names {
+            match unload_domain(&name, TeardownMode::NoSleep) {
+                Ok(()) => unloaded += 1,
+                Err(e) => warn!("[Domain: {}] failed to tear down: {:?}", name, e),
             }
-            
-            // 情况4: 旧domain不存在
-            None => {
-                println!(
-                    "<sys_update_domain> 错误：找不到旧domain {:?}",
-                    old_domain_name
+        }
+        Ok(unloaded)
+    }
+
+    fn sys_force_srcu_barrier(&self) -> LinuxResult<usize> {
+        let domains = super::DOMAIN_CONTAINER
+            .lock()
+            .domains
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+        let count = domains.len();
+        // the container lock is not held, the barrier sleeps
+        for domain in domains {
+            Proxy::from_domain(&domain)?.srcu_barrier()?;
+        }
+        Ok(count)
+    }
+
+    /// sys_update_domain - 系统调用：更新domain（热升级入口点）
+    ///
+    /// 旧domain的状态迁移到新domain，见replace_domain
+    fn sys_update_domain(
+        &self,
+        old_domain_name: &str,
+        new_domain_name: &str,
+        ty: DomainTypeRaw,
+    ) -> LinuxResult<()> {
+        check_upgrade_freeze()?;
+        let options = ReplaceOptions::default();
+        self.replace_domain(old_domain_name, new_domain_name, ty, false, options)
+    }
+
+    fn sys_update_domain_with(
+        &self,
+        old_domain_name: &str,
+        new_domain_name: &str,
+        ty: DomainTypeRaw,
+        options: &ReplaceOptions,
+    ) -> LinuxResult<()> {
+        check_upgrade_freeze()?;
+        let options = options.clone();
+        self.replace_domain(old_domain_name, new_domain_name, ty, false, options)
+    }
+
+    fn sys_set_cache_mode(&self, domain_name: &str, mode: CacheMode) -> LinuxResult<()> {
+        match super::query_domain(domain_name) {
+            Some(DomainType::BlockDeviceDomain(block_device)) => block_device.set_cache_mode(mode),
+            _ => Err(LinuxError::EINVAL),
+        }
+    }
+
+    fn sys_set_queue_depth(&self, domain_name: &str, depth: u32) -> LinuxResult<()> {
+        with_proxy(domain_name, |p| p.block_device()?.set_queue_depth(depth))
+    }
+
+    fn sys_block_domain_pause(&self, domain_name: &str) -> LinuxResult<()> {
+        with_proxy(domain_name, |p| p.block_device()?.pause_io())
+    }
+
+    fn sys_block_domain_resume(&self, domain_name: &str) -> LinuxResult<()> {
+        with_proxy(domain_name, |p| p.block_device()?.resume_io())
+    }
+
+    #[cfg(feature = "fault_injection")]
+    fn sys_inject_latency(&self, caller: u64, domain_id: u64, us: u64) -> LinuxResult<()> {
+        if caller != domain_id {
+            return Err(LinuxError::EPERM);
+        }
+        if !super::domain_is_live(domain_id) {
+            return Err(LinuxError::EINVAL);
+        }
+        crate::domain_proxy::inject_latency(domain_id, us);
+        Ok(())
+    }
+
+    #[cfg(not(feature = "fault_injection"))]
+    fn sys_inject_latency(&self, _caller: u64, _domain_id: u64, _us: u64) -> LinuxResult<()> {
+        Err(LinuxError::ENOSYS)
+    }
+
+    fn sys_set_upgrade_reserve(&self, domain_name: &str, bytes: usize) -> LinuxResult<()> {
+        if !super::domain_exists(domain_name) {
+            return Err(LinuxError::EINVAL);
+        }
+        super::set_upgrade_reserve(domain_name, bytes);
+        Ok(())
+    }
+
+    fn sys_domain_metrics_reset(&self, domain_name: &str, reset_panics: bool) -> LinuxResult<()> {
+        let domain_id = with_proxy(domain_name, |p| {
+            p.reset_metrics();
+            Ok(p.domain_id())
+        })?;
+        if reset_panics {
+            if let Some(data) = DOMAIN_INFO.lock().domain_list.get_mut(&domain_id) {
+                data.panic_count = 0;
+            }
+            super::reset_domain_restarts(domain_name);
+        }
+        Ok(())
+    }
+
+    fn sys_domain_latency(&self, domain_name: &str) -> LinuxResult<RRefVec<u8>> {
+        with_proxy(domain_name, |p| Ok(p.latency().encode()))
+    }
+
+    fn sys_domain_call_counts(&self, domain_name: &str) -> LinuxResult<RRefVec<u8>> {
+        with_proxy(domain_name, |p| Ok(p.call_counts().encode()))
+    }
+
+    fn sys_domain_idle_ms(&self, domain_name: &str) -> LinuxResult<u64> {
+        with_proxy(domain_name, |p| Ok(p.idle_ms()))
+    }
+
+    fn sys_bench_pinned_path(&self, domain_name: &str, iterations: u64) -> LinuxResult<(u64, u64)> {
+        with_proxy(domain_name, |p| {
+            Ok(p.empty_device()?.bench_pinned_path(iterations))
+        })
+    }
+
+    fn sys_domain_touch(&self, domain_id: u64) -> LinuxResult<()> {
+        with_proxy_by_id(domain_id, |p| {
+            p.touch();
+            Ok(())
+        })
+    }
+
+    fn sys_domain_call(
+        &self,
+        domain_id: u64,
+        op: u32,
+        in_buf: RRefVec<u8>,
+    ) -> LinuxResult<RRefVec<u8>> {
+        let domain = super::query_domain_by_id(domain_id).ok_or(LinuxError::EINVAL)?;
+        domain.invoke(op, in_buf)
+    }
+
+    fn sys_domain_call_timeout(
+        &self,
+        domain_id: u64,
+        op: u32,
+        in_buf: RRefVec<u8>,
+        timeout_ms: u64,
+    ) -> LinuxResult<RRefVec<u8>> {
+        let domain = super::query_domain_by_id(domain_id).ok_or(LinuxError::EINVAL)?;
+        let call_id = super::begin_call_timeout(domain_id, timeout_ms);
+        let res = domain.invoke_interruptible(op, in_buf, call_id);
+        let timed_out = super::call_timed_out(call_id);
+        super::end_call(call_id);
+        if timed_out {
+            warn!(
+                "domain {}: call {} took more than {}ms",
+                domain_id, op, timeout_ms
+            );
+        }
+        timeout_result(res, timed_out)
+    }
+
+    fn sys_snapshot_domain(&self, domain_name: &str) -> LinuxResult<RRefVec<u8>> {
+        let domain = super::query_domain(domain_name).ok_or(LinuxError::EINVAL)?;
+        domain.export_state()
+    }
+
+    fn sys_restore_domain(&self, domain_name: &str, state: &RRefVec<u8>) -> LinuxResult<()> {
+        let domain = super::query_domain(domain_name).ok_or(LinuxError::EINVAL)?;
+        domain.import_state(state)
+    }
+
+    fn sys_clone_domain_with_state(&self, src_name: &str, new_name: &str) -> LinuxResult<u64> {
+        if super::domain_exists(new_name) {
+            return Err(LinuxError::EEXIST);
+        }
+        let src = super::query_domain(src_name).ok_or(LinuxError::EINVAL)?;
+        let entry = DOMAIN_INFO
+            .lock()
+            .domain_list
+            .get(&src.domain_id())
+            .map(|data| ManifestEntry {
+                file: data.file_info.name.clone(),
+                identifier: new_name.to_string(),
+                ty: data.ty,
+                args: Vec::new(),
+                deps: Vec::new(),
+                required: true,
+            })
+            .ok_or(LinuxError::EINVAL)?;
+        // a frozen domain blocks the export until it is thawed, and the state exported
+        // while the domain is replaced may come from either version
+        let epoch = src.epoch();
+        if domain_state(&src) == DomainState::Upgrading {
+            return Err(LinuxError::EBUSY);
+        }
+        let state = src.export_state()?;
+        if domain_state(&src) == DomainState::Upgrading || src.epoch() != epoch {
+            return Err(LinuxError::EBUSY);
+        }
+        let new_id = create_manifest_entry(&entry)?;
+        let res = super::query_domain(new_name)
+            .ok_or(LinuxError::ENOENT)
+            .and_then(|copy| copy.import_state(&state));
+        if let Err(e) = res {
+            if let Err(e) = unload_domain(new_name, TeardownMode::Sleep) {
+                warn!(
+                    "<sys_clone_domain_with_state> failed to remove {}: {:?}",
+                    new_name, e
                 );
-                Err(LinuxError::EINVAL)
             }
-        }?;  // 如果出错，这里会提前返回
-        
-        // 步骤3: 更新domain信息表
-        let domain_data = DomainDataInfo {
-            name: old_domain_name.to_string(),  // 保持名称不变
-            ty,
-            panic_count: 0,  // 重置panic计数
-            file_info: domain_info,
+            return Err(e);
+        }
+        Ok(new_id)
+    }
+
+    fn sys_domain_load_info(&self, domain_name: &str) -> LinuxResult<RRefVec<u8>> {
+        with_proxy(domain_name, |p| Ok(p.load_info().encode()))
+    }
+
+    fn sys_domain_describe(&self, domain_name: &str) -> LinuxResult<RRefVec<u8>> {
+        let domain = super::query_domain(domain_name).ok_or(LinuxError::EINVAL)?;
+        let id = domain.domain_id();
+        let data = DOMAIN_INFO
+            .lock()
+            .domain_list
+            .get(&id)
+            .cloned()
+            .ok_or(LinuxError::EINVAL)?;
+        let proxy = Proxy::from_domain(&domain)?;
+        let report = DomainReport {
+            id,
+            name: data.name,
+            ty: data.ty,
+            load_info: proxy.load_info(),
+            ready: proxy.is_ready(),
+            upgrading: proxy.is_upgrading(),
+            readers: proxy.in_flight(),
+            shared_data: super::domain_shared_data(id),
+            panic_count: data.panic_count,
+            restarts: super::domain_restarts(domain_name),
+            last_upgrade: super::upgrade_history(domain_name).pop(),
         };
+        Ok(RRefVec::from_slice(report.to_string().as_bytes()))
+    }
 
-        // 原子地更新全局domain信息
-        let mut info = DOMAIN_INFO.lock();
-        info.domain_list.remove(&old_domain_id.unwrap());  // 移除旧记录
-        info.domain_list.insert(new_domain_id, domain_data);  // 插入新记录
-        
-        println!("domain信息表更新完成: 旧ID={:?} -> 新ID={}", old_domain_id, new_domain_id);
-        Ok(())
+    fn sys_list_domains_filtered(
+        &self,
+        ty: Option<DomainTypeRaw>,
+        name_prefix: &str,
+        offset: usize,
+        limit: usize,
+    ) -> LinuxResult<RRefVec<u8>> {
+        if limit == 0 {
+            return Err(LinuxError::EINVAL);
+        }
+        let page = DOMAIN_INFO.lock().list_page(ty, name_prefix, offset, limit);
+        Ok(page.encode())
+    }
+
+    fn sys_export_domain_graph(&self) -> LinuxResult<RRefVec<u8>> {
+        let domains = DOMAIN_INFO
+            .lock()
+            .domain_list
+            .values()
+            .map(|data| (data.name.clone(), data.ty))
+            .collect::<Vec<_>>();
+        let nodes = domains
+            .into_iter()
+            .filter_map(|(name, ty)| {
+                let state = domain_state(&super::query_domain(&name)?);
+                Some(DomainNode { name, ty, state })
+            })
+            .collect();
+        let graph = DomainGraph {
+            nodes,
+            edges: super::domain_dependencies(),
+        };
+        Ok(RRefVec::from_slice(graph.to_string().as_bytes()))
+    }
+
+    fn sys_audit(&self) -> LinuxResult<RRefVec<u8>> {
+        // every table is read under its own lock, nothing is changed
+        let domains = DOMAIN_INFO
+            .lock()
+            .domain_list
+            .iter()
+            .map(|(&id, data)| (id, data.name.clone()))
+            .collect();
+        let input = AuditInput {
+            domains,
+            proxies: super::registered_domains(),
+            shared_data: super::shared_data_owners(),
+            pages: super::page_map_owners(),
+            quotas: super::local_data_quotas(),
+        };
+        let report = AuditReport::check(&input, rref::domain_id());
+        if !report.is_clean() {
+            warn!("<sys_audit> {} violations found", report.violations.len());
+        }
+        Ok(RRefVec::from_slice(report.to_string().as_bytes()))
+    }
+
+    fn sys_upgrade_history(&self, domain_name: &str) -> LinuxResult<RRefVec<u8>> {
+        if !super::domain_exists(domain_name) {
+            return Err(LinuxError::EINVAL);
+        }
+        Ok(super::upgrade_history(domain_name).encode())
     }
+
+    fn sys_check_upgrade_compat(
+        &self,
+        old_domain_name: &str,
+        new_domain_name: &str,
+        ty: DomainTypeRaw,
+    ) -> LinuxResult<RRefVec<u8>> {
+        let old_ty = super::domain_type(old_domain_name).ok_or(LinuxError::EINVAL)?;
+        let (new_ty, interface_version) = creator::domain_elf_metadata(new_domain_name)?;
+        if new_ty != ty {
+            return Err(LinuxError::EINVAL);
+        }
+        // the dependents hold a proxy of the old type, and every loaded domain is built
+        // against the interface of the kernel
+        let requirements = super::domain_dependents(old_domain_name)
+            .into_iter()
+            .map(|dependent| UpgradeRequirement {
+                dependent,
+                ty: old_ty,
+                interface_version: INTERFACE_VERSION,
+            })
+            .collect::<Vec<_>>();
+        let report = UpgradeCompatReport::check(&requirements, ty, interface_version);
+        if !report.is_compatible() {
+            warn!(
+                "<sys_check_upgrade_compat> {} -> {}: {} issues found",
+                old_domain_name,
+                new_domain_name,
+                report.issues.len()
+            );
+        }
+        Ok(RRefVec::from_slice(report.to_string().as_bytes()))
+    }
+
     fn sys_reload_domain(&self, domain_name: &str) -> LinuxResult<()> {
+        check_upgrade_freeze()?;
         let domain = super::query_domain(domain_name).ok_or(LinuxError::EINVAL)?;
         match domain {
             // todo!(release old domain's resource)
@@ -211,11 +770,95 @@ impl CoreFunction for DomainSyscall {
         }
     }
 
-    fn checkout_shared_data(&self) -> LinuxResult<()> {
-        crate::domain_helper::checkout_shared_data();
+    fn sys_device_read_interruptible(
+        &self,
+        domain_id: u64,
+        data: &mut RRefVec<u8>,
+    ) -> LinuxResult<usize> {
+        match super::query_domain_by_id(domain_id) {
+            Some(DomainType::EmptyDeviceDomain(empty_device)) => {
+                let call_id = super::begin_call(domain_id);
+                let res = empty_device.read_interruptible(data, call_id);
+                super::end_call(call_id);
+                // the count returned is always the `len()` of the buffer, whatever the
+                // domain set
+                res.map(|filled| {
+                    data.set_len(filled.min(data.len()));
+                    data.len()
+                })
+            }
+            _ => Err(LinuxError::EINVAL),
+        }
+    }
+
+    fn sys_cancel_call(&self, domain_id: u64) -> LinuxResult<usize> {
+        Ok(super::cancel_calls(domain_id))
+    }
+
+    fn sys_call_canceled(&self, call_id: u64) -> bool {
+        super::call_canceled(call_id)
+    }
+
+    fn sys_restart_domain(&self, domain_name: &str) -> LinuxResult<()> {
+        check_upgrade_freeze()?;
+        let (file_name, ty) = DOMAIN_INFO
+            .lock()
+            .domain_list
+            .values()
+            .find(|data| data.name == domain_name)
+            .map(|data| (data.file_info.name.clone(), data.ty))
+            .ok_or(LinuxError::EINVAL)?;
+        self.replace_domain(domain_name, &file_name, ty, true, ReplaceOptions::default())
+    }
+
+    fn sys_set_upgrade_freeze(&self, frozen: bool) {
+        UPGRADE_FREEZE.set(frozen);
+    }
+
+    fn sys_set_domain_policy(
+        &self,
+        domain_name: &str,
+        max_restarts: usize,
+        action: PanicAction,
+    ) -> LinuxResult<()> {
+        if !super::domain_exists(domain_name) {
+            return Err(LinuxError::EINVAL);
+        }
+        super::set_domain_policy(domain_name, max_restarts, action);
         Ok(())
     }
 
+    fn sys_set_panic_policy(
+        &self,
+        caller: u64,
+        domain_id: u64,
+        policy: PanicPolicy,
+    ) -> LinuxResult<()> {
+        if caller != domain_id {
+            return Err(LinuxError::EPERM);
+        }
+        super::set_panic_policy(domain_id, policy)
+    }
+
+    fn checkout_shared_data(&self) -> LinuxResult<SharedDataReport> {
+        Ok(crate::domain_helper::checkout_shared_data())
+    }
+
+    fn sys_compact_shared_heap(&self) -> LinuxResult<usize> {
+        Ok(crate::domain_helper::compact_shared_heap())
+    }
+
+    fn sys_shared_data_owner(&self, addr: usize) -> Option<u64> {
+        crate::domain_helper::shared_data_owner(addr)
+    }
+
+    fn sys_domain_memory_map(&self, domain_id: u64) -> LinuxResult<RRefVec<u8>> {
+        if !super::domain_is_live(domain_id) {
+            return Err(LinuxError::EINVAL);
+        }
+        Ok(super::domain_memory_map(domain_id, MAX_MEMORY_MAP_ENTRIES).encode())
+    }
+
     fn domain_info(&self) -> LinuxResult<Arc<dyn Any + Send + Sync>> {
         let info = DOMAIN_INFO.clone();
         Ok(info)
@@ -303,6 +946,30 @@ impl CoreFunction for DomainSyscall {
         unsafe { kernel::bindings::blk_queue_flag_clear(flag, q) }
     }
 
+    fn sys_blk_queue_max_hw_sectors(&self, q: *mut request_queue, max_hw_sectors: c_uint) {
+        unsafe { kernel::bindings::blk_queue_max_hw_sectors(q, max_hw_sectors) }
+    }
+
+    fn sys_blk_queue_max_segments(&self, q: *mut request_queue, max_segments: c_ushort) {
+        unsafe { kernel::bindings::blk_queue_max_segments(q, max_segments) }
+    }
+
+    fn sys_blk_queue_max_discard_sectors(
+        &self,
+        q: *mut request_queue,
+        max_discard_sectors: c_uint,
+    ) {
+        unsafe { kernel::bindings::blk_queue_max_discard_sectors(q, max_discard_sectors) }
+    }
+
+    fn sys_blk_queue_write_cache(&self, q: *mut request_queue, enabled: bool, fua: bool) {
+        unsafe { kernel::bindings::blk_queue_write_cache(q, enabled, fua) }
+    }
+
+    fn sys_blk_mq_update_nr_requests(&self, q: *mut request_queue, nr: c_uint) -> c_int {
+        unsafe { kernel::bindings::blk_mq_update_nr_requests(q, nr) }
+    }
+
     fn sys_del_gendisk(&self, disk: *mut gendisk) {
         unsafe { kernel::bindings::del_gendisk(disk) }
     }
@@ -419,17 +1086,272 @@ impl CoreFunction for DomainSyscall {
     }
 
     fn sys_hrtimer_cancel(&self, timer: *mut hrtimer) -> c_int {
-        unsafe { kernel::bindings::hrtimer_cancel(timer) }
+        let r = unsafe { kernel::bindings::hrtimer_cancel(timer) };
+        super::forget_domain_timer(timer);
+        r
     }
 
     fn sys_hrtimer_start_range_ns(
         &self,
+        domain_id: u64,
         timer: *mut hrtimer,
         tim: ktime_t,
         range_ns: u64_,
         mode: hrtimer_mode,
     ) {
-        unsafe { kernel::bindings::hrtimer_start_range_ns(timer, tim, range_ns, mode) }
+        super::record_domain_timer(domain_id, timer);
+        let Some(mask) = super::domain_affinity(domain_id) else {
+            unsafe { kernel::bindings::hrtimer_start_range_ns(timer, tim, range_ns, mode) };
+            return;
+        };
+        let mut start = HrtimerStart {
+            timer,
+            tim,
+            range_ns,
+            mode: mode | hrtimer_mode_HRTIMER_MODE_PINNED,
+        };
+        hrtimer_start_affine(mask, &mut start);
+    }
+}
+
+impl DomainSyscall {
+    /// replace_domain - 更新domain（热升级和重启的实现）
+    ///
+    /// 处理不同类型的domain升级，cold为true时新domain不继承旧domain的状态，
+    /// options决定旧domain的哪些共享数据迁移到新domain（日志domain的共享数据总是被释放）：
+    /// 1. 查找旧domain
+    /// 2. 根据domain类型创建新domain
+    /// 3. 调用代理层的replace方法执行原子替换
+    /// 4. 更新domain信息表
+    fn replace_domain(
+        &self,
+        old_domain_name: &str,   // 旧domain名称
+        new_domain_name: &str,   // 新domain名称（ELF文件名）
+        ty: DomainTypeRaw,       // domain类型
+        cold: bool,              // 冷重启：不把旧domain的状态迁移到新domain
+        options: ReplaceOptions, // 共享数据的迁移选项
+    ) -> LinuxResult<()> {
+        // 步骤0: 为新domain预留共享堆，新domain的init可能从共享堆分配内存
+        // 预留失败时直接返回ENOMEM，此时还没有加载新domain，旧domain不受影响
+        // 新domain的init在当前任务中运行，它的共享堆分配优先从预留中扣除，其他任务不能占用预留
+        // 预留在函数返回时释放剩余部分，此时新domain已经完成初始化
+        let reserve = super::upgrade_reserve(old_domain_name);
+        let Ok(_reservation) = super::reserve_shared_heap(reserve) else {
+            println!(
+                "<sys_update_domain> 错误：无法为 {:?} 预留 {} 字节共享堆",
+                old_domain_name, reserve
+            );
+            return Err(LinuxError::ENOMEM);
+        };
+
+        // 步骤1: 查找旧domain
+        let old_domain = super::query_domain(old_domain_name);
+        let old_domain_id = old_domain.as_ref().map(|d| d.domain_id());
+        // 记录升级开始的时间和旧domain的ELF名称，用于升级历史
+        let timestamp_ns = kernel::time::Ktime::ktime_get().to_ns();
+        let old_file_name = old_domain_id.and_then(|id| {
+            DOMAIN_INFO
+                .lock()
+                .domain_list
+                .get(&id)
+                .map(|data| data.file_info.name.clone())
+        });
+        // 新domain的类型必须与旧domain兼容，否则旧domain的代理无法调用新domain
+        if let Some(old) = &old_domain {
+            if !old.to_raw().can_upgrade_to(ty) {
+                println!(
+                    "<sys_update_domain> 错误：domain {:?} 的类型 {:?} 不能升级为 {:?}",
+                    old_domain_name,
+                    old.to_raw(),
+                    ty
+                );
+                return Err(LinuxError::EINVAL);
+            }
+        }
+
+        // 步骤2: 根据domain类型执行不同的升级逻辑
+        // 代理类型与domain类型不匹配时返回EINVAL，此时还没有创建新domain
+        let mismatch = || {
+            println!(
+                "<sys_update_domain> 错误：domain {:?} 的代理类型不匹配",
+                old_domain_name
+            );
+            LinuxError::EINVAL
+        };
+        let res = match &old_domain {
+            // 情况1: LogDomain类型
+            Some(domain @ DomainType::LogDomain(_)) => {
+                let logger_proxy = domain.as_logger::<LogDomainProxy>().ok_or_else(mismatch)?;
+                let old_domain_id = logger_proxy.domain_id();
+                // 创建新domain实例，传递旧domain ID用于状态迁移
+                let (id, new_domain, loader) = creator::create_domain_or_empty::<LogDomainProxy, _>(
+                    ty,
+                    new_domain_name,
+                    None,
+                    (!cold).then_some(old_domain_id), // 传递旧domain ID
+                );
+                let domain_info = loader.domain_file_info();
+
+                // 关键步骤：调用代理层的replace方法执行原子替换
+                let res = logger_proxy.replace(new_domain, loader);
+
+                if res.is_ok() {
+                    println!(
+                        "日志domain热升级成功: {} -> {}",
+                        old_domain_name, new_domain_name
+                    );
+                }
+                res.map(|drain_iterations| (domain_info, id, drain_iterations))
+            }
+
+            // 情况2: EmptyDeviceDomain类型
+            Some(domain @ DomainType::EmptyDeviceDomain(_)) => {
+                let empty_device = domain
+                    .as_empty_device::<EmptyDeviceDomainProxy>()
+                    .ok_or_else(mismatch)?;
+                // 从不热升级的domain返回EPERM，此时还没有创建新domain
+                if empty_device.is_pinned() {
+                    println!(
+                        "<sys_update_domain> 错误：domain {:?} 不允许热升级",
+                        old_domain_name
+                    );
+                    return Err(LinuxError::EPERM);
+                }
+                let old_domain_id = empty_device.domain_id();
+                let (id, new_domain, loader) =
+                    creator::create_domain_or_empty::<EmptyDeviceDomainProxy, _>(
+                        ty,
+                        new_domain_name,
+                        None,
+                        (!cold).then_some(old_domain_id),
+                    );
+                let domain_info = loader.domain_file_info();
+
+                // 执行原子替换
+                let res = empty_device.replace(new_domain, loader, options);
+
+                if res.is_ok() {
+                    println!(
+                        "空设备domain热升级成功: {} -> {}",
+                        old_domain_name, new_domain_name
+                    );
+                }
+                res.map(|drain_iterations| (domain_info, id, drain_iterations))
+            }
+
+            // 情况3: BlockDeviceDomain类型
+            Some(domain @ DomainType::BlockDeviceDomain(_)) => {
+                let block_device = domain
+                    .as_block_device::<BlockDeviceDomainProxy>()
+                    .ok_or_else(mismatch)?;
+                let old_domain_id = block_device.domain_id();
+                let (id, new_domain, loader) =
+                    creator::create_domain_or_empty::<BlockDeviceDomainProxy, _>(
+                        ty,
+                        new_domain_name,
+                        None,
+                        (!cold).then_some(old_domain_id),
+                    );
+                let domain_info = loader.domain_file_info();
+
+                // 执行原子替换
+                let res = block_device.replace(new_domain, loader, options);
+
+                if res.is_ok() {
+                    println!(
+                        "块设备domain热升级成功: {} -> {}",
+                        old_domain_name, new_domain_name
+                    );
+                }
+                res.map(|drain_iterations| (domain_info, id, drain_iterations))
+            }
+
+            // 情况4: 旧domain不存在
+            None => {
+                println!(
+                    "<sys_update_domain> 错误：找不到旧domain {:?}",
+                    old_domain_name
+                );
+                Err(LinuxError::EINVAL)
+            }
+        };
+
+        // 记录本次升级，找不到旧domain时不记录
+        if let Some(from) = old_file_name {
+            super::record_upgrade(
+                old_domain_name,
+                UpgradeRecord {
+                    timestamp_ns,
+                    from,
+                    to: new_domain_name.to_string(),
+                    success: res.is_ok(),
+                    drain_iterations: res.as_ref().map_or(0, |(_, _, n)| *n),
+                },
+            );
+        }
+        let (domain_info, new_domain_id, _) = res?; // 如果出错，这里会提前返回
+
+        // 步骤3: 更新domain信息表
+        // 原子地更新全局domain信息
+        let mut info = DOMAIN_INFO.lock();
+        let old_data = info.domain_list.remove(&old_domain_id.unwrap()); // 移除旧记录
+        let domain_data = DomainDataInfo {
+            name: old_domain_name.to_string(), // 保持名称不变
+            ty,
+            panic_count: 0, // 重置panic计数
+            file_info: domain_info,
+            tags: old_data.map(|data| data.tags).unwrap_or_default(), // 保留运维标签
+        };
+        info.domain_list.insert(new_domain_id, domain_data); // 插入新记录
+
+        println!(
+            "domain信息表更新完成: 旧ID={:?} -> 新ID={}",
+            old_domain_id, new_domain_id
+        );
+        Ok(())
+    }
+}
+
+/// The arguments of `hrtimer_start_range_ns` sent to another CPU
+struct HrtimerStart {
+    timer: *mut hrtimer,
+    tim: ktime_t,
+    range_ns: u64_,
+    mode: hrtimer_mode,
+}
+
+unsafe extern "C" fn hrtimer_start_remote(info: *mut c_void) {
+    let start = unsafe { &*(info as *const HrtimerStart) };
+    unsafe {
+        kernel::bindings::hrtimer_start_range_ns(start.timer, start.tim, start.range_ns, start.mode)
+    }
+}
+
+/// Start a pinned timer on a CPU in `mask`.
+///
+/// The timer is started on the current CPU if it is in `mask`, otherwise on the first CPU
+/// of `mask` by an IPI. It falls back to the current CPU if the interrupts are disabled,
+/// because the IPI can not be waited then, or if the CPU went offline.
+fn hrtimer_start_affine(mask: u64, start: &mut HrtimerStart) {
+    let info = start as *mut HrtimerStart as *mut c_void;
+    let cpu = unsafe { kernel::bindings::get_cpu() } as u32;
+    let target = affinity_cpu(mask, cpu);
+    if target == cpu {
+        unsafe { hrtimer_start_remote(info) };
+        unsafe { kernel::bindings::put_cpu() };
+        return;
+    }
+    unsafe { kernel::bindings::put_cpu() };
+    let target = target as c_int;
+    let res = if unsafe { kernel::bindings::irqs_disabled() } == 0 {
+        unsafe {
+            kernel::bindings::smp_call_function_single(target, Some(hrtimer_start_remote), info, 1)
+        }
+    } else {
+        -1
+    };
+    if res != 0 {
+        unsafe { hrtimer_start_remote(info) };
     }
 }
 
@@ -437,3 +1359,104 @@ static BLK_CRASH: AtomicBool = AtomicBool::new(true);
 fn unwind() {
     BLK_CRASH.store(false, core::sync::atomic::Ordering::Relaxed);
 }
+
+/// Create the domain `identifier` with the reserved id `domain_id` and the default
+/// config, see `sys_create_domain_with_id`
+///
+/// A block device domain is loaded through the block device shim, which allocates its
+/// own id, so it can not use a reserved id.
+pub(crate) fn create_domain_with_id(
+    domain_id: u64,
+    domain_file_name: &str,
+    identifier: &str,
+) -> LinuxResult<()> {
+    let (ty, _) = creator::domain_elf_metadata(domain_file_name)?;
+    let (domain, file_info) = match ty {
+        DomainTypeRaw::EmptyDeviceDomain => {
+            let (empty_device, file_info) = creator::create_domain_with_id::<
+                EmptyDeviceDomainProxy,
+                _,
+            >(ty, domain_file_name, domain_id)?;
+            empty_device.init_by_box(Box::new(EmptyDeviceConfig::default()))?;
+            (DomainType::EmptyDeviceDomain(empty_device), file_info)
+        }
+        DomainTypeRaw::LogDomain => {
+            let (logger, file_info) = creator::create_domain_with_id::<LogDomainProxy, _>(
+                ty,
+                domain_file_name,
+                domain_id,
+            )?;
+            logger.init_by_box(Box::new(()))?;
+            (DomainType::LogDomain(logger), file_info)
+        }
+        DomainTypeRaw::BlockDeviceDomain => return Err(LinuxError::EINVAL),
+    };
+    crate::register_domain!(identifier, file_info, domain, true);
+    Ok(())
+}
+
+/// Create the domain of a manifest entry and register it with its identifier
+///
+/// The block devices are loaded like the command channel does, with the default config.
+fn create_manifest_entry(entry: &ManifestEntry) -> LinuxResult<u64> {
+    if super::domain_exists(&entry.identifier) {
+        return Err(LinuxError::EEXIST);
+    }
+    let (domain, file_info) = match entry.ty {
+        DomainTypeRaw::EmptyDeviceDomain => {
+            let config = manifest_args::<EmptyDeviceConfig>(&entry.args)?;
+            let (empty_device, file_info) =
+                crate::create_domain!(EmptyDeviceDomainProxy, entry.ty, &entry.file)?;
+            empty_device.init_by_box(Box::new(config))?;
+            (DomainType::EmptyDeviceDomain(empty_device), file_info)
+        }
+        DomainTypeRaw::LogDomain => {
+            if !entry.args.is_empty() {
+                return Err(LinuxError::EINVAL);
+            }
+            let (logger, file_info) = crate::create_domain!(LogDomainProxy, entry.ty, &entry.file)?;
+            logger.init_by_box(Box::new(()))?;
+            (DomainType::LogDomain(logger), file_info)
+        }
+        DomainTypeRaw::BlockDeviceDomain => {
+            if !entry.args.is_empty() {
+                return Err(LinuxError::EINVAL);
+            }
+            load_domain(&entry.file, &entry.identifier, entry.ty)?;
+            return super::query_domain(&entry.identifier)
+                .map(|domain| domain.domain_id())
+                .ok_or(LinuxError::ENOENT);
+        }
+    };
+    // the proxy falls back to an empty domain if the ELF is not registered
+    if domain.domain_id() == u64::MAX {
+        return Err(LinuxError::ENOENT);
+    }
+    let domain_id = domain.domain_id();
+    crate::register_domain!(&entry.identifier, file_info, domain, true);
+    Ok(domain_id)
+}
+
+/// Decode the config of a manifest entry, no args mean the default config
+fn manifest_args<T: Decode + Default>(args: &[u8]) -> LinuxResult<T> {
+    if args.is_empty() {
+        Ok(T::default())
+    } else {
+        T::decode_from_slice(args)
+    }
+}
+
+/// The state of the domain as seen by its proxy
+fn domain_state(domain: &DomainType) -> DomainState {
+    match Proxy::from_domain(domain) {
+        Ok(proxy) => DomainState::new(proxy.is_ready(), proxy.is_upgrading()),
+        Err(_) => DomainState::Running,
+    }
+}
+
+static UPGRADE_FREEZE: UpgradeFreeze = UpgradeFreeze::new();
+
+/// Return `EAGAIN` if the upgrades are frozen, see [UpgradeFreeze]
+fn check_upgrade_freeze() -> LinuxResult<()> {
+    UPGRADE_FREEZE.check()
+}
@@ -0,0 +1,44 @@
+use alloc::{
+    collections::BTreeMap,
+    string::{String, ToString},
+    vec::Vec,
+};
+
+use corelib::domain_info::UpgradeRecord;
+use ksync::Mutex;
+use tcb_helper::{domain::rename_key, upgrade::UpgradeHistory};
+
+use crate::config::MAX_UPGRADE_HISTORY;
+
+/// The recent upgrade records of the domains, indexed by domain name
+static UPGRADE_HISTORY: Mutex<BTreeMap<String, UpgradeHistory>> = Mutex::new(BTreeMap::new());
+
+/// Append an upgrade record of the domain `name`, the oldest record is dropped
+/// if there are already [MAX_UPGRADE_HISTORY] records.
+pub fn record_upgrade(name: &str, record: UpgradeRecord) {
+    let mut history = UPGRADE_HISTORY.lock();
+    history
+        .entry(name.to_string())
+        .or_default()
+        .record(record, MAX_UPGRADE_HISTORY);
+}
+
+/// Get the upgrade records of the domain `name`, the oldest first
+pub fn upgrade_history(name: &str) -> Vec<UpgradeRecord> {
+    UPGRADE_HISTORY
+        .lock()
+        .get(name)
+        .map(UpgradeHistory::records)
+        .unwrap_or_default()
+}
+
+/// Move the upgrade records of the domain `old_name` to `new_name`
+pub fn rename_upgrade_history(old_name: &str, new_name: &str) {
+    let mut history = UPGRADE_HISTORY.lock();
+    let _ = rename_key(&mut history, old_name, new_name);
+}
+
+/// Forget the upgrade records of the domain `name`
+pub fn remove_upgrade_history(name: &str) {
+    UPGRADE_HISTORY.lock().remove(name);
+}
@@ -0,0 +1,147 @@
+use alloc::{
+    collections::{BTreeMap, VecDeque},
+    string::{String, ToString},
+};
+
+use corelib::{
+    domain_info::{PanicAction, PanicPolicy},
+    LinuxError, LinuxResult,
+};
+use kernel::workqueue::StaticWork;
+use ksync::Mutex;
+use tcb_helper::{affinity::affinity_cpu, domain::rename_key, watchdog::Watchdog};
+
+use crate::{
+    config::PINNED_DOMAINS,
+    domain_helper::{domain_affinity_by_name, run_with_domain_nice, DOMAIN_INFO, DOMAIN_SYS},
+};
+
+/// The panic policies of the domains, indexed by domain name
+///
+/// The domain keeps its name when it is restarted, so the restart count survives the
+/// restarts. A domain without a policy follows its [PanicPolicy].
+static WATCHDOG: Mutex<BTreeMap<String, Watchdog>> = Mutex::new(BTreeMap::new());
+
+/// The panic policies set by `sys_set_panic_policy`, indexed by domain name
+///
+/// The domains without a policy are fenced, see [PanicPolicy].
+static PANIC_POLICY: Mutex<BTreeMap<String, PanicPolicy>> = Mutex::new(BTreeMap::new());
+
+/// Set how the domain `domain_id` responds to a panicking call, the policy is kept across
+/// its restarts and upgrades. Return `EINVAL` if the domain is not registered
+pub fn set_panic_policy(domain_id: u64, policy: PanicPolicy) -> LinuxResult<()> {
+    let name = DOMAIN_INFO
+        .lock()
+        .domain_list
+        .get(&domain_id)
+        .map(|data| data.name.clone())
+        .ok_or(LinuxError::EINVAL)?;
+    PANIC_POLICY.lock().insert(name, policy);
+    Ok(())
+}
+
+/// Set the panic policy of the domain `name` and reset its restart count
+pub fn set_domain_policy(name: &str, max_restarts: usize, action: PanicAction) {
+    WATCHDOG
+        .lock()
+        .insert(name.to_string(), Watchdog::new(max_restarts, action));
+}
+
+/// Get how many times the domain `name` has been restarted by the watchdog
+pub fn domain_restarts(name: &str) -> usize {
+    WATCHDOG.lock().get(name).map_or(0, |w| w.restarts())
+}
+
+/// Reset the restart count of the domain `name`, its policy is kept
+pub fn reset_domain_restarts(name: &str) {
+    if let Some(w) = WATCHDOG.lock().get_mut(name) {
+        w.reset_restarts();
+    }
+}
+
+/// Decide what to do with the domain `domain_id` whose call has just panicked.
+///
+/// Return the action and the name of the domain. The watchdog policy, see
+/// [Watchdog::on_panic], is combined with the panic policy of the domain, see
+/// [PanicPolicy::action]. A `Restart` must be run by [schedule_restart]. A domain in
+/// [PINNED_DOMAINS] can not be restarted, it is disabled instead.
+pub fn on_domain_panic(domain_id: u64) -> Option<(PanicAction, String)> {
+    let (name, pinned) = DOMAIN_INFO.lock().domain_list.get(&domain_id).map(|data| {
+        let pinned = PINNED_DOMAINS.contains(&data.file_info.name.as_str());
+        (data.name.clone(), pinned)
+    })?;
+    let policy = PANIC_POLICY.lock().get(&name).copied().unwrap_or_default();
+    // an aborting domain is never restarted, so its watchdog is not asked
+    let action = match WATCHDOG.lock().get_mut(&name) {
+        Some(w) if policy != PanicPolicy::Abort && pinned => Some(w.on_panic_pinned()),
+        Some(w) if policy != PanicPolicy::Abort => Some(w.on_panic()),
+        _ => None,
+    };
+    Some((policy.action(action), name))
+}
+
+/// The domains whose restart is decided but not run yet
+static RESTARTS: Mutex<VecDeque<String>> = Mutex::new(VecDeque::new());
+/// Restart the domains in [RESTARTS] from the system workqueue
+static RESTART_WORK: StaticWork = StaticWork::new(run_restarts);
+
+/// Restart the domain `name` later from the system workqueue.
+///
+/// The panic is caught in the proxy call, which may run in atomic context, e.g. the
+/// completion of a block request, while a restart sleeps. It does not sleep.
+///
+/// The work runs on a CPU in the affinity of the domain, see [affinity_cpu]. If it is
+/// already queued for another domain, the restart runs on the CPU of that one.
+pub fn schedule_restart(name: String) {
+    let affinity = domain_affinity_by_name(&name);
+    RESTARTS.lock().push_back(name);
+    match affinity {
+        Some(mask) => {
+            let cpu = unsafe { kernel::bindings::get_cpu() } as u32;
+            RESTART_WORK.schedule_on(affinity_cpu(mask, cpu));
+            unsafe { kernel::bindings::put_cpu() };
+        }
+        None => {
+            RESTART_WORK.schedule();
+        }
+    }
+}
+
+fn run_restarts() {
+    loop {
+        let Some(name) = RESTARTS.lock().pop_front() else {
+            return;
+        };
+        let res = run_with_domain_nice(&name, || DOMAIN_SYS.sys_restart_domain(&name));
+        if let Some(w) = WATCHDOG.lock().get_mut(&name) {
+            w.restart_done(res.is_ok());
+        }
+        match res {
+            Ok(()) => warn!(
+                "domain {}: restarted ({} restarts)",
+                name,
+                domain_restarts(&name)
+            ),
+            Err(e) => warn!("domain {}: failed to restart: {:?}", name, e),
+        }
+    }
+}
+
+/// Move the watchdog and panic policies of the domain `old_name` to `new_name`
+pub fn rename_watchdog(old_name: &str, new_name: &str) {
+    let mut watchdog = WATCHDOG.lock();
+    let _ = rename_key(&mut watchdog, old_name, new_name);
+    let mut policies = PANIC_POLICY.lock();
+    let _ = rename_key(&mut policies, old_name, new_name);
+    RESTARTS
+        .lock()
+        .iter_mut()
+        .filter(|name| *name == old_name)
+        .for_each(|name| *name = new_name.to_string());
+}
+
+/// Forget the watchdog and panic policies of the domain `name`
+pub fn remove_watchdog(name: &str) {
+    WATCHDOG.lock().remove(name);
+    PANIC_POLICY.lock().remove(name);
+}
@@ -1,17 +1,20 @@
 use alloc::{
     boxed::Box,
     collections::BTreeMap,
+    format,
     string::{String, ToString},
     sync::Arc,
     vec::Vec,
 };
 
-use corelib::{domain_info::DomainFileInfo, LinuxResult};
+use corelib::{domain_info::DomainFileInfo, LinuxError, LinuxResult};
 use interface::*;
-use ksync::RwLock;
+use ksync::{Mutex, RwLock};
+use tcb_helper::loader::{blob_hash, share_blob, write_identifier, ChunkedElf};
 
 use crate::{
-    domain_helper::{alloc_domain_id, DomainCreate, DOMAIN_INFO},
+    config::PINNED_DOMAINS,
+    domain_helper::{self, alloc_domain_id, query_domain, DomainCreate, DOMAIN_INFO},
     domain_loader::loader::{DomainCall, DomainLoader},
     domain_proxy::*,
 };
@@ -21,11 +24,32 @@ static DOMAIN_ELF: RwLock<BTreeMap<String, DomainData>> = RwLock::new(BTreeMap::
 #[derive(Clone)]
 struct DomainData {
     ty: DomainTypeRaw,
+    /// The identifiers registered with the same bytes share the data, it is freed when the
+    /// last identifier is unregistered and the last domain created from it is gone
     data: Arc<Vec<u8>>,
+    /// The [blob_hash] of `data`
+    hash: u64,
+    /// The elf data is kept by [trim_domain_elf] so that the domain can be reloaded
+    reloadable: bool,
+}
+
+/// The domain elf data which is being registered chunk by chunk.
+static DOMAIN_ELF_PENDING: Mutex<BTreeMap<String, PendingDomainData>> = Mutex::new(BTreeMap::new());
+
+struct PendingDomainData {
+    ty: DomainTypeRaw,
+    elf: ChunkedElf,
 }
 
 /// Register the domain elf data with the given identifier.
-pub fn register_domain_elf(domain_file_name: &str, elf: Vec<u8>, ty: DomainTypeRaw) {
+///
+/// Return `ENOEXEC` if the domain is not built against the same `interface` as the kernel.
+pub fn register_domain_elf(
+    domain_file_name: &str,
+    elf: Vec<u8>,
+    ty: DomainTypeRaw,
+) -> LinuxResult<()> {
+    check_interface_version(domain_file_name, &elf)?;
     let elf_len = elf.len();
     let mut binding = DOMAIN_ELF.write();
 
@@ -34,14 +58,24 @@ pub fn register_domain_elf(domain_file_name: &str, elf: Vec<u8>, ty: DomainTypeR
         .any(|(k, f)| k == domain_file_name && elf.len() == f.data.len())
     {
         println!("Domain {} already registered", domain_file_name);
-        return;
+        return Ok(());
+    }
+    let hash = blob_hash(&elf);
+    let (data, shared) = share_blob(binding.iter().map(|(k, f)| (k, f.hash, &f.data)), hash, elf);
+    match shared {
+        Some(k) => println!(
+            "<register domain>: {}, share the data of {}",
+            domain_file_name, k
+        ),
+        None => println!("<register domain>: {}", domain_file_name),
     }
-    println!("<register domain>: {}", domain_file_name);
     binding.insert(
         domain_file_name.to_string(),
         DomainData {
             ty,
-            data: Arc::new(elf),
+            data,
+            hash,
+            reloadable: false,
         },
     );
     // update domain info
@@ -52,13 +86,196 @@ pub fn register_domain_elf(domain_file_name: &str, elf: Vec<u8>, ty: DomainTypeR
         .entry(ty)
         .or_default()
         .push(file_info);
+    Ok(())
+}
+
+/// Check the interface version recorded in the domain elf data.
+///
+/// A domain built against another version of `interface` may have a different trait layout,
+/// so it must not be loaded.
+fn check_interface_version(domain_file_name: &str, elf: &[u8]) -> LinuxResult<()> {
+    match loader::interface_version(elf) {
+        Some(INTERFACE_VERSION) => Ok(()),
+        version => {
+            println!(
+                "Domain {} is built against interface version {:?}, expect {}",
+                domain_file_name, version, INTERFACE_VERSION
+            );
+            Err(LinuxError::ENOEXEC)
+        }
+    }
+}
+
+/// Start to register the domain elf data with the given identifier chunk by chunk.
+///
+/// The data is accumulated by [register_domain_elf_chunk] and registered by
+/// [register_domain_elf_finish].
+pub fn register_domain_elf_begin(
+    domain_file_name: &str,
+    ty: DomainTypeRaw,
+    total_len: usize,
+) -> LinuxResult<()> {
+    let elf = ChunkedElf::new(total_len)?;
+    let mut pending = DOMAIN_ELF_PENDING.lock();
+    if pending.contains_key(domain_file_name) {
+        println!("Domain {} is already being registered", domain_file_name);
+        return Err(LinuxError::EBUSY);
+    }
+    println!(
+        "<register domain begin>: {}, total size: {}",
+        domain_file_name, total_len
+    );
+    pending.insert(domain_file_name.to_string(), PendingDomainData { ty, elf });
+    Ok(())
+}
+
+/// Append a chunk of the domain elf data.
+///
+/// The chunks must be sent in order, `offset` should be the length of the data received so far.
+pub fn register_domain_elf_chunk(
+    domain_file_name: &str,
+    offset: usize,
+    data: &[u8],
+) -> LinuxResult<()> {
+    let mut pending = DOMAIN_ELF_PENDING.lock();
+    let domain_data = pending
+        .get_mut(domain_file_name)
+        .ok_or(LinuxError::ENOENT)?;
+    domain_data.elf.append(offset, data).inspect_err(|_| {
+        println!(
+            "Domain {} chunk at offset {} of {} bytes is refused, received {} of {} bytes",
+            domain_file_name,
+            offset,
+            data.len(),
+            domain_data.elf.received(),
+            domain_data.elf.total_len()
+        );
+    })
+}
+
+/// Finish the registration of the domain elf data which is sent chunk by chunk.
+///
+/// If the received data is not the same length as declared in [register_domain_elf_begin],
+/// the pending data will be discarded.
+pub fn register_domain_elf_finish(domain_file_name: &str) -> LinuxResult<()> {
+    let domain_data = DOMAIN_ELF_PENDING
+        .lock()
+        .remove(domain_file_name)
+        .ok_or(LinuxError::ENOENT)?;
+    let (received, total_len) = (domain_data.elf.received(), domain_data.elf.total_len());
+    let data = domain_data.elf.finish().inspect_err(|_| {
+        println!(
+            "Domain {} is incomplete, expect {} bytes, but got {}",
+            domain_file_name, total_len, received
+        );
+    })?;
+    register_domain_elf(domain_file_name, data, domain_data.ty)
 }
 
 /// Unregister the domain elf data with the given identifier.
-#[allow(unused)]
-pub fn unregister_domain_elf(identifier: &str) {
+///
+/// The data is only released when no other identifier shares it and no loaded domain
+/// uses it. Return the bytes released right now, or `ENOENT` if it is not registered.
+pub fn unregister_domain_elf(identifier: &str) -> LinuxResult<usize> {
+    let domain_data = DOMAIN_ELF
+        .write()
+        .remove(identifier)
+        .ok_or(LinuxError::ENOENT)?;
+    Ok(release_domain_elf(identifier, domain_data))
+}
+
+/// Get the type and the interface version of the registered domain elf data, the version
+/// is `None` if the elf records none.
+///
+/// Return `ENOENT` if the data is not registered.
+pub fn domain_elf_metadata(domain_file_name: &str) -> LinuxResult<(DomainTypeRaw, Option<u32>)> {
+    let binding = DOMAIN_ELF.read();
+    let domain_data = binding.get(domain_file_name).ok_or(LinuxError::ENOENT)?;
+    Ok((domain_data.ty, loader::interface_version(&domain_data.data)))
+}
+
+/// Mark whether the registered domain elf data should be kept for reloading.
+pub fn set_domain_elf_reloadable(domain_file_name: &str, reloadable: bool) -> LinuxResult<()> {
+    let mut binding = DOMAIN_ELF.write();
+    let domain_data = binding
+        .get_mut(domain_file_name)
+        .ok_or(LinuxError::ENOENT)?;
+    domain_data.reloadable = reloadable;
+    Ok(())
+}
+
+/// Remove the registered domain elf data which is not marked reloadable.
+///
+/// The data of a loaded domain is kept, it is needed to restart the domain. Return the
+/// bytes released right now, which are 0 if another identifier shares the data.
+///
+/// Return `ENOENT` if the data is not registered and `EBUSY` if it is marked reloadable or
+/// used by a loaded domain.
+pub fn trim_domain_elf(domain_file_name: &str) -> LinuxResult<usize> {
     let mut binding = DOMAIN_ELF.write();
-    binding.remove(identifier);
+    let domain_data = binding.get(domain_file_name).ok_or(LinuxError::ENOENT)?;
+    if domain_data.reloadable || live_users(&binding, &domain_data.data) != 0 {
+        return Err(LinuxError::EBUSY);
+    }
+    let domain_data = binding.remove(domain_file_name).unwrap();
+    drop(binding);
+    Ok(release_domain_elf(domain_file_name, domain_data))
+}
+
+/// Remove all the registered domain elf data which is not marked reloadable and not used
+/// by a loaded domain.
+///
+/// Return the bytes released right now.
+pub fn trim_domain_elf_all() -> usize {
+    let mut binding = DOMAIN_ELF.write();
+    let names = binding
+        .iter()
+        .filter(|(_, data)| !data.reloadable && live_users(&binding, &data.data) == 0)
+        .map(|(name, _)| name.clone())
+        .collect::<Vec<_>>();
+    let trimmed = names
+        .into_iter()
+        .map(|name| {
+            let domain_data = binding.remove(&name).unwrap();
+            (name, domain_data)
+        })
+        .collect::<Vec<_>>();
+    drop(binding);
+    trimmed
+        .into_iter()
+        .map(|(name, domain_data)| release_domain_elf(&name, domain_data))
+        .sum()
+}
+
+/// The number of the loaded domains which hold `data`.
+///
+/// Each [DomainLoader] keeps a reference to the data it is loaded from, and the
+/// identifiers registered with the same bytes share it, so the references which are not
+/// held by the registry are the live users.
+fn live_users(binding: &BTreeMap<String, DomainData>, data: &Arc<Vec<u8>>) -> usize {
+    let registered = binding
+        .values()
+        .filter(|domain_data| Arc::ptr_eq(&domain_data.data, data))
+        .count();
+    Arc::strong_count(data) - registered
+}
+
+fn release_domain_elf(domain_file_name: &str, domain_data: DomainData) -> usize {
+    if let Some(files) = DOMAIN_INFO.lock().ty_list.get_mut(&domain_data.ty) {
+        files.retain(|file| file.name != domain_file_name);
+    }
+    let size = domain_data.data.len();
+    // the data is still shared by another identifier
+    let released = if Arc::strong_count(&domain_data.data) == 1 {
+        size
+    } else {
+        0
+    };
+    println!(
+        "<trim domain>: {}, size: {}, released: {}",
+        domain_file_name, size, released
+    );
+    released
 }
 
 #[macro_export]
@@ -105,7 +322,12 @@ where
     let res = create_domain(ty, domain_file_name, data, use_old_id)
         .map(|(_id, domain, loader)| {
             let file_info = loader.domain_file_info();
-            (Arc::new(P::build(domain, loader)), file_info)
+            let proxy = if PINNED_DOMAINS.contains(&domain_file_name) {
+                P::build_no_upgrade(domain, loader)
+            } else {
+                P::build(domain, loader)
+            };
+            (Arc::new(proxy), file_info)
         })
         .unwrap_or_else(|| {
             println!("Create empty domain: {}", domain_file_name);
@@ -120,16 +342,20 @@ where
 pub struct DomainCreateImpl;
 
 impl DomainCreate for DomainCreateImpl {
+    /// The new domain is named `{domain_file_name}-{domain_id}` and gets the default config
+    ///
+    /// The identifier is written before the domain is created, so a domain is never left
+    /// behind without its caller knowing its name.
     fn create_domain(
         &self,
         domain_file_name: &str,
-        _identifier: &mut [u8],
+        identifier: &mut [u8],
     ) -> LinuxResult<DomainType> {
-        match domain_file_name {
-            name => {
-                panic!("Domain {} not found", name);
-            }
-        }
+        let domain_id = alloc_domain_id();
+        let name = format!("{}-{}", domain_file_name, domain_id);
+        write_identifier(&name, identifier)?;
+        domain_helper::create_domain_with_id(domain_id, domain_file_name, &name)?;
+        query_domain(&name).ok_or(LinuxError::ENOENT)
     }
 }
 
@@ -140,8 +366,43 @@ pub fn create_domain<T: ?Sized>(
     use_old_id: Option<u64>,
 ) -> Option<(u64, Box<T>, DomainLoader)> {
     if let Some(data) = elf {
-        register_domain_elf(domain_file_name, data, ty);
+        register_domain_elf(domain_file_name, data, ty).ok()?;
     }
+    load_domain_elf(ty, domain_file_name, use_old_id, alloc_domain_id)
+}
+
+/// Create a domain with the id `domain_id` reserved by `reserve_domain_id`
+///
+/// Unlike [create_domain_special], it never falls back to an empty domain, which would
+/// not use the id. Return `ENOENT` if the elf data is not registered with the type `ty`.
+pub fn create_domain_with_id<P, T>(
+    ty: DomainTypeRaw,
+    domain_file_name: &str,
+    domain_id: u64,
+) -> LinuxResult<(Arc<P>, DomainFileInfo)>
+where
+    P: ProxyBuilder<T = Box<T>>,
+    T: ?Sized,
+{
+    let (_id, domain, loader) =
+        load_domain_elf(ty, domain_file_name, None, || domain_id).ok_or(LinuxError::ENOENT)?;
+    let file_info = loader.domain_file_info();
+    let proxy = if PINNED_DOMAINS.contains(&domain_file_name) {
+        P::build_no_upgrade(domain, loader)
+    } else {
+        P::build(domain, loader)
+    };
+    Ok((Arc::new(proxy), file_info))
+}
+
+/// Load the registered elf data and run the domain with the id returned by `id`, which
+/// is only called once the data is found
+fn load_domain_elf<T: ?Sized>(
+    ty: DomainTypeRaw,
+    domain_file_name: &str,
+    use_old_id: Option<u64>,
+    id: impl FnOnce() -> u64,
+) -> Option<(u64, Box<T>, DomainLoader)> {
     let data = DOMAIN_ELF.read().get(domain_file_name)?.clone();
     if data.ty != ty {
         return None;
@@ -149,7 +410,7 @@ pub fn create_domain<T: ?Sized>(
     info!("Load {:?} domain, size: {}KB", ty, data.data.len() / 1024);
     let mut domain_loader = DomainLoader::new(data.data, domain_file_name);
     domain_loader.load().unwrap();
-    let id = alloc_domain_id();
+    let id = id();
     let domain = domain_loader.call_main(id, use_old_id);
     Some((id, domain, domain_loader))
 }
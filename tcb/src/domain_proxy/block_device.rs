@@ -1,24 +1,93 @@
-use alloc::boxed::Box;
-use core::{any::Any, mem::forget, pin::Pin, sync::atomic::AtomicBool};
+use alloc::{boxed::Box, vec::Vec};
+use core::{
+    any::Any,
+    mem::forget,
+    pin::Pin,
+    sync::atomic::{AtomicBool, AtomicPtr, AtomicU64},
+};
 
 use basic::SafePtr;
-use corelib::{LinuxError, LinuxResult};
+use corelib::{
+    domain_info::{queue_depth_valid, DomainLoadInfo, MethodCount, ReplaceOptions},
+    LinuxError, LinuxResult,
+};
 use interface::{
-    null_block::{BlockArgs, BlockDeviceDomain},
+    null_block::{BlockArgs, BlockDeviceDomain, CacheMode},
     Basic,
 };
 use kernel::{
+    bindings,
     init::InPlaceInit,
-    sync::{LongLongPerCpu, Mutex, SRcuData},
+    sync::{
+        lock::{mutex::MutexBackend, Guard},
+        LongLongPerCpu, Mutex, SRcuData, WaitQueue,
+    },
 };
+use rref::RRefVec;
 use spin::Once;
+use tcb_helper::proxy::{CallCounts, FreezeFlag, IoPause, LastActive, RateLimiter};
 
 use crate::{
-    domain_helper::{free_domain_resource, FreeShared},
+    config::DRAIN_TIMEOUT_MS,
+    domain_helper::{free_domain_resource, AllocScope, FreeShared},
     domain_loader::loader::DomainLoader,
-    domain_proxy::ProxyBuilder,
+    domain_proxy::{
+        check_rate_limit, check_ready, export_domain_state, invoke_domain, now_ns, reentry,
+        spin_quiescent, wait_quiescent, wait_ready, warn_partial_free, watch_crash,
+        LatencyHistogram, ProxyBuilder,
+    },
 };
 
+/// The methods counted by the proxy, the index of a method in [METHODS]
+#[derive(Debug, Clone, Copy)]
+enum Method {
+    Invoke,
+    ExportState,
+    ImportState,
+    TagSetWithQueueData,
+    SetGenDisk,
+    InitRequest,
+    ExitRequest,
+    InitHctx,
+    ExitHctx,
+    QueueRq,
+    CommitRqs,
+    CompleteRequest,
+    SetCacheMode,
+    Exit,
+    OnShutdown,
+    OnMemoryPressure,
+}
+
+impl Method {
+    /// Whether the calls of the method are rate limited
+    ///
+    /// Only the submissions are limited. Refusing a completion or a teardown call would
+    /// lose the request or leak its resources.
+    fn rate_limited(self) -> bool {
+        matches!(self, Method::Invoke | Method::QueueRq)
+    }
+}
+
+const METHODS: [&str; 16] = [
+    "invoke",
+    "export_state",
+    "import_state",
+    "tag_set_with_queue_data",
+    "set_gen_disk",
+    "init_request",
+    "exit_request",
+    "init_hctx",
+    "exit_hctx",
+    "queue_rq",
+    "commit_rqs",
+    "complete_request",
+    "set_cache_mode",
+    "exit",
+    "on_shutdown",
+    "on_memory_pressure",
+];
+
 #[derive(Debug)]
 pub struct BlockDeviceDomainProxy {
     domain: SRcuData<Box<dyn BlockDeviceDomain>>,
@@ -27,10 +96,44 @@ pub struct BlockDeviceDomainProxy {
     flag: AtomicBool,
     counter: LongLongPerCpu,
     resource: Once<Box<dyn Any + Send + Sync>>,
+    /// Set by [Self::freeze] with `lock` held, the calls on the lock path sleep on `thawed`
+    /// until [Self::thaw]
+    frozen: FreezeFlag,
+    thawed: WaitQueue,
+    /// Set by the watchdog when the domain crashed, the calls fail with `EIO` until the
+    /// domain is replaced
+    disabled: AtomicBool,
+    /// The latency of the calls, it is kept across the hot upgrades
+    latency: LatencyHistogram,
+    /// The number of the calls by method, it is kept across the hot upgrades
+    calls: CallCounts<16>,
+    /// When the last call was made, it is kept across the hot upgrades
+    last_active: LastActive,
+    /// The disk passed to `set_gen_disk`, it is owned by the kernel shim and outlives the
+    /// domains
+    gen_disk: AtomicPtr<bindings::gendisk>,
+    /// Whether the real domain is initialized, a proxy made by `build_empty` is not ready
+    /// until it is replaced and its calls fail with `EAGAIN`
+    ready: AtomicBool,
+    /// The calls refused with `EAGAIN` because the domain was not ready
+    unready_calls: AtomicU64,
+    /// Whether the cache mode is [CacheMode::WriteBack], it is set again on the new domain
+    /// after a hot upgrade
+    write_back: AtomicBool,
+    /// Whether the queue is quiesced by [Self::pause_io]
+    paused: IoPause,
+    /// The generation of the domain, it is increased by every `replace`
+    epoch: AtomicU64,
+    /// The id of the current domain, a call reads it without calling into the domain
+    id: AtomicU64,
+    /// The rate limit of the submissions, see [Method::rate_limited]. It is kept across the
+    /// hot upgrades
+    rate_limit: RateLimiter,
 }
 
 impl BlockDeviceDomainProxy {
     pub fn new(domain: Box<dyn BlockDeviceDomain>, domain_loader: DomainLoader) -> Self {
+        let id = domain.domain_id();
         BlockDeviceDomainProxy {
             domain: SRcuData::new(domain),
             lock: Box::pin_init(new_mutex!(())).unwrap(),
@@ -38,6 +141,20 @@ impl BlockDeviceDomainProxy {
             flag: AtomicBool::new(false),
             counter: LongLongPerCpu::new(),
             resource: Once::new(),
+            frozen: FreezeFlag::new(),
+            thawed: WaitQueue::new(),
+            disabled: AtomicBool::new(false),
+            latency: LatencyHistogram::new(),
+            calls: CallCounts::new(METHODS),
+            last_active: LastActive::new(now_ns()),
+            gen_disk: AtomicPtr::new(core::ptr::null_mut()),
+            ready: AtomicBool::new(false),
+            unready_calls: AtomicU64::new(0),
+            write_back: AtomicBool::new(false),
+            paused: IoPause::new(),
+            epoch: AtomicU64::new(0),
+            id: AtomicU64::new(id),
+            rate_limit: RateLimiter::new(),
         }
     }
 }
@@ -71,25 +188,110 @@ impl Basic for BlockDeviceDomainProxy {
             self._domain_id_no_lock()
         }
     }
+
+    fn on_memory_pressure(&self, level: u8) -> LinuxResult<usize> {
+        self.call(Method::OnMemoryPressure, || {
+            if self.flag.load(core::sync::atomic::Ordering::Relaxed) {
+                self._on_memory_pressure_with_lock(level)
+            } else {
+                self._on_memory_pressure_no_lock(level)
+            }
+        })
+    }
+
+    fn on_shutdown(&self) -> LinuxResult<()> {
+        self.call(Method::OnShutdown, || {
+            if self.flag.load(core::sync::atomic::Ordering::Relaxed) {
+                self._on_shutdown_with_lock()
+            } else {
+                self._on_shutdown_no_lock()
+            }
+        })
+    }
+
+    fn epoch(&self) -> u64 {
+        self.epoch.load(core::sync::atomic::Ordering::Acquire)
+    }
+
+    fn invoke(&self, op: u32, buf: RRefVec<u8>) -> LinuxResult<RRefVec<u8>> {
+        self.call(Method::Invoke, || {
+            if self.flag.load(core::sync::atomic::Ordering::Relaxed) {
+                self._invoke_with_lock(op, buf, None)
+            } else {
+                self._invoke_no_lock(op, buf, None)
+            }
+        })
+    }
+
+    fn invoke_interruptible(
+        &self,
+        op: u32,
+        buf: RRefVec<u8>,
+        call_id: u64,
+    ) -> LinuxResult<RRefVec<u8>> {
+        self.call(Method::Invoke, || {
+            if self.flag.load(core::sync::atomic::Ordering::Relaxed) {
+                self._invoke_with_lock(op, buf, Some(call_id))
+            } else {
+                self._invoke_no_lock(op, buf, Some(call_id))
+            }
+        })
+    }
+
+    fn export_state(&self) -> LinuxResult<RRefVec<u8>> {
+        self.call(Method::ExportState, || {
+            if self.flag.load(core::sync::atomic::Ordering::Relaxed) {
+                self._export_state_with_lock()
+            } else {
+                self._export_state_no_lock()
+            }
+        })
+    }
+
+    fn import_state(&self, state: &RRefVec<u8>) -> LinuxResult<()> {
+        self.call(Method::ImportState, || {
+            if self.flag.load(core::sync::atomic::Ordering::Relaxed) {
+                self._import_state_with_lock(state)
+            } else {
+                self._import_state_no_lock(state)
+            }
+        })
+    }
 }
 
 impl BlockDeviceDomain for BlockDeviceDomainProxy {
     fn init(&self, args: &BlockArgs) -> LinuxResult<()> {
-        self.domain.read_directly(|domain| domain.init(args))
+        self.domain.read_directly(|domain| {
+            domain.init(args)?;
+            // the id of BlockDeviceDomainEmptyImpl is u64::MAX, it is never ready
+            if domain.domain_id() != u64::MAX {
+                self.ready
+                    .store(true, core::sync::atomic::Ordering::Release);
+            }
+            Ok(())
+        })
     }
     fn tag_set_with_queue_data(&self) -> LinuxResult<(SafePtr, SafePtr)> {
-        if self.flag.load(core::sync::atomic::Ordering::Relaxed) {
-            self._tag_set_with_queue_data_with_lock()
-        } else {
-            self._tag_set_with_queue_data_no_lock()
-        }
+        self.call(Method::TagSetWithQueueData, || {
+            if self.flag.load(core::sync::atomic::Ordering::Relaxed) {
+                self._tag_set_with_queue_data_with_lock()
+            } else {
+                self._tag_set_with_queue_data_no_lock()
+            }
+        })
     }
     fn set_gen_disk(&self, gen_disk: SafePtr) -> LinuxResult<()> {
-        if self.flag.load(core::sync::atomic::Ordering::Relaxed) {
-            self._set_gen_disk_with_lock(gen_disk)
-        } else {
-            self._set_gen_disk_no_lock(gen_disk)
-        }
+        self.gen_disk.store(
+            unsafe { gen_disk.raw_ptr() as *mut bindings::gendisk },
+            core::sync::atomic::Ordering::Release,
+        );
+        self.call(Method::SetGenDisk, || {
+            if self.flag.load(core::sync::atomic::Ordering::Relaxed) {
+                self._set_gen_disk_with_lock(gen_disk)
+            } else {
+                self._set_gen_disk_no_lock(gen_disk)
+            }
+        })
     }
     fn open(&self, mode: u32) -> LinuxResult<()> {
         // todo!
@@ -105,18 +307,22 @@ impl BlockDeviceDomain for BlockDeviceDomainProxy {
         rq_ptr: SafePtr,
         driver_data_ptr: SafePtr,
     ) -> LinuxResult<()> {
-        if self.flag.load(core::sync::atomic::Ordering::Relaxed) {
-            self._init_request_with_lock(tag_set_ptr, rq_ptr, driver_data_ptr)
-        } else {
-            self._init_request_no_lock(tag_set_ptr, rq_ptr, driver_data_ptr)
-        }
+        self.call(Method::InitRequest, || {
+            if self.flag.load(core::sync::atomic::Ordering::Relaxed) {
+                self._init_request_with_lock(tag_set_ptr, rq_ptr, driver_data_ptr)
+            } else {
+                self._init_request_no_lock(tag_set_ptr, rq_ptr, driver_data_ptr)
+            }
+        })
     }
     fn exit_request(&self, tag_set_ptr: SafePtr, rq_ptr: SafePtr) -> LinuxResult<()> {
-        if self.flag.load(core::sync::atomic::Ordering::Relaxed) {
-            self._exit_request_with_lock(tag_set_ptr, rq_ptr)
-        } else {
-            self._exit_request_no_lock(tag_set_ptr, rq_ptr)
-        }
+        self.call(Method::ExitRequest, || {
+            if self.flag.load(core::sync::atomic::Ordering::Relaxed) {
+                self._exit_request_with_lock(tag_set_ptr, rq_ptr)
+            } else {
+                self._exit_request_no_lock(tag_set_ptr, rq_ptr)
+            }
+        })
     }
     fn init_hctx(
         &self,
@@ -124,19 +330,23 @@ impl BlockDeviceDomain for BlockDeviceDomainProxy {
         tag_set_data_ptr: SafePtr,
         hctx_idx: usize,
     ) -> LinuxResult<()> {
-        if self.flag.load(core::sync::atomic::Ordering::Relaxed) {
-            self._init_hctx_with_lock(hctx_ptr, tag_set_data_ptr, hctx_idx)
-        } else {
-            self._init_hctx_no_lock(hctx_ptr, tag_set_data_ptr, hctx_idx)
-        }
+        self.call(Method::InitHctx, || {
+            if self.flag.load(core::sync::atomic::Ordering::Relaxed) {
+                self._init_hctx_with_lock(hctx_ptr, tag_set_data_ptr, hctx_idx)
+            } else {
+                self._init_hctx_no_lock(hctx_ptr, tag_set_data_ptr, hctx_idx)
+            }
+        })
     }
 
     fn exit_hctx(&self, hctx_ptr: SafePtr, hctx_idx: usize) -> LinuxResult<()> {
-        if self.flag.load(core::sync::atomic::Ordering::Relaxed) {
-            self._exit_hctx_with_lock(hctx_ptr, hctx_idx)
-        } else {
-            self._exit_hctx_no_lock(hctx_ptr, hctx_idx)
-        }
+        self.call(Method::ExitHctx, || {
+            if self.flag.load(core::sync::atomic::Ordering::Relaxed) {
+                self._exit_hctx_with_lock(hctx_ptr, hctx_idx)
+            } else {
+                self._exit_hctx_no_lock(hctx_ptr, hctx_idx)
+            }
+        })
     }
     fn queue_rq(
         &self,
@@ -144,36 +354,97 @@ impl BlockDeviceDomain for BlockDeviceDomainProxy {
         bd_ptr: SafePtr,
         hctx_driver_data_ptr: SafePtr,
     ) -> LinuxResult<()> {
-        if self.flag.load(core::sync::atomic::Ordering::Relaxed) {
-            self._queue_rq_with_lock(hctx_ptr, bd_ptr, hctx_driver_data_ptr)
-        } else {
-            self._queue_rq_no_lock(hctx_ptr, bd_ptr, hctx_driver_data_ptr)
-        }
+        self.call(Method::QueueRq, || {
+            if self.flag.load(core::sync::atomic::Ordering::Relaxed) {
+                self._queue_rq_with_lock(hctx_ptr, bd_ptr, hctx_driver_data_ptr)
+            } else {
+                self._queue_rq_no_lock(hctx_ptr, bd_ptr, hctx_driver_data_ptr)
+            }
+        })
     }
     fn commit_rqs(&self, hctx_ptr: SafePtr, hctx_driver_data_ptr: SafePtr) -> LinuxResult<()> {
-        if self.flag.load(core::sync::atomic::Ordering::Relaxed) {
-            self._commit_rqs_with_lock(hctx_ptr, hctx_driver_data_ptr)
-        } else {
-            self._commit_rqs_no_lock(hctx_ptr, hctx_driver_data_ptr)
-        }
+        self.call(Method::CommitRqs, || {
+            if self.flag.load(core::sync::atomic::Ordering::Relaxed) {
+                self._commit_rqs_with_lock(hctx_ptr, hctx_driver_data_ptr)
+            } else {
+                self._commit_rqs_no_lock(hctx_ptr, hctx_driver_data_ptr)
+            }
+        })
     }
     fn complete_request(&self, rq_ptr: SafePtr) -> LinuxResult<()> {
-        if self.flag.load(core::sync::atomic::Ordering::Relaxed) {
-            self._complete_request_with_lock(rq_ptr)
-        } else {
-            self._complete_request_no_lock(rq_ptr)
-        }
+        self.call(Method::CompleteRequest, || {
+            if self.flag.load(core::sync::atomic::Ordering::Relaxed) {
+                self._complete_request_with_lock(rq_ptr)
+            } else {
+                self._complete_request_no_lock(rq_ptr)
+            }
+        })
+    }
+    fn set_cache_mode(&self, mode: CacheMode) -> LinuxResult<()> {
+        self.call(Method::SetCacheMode, || {
+            if self.flag.load(core::sync::atomic::Ordering::Relaxed) {
+                self._set_cache_mode_with_lock(mode)
+            } else {
+                self._set_cache_mode_no_lock(mode)
+            }
+        })?;
+        self.write_back.store(
+            mode == CacheMode::WriteBack,
+            core::sync::atomic::Ordering::Relaxed,
+        );
+        Ok(())
     }
     fn exit(&self) -> LinuxResult<()> {
-        if self.flag.load(core::sync::atomic::Ordering::Relaxed) {
-            self._exit_with_lock()
-        } else {
-            self._exit_no_lock()
+        // a paused queue would never drain the requests held by the block layer
+        if self.paused.is_paused() {
+            let _ = self.resume_io();
         }
+        self.call(Method::Exit, || {
+            if self.flag.load(core::sync::atomic::Ordering::Relaxed) {
+                self._exit_with_lock()
+            } else {
+                self._exit_no_lock()
+            }
+        })
     }
 }
 
 impl BlockDeviceDomainProxy {
+    /// Run a call into the domain, see [watch_crash] for what happens if it crashes.
+    #[inline]
+    fn call<R>(&self, method: Method, f: impl FnOnce() -> LinuxResult<R>) -> LinuxResult<R> {
+        check_ready("BlockDeviceDomainProxy", &self.ready, &self.unready_calls)?;
+        if self.disabled.load(core::sync::atomic::Ordering::Relaxed) {
+            return Err(LinuxError::EIO);
+        }
+        if method.rate_limited() {
+            check_rate_limit(&self.rate_limit)?;
+        }
+        let id = self.id.load(core::sync::atomic::Ordering::Relaxed);
+        let scope = AllocScope::begin(id);
+        #[cfg(feature = "fault_injection")]
+        crate::domain_proxy::fault::delay(id);
+        self.calls.count(method as usize);
+        self.last_active.touch(now_ns());
+        let r = self
+            .latency
+            .measure(|| reentry::enter_reentrant(self as *const Self as usize, f));
+        watch_crash(scope, &self.disabled, r)
+    }
+    /// Take the lock of the lock path, sleep until [Self::thaw] first if the domain is frozen
+    ///
+    /// `frozen` is only set with the lock held, so a call which gets the lock while the
+    /// domain is not frozen finishes before `freeze` returns.
+    fn lock_thawed(&self) -> Guard<'_, (), MutexBackend> {
+        loop {
+            let lock = self.lock.lock();
+            if !self.frozen.is_frozen() {
+                return lock;
+            }
+            drop(lock);
+            self.thawed.wait(|| !self.frozen.is_frozen());
+        }
+    }
     #[inline]
     fn _domain_id(&self) -> u64 {
         self.domain.read_directly(|domain| domain.domain_id())
@@ -191,7 +462,7 @@ impl BlockDeviceDomainProxy {
     }
     #[inline]
     fn _domain_id_with_lock(&self) -> u64 {
-        let lock = self.lock.lock();
+        let lock = self.lock_thawed();
         let r = self._domain_id();
         drop(lock);
         r
@@ -214,7 +485,7 @@ impl BlockDeviceDomainProxy {
     }
     #[inline]
     fn _tag_set_with_queue_data_with_lock(&self) -> LinuxResult<(SafePtr, SafePtr)> {
-        let lock = self.lock.lock();
+        let lock = self.lock_thawed();
         let r = self._tag_set_with_queue_data();
         drop(lock);
         r
@@ -237,7 +508,7 @@ impl BlockDeviceDomainProxy {
     }
     #[inline]
     fn _set_gen_disk_with_lock(&self, gen_disk: SafePtr) -> LinuxResult<()> {
-        let lock = self.lock.lock();
+        let lock = self.lock_thawed();
         let r = self._set_gen_disk(gen_disk);
         drop(lock);
         r
@@ -276,7 +547,7 @@ impl BlockDeviceDomainProxy {
         rq_ptr: SafePtr,
         driver_data_ptr: SafePtr,
     ) -> LinuxResult<()> {
-        let lock = self.lock.lock();
+        let lock = self.lock_thawed();
         let r = self._init_request(tag_set_ptr, rq_ptr, driver_data_ptr);
         drop(lock);
         r
@@ -300,7 +571,7 @@ impl BlockDeviceDomainProxy {
     }
     #[inline]
     fn _exit_request_with_lock(&self, tag_set_ptr: SafePtr, rq_ptr: SafePtr) -> LinuxResult<()> {
-        let lock = self.lock.lock();
+        let lock = self.lock_thawed();
         let r = self._exit_request(tag_set_ptr, rq_ptr);
         drop(lock);
         r
@@ -339,7 +610,7 @@ impl BlockDeviceDomainProxy {
         tag_set_data_ptr: SafePtr,
         hctx_idx: usize,
     ) -> LinuxResult<()> {
-        let lock = self.lock.lock();
+        let lock = self.lock_thawed();
         let r = self._init_hctx(hctx_ptr, tag_set_data_ptr, hctx_idx);
         drop(lock);
         r
@@ -362,7 +633,7 @@ impl BlockDeviceDomainProxy {
     }
     #[inline]
     fn _exit_hctx_with_lock(&self, hctx_ptr: SafePtr, hctx_idx: usize) -> LinuxResult<()> {
-        let lock = self.lock.lock();
+        let lock = self.lock_thawed();
         let r = self._exit_hctx(hctx_ptr, hctx_idx);
         drop(lock);
         r
@@ -400,7 +671,7 @@ impl BlockDeviceDomainProxy {
         bd_ptr: SafePtr,
         hctx_driver_data_ptr: SafePtr,
     ) -> LinuxResult<()> {
-        let lock = self.lock.lock();
+        let lock = self.lock_thawed();
         let r = self._queue_rq(hctx_ptr, bd_ptr, hctx_driver_data_ptr);
         drop(lock);
         r
@@ -432,7 +703,7 @@ impl BlockDeviceDomainProxy {
         hctx_ptr: SafePtr,
         hctx_driver_data_ptr: SafePtr,
     ) -> LinuxResult<()> {
-        let lock = self.lock.lock();
+        let lock = self.lock_thawed();
         let r = self._commit_rqs(hctx_ptr, hctx_driver_data_ptr);
         drop(lock);
         r
@@ -455,12 +726,159 @@ impl BlockDeviceDomainProxy {
     }
     #[inline]
     fn _complete_request_with_lock(&self, rq_ptr: SafePtr) -> LinuxResult<()> {
-        let lock = self.lock.lock();
+        let lock = self.lock_thawed();
         let r = self._complete_request(rq_ptr);
         drop(lock);
         r
     }
     #[inline]
+    fn _invoke(&self, op: u32, buf: RRefVec<u8>, call_id: Option<u64>) -> LinuxResult<RRefVec<u8>> {
+        self.domain
+            .read_directly(|domain| invoke_domain(domain.as_ref(), op, buf, call_id))
+    }
+    #[inline]
+    fn _invoke_no_lock(
+        &self,
+        op: u32,
+        buf: RRefVec<u8>,
+        call_id: Option<u64>,
+    ) -> LinuxResult<RRefVec<u8>> {
+        self.counter.get_with(|counter| {
+            *counter += 1;
+        });
+        let r = self._invoke(op, buf, call_id);
+        self.counter.get_with(|counter| {
+            *counter -= 1;
+        });
+        r
+    }
+    #[inline]
+    fn _invoke_with_lock(
+        &self,
+        op: u32,
+        buf: RRefVec<u8>,
+        call_id: Option<u64>,
+    ) -> LinuxResult<RRefVec<u8>> {
+        let lock = self.lock_thawed();
+        let r = self._invoke(op, buf, call_id);
+        drop(lock);
+        r
+    }
+    #[inline]
+    fn _export_state(&self) -> LinuxResult<RRefVec<u8>> {
+        self.domain
+            .read_directly(|domain| export_domain_state(domain.as_ref()))
+    }
+    #[inline]
+    fn _export_state_no_lock(&self) -> LinuxResult<RRefVec<u8>> {
+        self.counter.get_with(|counter| {
+            *counter += 1;
+        });
+        let r = self._export_state();
+        self.counter.get_with(|counter| {
+            *counter -= 1;
+        });
+        r
+    }
+    #[inline]
+    fn _export_state_with_lock(&self) -> LinuxResult<RRefVec<u8>> {
+        let lock = self.lock_thawed();
+        let r = self._export_state();
+        drop(lock);
+        r
+    }
+    #[inline]
+    fn _import_state(&self, state: &RRefVec<u8>) -> LinuxResult<()> {
+        self.domain
+            .read_directly(|domain| domain.import_state(state))
+    }
+    #[inline]
+    fn _import_state_no_lock(&self, state: &RRefVec<u8>) -> LinuxResult<()> {
+        self.counter.get_with(|counter| {
+            *counter += 1;
+        });
+        let r = self._import_state(state);
+        self.counter.get_with(|counter| {
+            *counter -= 1;
+        });
+        r
+    }
+    #[inline]
+    fn _import_state_with_lock(&self, state: &RRefVec<u8>) -> LinuxResult<()> {
+        let lock = self.lock_thawed();
+        let r = self._import_state(state);
+        drop(lock);
+        r
+    }
+    #[inline]
+    fn _on_shutdown(&self) -> LinuxResult<()> {
+        self.domain.read_directly(|domain| domain.on_shutdown())
+    }
+    #[inline]
+    fn _on_shutdown_no_lock(&self) -> LinuxResult<()> {
+        self.counter.get_with(|counter| {
+            *counter += 1;
+        });
+        let r = self._on_shutdown();
+        self.counter.get_with(|counter| {
+            *counter -= 1;
+        });
+        r
+    }
+    #[inline]
+    fn _on_shutdown_with_lock(&self) -> LinuxResult<()> {
+        let lock = self.lock_thawed();
+        let r = self._on_shutdown();
+        drop(lock);
+        r
+    }
+    #[inline]
+    fn _on_memory_pressure(&self, level: u8) -> LinuxResult<usize> {
+        self.domain
+            .read_directly(|domain| domain.on_memory_pressure(level))
+    }
+    #[inline]
+    fn _on_memory_pressure_no_lock(&self, level: u8) -> LinuxResult<usize> {
+        self.counter.get_with(|counter| {
+            *counter += 1;
+        });
+        let r = self._on_memory_pressure(level);
+        self.counter.get_with(|counter| {
+            *counter -= 1;
+        });
+        r
+    }
+    #[inline]
+    fn _on_memory_pressure_with_lock(&self, level: u8) -> LinuxResult<usize> {
+        let lock = self.lock_thawed();
+        let r = self._on_memory_pressure(level);
+        drop(lock);
+        r
+    }
+    #[inline]
+    fn _set_cache_mode(&self, mode: CacheMode) -> LinuxResult<()> {
+        self.domain
+            .read_directly(|domain| domain.set_cache_mode(mode))
+    }
+    #[inline]
+    fn _set_cache_mode_no_lock(&self, mode: CacheMode) -> LinuxResult<()> {
+        self.counter.get_with(|counter| {
+            *counter += 1;
+        });
+        let r = self._set_cache_mode(mode);
+        self.counter.get_with(|counter| {
+            *counter -= 1;
+        });
+        r
+    }
+    #[inline]
+    fn _set_cache_mode_with_lock(&self, mode: CacheMode) -> LinuxResult<()> {
+        let lock = self.lock_thawed();
+        let r = self._set_cache_mode(mode);
+        drop(lock);
+        r
+    }
+    #[inline]
     fn _exit(&self) -> LinuxResult<()> {
         self.domain.read_directly(|domain| domain.exit())
     }
@@ -478,7 +896,7 @@ impl BlockDeviceDomainProxy {
 
     #[inline]
     fn _exit_with_lock(&self) -> LinuxResult<()> {
-        let lock = self.lock.lock();
+        let lock = self.lock_thawed();
         let r = self._exit();
         drop(lock);
         r
@@ -486,46 +904,335 @@ impl BlockDeviceDomainProxy {
 }
 
 impl BlockDeviceDomainProxy {
+    /// Replace the domain with `new_domain`.
+    ///
+    /// The shared data of the old domain is moved to the new domain, except what `options`
+    /// frees. Return the number of times we polled for the in-flight readers to drain, or
+    /// `EINVAL` if the proxy was never initialized by `init_by_box`. If the readers do not
+    /// drain in [DRAIN_TIMEOUT_MS], return `ETIMEDOUT` and keep the old domain.
     pub fn replace(
         &self,
         new_domain: Box<dyn BlockDeviceDomain>,
         domain_loader: DomainLoader,
-    ) -> LinuxResult<()> {
+        options: ReplaceOptions,
+    ) -> LinuxResult<usize> {
+        // The new domain is initialized with the args given to init_by_box, they are checked
+        // before anything is changed
+        let args = self
+            .resource
+            .get()
+            .and_then(|resource| resource.as_ref().downcast_ref::<BlockArgs>())
+            .ok_or(LinuxError::EINVAL)?;
+        // The loader lock must be taken before the writer lock
+        self.lock.assert_not_held();
         let mut loader_guard = self.domain_loader.lock();
+        // A frozen domain is not replaced until it is thawed
+        if self.frozen.is_frozen() {
+            return Err(LinuxError::EBUSY);
+        }
         // The writer lock before enable the lock path
         let w_lock = self.lock.lock();
         let old_id = self.domain_id();
         // enable lock path
         self.flag.store(true, core::sync::atomic::Ordering::Relaxed);
 
-        // wait all readers to finish
-        while self.counter.sum() != 0 {
-            println!("Wait for all reader to finish");
-            // yield_now();
-        }
-        let resource = self.resource.get().unwrap();
-        let args = resource.as_ref().downcast_ref::<BlockArgs>().unwrap();
-
+        // wait all readers to finish, give up the upgrade if they do not
+        let drain_iterations = match wait_quiescent(&self.counter, DRAIN_TIMEOUT_MS) {
+            Ok(polls) => polls,
+            Err(e) => {
+                self.flag
+                    .store(false, core::sync::atomic::Ordering::Relaxed);
+                return Err(e);
+            }
+        };
         let new_domain_id = new_domain.domain_id();
         new_domain.init(args).unwrap();
+        // keep the cache mode, the writes cached by the old domain are written back first
+        let mode = self.cache_mode();
+        if mode == CacheMode::WriteBack {
+            if let Err(e) = self._set_cache_mode(CacheMode::WriteThrough) {
+                warn!(
+                    "failed to write back the cache of domain {}: {:?}",
+                    old_id, e
+                );
+            }
+        }
+        if let Err(e) = new_domain.set_cache_mode(mode) {
+            warn!(
+                "failed to set the cache mode of domain {}: {:?}",
+                new_domain_id, e
+            );
+        }
 
         // stage4: swap the domain and change to normal state
         let old_domain = self.domain.update_directly(new_domain);
+        self.id
+            .store(new_domain_id, core::sync::atomic::Ordering::Relaxed);
+        self.epoch
+            .fetch_add(1, core::sync::atomic::Ordering::Release);
 
+        // the new domain has not crashed, undo the watchdog
+        self.disabled
+            .store(false, core::sync::atomic::Ordering::Relaxed);
         // disable lock path
         self.flag
             .store(false, core::sync::atomic::Ordering::Relaxed);
+        // the new domain is initialized
+        self.ready
+            .store(true, core::sync::atomic::Ordering::Release);
         // stage5: recycle all resources
         let real_domain = Box::into_inner(old_domain);
         // forget the old domain, it will be dropped by the `free_domain_resource`
         forget(real_domain);
 
         // We should not free the shared data here, because the shared data will be used
-        // in new domain.
-        free_domain_resource(old_id, FreeShared::NotFree(new_domain_id));
+        // in new domain. Only what the options select, e.g. the caches, is freed.
+        let res = free_domain_resource(old_id, FreeShared::NotFree(new_domain_id, options));
+        warn_partial_free(old_id, res);
         *loader_guard = domain_loader;
         drop(w_lock);
         drop(loader_guard);
+        Ok(drain_iterations)
+    }
+
+    /// Replace the loader with `domain_loader` without replacing the domain.
+    ///
+    /// The new loader must not be loaded, it takes over the image of the running domain, e.g.
+    /// to attach an ELF with richer debug info. The calls are not blocked. Return `EINVAL` if
+    /// the new loader is loaded.
+    pub fn replace_loader_only(&self, mut domain_loader: DomainLoader) -> LinuxResult<()> {
+        // The loader lock must be taken before the writer lock
+        self.lock.assert_not_held();
+        let mut loader_guard = self.domain_loader.lock();
+        domain_loader
+            .take_over(&mut loader_guard)
+            .map_err(|_| LinuxError::EINVAL)?;
+        *loader_guard = domain_loader;
+        Ok(())
+    }
+}
+
+impl BlockDeviceDomainProxy {
+    /// Whether the calls go through the lock path, i.e. the domain is being replaced or is frozen.
+    ///
+    /// It only reads the flag and never takes the lock.
+    pub fn is_upgrading(&self) -> bool {
+        self.flag.load(core::sync::atomic::Ordering::Relaxed)
+    }
+
+    /// A safe point of a long call into the domain, see `sys_domain_yield`.
+    ///
+    /// Return `EAGAIN` if the domain is being replaced or frozen, the domain should then give
+    /// up the call so that its reader count drops and the drain finishes. The count is not
+    /// dropped here: once it reaches 0 the old domain is freed, while it would still be
+    /// running the call.
+    pub fn yield_point(&self) -> LinuxResult<()> {
+        if self.flag.load(core::sync::atomic::Ordering::Relaxed) {
+            return Err(LinuxError::EAGAIN);
+        }
+        Ok(())
+    }
+
+    /// The cache mode last set through the proxy
+    pub fn cache_mode(&self) -> CacheMode {
+        if self.write_back.load(core::sync::atomic::Ordering::Relaxed) {
+            CacheMode::WriteBack
+        } else {
+            CacheMode::WriteThrough
+        }
+    }
+
+    /// Whether the real domain is initialized, the calls fail with `EAGAIN` until it is
+    pub fn is_ready(&self) -> bool {
+        self.ready.load(core::sync::atomic::Ordering::Acquire)
+    }
+
+    /// The calls refused with `EAGAIN` because the domain was not ready
+    pub fn unready_calls(&self) -> u64 {
+        self.unready_calls
+            .load(core::sync::atomic::Ordering::Relaxed)
+    }
+
+    /// Wait until the domain is ready, or return `ETIMEDOUT` after `timeout_ms` milliseconds
+    pub fn wait_ready(&self, timeout_ms: u64) -> LinuxResult<()> {
+        wait_ready(&self.ready, timeout_ms)
+    }
+
+    /// The number of calls in each bucket of the latency histogram
+    pub fn latency(&self) -> Vec<u64> {
+        self.latency.counts()
+    }
+
+    /// The number of the calls of each method
+    pub fn call_counts(&self) -> Vec<MethodCount> {
+        self.calls.counts()
+    }
+
+    /// The milliseconds since the last call, or since the proxy was made if there was none
+    pub fn idle_ms(&self) -> u64 {
+        self.last_active.idle_ms(now_ns())
+    }
+
+    /// Record an activity which does not go through the proxy, so the domain is not idle
+    pub fn touch(&self) {
+        self.last_active.touch(now_ns());
+    }
+
+    /// Limit the submissions, see `sys_set_domain_rate_limit`
+    pub fn set_rate_limit(&self, calls_per_sec: u64, burst: u64) -> LinuxResult<()> {
+        self.rate_limit.set(calls_per_sec, burst, now_ns())
+    }
+
+    /// Zero the statistics of the proxy, the calls in flight are not blocked
+    pub fn reset_metrics(&self) {
+        self.latency.reset();
+        self.calls.reset();
+        self.unready_calls
+            .store(0, core::sync::atomic::Ordering::Relaxed);
+    }
+
+    /// Set the number of requests the queue of the disk accepts.
+    ///
+    /// The queue is frozen and quiesced while the tags are resized, so the requests in flight
+    /// are completed first. Return `EINVAL` if the disk is not set up yet or `depth` is
+    /// refused by [queue_depth_valid], and `EBUSY` if the queue has an I/O scheduler, whose
+    /// tags can only be resized by the block layer.
+    pub fn set_queue_depth(&self, depth: u32) -> LinuxResult<()> {
+        let gen_disk = self.gen_disk.load(core::sync::atomic::Ordering::Acquire);
+        if gen_disk.is_null() {
+            return Err(LinuxError::EINVAL);
+        }
+        // SAFETY: The disk is alive as long as the kernel shim of this proxy.
+        let q = unsafe { (*gen_disk).queue };
+        let set = unsafe { &*(*q).tag_set };
+        if !queue_depth_valid(depth, set.reserved_tags, set.queue_depth) {
+            return Err(LinuxError::EINVAL);
+        }
+        // the depth is checked above, so it only fails for a queue with an I/O scheduler
+        match unsafe { bindings::blk_mq_update_nr_requests(q, depth) } {
+            0 => Ok(()),
+            _ => Err(LinuxError::EBUSY),
+        }
+    }
+
+    /// Stop the block layer from dispatching requests to the domain.
+    ///
+    /// Unlike `freeze`, the calls into the domain are not blocked, the queue is quiesced so
+    /// the new requests are held by the block layer until [Self::resume_io]. Return `EINVAL`
+    /// if the disk is not set up yet and `EBUSY` if the queue is already paused. The queue
+    /// is only reported as paused once it is quiesced.
+    pub fn pause_io(&self) -> LinuxResult<()> {
+        let gen_disk = self.gen_disk.load(core::sync::atomic::Ordering::Acquire);
+        if gen_disk.is_null() {
+            return Err(LinuxError::EINVAL);
+        }
+        // SAFETY: The disk is alive as long as the kernel shim of this proxy.
+        self.paused
+            .pause(|| unsafe { bindings::blk_mq_quiesce_queue((*gen_disk).queue) })
+    }
+
+    /// Dispatch the requests held since [Self::pause_io].
+    ///
+    /// Return `EINVAL` if the queue is not paused.
+    pub fn resume_io(&self) -> LinuxResult<()> {
+        let gen_disk = self.gen_disk.load(core::sync::atomic::Ordering::Acquire);
+        // SAFETY: `paused` is only set once the disk is set up.
+        self.paused
+            .resume(|| unsafe { bindings::blk_mq_unquiesce_queue((*gen_disk).queue) })
+    }
+
+    /// Whether the queue is paused by [Self::pause_io]
+    pub fn is_io_paused(&self) -> bool {
+        self.paused.is_paused()
+    }
+
+    /// The ELF image of the current domain, it changes with `replace`
+    pub fn load_info(&self) -> DomainLoadInfo {
+        self.lock.assert_not_held();
+        self.domain_loader.lock().domain_load_info()
+    }
+
+    /// The number of the calls in flight on the lock-free path, it is only a snapshot.
+    pub fn in_flight(&self) -> i64 {
+        self.counter.sum()
+    }
+
+    /// Wait until no call is running on the no-lock path, or return `ETIMEDOUT` after
+    /// `timeout_ms` milliseconds.
+    ///
+    /// Unlike `freeze`, the new calls are not blocked.
+    pub fn wait_quiescent(&self, timeout_ms: u64) -> LinuxResult<()> {
+        wait_quiescent(&self.counter, timeout_ms).map(|_| ())
+    }
+
+    /// Wait until all the callbacks queued on the SRCU of the domain have run.
+    ///
+    /// It sleeps, `EDEADLK` is returned in the atomic context.
+    pub fn srcu_barrier(&self) -> LinuxResult<()> {
+        self.domain.barrier().map_err(|_| LinuxError::EDEADLK)
+    }
+
+    /// Swap the domain for an empty one without sleeping, used by the teardown late in
+    /// shutdown.
+    ///
+    /// It busy-waits for the calls on the no-lock path to drain, then for the grace period
+    /// of [SRcuData::update_no_sleep], at most `timeout_ms` milliseconds each. `ETIMEDOUT`
+    /// is returned if the calls do not drain, and the domain is left in place, or if the
+    /// grace period does not end, and the old domain is leaked as it may still be read.
+    ///
+    /// The old domain is forgotten like in [Self::replace], its loader frees it.
+    pub fn retire_no_sleep(&self, timeout_ms: u64) -> LinuxResult<()> {
+        spin_quiescent(&self.counter, timeout_ms)?;
+        self.ready
+            .store(false, core::sync::atomic::Ordering::Release);
+        let old_domain = self
+            .domain
+            .update_no_sleep(Box::new(BlockDeviceDomainEmptyImpl::new()), timeout_ms)
+            .map_err(|_| LinuxError::ETIMEDOUT)?;
+        forget(Box::into_inner(old_domain));
+        Ok(())
+    }
+
+    /// Stop the domain from processing new calls.
+    ///
+    /// The lock path is enabled and all in-flight readers are drained, then the domain is
+    /// marked frozen so that new calls sleep on the lock path until [`Self::thaw`]. No lock
+    /// is held when it returns. Return `EBUSY` if the domain is already frozen, and
+    /// `ETIMEDOUT` without freezing it if the readers do not drain in [DRAIN_TIMEOUT_MS].
+    pub fn freeze(&self) -> LinuxResult<()> {
+        // The loader lock serializes freeze/thaw/replace
+        self.lock.assert_not_held();
+        let loader_guard = self.domain_loader.lock();
+        if self.frozen.is_frozen() {
+            return Err(LinuxError::EBUSY);
+        }
+        let w_lock = self.lock.lock();
+        // enable lock path
+        self.flag.store(true, core::sync::atomic::Ordering::Relaxed);
+        // wait all readers to finish, leave the domain running if they do not
+        if let Err(e) = wait_quiescent(&self.counter, DRAIN_TIMEOUT_MS) {
+            self.flag
+                .store(false, core::sync::atomic::Ordering::Relaxed);
+            return Err(e);
+        }
+        // set with the lock held, see `lock_thawed`
+        self.frozen.freeze()?;
+        drop(w_lock);
+        drop(loader_guard);
+        Ok(())
+    }
+
+    /// Wake up the calls blocked by [`Self::freeze`].
+    ///
+    /// Return `EINVAL` if the domain is not frozen.
+    pub fn thaw(&self) -> LinuxResult<()> {
+        let loader_guard = self.domain_loader.lock();
+        self.frozen.thaw()?;
+        // disable lock path
+        self.flag
+            .store(false, core::sync::atomic::Ordering::Relaxed);
+        drop(loader_guard);
+        self.thawed.wake_up_all();
         Ok(())
     }
 }
@@ -605,6 +1312,9 @@ impl BlockDeviceDomain for BlockDeviceDomainEmptyImpl {
     fn complete_request(&self, _rq_ptr: SafePtr) -> LinuxResult<()> {
         Err(LinuxError::ENOSYS)
     }
+    fn set_cache_mode(&self, _mode: CacheMode) -> LinuxResult<()> {
+        Err(LinuxError::ENOSYS)
+    }
 
     fn exit(&self) -> LinuxResult<()> {
         Ok(())
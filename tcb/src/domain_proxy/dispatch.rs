@@ -0,0 +1,205 @@
+use alloc::{sync::Arc, vec::Vec};
+
+use corelib::{
+    domain_info::{DomainLoadInfo, MethodCount},
+    LinuxError, LinuxResult,
+};
+use interface::{Basic, DomainType};
+
+use super::{
+    block_device::BlockDeviceDomainProxy, empty_device::EmptyDeviceDomainProxy,
+    logger::LogDomainProxy,
+};
+use crate::domain_helper::{query_domain, query_domain_by_id};
+
+/// The proxy of a registered domain, see [with_proxy]
+#[derive(Debug, Clone)]
+pub enum Proxy {
+    EmptyDevice(Arc<EmptyDeviceDomainProxy>),
+    BlockDevice(Arc<BlockDeviceDomainProxy>),
+    Log(Arc<LogDomainProxy>),
+}
+
+/// Evaluate `$e` with `$p` bound to the proxy, whatever its type
+macro_rules! each_proxy {
+    ($proxy:expr, $p:ident => $e:expr) => {
+        match $proxy {
+            Proxy::EmptyDevice($p) => $e,
+            Proxy::BlockDevice($p) => $e,
+            Proxy::Log($p) => $e,
+        }
+    };
+}
+
+impl Proxy {
+    /// Get the proxy behind `domain`, return `EINVAL` if it is not a proxy
+    pub fn from_domain(domain: &DomainType) -> LinuxResult<Self> {
+        let proxy = match domain {
+            DomainType::EmptyDeviceDomain(_) => domain.as_empty_device().map(Proxy::EmptyDevice),
+            DomainType::BlockDeviceDomain(_) => domain.as_block_device().map(Proxy::BlockDevice),
+            DomainType::LogDomain(_) => domain.as_logger().map(Proxy::Log),
+        };
+        proxy.ok_or(LinuxError::EINVAL)
+    }
+
+    pub fn domain_id(&self) -> u64 {
+        each_proxy!(self, p => p.domain_id())
+    }
+
+    /// Whether the calls go through the lock path, the logger has no lock path
+    pub fn is_upgrading(&self) -> bool {
+        match self {
+            Proxy::EmptyDevice(p) => p.is_upgrading(),
+            Proxy::BlockDevice(p) => p.is_upgrading(),
+            Proxy::Log(_) => false,
+        }
+    }
+
+    /// Let a long call of the domain wait for a pending upgrade, see `sys_domain_yield`
+    pub fn yield_point(&self) -> LinuxResult<()> {
+        match self {
+            Proxy::EmptyDevice(p) => p.yield_point(),
+            Proxy::BlockDevice(p) => p.yield_point(),
+            Proxy::Log(_) => Ok(()),
+        }
+    }
+
+    /// Whether the real domain is initialized, the logger forwards the calls even to
+    /// `LogDomainEmptyImpl`
+    pub fn is_ready(&self) -> bool {
+        match self {
+            Proxy::EmptyDevice(p) => p.is_ready(),
+            Proxy::BlockDevice(p) => p.is_ready(),
+            Proxy::Log(_) => true,
+        }
+    }
+
+    /// The calls refused because the domain was not ready, the logger is always ready
+    pub fn unready_calls(&self) -> u64 {
+        match self {
+            Proxy::EmptyDevice(p) => p.unready_calls(),
+            Proxy::BlockDevice(p) => p.unready_calls(),
+            Proxy::Log(_) => 0,
+        }
+    }
+
+    pub fn wait_ready(&self, timeout_ms: u64) -> LinuxResult<()> {
+        match self {
+            Proxy::EmptyDevice(p) => p.wait_ready(timeout_ms),
+            Proxy::BlockDevice(p) => p.wait_ready(timeout_ms),
+            Proxy::Log(_) => Ok(()),
+        }
+    }
+
+    /// The calls in flight on the no-lock path, `None` for the logger which does not count
+    /// them
+    pub fn in_flight(&self) -> Option<i64> {
+        match self {
+            Proxy::EmptyDevice(p) => Some(p.in_flight()),
+            Proxy::BlockDevice(p) => Some(p.in_flight()),
+            Proxy::Log(_) => None,
+        }
+    }
+
+    /// Wait until the calls in flight drain, the readers of the logger are waited by the SRCU
+    pub fn wait_quiescent(&self, timeout_ms: u64) -> LinuxResult<()> {
+        match self {
+            Proxy::EmptyDevice(p) => p.wait_quiescent(timeout_ms),
+            Proxy::BlockDevice(p) => p.wait_quiescent(timeout_ms),
+            Proxy::Log(_) => Ok(()),
+        }
+    }
+
+    /// Block the calls until [Proxy::thaw], the logger has no lock path
+    pub fn freeze(&self) -> LinuxResult<()> {
+        match self {
+            Proxy::EmptyDevice(p) => p.freeze(),
+            Proxy::BlockDevice(p) => p.freeze(),
+            Proxy::Log(_) => Err(LinuxError::EINVAL),
+        }
+    }
+
+    pub fn thaw(&self) -> LinuxResult<()> {
+        match self {
+            Proxy::EmptyDevice(p) => p.thaw(),
+            Proxy::BlockDevice(p) => p.thaw(),
+            Proxy::Log(_) => Err(LinuxError::EINVAL),
+        }
+    }
+
+    pub fn srcu_barrier(&self) -> LinuxResult<()> {
+        each_proxy!(self, p => p.srcu_barrier())
+    }
+
+    /// Swap the domain for an empty one without sleeping, see `TeardownMode::NoSleep`
+    pub fn retire_no_sleep(&self, timeout_ms: u64) -> LinuxResult<()> {
+        each_proxy!(self, p => p.retire_no_sleep(timeout_ms))
+    }
+
+    pub fn latency(&self) -> Vec<u64> {
+        each_proxy!(self, p => p.latency())
+    }
+
+    pub fn call_counts(&self) -> Vec<MethodCount> {
+        each_proxy!(self, p => p.call_counts())
+    }
+
+    pub fn idle_ms(&self) -> u64 {
+        each_proxy!(self, p => p.idle_ms())
+    }
+
+    pub fn touch(&self) {
+        each_proxy!(self, p => p.touch())
+    }
+
+    pub fn reset_metrics(&self) {
+        each_proxy!(self, p => p.reset_metrics())
+    }
+
+    pub fn load_info(&self) -> DomainLoadInfo {
+        each_proxy!(self, p => p.load_info())
+    }
+
+    /// Limit the submissions into the domain, return `EINVAL` for the logger, which is not
+    /// rate limited
+    pub fn set_rate_limit(&self, calls_per_sec: u64, burst: u64) -> LinuxResult<()> {
+        match self {
+            Proxy::EmptyDevice(p) => p.set_rate_limit(calls_per_sec, burst),
+            Proxy::BlockDevice(p) => p.set_rate_limit(calls_per_sec, burst),
+            Proxy::Log(_) => Err(LinuxError::EINVAL),
+        }
+    }
+
+    /// Get the empty device proxy, return `EINVAL` for the other proxies
+    pub fn empty_device(&self) -> LinuxResult<&EmptyDeviceDomainProxy> {
+        match self {
+            Proxy::EmptyDevice(p) => Ok(p),
+            _ => Err(LinuxError::EINVAL),
+        }
+    }
+
+    /// Get the block device proxy, return `EINVAL` for the other proxies
+    pub fn block_device(&self) -> LinuxResult<&BlockDeviceDomainProxy> {
+        match self {
+            Proxy::BlockDevice(p) => Ok(p),
+            _ => Err(LinuxError::EINVAL),
+        }
+    }
+}
+
+/// Run `f` on the proxy of the domain `name`
+///
+/// Return `EINVAL` if no domain is registered as `name`.
+pub fn with_proxy<R>(name: &str, f: impl FnOnce(&Proxy) -> LinuxResult<R>) -> LinuxResult<R> {
+    let domain = query_domain(name).ok_or(LinuxError::EINVAL)?;
+    f(&Proxy::from_domain(&domain)?)
+}
+
+/// Run `f` on the proxy of the domain `domain_id`, see [with_proxy]
+pub fn with_proxy_by_id<R>(
+    domain_id: u64,
+    f: impl FnOnce(&Proxy) -> LinuxResult<R>,
+) -> LinuxResult<R> {
+    let domain = query_domain_by_id(domain_id).ok_or(LinuxError::EINVAL)?;
+    f(&Proxy::from_domain(&domain)?)
+}
@@ -1,20 +1,81 @@
-use alloc::boxed::Box;
-use core::{any::Any, mem::forget, pin::Pin, sync::atomic::AtomicBool};
+use alloc::{boxed::Box, vec::Vec};
+use core::{
+    any::Any,
+    mem::forget,
+    pin::Pin,
+    sync::atomic::{AtomicBool, AtomicU64},
+};
 
-use corelib::{LinuxError, LinuxResult};
-use interface::{empty_device::EmptyDeviceDomain, Basic};
+use corelib::{
+    domain_info::{DomainLoadInfo, MethodCount, ReplaceOptions},
+    LinuxError, LinuxResult,
+};
+use interface::{
+    empty_device::{EmptyDeviceConfig, EmptyDeviceDomain},
+    Basic,
+};
 use kernel::{
     init::InPlaceInit,
-    sync::{LongLongPerCpu, Mutex, SRcuData},
+    sync::{
+        lock::{mutex::MutexBackend, Guard},
+        LongLongPerCpu, Mutex, SRcuData, WaitQueue,
+    },
 };
 use rref::{RRefVec, SharedData};
+use spin::Once;
+use tcb_helper::proxy::{CallCounts, FreezeFlag, LastActive, RateLimiter};
 
 use crate::{
-    domain_helper::{free_domain_resource, FreeShared},
+    config::DRAIN_TIMEOUT_MS,
+    domain_helper::{free_domain_resource, AllocScope, FreeShared},
     domain_loader::loader::DomainLoader,
-    domain_proxy::ProxyBuilder,
+    domain_proxy::{
+        check_move_target, check_rate_limit, check_ready, export_domain_state, invoke_domain,
+        now_ns, reentry, spin_quiescent, wait_quiescent, wait_ready, warn_partial_free,
+        watch_crash, LatencyHistogram, ProxyBuilder,
+    },
 };
 
+/// Method - 代理统计调用次数的方法，是方法在METHODS中的下标
+#[derive(Debug, Clone, Copy)]
+enum Method {
+    Invoke,
+    ExportState,
+    ImportState,
+    Read,
+    Write,
+    WriteRead,
+    ReadInterruptible,
+    OnShutdown,
+    OnMemoryPressure,
+}
+
+impl Method {
+    /// rate_limited - 方法是否受调用频率限制，状态的导出、导入、关机通知和内存压力通知是
+    /// 管理操作，不受限制
+    fn rate_limited(self) -> bool {
+        !matches!(
+            self,
+            Method::ExportState
+                | Method::ImportState
+                | Method::OnShutdown
+                | Method::OnMemoryPressure
+        )
+    }
+}
+
+const METHODS: [&str; 9] = [
+    "invoke",
+    "export_state",
+    "import_state",
+    "read",
+    "write",
+    "write_read",
+    "read_interruptible",
+    "on_shutdown",
+    "on_memory_pressure",
+];
+
 /// EmptyDeviceDomainProxy - 空设备域代理
 /// 这是实现热升级的核心组件，负责管理domain的生命周期和原子替换
 #[derive(Debug)]
@@ -37,6 +98,51 @@ pub struct EmptyDeviceDomainProxy {
     /// counter: 每CPU计数器，用于跟踪当前活跃的读操作数量
     /// 这是实现无锁读取和优雅升级的关键机制
     counter: LongLongPerCpu,
+
+    /// resource: init_by_box传入的EmptyDeviceConfig，热升级时用于初始化新domain
+    resource: Once<Box<dyn Any + Send + Sync>>,
+
+    /// frozen: domain是否被freeze冻结，冻结期间锁定路径上的调用在thawed上睡眠，
+    /// 只在持有lock时修改为true
+    frozen: FreezeFlag,
+
+    /// thawed: 等待thaw的调用睡眠在这里，thaw时全部唤醒
+    thawed: WaitQueue,
+
+    /// disabled: domain崩溃后被watchdog禁用，之后的调用都返回EIO，热升级后恢复
+    disabled: AtomicBool,
+
+    /// latency: 经过代理的调用的延迟直方图，属于代理，热升级后继续统计
+    latency: LatencyHistogram,
+
+    /// calls: 每个方法的调用次数，属于代理，热升级后继续统计
+    calls: CallCounts<9>,
+
+    /// last_active: 最后一次调用的时间，属于代理，热升级后保留
+    last_active: LastActive,
+
+    /// ready: 真正的domain是否已经初始化完成
+    /// build_empty创建的代理在第一次replace之前没有就绪，之前的调用返回EAGAIN
+    ready: AtomicBool,
+
+    /// unready_calls: 就绪之前被拒绝的调用次数，通常说明调用者在domain加载之前就使用了它
+    unready_calls: AtomicU64,
+
+    /// epoch: domain的代数，每次replace换上新domain后加一，缓存了DomainType的调用者
+    /// 可以通过它发现domain已经被热升级
+    epoch: AtomicU64,
+
+    /// id: 当前domain的id，replace时更新，调用不需要进入domain读取它
+    id: AtomicU64,
+
+    /// no_upgrade: domain从不热升级，调用不检查flag，直接走无锁路径
+    /// 无锁路径仍然更新计数器，in_flight、wait_quiescent和retire_no_sleep依赖它等待读者退出
+    /// 创建后不再改变，replace和freeze返回EPERM
+    no_upgrade: bool,
+
+    /// rate_limit: 调用频率限制，只限制提交数据的调用，见Method::rate_limited
+    /// 属于代理，热升级后继续对新domain生效
+    rate_limit: RateLimiter,
 }
 
 impl EmptyDeviceDomainProxy {
@@ -53,6 +159,7 @@ impl EmptyDeviceDomainProxy {
     /// 4. 初始化原子标志为false（正常模式）
     /// 5. 初始化每CPU计数器，用于跟踪活跃读操作
     pub fn new(domain: Box<dyn EmptyDeviceDomain>, domain_loader: DomainLoader) -> Self {
+        let id = domain.domain_id();
         EmptyDeviceDomainProxy {
             // 使用SRcuData包装domain，这是实现无锁读取的关键
             // SRcuData基于Linux内核的SRCU机制，允许读者在持有引用时睡眠
@@ -73,6 +180,45 @@ impl EmptyDeviceDomainProxy {
             // 每CPU计数器，用于跟踪当前活跃的读操作数量
             // 这是实现优雅升级的关键：等待所有现有读操作完成
             counter: LongLongPerCpu::new(),
+
+            // 在init_by_box时保存配置，热升级时用同样的配置初始化新domain
+            resource: Once::new(),
+
+            // 初始状态未冻结
+            frozen: FreezeFlag::new(),
+
+            thawed: WaitQueue::new(),
+
+            disabled: AtomicBool::new(false),
+
+            latency: LatencyHistogram::new(),
+
+            calls: CallCounts::new(METHODS),
+
+            last_active: LastActive::new(now_ns()),
+
+            // init或replace成功之后才就绪
+            ready: AtomicBool::new(false),
+
+            unready_calls: AtomicU64::new(0),
+
+            epoch: AtomicU64::new(0),
+
+            id: AtomicU64::new(id),
+
+            no_upgrade: false,
+
+            rate_limit: RateLimiter::new(),
+        }
+    }
+
+    /// new_no_upgrade - 创建从不热升级的EmptyDeviceDomainProxy实例
+    ///
+    /// 每次调用省去flag的读取和分支，代价是domain不能被热升级，见bench_pinned_path
+    pub fn new_no_upgrade(domain: Box<dyn EmptyDeviceDomain>, domain_loader: DomainLoader) -> Self {
+        EmptyDeviceDomainProxy {
+            no_upgrade: true,
+            ..Self::new(domain, domain_loader)
         }
     }
 }
@@ -84,6 +230,10 @@ impl ProxyBuilder for EmptyDeviceDomainProxy {
         Self::new(domain, domain_loader)
     }
 
+    fn build_no_upgrade(domain: Self::T, domain_loader: DomainLoader) -> Self {
+        Self::new_no_upgrade(domain, domain_loader)
+    }
+
     fn build_empty(domain_loader: DomainLoader) -> Self {
         Self::new(Box::new(EmptyDeviceDomainEmptyImpl::new()), domain_loader)
     }
@@ -91,8 +241,13 @@ impl ProxyBuilder for EmptyDeviceDomainProxy {
         Box::new(EmptyDeviceDomainEmptyImpl::new())
     }
 
-    fn init_by_box(&self, _argv: Box<dyn Any + Send + Sync>) -> LinuxResult<()> {
-        self.init()
+    fn init_by_box(&self, argv: Box<dyn Any + Send + Sync>) -> LinuxResult<()> {
+        let config = argv
+            .downcast_ref::<EmptyDeviceConfig>()
+            .ok_or(LinuxError::EINVAL)?;
+        self.init(config)?;
+        self.resource.call_once(|| argv);
+        Ok(())
     }
 }
 
@@ -109,6 +264,10 @@ impl Basic for EmptyDeviceDomainProxy {
     /// 
     /// 原子读取flag确保模式切换是原子的，不会出现中间状态
     fn domain_id(&self) -> u64 {
+        // 从不热升级的domain不检查flag，直接走无锁路径
+        if self.no_upgrade {
+            return self._domain_id_no_lock();
+        }
         // 原子地读取flag标志
         // Relaxed内存序足够，因为这里只需要原子性，不需要与其他操作同步
         if self.flag.load(core::sync::atomic::Ordering::Relaxed) {
@@ -121,31 +280,191 @@ impl Basic for EmptyDeviceDomainProxy {
             self._domain_id_no_lock()
         }
     }
+
+    fn on_memory_pressure(&self, level: u8) -> LinuxResult<usize> {
+        self.call(Method::OnMemoryPressure, || {
+            if self.no_upgrade {
+                self._on_memory_pressure_no_lock(level)
+            } else if self.flag.load(core::sync::atomic::Ordering::Relaxed) {
+                self._on_memory_pressure_with_lock(level)
+            } else {
+                self._on_memory_pressure_no_lock(level)
+            }
+        })
+    }
+
+    fn on_shutdown(&self) -> LinuxResult<()> {
+        self.call(Method::OnShutdown, || {
+            if self.no_upgrade {
+                self._on_shutdown_no_lock()
+            } else if self.flag.load(core::sync::atomic::Ordering::Relaxed) {
+                self._on_shutdown_with_lock()
+            } else {
+                self._on_shutdown_no_lock()
+            }
+        })
+    }
+
+    fn epoch(&self) -> u64 {
+        self.epoch.load(core::sync::atomic::Ordering::Acquire)
+    }
+
+    fn invoke(&self, op: u32, buf: RRefVec<u8>) -> LinuxResult<RRefVec<u8>> {
+        self.call(Method::Invoke, || {
+            if self.no_upgrade {
+                self._invoke_no_lock(op, buf, None)
+            } else if self.flag.load(core::sync::atomic::Ordering::Relaxed) {
+                self._invoke_with_lock(op, buf, None)
+            } else {
+                self._invoke_no_lock(op, buf, None)
+            }
+        })
+    }
+
+    fn invoke_interruptible(
+        &self,
+        op: u32,
+        buf: RRefVec<u8>,
+        call_id: u64,
+    ) -> LinuxResult<RRefVec<u8>> {
+        self.call(Method::Invoke, || {
+            if self.no_upgrade {
+                self._invoke_no_lock(op, buf, Some(call_id))
+            } else if self.flag.load(core::sync::atomic::Ordering::Relaxed) {
+                self._invoke_with_lock(op, buf, Some(call_id))
+            } else {
+                self._invoke_no_lock(op, buf, Some(call_id))
+            }
+        })
+    }
+
+    fn export_state(&self) -> LinuxResult<RRefVec<u8>> {
+        self.call(Method::ExportState, || {
+            if self.no_upgrade {
+                self._export_state_no_lock()
+            } else if self.flag.load(core::sync::atomic::Ordering::Relaxed) {
+                self._export_state_with_lock()
+            } else {
+                self._export_state_no_lock()
+            }
+        })
+    }
+
+    fn import_state(&self, state: &RRefVec<u8>) -> LinuxResult<()> {
+        self.call(Method::ImportState, || {
+            if self.no_upgrade {
+                self._import_state_no_lock(state)
+            } else if self.flag.load(core::sync::atomic::Ordering::Relaxed) {
+                self._import_state_with_lock(state)
+            } else {
+                self._import_state_no_lock(state)
+            }
+        })
+    }
 }
 
 impl EmptyDeviceDomain for EmptyDeviceDomainProxy {
-    fn init(&self) -> LinuxResult<()> {
-        self.domain.read_directly(|domain| domain.init())
+    fn init(&self, config: &EmptyDeviceConfig) -> LinuxResult<()> {
+        self.domain.read_directly(|domain| {
+            domain.init(config)?;
+            // EmptyDeviceDomainEmptyImpl的id是u64::MAX，它初始化后仍然没有就绪
+            if domain.domain_id() != u64::MAX {
+                self.ready
+                    .store(true, core::sync::atomic::Ordering::Release);
+            }
+            Ok(())
+        })
     }
 
     fn read(&self, data: RRefVec<u8>) -> LinuxResult<RRefVec<u8>> {
-        if self.flag.load(core::sync::atomic::Ordering::Relaxed) {
-            self._read_with_lock(data)
-        } else {
-            self._read_no_lock(data)
-        }
+        self.call(Method::Read, || {
+            if self.no_upgrade {
+                self._read_no_lock(data)
+            } else if self.flag.load(core::sync::atomic::Ordering::Relaxed) {
+                self._read_with_lock(data)
+            } else {
+                self._read_no_lock(data)
+            }
+        })
     }
 
     fn write(&self, data: &RRefVec<u8>) -> LinuxResult<usize> {
-        if self.flag.load(core::sync::atomic::Ordering::Relaxed) {
-            self._write_with_lock(data)
-        } else {
-            self._write_no_lock(data)
-        }
+        self.call(Method::Write, || {
+            if self.no_upgrade {
+                self._write_no_lock(data)
+            } else if self.flag.load(core::sync::atomic::Ordering::Relaxed) {
+                self._write_with_lock(data)
+            } else {
+                self._write_no_lock(data)
+            }
+        })
+    }
+
+    fn write_read(&self, data: RRefVec<u8>) -> LinuxResult<RRefVec<u8>> {
+        self.call(Method::WriteRead, || {
+            if self.no_upgrade {
+                self._write_read_no_lock(data)
+            } else if self.flag.load(core::sync::atomic::Ordering::Relaxed) {
+                self._write_read_with_lock(data)
+            } else {
+                self._write_read_no_lock(data)
+            }
+        })
+    }
+
+    fn read_interruptible(&self, data: &mut RRefVec<u8>, call_id: u64) -> LinuxResult<usize> {
+        self.call(Method::ReadInterruptible, || {
+            if self.no_upgrade {
+                self._read_interruptible_no_lock(data, call_id)
+            } else if self.flag.load(core::sync::atomic::Ordering::Relaxed) {
+                self._read_interruptible_with_lock(data, call_id)
+            } else {
+                self._read_interruptible_no_lock(data, call_id)
+            }
+        })
     }
 }
 
 impl EmptyDeviceDomainProxy {
+    /// call - 进入domain的一次调用
+    ///
+    /// 被禁用的domain直接返回EIO，重入检测见reentry::enter，
+    /// 调用崩溃时由watch_crash按照watchdog的策略重启或禁用domain
+    fn call<R>(&self, method: Method, f: impl FnOnce() -> LinuxResult<R>) -> LinuxResult<R> {
+        check_ready("EmptyDeviceDomainProxy", &self.ready, &self.unready_calls)?;
+        if self.disabled.load(core::sync::atomic::Ordering::Relaxed) {
+            return Err(LinuxError::EIO);
+        }
+        if method.rate_limited() {
+            check_rate_limit(&self.rate_limit)?;
+        }
+        let id = self.id.load(core::sync::atomic::Ordering::Relaxed);
+        let scope = AllocScope::begin(id);
+        #[cfg(feature = "fault_injection")]
+        crate::domain_proxy::fault::delay(id);
+        self.calls.count(method as usize);
+        self.last_active.touch(now_ns());
+        let r = self
+            .latency
+            .measure(|| reentry::enter(self as *const Self as usize, f));
+        watch_crash(scope, &self.disabled, r)
+    }
+
+    /// lock_thawed - 获取锁定路径的锁，domain被冻结时先睡眠到thaw
+    ///
+    /// frozen只在持有锁时变为true，所以拿到锁并且没有冻结的调用一定在freeze之前完成，
+    /// freeze之后的调用都会在这里等待。
+    fn lock_thawed(&self) -> Guard<'_, (), MutexBackend> {
+        loop {
+            let lock = self.lock.lock();
+            if !self.frozen.is_frozen() {
+                return lock;
+            }
+            drop(lock);
+            self.thawed.wait(|| !self.frozen.is_frozen());
+        }
+    }
+
     /// _domain_id - 内部方法：获取domain ID（基础版本）
     /// 
     /// 直接通过SRcuData读取domain的ID，不涉及任何锁或计数器
@@ -202,8 +521,8 @@ impl EmptyDeviceDomainProxy {
     /// - 与replace方法中的写锁配合使用
     fn _domain_id_with_lock(&self) -> u64 {
         // 步骤1: 获取互斥锁
-        // 这会阻塞，直到锁可用
-        let lock = self.lock.lock();
+        // 这会阻塞，直到锁可用；domain被冻结时一直等到thaw
+        let lock = self.lock_thawed();
         
         // 步骤2: 在锁保护下读取domain ID
         let r = self._domain_id();
@@ -232,31 +551,21 @@ impl EmptyDeviceDomainProxy {
     /// 2. 热升级时数据可以安全迁移
     /// 3. 避免数据竞争和所有权混乱
     fn _read(&self, data: RRefVec<u8>) -> LinuxResult<RRefVec<u8>> {
+        let old_id = data.domain_id();
         // 使用SRcuData的read_directly方法，在RCU保护下访问domain
-        let (res, old_id) = self.domain.read_directly(|domain| {
-            // 步骤1: 获取当前domain的ID
-            // 这个ID用于数据所有权管理
+        let res = self.domain.read_directly(|domain| {
+            // 获取当前domain的ID，数据所有权迁移到这个domain
             let id = domain.domain_id();
-            
-            // 步骤2: 将数据所有权迁移到当前domain
-            // data.move_to(id)返回原始domain ID，用于后续恢复
-            let old_id = data.move_to(id);
-            
-            // 步骤3: 调用实际domain的read方法
-            // 此时数据属于当前domain，可以安全访问
-            let r = domain.read(data);
-            
-            // 步骤4: 返回结果和原始domain ID
-            (r, old_id)
+            check_move_target(id);
+            // 调用实际domain的read方法，结果数据的所有权迁移回原始domain；
+            // 返回的数据必须属于被调用的domain，否则可能是其他domain的数据，
+            // 不能释放，返回EPROTO，见RRefVec::call_moved
+            data.call_moved(id, |data| domain.read(data))
         });
-        
-        // 处理结果：将数据所有权迁移回原始domain
-        res.map(|r| {
-            // 将结果数据的所有权迁移回原始domain
-            // 这是为了保持数据所有权的一致性
-            r.move_to(old_id);
-            r
-        })
+
+        // debug构建下检查原始domain是否还存在，迁移到不存在的domain会让数据成为孤儿
+        check_move_target(old_id);
+        res
     }
 
     /// _write - 内部方法：写入数据（基础版本）
@@ -272,6 +581,29 @@ impl EmptyDeviceDomainProxy {
         self.domain.read_directly(|domain| domain.write(data))
     }
 
+    /// _write_read - 内部方法：写入数据并读取响应（基础版本）
+    ///
+    /// 与_read相同，请求数据的所有权迁移到当前domain，
+    /// 响应数据的所有权再迁移回原始domain，见RRefVec::call_moved
+    fn _write_read(&self, data: RRefVec<u8>) -> LinuxResult<RRefVec<u8>> {
+        let old_id = data.domain_id();
+        let res = self.domain.read_directly(|domain| {
+            let id = domain.domain_id();
+            check_move_target(id);
+            data.call_moved(id, |data| domain.write_read(data))
+        });
+        check_move_target(old_id);
+        res
+    }
+
+    /// _read_interruptible - 内部方法：可取消的读取（基础版本）
+    ///
+    /// 与_write相同，数据通过引用传递，不需要所有权转移
+    fn _read_interruptible(&self, data: &mut RRefVec<u8>, call_id: u64) -> LinuxResult<usize> {
+        self.domain
+            .read_directly(|domain| domain.read_interruptible(data, call_id))
+    }
+
     fn _read_no_lock(&self, data: RRefVec<u8>) -> LinuxResult<RRefVec<u8>> {
         self.counter.get_with(|counter| {
             *counter += 1;
@@ -294,19 +626,192 @@ impl EmptyDeviceDomainProxy {
         r
     }
 
+    fn _write_read_no_lock(&self, data: RRefVec<u8>) -> LinuxResult<RRefVec<u8>> {
+        self.counter.get_with(|counter| {
+            *counter += 1;
+        });
+        let r = self._write_read(data);
+        self.counter.get_with(|counter| {
+            *counter -= 1;
+        });
+        r
+    }
+
+    fn _read_interruptible_no_lock(
+        &self,
+        data: &mut RRefVec<u8>,
+        call_id: u64,
+    ) -> LinuxResult<usize> {
+        self.counter.get_with(|counter| {
+            *counter += 1;
+        });
+        let r = self._read_interruptible(data, call_id);
+        self.counter.get_with(|counter| {
+            *counter -= 1;
+        });
+        r
+    }
+
+    /// _invoke - 内部方法：通用调用，所有权迁移与_read相同，有call_id时调用invoke_interruptible
+    fn _invoke(&self, op: u32, buf: RRefVec<u8>, call_id: Option<u64>) -> LinuxResult<RRefVec<u8>> {
+        self.domain
+            .read_directly(|domain| invoke_domain(domain.as_ref(), op, buf, call_id))
+    }
+
+    fn _invoke_no_lock(
+        &self,
+        op: u32,
+        buf: RRefVec<u8>,
+        call_id: Option<u64>,
+    ) -> LinuxResult<RRefVec<u8>> {
+        self.counter.get_with(|counter| {
+            *counter += 1;
+        });
+        let r = self._invoke(op, buf, call_id);
+        self.counter.get_with(|counter| {
+            *counter -= 1;
+        });
+        r
+    }
+
+    fn _invoke_with_lock(
+        &self,
+        op: u32,
+        buf: RRefVec<u8>,
+        call_id: Option<u64>,
+    ) -> LinuxResult<RRefVec<u8>> {
+        let lock = self.lock_thawed();
+        let r = self._invoke(op, buf, call_id);
+        drop(lock);
+        r
+    }
+
+    /// _export_state - 内部方法：导出domain的状态，状态数据的所有权迁移到内核
+    fn _export_state(&self) -> LinuxResult<RRefVec<u8>> {
+        self.domain
+            .read_directly(|domain| export_domain_state(domain.as_ref()))
+    }
+
+    fn _export_state_no_lock(&self) -> LinuxResult<RRefVec<u8>> {
+        self.counter.get_with(|counter| {
+            *counter += 1;
+        });
+        let r = self._export_state();
+        self.counter.get_with(|counter| {
+            *counter -= 1;
+        });
+        r
+    }
+
+    fn _export_state_with_lock(&self) -> LinuxResult<RRefVec<u8>> {
+        let lock = self.lock_thawed();
+        let r = self._export_state();
+        drop(lock);
+        r
+    }
+
+    /// _import_state - 内部方法：导入状态，与_write相同，数据通过引用传递
+    fn _import_state(&self, state: &RRefVec<u8>) -> LinuxResult<()> {
+        self.domain
+            .read_directly(|domain| domain.import_state(state))
+    }
+
+    fn _import_state_no_lock(&self, state: &RRefVec<u8>) -> LinuxResult<()> {
+        self.counter.get_with(|counter| {
+            *counter += 1;
+        });
+        let r = self._import_state(state);
+        self.counter.get_with(|counter| {
+            *counter -= 1;
+        });
+        r
+    }
+
+    fn _import_state_with_lock(&self, state: &RRefVec<u8>) -> LinuxResult<()> {
+        let lock = self.lock_thawed();
+        let r = self._import_state(state);
+        drop(lock);
+        r
+    }
+
+    /// _on_shutdown - 内部方法：关机前通知domain刷新缓存的数据
+    fn _on_shutdown(&self) -> LinuxResult<()> {
+        self.domain.read_directly(|domain| domain.on_shutdown())
+    }
+
+    fn _on_shutdown_no_lock(&self) -> LinuxResult<()> {
+        self.counter.get_with(|counter| {
+            *counter += 1;
+        });
+        let r = self._on_shutdown();
+        self.counter.get_with(|counter| {
+            *counter -= 1;
+        });
+        r
+    }
+
+    fn _on_shutdown_with_lock(&self) -> LinuxResult<()> {
+        let lock = self.lock_thawed();
+        let r = self._on_shutdown();
+        drop(lock);
+        r
+    }
+
+    /// _on_memory_pressure - 内部方法：通知domain释放缓存，返回释放的字节数
+    fn _on_memory_pressure(&self, level: u8) -> LinuxResult<usize> {
+        self.domain
+            .read_directly(|domain| domain.on_memory_pressure(level))
+    }
+
+    fn _on_memory_pressure_no_lock(&self, level: u8) -> LinuxResult<usize> {
+        self.counter.get_with(|counter| {
+            *counter += 1;
+        });
+        let r = self._on_memory_pressure(level);
+        self.counter.get_with(|counter| {
+            *counter -= 1;
+        });
+        r
+    }
+
+    fn _on_memory_pressure_with_lock(&self, level: u8) -> LinuxResult<usize> {
+        let lock = self.lock_thawed();
+        let r = self._on_memory_pressure(level);
+        drop(lock);
+        r
+    }
+
     fn _read_with_lock(&self, data: RRefVec<u8>) -> LinuxResult<RRefVec<u8>> {
-        let lock = self.lock.lock();
+        let lock = self.lock_thawed();
         let r = self._read(data);
         drop(lock);
         r
     }
 
     fn _write_with_lock(&self, data: &RRefVec<u8>) -> LinuxResult<usize> {
-        let lock = self.lock.lock();
+        let lock = self.lock_thawed();
         let r = self._write(data);
         drop(lock);
         r
     }
+
+    fn _write_read_with_lock(&self, data: RRefVec<u8>) -> LinuxResult<RRefVec<u8>> {
+        let lock = self.lock_thawed();
+        let r = self._write_read(data);
+        drop(lock);
+        r
+    }
+
+    fn _read_interruptible_with_lock(
+        &self,
+        data: &mut RRefVec<u8>,
+        call_id: u64,
+    ) -> LinuxResult<usize> {
+        let lock = self.lock_thawed();
+        let r = self._read_interruptible(data, call_id);
+        drop(lock);
+        r
+    }
 }
 
 impl EmptyDeviceDomainProxy {
@@ -317,15 +822,38 @@ impl EmptyDeviceDomainProxy {
     /// 3. 等待所有现有读操作完成
     /// 4. 原子替换domain实例
     /// 5. 清理旧domain资源
+    ///
+    /// options决定旧domain的哪些共享数据迁移到新domain，其余的被释放，
+    /// 默认全部迁移。返回等待读操作完成时的轮询次数，
+    /// 代理还没有通过init_by_box初始化时返回EINVAL。
+    /// 读操作超过DRAIN_TIMEOUT_MS毫秒仍未完成时返回ETIMEDOUT，旧domain继续服务
     pub fn replace(
         &self,
         new_domain: Box<dyn EmptyDeviceDomain>,  // 新版本的domain实例
         domain_loader: DomainLoader,             // 新domain的加载器
-    ) -> LinuxResult<()> {
+        options: ReplaceOptions,                 // 共享数据的迁移选项
+    ) -> LinuxResult<usize> {
+        // 从不热升级的domain没有锁定路径，不能替换
+        if self.no_upgrade {
+            return Err(LinuxError::EPERM);
+        }
         println!("EmptyDeviceDomainProxy replace - 开始热升级");
+        // 新domain使用init_by_box保存的配置初始化，在改变任何状态之前检查它
+        let config = self
+            .resource
+            .get()
+            .and_then(|resource| resource.as_ref().downcast_ref::<EmptyDeviceConfig>())
+            .ok_or(LinuxError::EINVAL)?;
         
         // 步骤1: 获取domain_loader的锁，防止在升级过程中加载器被修改
+        // 锁的顺序是先domain_loader后lock
+        self.lock.assert_not_held();
         let mut loader_guard = self.domain_loader.lock();
+
+        // 冻结期间不能进行热升级
+        if self.frozen.is_frozen() {
+            return Err(LinuxError::EBUSY);
+        }
         
         // 步骤2: 获取写锁，阻止新的写操作
         // 在启用锁定路径之前获取写锁，确保原子性
@@ -339,26 +867,38 @@ impl EmptyDeviceDomainProxy {
         self.flag.store(true, core::sync::atomic::Ordering::Relaxed);
 
         // 步骤4: 等待所有现有的读操作完成
-        // 检查每CPU计数器，确保所有无锁读操作都已完成
-        while self.counter.sum() != 0 {
-            println!("等待所有读操作完成，当前活跃读操作数: {}", self.counter.sum());
-            // 在实际实现中，这里可能会调用yield_now()让出CPU
-            // yield_now();
-        }
+        // 检查每CPU计数器，确保所有无锁读操作都已完成，超时后关闭锁定路径并放弃升级
+        let drain_iterations = match wait_quiescent(&self.counter, DRAIN_TIMEOUT_MS) {
+            Ok(polls) => polls,
+            Err(e) => {
+                self.flag
+                    .store(false, core::sync::atomic::Ordering::Relaxed);
+                return Err(e);
+            }
+        };
 
-        // 步骤5: 初始化新domain
+        // 步骤5: 使用init_by_box保存的配置初始化新domain
         let new_domain_id = new_domain.domain_id();
-        new_domain.init().unwrap();
+        new_domain.init(config).unwrap();
 
         // 步骤6: 原子替换domain实例
         // 使用SRcuData的update_directly方法原子地替换domain
         // 这是热升级的关键步骤，确保替换操作是原子的
         let old_domain = self.domain.update_directly(new_domain);
+        self.id
+            .store(new_domain_id, core::sync::atomic::Ordering::Relaxed);
+        self.epoch
+            .fetch_add(1, core::sync::atomic::Ordering::Release);
 
         // 步骤7: 禁用锁定路径
         // 将flag设回false，新请求可以继续走无锁路径
+        // 新domain没有崩溃过，解除watchdog的禁用
+        self.disabled
+            .store(false, core::sync::atomic::Ordering::Relaxed);
         self.flag
             .store(false, core::sync::atomic::Ordering::Relaxed);
+        // 新domain已经初始化，代理就绪
+        self.ready.store(true, core::sync::atomic::Ordering::Release);
         
         // 步骤8: 清理旧domain资源
         // 将旧domain从Box中取出，但不立即drop
@@ -369,8 +909,10 @@ impl EmptyDeviceDomainProxy {
         forget(real_domain);
 
         // 步骤9: 释放旧domain的资源，但保留共享数据
-        // FreeShared::NotFree(new_domain_id)表示共享数据不释放，因为新domain还在使用
-        free_domain_resource(old_id, FreeShared::NotFree(new_domain_id));
+        // FreeShared::NotFree(new_domain_id, options)表示共享数据不释放，因为新domain还在使用，
+        // 只有options选中的数据（例如缓存）被释放
+        let res = free_domain_resource(old_id, FreeShared::NotFree(new_domain_id, options));
+        warn_partial_free(old_id, res);
         
         // 步骤10: 更新domain_loader
         *loader_guard = domain_loader;
@@ -380,6 +922,211 @@ impl EmptyDeviceDomainProxy {
         drop(loader_guard);
         
         println!("热升级完成，旧domain ID: {} -> 新domain ID: {}", old_id, new_domain_id);
+        Ok(drain_iterations)
+    }
+
+    /// replace_loader_only - 只替换domain_loader，不替换domain
+    ///
+    /// 新的加载器必须还没有加载，它接管正在运行的domain的镜像，
+    /// 例如换上带有更完整调试信息的ELF。调用不会被阻塞，新的加载器已经加载时返回EINVAL
+    pub fn replace_loader_only(&self, mut domain_loader: DomainLoader) -> LinuxResult<()> {
+        // 锁的顺序是先domain_loader后lock
+        self.lock.assert_not_held();
+        let mut loader_guard = self.domain_loader.lock();
+        domain_loader
+            .take_over(&mut loader_guard)
+            .map_err(|_| LinuxError::EINVAL)?;
+        *loader_guard = domain_loader;
+        Ok(())
+    }
+}
+
+impl EmptyDeviceDomainProxy {
+    /// is_upgrading - domain当前是否处于锁定路径（正在热升级或被冻结）
+    ///
+    /// 只读取flag，不获取任何锁
+    pub fn is_upgrading(&self) -> bool {
+        self.flag.load(core::sync::atomic::Ordering::Relaxed)
+    }
+
+    /// yield_point - domain在长时间调用中的安全点，见sys_domain_yield
+    ///
+    /// 正在热升级或被冻结时返回EAGAIN，domain应该放弃这次调用，让replace等到读操作完成。
+    /// 计数器不会被减少：计数器归零后replace就会释放旧domain，而它还在执行这次调用
+    pub fn yield_point(&self) -> LinuxResult<()> {
+        if !self.no_upgrade && self.flag.load(core::sync::atomic::Ordering::Relaxed) {
+            return Err(LinuxError::EAGAIN);
+        }
+        Ok(())
+    }
+
+    /// is_pinned - domain是否从不热升级，见new_no_upgrade
+    pub fn is_pinned(&self) -> bool {
+        self.no_upgrade
+    }
+
+    /// bench_pinned_path - 测量domain_id经过可升级路径和固定路径的每次调用耗时（纳秒）
+    ///
+    /// 两条路径都在这个代理上运行，与代理本身是否固定无关，差值就是固定路径每次调用省下的时间。
+    /// 可升级路径读取flag后走无锁或锁定路径，固定路径直接走无锁路径，两者都更新计数器
+    pub fn bench_pinned_path(&self, iterations: u64) -> (u64, u64) {
+        let iterations = iterations.max(1);
+        let time = |f: &dyn Fn() -> u64| {
+            let start = now_ns();
+            for _ in 0..iterations {
+                core::hint::black_box(f());
+            }
+            (now_ns() - start) / iterations
+        };
+        let upgradable = time(&|| {
+            if self.flag.load(core::sync::atomic::Ordering::Relaxed) {
+                self._domain_id_with_lock()
+            } else {
+                self._domain_id_no_lock()
+            }
+        });
+        let pinned = time(&|| self._domain_id_no_lock());
+        (upgradable, pinned)
+    }
+
+    /// is_ready - 真正的domain是否已经初始化完成，没有就绪时调用返回EAGAIN
+    pub fn is_ready(&self) -> bool {
+        self.ready.load(core::sync::atomic::Ordering::Acquire)
+    }
+
+    /// unready_calls - 就绪之前被拒绝的调用次数
+    pub fn unready_calls(&self) -> u64 {
+        self.unready_calls
+            .load(core::sync::atomic::Ordering::Relaxed)
+    }
+
+    /// wait_ready - 等待domain就绪，timeout_ms毫秒后返回ETIMEDOUT
+    pub fn wait_ready(&self, timeout_ms: u64) -> LinuxResult<()> {
+        wait_ready(&self.ready, timeout_ms)
+    }
+
+    /// latency - 调用延迟直方图每个桶的调用次数
+    pub fn latency(&self) -> Vec<u64> {
+        self.latency.counts()
+    }
+
+    /// call_counts - 每个方法的调用次数
+    pub fn call_counts(&self) -> Vec<MethodCount> {
+        self.calls.counts()
+    }
+
+    /// idle_ms - 距离最后一次调用的毫秒数，没有调用过时从代理创建开始计算
+    pub fn idle_ms(&self) -> u64 {
+        self.last_active.idle_ms(now_ns())
+    }
+
+    /// touch - 记录一次活动，不经过代理的使用也可以让domain不被当作空闲
+    pub fn touch(&self) {
+        self.last_active.touch(now_ns());
+    }
+
+    /// set_rate_limit - 限制调用频率，见sys_set_domain_rate_limit
+    pub fn set_rate_limit(&self, calls_per_sec: u64, burst: u64) -> LinuxResult<()> {
+        self.rate_limit.set(calls_per_sec, burst, now_ns())
+    }
+
+    /// reset_metrics - 清零代理的统计，不阻塞正在进行的调用
+    pub fn reset_metrics(&self) {
+        self.latency.reset();
+        self.calls.reset();
+        self.unready_calls
+            .store(0, core::sync::atomic::Ordering::Relaxed);
+    }
+
+    /// load_info - 当前domain的ELF镜像信息，热升级后是新domain的镜像
+    pub fn load_info(&self) -> DomainLoadInfo {
+        // 锁的顺序是先domain_loader后lock
+        self.lock.assert_not_held();
+        self.domain_loader.lock().domain_load_info()
+    }
+
+    /// in_flight - 正在执行的无锁调用数量，只是一个瞬时值
+    pub fn in_flight(&self) -> i64 {
+        self.counter.sum()
+    }
+
+    /// wait_quiescent - 等待domain没有正在执行的无锁调用
+    ///
+    /// 不启用锁定路径，也不阻塞新的请求，只观察一个瞬时的空闲点。
+    /// 超过timeout_ms毫秒仍未空闲则返回ETIMEDOUT。
+    pub fn wait_quiescent(&self, timeout_ms: u64) -> LinuxResult<()> {
+        wait_quiescent(&self.counter, timeout_ms).map(|_| ())
+    }
+
+    /// srcu_barrier - 等待domain的SRCU上所有已经提交的回调执行完毕
+    ///
+    /// 会睡眠，在原子上下文中返回EDEADLK。
+    pub fn srcu_barrier(&self) -> LinuxResult<()> {
+        self.domain.barrier().map_err(|_| LinuxError::EDEADLK)
+    }
+
+    /// retire_no_sleep - 不睡眠地把domain换成空domain，用于关机时的卸载
+    ///
+    /// 忙等无锁调用退出，再用update_no_sleep替换domain并忙等宽限期结束，各最多
+    /// timeout_ms毫秒。调用没有退出时返回ETIMEDOUT，domain不变；宽限期超时同样
+    /// 返回ETIMEDOUT，旧domain可能还有读者，不会被释放。
+    ///
+    /// 旧domain和replace中一样被forget，由它的加载器释放。
+    pub fn retire_no_sleep(&self, timeout_ms: u64) -> LinuxResult<()> {
+        spin_quiescent(&self.counter, timeout_ms)?;
+        self.ready
+            .store(false, core::sync::atomic::Ordering::Release);
+        let old_domain = self
+            .domain
+            .update_no_sleep(Box::new(EmptyDeviceDomainEmptyImpl::new()), timeout_ms)
+            .map_err(|_| LinuxError::ETIMEDOUT)?;
+        forget(Box::into_inner(old_domain));
+        Ok(())
+    }
+
+    /// freeze - 冻结domain
+    ///
+    /// 启用锁定路径并等待所有无锁读操作完成，然后设置frozen，
+    /// 之后的新请求都会在锁定路径上睡眠，直到调用thaw。freeze返回时不持有任何锁，
+    /// 冻结期间的系统调用不受影响。
+    /// 如果domain已经被冻结，返回EBUSY；从不热升级的domain返回EPERM；
+    /// 读操作超过DRAIN_TIMEOUT_MS毫秒仍未完成时返回ETIMEDOUT，domain不被冻结。
+    pub fn freeze(&self) -> LinuxResult<()> {
+        // 从不热升级的domain的调用不经过锁定路径，无法冻结
+        if self.no_upgrade {
+            return Err(LinuxError::EPERM);
+        }
+        // domain_loader的锁用于和replace互斥
+        self.lock.assert_not_held();
+        let loader_guard = self.domain_loader.lock();
+        if self.frozen.is_frozen() {
+            return Err(LinuxError::EBUSY);
+        }
+        let w_lock = self.lock.lock();
+        self.flag.store(true, core::sync::atomic::Ordering::Relaxed);
+        if let Err(e) = wait_quiescent(&self.counter, DRAIN_TIMEOUT_MS) {
+            self.flag
+                .store(false, core::sync::atomic::Ordering::Relaxed);
+            return Err(e);
+        }
+        // 持有锁时设置，锁定路径上的调用拿到锁之后就能看到
+        self.frozen.freeze()?;
+        drop(w_lock);
+        drop(loader_guard);
+        Ok(())
+    }
+
+    /// thaw - 解冻domain，唤醒在锁定路径上等待的调用
+    ///
+    /// 如果domain没有被冻结，返回EINVAL。
+    pub fn thaw(&self) -> LinuxResult<()> {
+        // domain_loader的锁用于和freeze、replace互斥
+        let loader_guard = self.domain_loader.lock();
+        self.frozen.thaw()?;
+        self.flag
+            .store(false, core::sync::atomic::Ordering::Relaxed);
+        drop(loader_guard);
+        self.thawed.wake_up_all();
         Ok(())
     }
 }
@@ -400,7 +1147,7 @@ impl Basic for EmptyDeviceDomainEmptyImpl {
 }
 
 impl EmptyDeviceDomain for EmptyDeviceDomainEmptyImpl {
-    fn init(&self) -> LinuxResult<()> {
+    fn init(&self, _config: &EmptyDeviceConfig) -> LinuxResult<()> {
         Ok(())
     }
 
@@ -411,4 +1158,12 @@ impl EmptyDeviceDomain for EmptyDeviceDomainEmptyImpl {
     fn write(&self, _data: &RRefVec<u8>) -> LinuxResult<usize> {
         Err(LinuxError::ENOSYS)
     }
+
+    fn write_read(&self, _data: RRefVec<u8>) -> LinuxResult<RRefVec<u8>> {
+        Err(LinuxError::ENOSYS)
+    }
+
+    fn read_interruptible(&self, _data: &mut RRefVec<u8>, _call_id: u64) -> LinuxResult<usize> {
+        Err(LinuxError::ENOSYS)
+    }
 }
@@ -0,0 +1,62 @@
+//! Fault injection into the calls of the proxies, only built with the `fault_injection`
+//! feature.
+use alloc::collections::BTreeMap;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use kernel::{sync::WaitQueue, time::Ktime};
+use ksync::{Lazy, Mutex};
+
+/// The latency injected at the start of each call into a domain in microseconds, indexed by
+/// domain id
+static INJECTED_LATENCY: Mutex<BTreeMap<u64, u64>> = Mutex::new(BTreeMap::new());
+/// The number of the domains in [INJECTED_LATENCY], updated under its lock
+///
+/// The calls only take the lock while some latency is injected.
+static INJECTED_DOMAINS: AtomicUsize = AtomicUsize::new(0);
+/// Woken up when the injected latencies change, so that a delayed call which sleeps stops
+/// once its latency is removed
+static LATENCY_CHANGED: Lazy<WaitQueue> = Lazy::new(WaitQueue::new);
+
+/// Delay each call into the domain `domain_id` by `us` microseconds, 0 removes the delay
+///
+/// The delay is bound to the domain id, so the new domain of an upgrade is not delayed.
+pub fn inject_latency(domain_id: u64, us: u64) {
+    let mut latency = INJECTED_LATENCY.lock();
+    if us == 0 {
+        latency.remove(&domain_id);
+    } else {
+        latency.insert(domain_id, us);
+    }
+    INJECTED_DOMAINS.store(latency.len(), Ordering::Release);
+    drop(latency);
+    LATENCY_CHANGED.wake_up_all();
+}
+
+fn injected_latency(domain_id: u64) -> Option<u64> {
+    if INJECTED_DOMAINS.load(Ordering::Acquire) == 0 {
+        return None;
+    }
+    INJECTED_LATENCY.lock().get(&domain_id).copied()
+}
+
+/// Wait for the latency injected into the domain `domain_id`
+///
+/// It sleeps on [LATENCY_CHANGED] in process context, rounded up to milliseconds. A call
+/// may also run in atomic context, like `queue_rq`, where it spins instead.
+pub(super) fn delay(domain_id: u64) {
+    let Some(us) = injected_latency(domain_id) else {
+        return;
+    };
+    let can_sleep =
+        unsafe { kernel::bindings::in_atomic() == 0 && kernel::bindings::irqs_disabled() == 0 };
+    if can_sleep {
+        LATENCY_CHANGED.wait_timeout(us.div_ceil(1000), || {
+            injected_latency(domain_id) != Some(us)
+        });
+        return;
+    }
+    let start = Ktime::ktime_get().to_ns();
+    while ((Ktime::ktime_get().to_ns() - start) as u64) < us * 1000 {
+        core::hint::spin_loop();
+    }
+}
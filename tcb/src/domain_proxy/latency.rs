@@ -0,0 +1,46 @@
+use alloc::vec::Vec;
+
+use kernel::{sync::LongLongPerCpu, time::Ktime};
+use tcb_helper::proxy::{latency_bucket, LATENCY_BUCKETS};
+
+/// The latency histogram of the calls through a proxy
+///
+/// It belongs to the proxy rather than the domain, so it keeps counting across the hot
+/// upgrades. Each bucket is a per-CPU counter, recording a call only reads the clock twice
+/// and increments the counter of the current CPU.
+#[derive(Debug)]
+pub struct LatencyHistogram {
+    buckets: [LongLongPerCpu; LATENCY_BUCKETS],
+}
+
+impl LatencyHistogram {
+    pub fn new() -> Self {
+        Self {
+            buckets: core::array::from_fn(|_| LongLongPerCpu::new()),
+        }
+    }
+
+    /// Run `f` and count how long it takes
+    #[inline]
+    pub fn measure<R>(&self, f: impl FnOnce() -> R) -> R {
+        let start = Ktime::ktime_get();
+        let r = f();
+        let ns = (Ktime::ktime_get() - start).to_ns();
+        self.buckets[latency_bucket(ns.max(0) as u64)].get_with(|v| *v += 1);
+        r
+    }
+
+    /// Zero all the buckets
+    ///
+    /// The calls in flight are not blocked, an increment racing with the reset on another
+    /// CPU may be lost.
+    pub fn reset(&self) {
+        self.buckets.iter().for_each(|b| b.for_each_cpu(|v| *v = 0));
+    }
+
+    /// The number of calls in each bucket, see
+    /// [LATENCY_BUCKETS_NS](corelib::domain_info::LATENCY_BUCKETS_NS) for the bounds
+    pub fn counts(&self) -> Vec<u64> {
+        self.buckets.iter().map(|b| b.sum() as u64).collect()
+    }
+}
@@ -1,42 +1,204 @@
-use alloc::boxed::Box;
-use core::{any::Any, mem::forget, pin::Pin};
+use alloc::{boxed::Box, vec::Vec};
+use core::{
+    any::Any,
+    mem::forget,
+    pin::Pin,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
 
-use corelib::{LinuxErrno, LinuxResult};
+use corelib::{
+    domain_info::{DomainLoadInfo, MethodCount},
+    LinuxErrno, LinuxError, LinuxResult,
+};
 use interface::{logger::LogDomain, Basic};
 use kernel::{
     init::InPlaceInit,
     sync::{Mutex, SRcuData},
 };
 use rref::RRefVec;
+use tcb_helper::proxy::{CallCounts, LastActive};
 
 use crate::{
-    domain_helper::{free_domain_resource, FreeShared},
+    domain_helper::{free_domain_resource, AllocScope, FreeShared},
     domain_loader::loader::DomainLoader,
-    domain_proxy::ProxyBuilder,
+    domain_proxy::{
+        export_domain_state, invoke_domain, now_ns, reentry, warn_partial_free, watch_crash,
+        LatencyHistogram, ProxyBuilder,
+    },
 };
 
+/// The methods counted by the proxy, the index of a method in [METHODS]
+#[derive(Debug, Clone, Copy)]
+enum Method {
+    Invoke,
+    ExportState,
+    ImportState,
+    Log,
+    SetMaxLevel,
+    OnShutdown,
+    OnMemoryPressure,
+}
+
+const METHODS: [&str; 7] = [
+    "invoke",
+    "export_state",
+    "import_state",
+    "log",
+    "set_max_level",
+    "on_shutdown",
+    "on_memory_pressure",
+];
+
 #[derive(Debug)]
 pub struct LogDomainProxy {
     domain: SRcuData<Box<dyn LogDomain>>,
     domain_loader: Pin<Box<Mutex<DomainLoader>>>,
+    /// The latency of `log` and `set_max_level`, it is kept across the hot upgrades
+    latency: LatencyHistogram,
+    /// The number of the calls by method, it is kept across the hot upgrades
+    calls: CallCounts<7>,
+    /// When the last call was made, it is kept across the hot upgrades
+    last_active: LastActive,
+    /// The generation of the domain, it is increased by every `replace`
+    epoch: AtomicU64,
+    /// The id of the current domain, a call reads it without calling into the domain
+    id: AtomicU64,
+    /// Set by the watchdog when the domain crashed, the calls fail with `EIO` until the
+    /// domain is replaced
+    disabled: AtomicBool,
 }
 
 impl LogDomainProxy {
     pub fn new(domain: Box<dyn LogDomain>, domain_loader: DomainLoader) -> Self {
+        let id = domain.domain_id();
         LogDomainProxy {
             domain: SRcuData::new(domain),
             domain_loader: Box::pin_init(new_mutex!(domain_loader)).unwrap(),
+            latency: LatencyHistogram::new(),
+            calls: CallCounts::new(METHODS),
+            last_active: LastActive::new(now_ns()),
+            epoch: AtomicU64::new(0),
+            id: AtomicU64::new(id),
+            disabled: AtomicBool::new(false),
         }
     }
     pub fn domain_loader(&self) -> DomainLoader {
         self.domain_loader.lock().clone()
     }
+    /// The number of calls in each bucket of the latency histogram
+    pub fn latency(&self) -> Vec<u64> {
+        self.latency.counts()
+    }
+    /// The number of the calls of each method
+    pub fn call_counts(&self) -> Vec<MethodCount> {
+        self.calls.counts()
+    }
+    /// The milliseconds since the last call, or since the proxy was made if there was none
+    pub fn idle_ms(&self) -> u64 {
+        self.last_active.idle_ms(now_ns())
+    }
+    /// Record an activity which does not go through the proxy, so the domain is not idle
+    pub fn touch(&self) {
+        self.last_active.touch(now_ns());
+    }
+    /// Count a call of `method` and record it as the last activity
+    fn record_call(&self, method: Method) {
+        self.calls.count(method as usize);
+        self.last_active.touch(now_ns());
+    }
+    /// Run `f` as a call into the domain, see [reentry::enter], and measure its latency
+    ///
+    /// See [watch_crash] for what happens if it crashes.
+    fn measure<R>(&self, f: impl FnOnce() -> LinuxResult<R>) -> LinuxResult<R> {
+        if self.disabled.load(Ordering::Relaxed) {
+            return Err(LinuxError::EIO);
+        }
+        let id = self.id.load(Ordering::Relaxed);
+        let scope = AllocScope::begin(id);
+        #[cfg(feature = "fault_injection")]
+        crate::domain_proxy::fault::delay(id);
+        let r = self
+            .latency
+            .measure(|| reentry::enter(self as *const Self as usize, f));
+        watch_crash(scope, &self.disabled, r)
+    }
+    /// Zero the statistics of the proxy, the calls in flight are not blocked
+    pub fn reset_metrics(&self) {
+        self.latency.reset();
+        self.calls.reset();
+    }
+    /// The ELF image of the current domain, it changes with `replace`
+    pub fn load_info(&self) -> DomainLoadInfo {
+        self.domain_loader.lock().domain_load_info()
+    }
+    /// Wait until all the callbacks queued on the SRCU of the domain have run, it sleeps
+    pub fn srcu_barrier(&self) -> LinuxResult<()> {
+        self.domain.barrier().map_err(|_| LinuxError::EDEADLK)
+    }
+    /// Swap the domain for [LogDomainEmptyImpl] without sleeping, busy-waiting at most
+    /// `timeout_ms` milliseconds for the grace period of its readers, or return `ETIMEDOUT`
+    ///
+    /// The old domain is forgotten like in [Self::replace], its loader frees it.
+    pub fn retire_no_sleep(&self, timeout_ms: u64) -> LinuxResult<()> {
+        let old_domain = self
+            .domain
+            .update_no_sleep(Box::new(LogDomainEmptyImpl::new()), timeout_ms)
+            .map_err(|_| LinuxError::ETIMEDOUT)?;
+        forget(Box::into_inner(old_domain));
+        Ok(())
+    }
 }
 
 impl Basic for LogDomainProxy {
     fn domain_id(&self) -> u64 {
         self.domain.read(|domain| domain.domain_id())
     }
+
+    fn on_memory_pressure(&self, level: u8) -> LinuxResult<usize> {
+        self.record_call(Method::OnMemoryPressure);
+        self.measure(|| self.domain.read(|domain| domain.on_memory_pressure(level)))
+    }
+
+    fn on_shutdown(&self) -> LinuxResult<()> {
+        self.record_call(Method::OnShutdown);
+        self.measure(|| self.domain.read(|domain| domain.on_shutdown()))
+    }
+
+    fn epoch(&self) -> u64 {
+        self.epoch.load(Ordering::Acquire)
+    }
+
+    fn invoke(&self, op: u32, buf: RRefVec<u8>) -> LinuxResult<RRefVec<u8>> {
+        self.record_call(Method::Invoke);
+        self.measure(|| {
+            self.domain
+                .read(|domain| invoke_domain(domain.as_ref(), op, buf, None))
+        })
+    }
+
+    fn invoke_interruptible(
+        &self,
+        op: u32,
+        buf: RRefVec<u8>,
+        call_id: u64,
+    ) -> LinuxResult<RRefVec<u8>> {
+        self.record_call(Method::Invoke);
+        self.measure(|| {
+            self.domain
+                .read(|domain| invoke_domain(domain.as_ref(), op, buf, Some(call_id)))
+        })
+    }
+
+    fn export_state(&self) -> LinuxResult<RRefVec<u8>> {
+        self.record_call(Method::ExportState);
+        self.domain
+            .read(|domain| export_domain_state(domain.as_ref()))
+    }
+
+    fn import_state(&self, state: &RRefVec<u8>) -> LinuxResult<()> {
+        self.record_call(Method::ImportState);
+        self.domain.read(|domain| domain.import_state(state))
+    }
 }
 
 impl LogDomain for LogDomainProxy {
@@ -45,30 +207,55 @@ impl LogDomain for LogDomainProxy {
     }
 
     fn log(&self, level: interface::logger::Level, msg: &RRefVec<u8>) -> LinuxResult<()> {
-        self.domain.read(|domain| domain.log(level, msg))
+        self.record_call(Method::Log);
+        self.measure(|| self.domain.read(|domain| domain.log(level, msg)))
     }
 
     fn set_max_level(&self, level: interface::logger::LevelFilter) -> LinuxResult<()> {
-        self.domain.read(|domain| domain.set_max_level(level))
+        self.record_call(Method::SetMaxLevel);
+        self.measure(|| self.domain.read(|domain| domain.set_max_level(level)))
     }
 }
 
 impl LogDomainProxy {
+    /// Replace the domain with `new_domain`.
+    ///
+    /// The readers are waited by the SRCU, so the number of drain iterations is always 0.
     pub fn replace(
         &self,
         new_domain: Box<dyn LogDomain>,
         domain_loader: DomainLoader,
-    ) -> LinuxResult<()> {
+    ) -> LinuxResult<usize> {
         let mut loader_guard = self.domain_loader.lock();
         let old_id = self.domain_id();
         // init new domain
         new_domain.init().unwrap();
         // swap domain
+        let new_id = new_domain.domain_id();
         let old_domain = self.domain.update(new_domain);
+        self.id.store(new_id, Ordering::Relaxed);
+        self.epoch.fetch_add(1, Ordering::Release);
+        // the new domain has not crashed, undo the watchdog
+        self.disabled.store(false, Ordering::Relaxed);
         // free old domain
         let real_domain = Box::into_inner(old_domain);
         forget(real_domain);
-        free_domain_resource(old_id, FreeShared::Free);
+        let res = free_domain_resource(old_id, FreeShared::Free);
+        warn_partial_free(old_id, res);
+        *loader_guard = domain_loader;
+        Ok(0)
+    }
+
+    /// Replace the loader with `domain_loader` without replacing the domain.
+    ///
+    /// The new loader must not be loaded, it takes over the image of the running domain, e.g.
+    /// to attach an ELF with richer debug info. The calls are not blocked. Return `EINVAL` if
+    /// the new loader is loaded.
+    pub fn replace_loader_only(&self, mut domain_loader: DomainLoader) -> LinuxResult<()> {
+        let mut loader_guard = self.domain_loader.lock();
+        domain_loader
+            .take_over(&mut loader_guard)
+            .map_err(|_| LinuxError::EINVAL)?;
         *loader_guard = domain_loader;
         Ok(())
     }
@@ -1,18 +1,285 @@
+//! The proxies of the domains.
+//!
+//! # Lock order
+//!
+//! A proxy has two locks: `domain_loader` which serializes `replace`/`freeze`, and `lock`
+//! which is the writer lock of the lock path. When both are needed, `domain_loader` must be
+//! taken first. The calls on the lock path only take `lock`, and `thaw` takes neither
+//! because `lock` may still be held by the task which called `freeze`. The functions which
+//! take `domain_loader` assert with lockdep that `lock` is not held by the current task.
 use alloc::boxed::Box;
-use core::any::Any;
+use core::{
+    any::Any,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
 
-use corelib::LinuxResult;
+use corelib::{domain_info::PanicAction, LinuxError, LinuxResult};
+use interface::Basic;
+use kernel::{
+    sync::LongLongPerCpu,
+    time::{ktime_ms_delta, Ktime},
+};
+use rref::{RRefVec, SharedData};
+use tcb_helper::proxy::{wait_until, RateLimiter};
 
-use crate::domain_loader::loader::DomainLoader;
+use crate::{
+    domain_helper::{
+        domain_is_live, end_unwind, on_domain_panic, schedule_restart, AllocScope, FreeReport,
+    },
+    domain_loader::loader::DomainLoader,
+};
 
 pub mod block_device;
+mod dispatch;
 pub mod empty_device;
+#[cfg(feature = "fault_injection")]
+mod fault;
+mod latency;
 pub mod logger;
+mod reentry;
+
+pub use dispatch::{with_proxy, with_proxy_by_id, Proxy};
+#[cfg(feature = "fault_injection")]
+pub use fault::inject_latency;
+pub use latency::LatencyHistogram;
 
 pub trait ProxyBuilder {
     type T;
     fn build(domain: Self::T, domain_loader: DomainLoader) -> Self;
+    /// Build the proxy of a domain which is never upgraded, see
+    /// [crate::config::PINNED_DOMAINS]. A proxy without a fast path builds a normal one.
+    fn build_no_upgrade(domain: Self::T, domain_loader: DomainLoader) -> Self
+    where
+        Self: Sized,
+    {
+        Self::build(domain, domain_loader)
+    }
     fn build_empty(domain_loader: DomainLoader) -> Self;
     fn build_empty_no_proxy() -> Self::T;
     fn init_by_box(&self, argv: Box<dyn Any + Send + Sync>) -> LinuxResult<()>;
 }
+
+/// Poll `done` every millisecond until it holds, or return `ETIMEDOUT` after `timeout_ms`
+/// milliseconds, see [wait_until]
+///
+/// It sleeps between the polls, so it must be called in process context without a spinlock
+/// held.
+fn wait_for(timeout_ms: u64, done: impl FnMut() -> bool) -> LinuxResult<()> {
+    let start = Ktime::ktime_get();
+    wait_until(
+        timeout_ms,
+        || ktime_ms_delta(Ktime::ktime_get(), start) as u64,
+        || kernel::time::msleep(1),
+        done,
+    )
+}
+
+/// Wait until the reader `counter` of a proxy drains, or return `ETIMEDOUT` after
+/// `timeout_ms` milliseconds. Return the number of the polls which found a reader in flight.
+///
+/// A reader may increment the counter of one CPU and decrement the counter of another one
+/// after it migrates, and the sum reads the CPUs one after another. The sum is therefore
+/// transiently negative when the decrement of a reader is read but not its increment, and
+/// waiting for exactly 0 could spin forever. Such a reader has already returned, so a
+/// negative sum means the same as 0.
+///
+/// Only the calls on the no-lock path are counted, and new calls are not blocked unless the
+/// caller enabled the lock path, so the domain may be busy again as soon as this returns.
+fn wait_quiescent(counter: &LongLongPerCpu, timeout_ms: u64) -> LinuxResult<usize> {
+    let mut polls = 0;
+    wait_for(timeout_ms, || {
+        let drained = counter.sum() <= 0;
+        if !drained {
+            polls += 1;
+        }
+        drained
+    })?;
+    Ok(polls)
+}
+
+/// Like [wait_quiescent], but spin between the polls, so it never sleeps
+fn spin_quiescent(counter: &LongLongPerCpu, timeout_ms: u64) -> LinuxResult<()> {
+    let start = Ktime::ktime_get();
+    wait_until(
+        timeout_ms,
+        || ktime_ms_delta(Ktime::ktime_get(), start) as u64,
+        core::hint::spin_loop,
+        || counter.sum() <= 0,
+    )
+}
+
+/// Wait until the domain behind a proxy is `ready`, or return `ETIMEDOUT` after
+/// `timeout_ms` milliseconds.
+fn wait_ready(ready: &AtomicBool, timeout_ms: u64) -> LinuxResult<()> {
+    wait_for(timeout_ms, || ready.load(Ordering::Acquire))
+}
+
+/// The current time in nanoseconds, the clock of [LastActive]
+fn now_ns() -> u64 {
+    Ktime::ktime_get().to_ns() as u64
+}
+
+/// Take a token of `limiter` for a call submitted to the domain, return `EAGAIN` if it has
+/// none, see `sys_set_domain_rate_limit`
+///
+/// It is lock-free, the submissions may run in interrupt context.
+#[inline]
+fn check_rate_limit(limiter: &RateLimiter) -> LinuxResult<()> {
+    if !limiter.is_limited() || limiter.try_take(now_ns()) {
+        Ok(())
+    } else {
+        Err(LinuxError::EAGAIN)
+    }
+}
+
+/// Check that the domain of the proxy `proxy` is ready before a call into it
+///
+/// A call before the domain is ready is refused with `EAGAIN` and counted in
+/// `unready_calls`, as it is usually a bug of the caller, which uses the domain before it is
+/// loaded. The warning is throttled to the counts which are a power of two.
+#[inline]
+fn check_ready(proxy: &str, ready: &AtomicBool, unready_calls: &AtomicU64) -> LinuxResult<()> {
+    if ready.load(Ordering::Acquire) {
+        return Ok(());
+    }
+    let n = unready_calls.fetch_add(1, Ordering::Relaxed) + 1;
+    if n.is_power_of_two() {
+        warn!("{}: {} calls before the domain is ready", proxy, n);
+    }
+    Err(LinuxError::EAGAIN)
+}
+
+/// Warn if shared data is being moved to `domain_id` which is not a live domain.
+///
+/// It takes the [DOMAIN_INFO](crate::domain_helper::DOMAIN_INFO) lock, so it is only
+/// checked in debug builds and only on the migration paths of the proxies, never in
+/// `move_to` itself.
+#[inline]
+fn check_move_target(domain_id: u64) {
+    if cfg!(debug_assertions) && !domain_is_live(domain_id) {
+        warn!(
+            "shared data is moved to domain {} which does not exist",
+            domain_id
+        );
+    }
+}
+
+/// Check that the data returned by the domain `domain_id` is still owned by it.
+///
+/// A domain could hand over the data of another domain by forging its owner, and moving
+/// that data back to the caller would cross the isolation boundary. Dropping it would free
+/// the data the other domain still uses, so it is forgotten and left to its owner, and
+/// `EPROTO` is returned.
+fn check_return_owner(domain_id: u64, r: RRefVec<u8>) -> LinuxResult<RRefVec<u8>> {
+    let owner = r.domain_id();
+    if owner != domain_id {
+        warn!(
+            "domain {} returned data owned by domain {}, reject it",
+            domain_id, owner
+        );
+        core::mem::forget(r);
+        return Err(LinuxError::EPROTO);
+    }
+    Ok(r)
+}
+
+/// Call `invoke` of `domain` with `buf` moved to it, and move the result back to the owner
+/// of `buf`. With a `call_id`, `invoke_interruptible` is called instead.
+///
+/// The ownership is migrated like the `read` of the empty device proxy, see
+/// [RRefVec::call_moved], which also rejects a result not owned by the domain.
+fn invoke_domain<D: Basic + ?Sized>(
+    domain: &D,
+    op: u32,
+    buf: RRefVec<u8>,
+    call_id: Option<u64>,
+) -> LinuxResult<RRefVec<u8>> {
+    let id = domain.domain_id();
+    check_move_target(id);
+    let old_id = buf.domain_id();
+    let res = buf.call_moved(id, |buf| match call_id {
+        Some(call_id) => domain.invoke_interruptible(op, buf, call_id),
+        None => domain.invoke(op, buf),
+    });
+    check_move_target(old_id);
+    res
+}
+
+/// Call `export_state` of `domain`, and move the state to the kernel.
+///
+/// The state must be owned by the domain, see [check_return_owner].
+fn export_domain_state<D: Basic + ?Sized>(domain: &D) -> LinuxResult<RRefVec<u8>> {
+    let id = domain.domain_id();
+    let r = check_return_owner(id, domain.export_state()?)?;
+    r.move_to(rref::domain_id());
+    Ok(r)
+}
+
+/// Log the resources of the old domain which could not be freed by `replace`.
+fn warn_partial_free(old_id: u64, res: LinuxResult<FreeReport>) {
+    match res {
+        Ok(report) if !report.is_complete() => warn!(
+            "domain {}: freed {} pages, leaked pages {:?}",
+            old_id, report.pages, report.leaked_pages
+        ),
+        Ok(_) => {}
+        // the old domain is an empty domain which has no resources
+        Err(LinuxError::EINVAL) if old_id == u64::MAX => {}
+        Err(e) => warn!("domain {}: failed to free resources: {:?}", old_id, e),
+    }
+}
+
+/// Apply the watchdog and panic policies of the domain if its call returned `DOMAINCRASH`.
+///
+/// `scope` must begin before the call, so that a call which crashed an instance that has
+/// already been restarted does not restart the new one. The restart is deferred to the
+/// system workqueue, see [schedule_restart], and the calls which crash while it is pending
+/// do not restart the domain again. The unwinding of the task is over, so the RRefs whose
+/// drop was deferred while unwinding are freed first. Then, if the domain is restarted or
+/// disabled, the shared heap allocations the crashed call made and did not return are
+/// reclaimed, see [PanicAction::reclaims_call_allocations], so none of them is freed
+/// twice. A disabled proxy sets `disabled` and fails all the later calls with `EIO`.
+fn watch_crash<R>(scope: AllocScope, disabled: &AtomicBool, res: LinuxResult<R>) -> LinuxResult<R> {
+    if !matches!(res, Err(LinuxError::DOMAINCRASH)) {
+        return res;
+    }
+    let domain_id = scope.domain_id();
+    end_unwind();
+    let deferred = rref::process_deferred_dealloc();
+    if deferred != 0 {
+        warn!(
+            "domain {}: freed {} RRefs dropped while unwinding",
+            domain_id, deferred
+        );
+    }
+    let action = on_domain_panic(domain_id);
+    if action
+        .as_ref()
+        .is_some_and(|(action, _)| action.reclaims_call_allocations())
+    {
+        let reclaimed = scope.reclaim();
+        if reclaimed != 0 {
+            warn!(
+                "domain {}: freed {} RRefs left by the crashed call",
+                domain_id, reclaimed
+            );
+        }
+    }
+    match action {
+        Some((PanicAction::Restart, name)) => {
+            warn!("domain {}: panicked, restart it", name);
+            schedule_restart(name);
+        }
+        Some((PanicAction::Disable, name)) => {
+            warn!("domain {}: panicked, disable it", name);
+            disabled.store(true, Ordering::Relaxed);
+        }
+        Some((PanicAction::Abort, name)) => {
+            warn!("domain {}: panicked, abort the kernel", name);
+            let msg = c_str!("domain panicked, aborted by its panic policy\n");
+            unsafe { kernel::bindings::panic(msg.as_char_ptr()) };
+        }
+        Some((PanicAction::Ignore, _)) | None => {}
+    }
+    res
+}
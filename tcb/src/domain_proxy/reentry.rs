@@ -0,0 +1,55 @@
+//! Detect a domain calling back into itself through its own proxy.
+//!
+//! Reentrancy is not supported: a nested call would increase the per-CPU reader
+//! counter twice and nest the SRCU read section, and on the lock path it would
+//! try to take the proxy lock it already holds, so it can deadlock against
+//! `replace`. Such a call fails with `EDEADLK` instead.
+//!
+//! Calls through different proxies may still nest, but no deeper than
+//! [MAX_DOMAIN_CALL_DEPTH] on one task, a deeper call fails with `ELOOP` so a
+//! pathological domain graph cannot overflow the kernel stack.
+//!
+//! The proxies every task is calling through are recorded in [TASK_CALLS], keyed
+//! by the task, so a task which sleeps and migrates in a call is still tracked.
+//! A call in interrupt context runs on behalf of no task and on its own stack, it
+//! is not tracked.
+use corelib::LinuxResult;
+use tcb_helper::proxy::TaskCalls;
+
+use crate::config::{MAX_DOMAIN_CALL_DEPTH, MAX_DOMAIN_CALL_TASKS};
+
+/// The proxies the tasks in a domain call are calling through
+static TASK_CALLS: TaskCalls<MAX_DOMAIN_CALL_TASKS, MAX_DOMAIN_CALL_DEPTH> = TaskCalls::new();
+
+/// The current task, `None` in interrupt context
+fn current_task() -> Option<usize> {
+    if unsafe { kernel::bindings::in_task() } == 0 {
+        return None;
+    }
+    Some(unsafe { kernel::bindings::get_current() } as usize)
+}
+
+/// Run `f` as a call into the domain through the proxy at `proxy`.
+///
+/// Return `EDEADLK` without running `f` if the current task is already in a call
+/// through the same proxy, and `ELOOP` if its calls are already nested
+/// [MAX_DOMAIN_CALL_DEPTH] deep. If [MAX_DOMAIN_CALL_TASKS] tasks are already in
+/// domain calls, the call is not checked.
+pub fn enter<R>(proxy: usize, f: impl FnOnce() -> LinuxResult<R>) -> LinuxResult<R> {
+    match current_task() {
+        Some(task) => TASK_CALLS.call(task, proxy, f),
+        None => f(),
+    }
+}
+
+/// Like [enter], but the current task may call through `proxy` again in `f`.
+///
+/// The block layer calls back into the block device in its calls, e.g. a request
+/// completed in `queue_rq` is completed through the proxy inline, so only the
+/// depth is checked.
+pub fn enter_reentrant<R>(proxy: usize, f: impl FnOnce() -> LinuxResult<R>) -> LinuxResult<R> {
+    match current_task() {
+        Some(task) => TASK_CALLS.call_reentrant(task, proxy, f),
+        None => f(),
+    }
+}
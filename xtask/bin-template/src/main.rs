@@ -9,11 +9,10 @@ use core::panic::PanicInfo;
 
 use basic::println;
 use corelib::CoreFunction;
-use interface::{INTERFACE};
+use interface::INTERFACE;
 use rref::{domain_id, SharedHeapAlloc};
 use storage::StorageArg;
 
-
 #[no_mangle]
 fn main(
     sys: &'static dyn CoreFunction,
@@ -36,6 +35,10 @@ fn main(
     DOMAIN_NAME::main()
 }
 
+#[used]
+#[link_section = ".domain_interface"]
+static DOMAIN_INTERFACE_VERSION: u32 = interface::INTERFACE_VERSION;
+
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     if let Some(p) = info.location() {